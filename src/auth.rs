@@ -1,25 +1,67 @@
 use crate::error::ImageError;
 use crate::limiter::ApiKeyRateLimiter;
-use crate::models::ApiKey;
+use crate::models::{ApiKey, ApiKeyScope};
 use crate::store::ImageStore;
+use futures_util::future::BoxFuture;
 use std::sync::Arc;
 use time::OffsetDateTime;
 use tracing::warn;
 use warp::{Filter, Rejection};
 
+/// Resolves bearer tokens other than the admin key to the key they stand
+/// for. `Ok(None)` means the token is unknown to this validator and the next
+/// one is asked.
+pub trait KeyValidator: Send + Sync {
+    fn validate<'a>(&'a self, token: &'a str) -> BoxFuture<'a, Result<Option<ApiKey>, ImageError>>;
+}
+
+/// Keys created through `/api-keys` and stored in SQLite.
+pub struct LocalKeys {
+    store: ImageStore,
+}
+
+impl LocalKeys {
+    pub fn new(store: ImageStore) -> Self {
+        Self { store }
+    }
+}
+
+impl KeyValidator for LocalKeys {
+    fn validate<'a>(&'a self, token: &'a str) -> BoxFuture<'a, Result<Option<ApiKey>, ImageError>> {
+        Box::pin(async move { Ok(self.store.get_api_key(token).ok()) })
+    }
+}
+
+/// The token of a `Bearer` Authorization header. The scheme is matched
+/// case-insensitively, as HTTP auth schemes are.
+pub(crate) fn bearer_token(header: &str) -> Option<&str> {
+    let (scheme, token) = header.split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("Bearer")
+        .then_some(token.trim())
+}
+
 #[derive(Clone)]
 pub struct Auth {
     admin_key: Arc<String>,
     store: ImageStore,
     rate_limiter: ApiKeyRateLimiter,
+    validators: Arc<Vec<Box<dyn KeyValidator>>>,
 }
 
 impl Auth {
-    pub fn new(admin_key: String, store: ImageStore, rate_limiter: ApiKeyRateLimiter) -> Self {
+    /// Tokens are tried against `validators` in order.
+    pub fn new(
+        admin_key: String,
+        store: ImageStore,
+        rate_limiter: ApiKeyRateLimiter,
+        validators: Vec<Box<dyn KeyValidator>>,
+    ) -> Self {
         Self {
             admin_key: Arc::new(admin_key),
             store,
             rate_limiter,
+            validators: Arc::new(validators),
         }
     }
 
@@ -27,56 +69,75 @@ impl Auth {
         key.chars().take(8).collect::<String>() + "..."
     }
 
-    pub async fn check_api_key(&self, auth_header: Option<String>) -> Result<(), Rejection> {
+    /// The key in a `Bearer` Authorization header. A header using another
+    /// scheme gets its own error, so the client can tell what's wrong.
+    fn bearer_key(auth_header: Option<&str>) -> Result<&str, Rejection> {
         match auth_header {
-            Some(header) if header.starts_with("Bearer ") => {
-                let key = header.trim_start_matches("Bearer ").trim();
+            Some(header) => bearer_token(header)
+                .ok_or_else(|| warp::reject::custom(ImageError::InvalidAuthScheme)),
+            None => Err(warp::reject::custom(ImageError::Unauthorized)),
+        }
+    }
 
-                // admin is almighty and we don't track usage
-                if key == self.admin_key.as_str() {
-                    return Ok(());
-                }
+    pub async fn check_api_key(&self, auth_header: Option<String>) -> Result<(), Rejection> {
+        let key = Self::bearer_key(auth_header.as_deref())?;
 
-                if !self.rate_limiter.check_rate_limit(key).await {
-                    warn!(
-                        api_key = %Self::truncate_key(key),
-                        "Rate limit exceeded for API key"
-                    );
-                    return Err(warp::reject::custom(ImageError::RateLimitExceeded));
-                }
+        // admin is almighty and we don't track usage
+        if key == self.admin_key.as_str() {
+            return Ok(());
+        }
 
-                if let Err(e) = self.store.update_key_last_used(key) {
-                    warn!(
-                        api_key = %Self::truncate_key(key),
-                        error = %e,
-                        "Failed to update last_used_at timestamp"
-                    );
-                }
+        self.authenticate(key).await.map(|_| ())
+    }
 
-                match self.store.validate_api_key(key) {
-                    Ok(true) => Ok(()),
-                    Ok(false) => Err(warp::reject::custom(ImageError::Unauthorized)),
-                    Err(e) if e.to_string().contains("inactive_key") => {
-                        Err(warp::reject::custom(ImageError::InactiveKey))
-                    }
-                    Err(_) => Err(warp::reject::custom(ImageError::Unauthorized)),
-                }
+    /// Resolves a non-admin token through the validators, then applies the
+    /// resolved key's rate limit. Unknown tokens count against the default
+    /// limit before being rejected.
+    async fn authenticate(&self, key: &str) -> Result<ApiKey, Rejection> {
+        let mut resolved = None;
+        for validator in self.validators.iter() {
+            if let Some(api_key) = validator
+                .validate(key)
+                .await
+                .map_err(warp::reject::custom)?
+            {
+                resolved = Some(api_key);
+                break;
             }
-            _ => Err(warp::reject::custom(ImageError::Unauthorized)),
+        }
+
+        if !self
+            .rate_limiter
+            .check_rate_limit(key, resolved.as_ref())
+            .await
+        {
+            warn!(
+                api_key = %Self::truncate_key(key),
+                "Rate limit exceeded for API key"
+            );
+            return Err(warp::reject::custom(ImageError::RateLimitExceeded));
+        }
+
+        if let Err(e) = self.store.update_key_last_used(key) {
+            warn!(
+                api_key = %Self::truncate_key(key),
+                error = %e,
+                "Failed to update last_used_at timestamp"
+            );
+        }
+
+        match resolved {
+            Some(api_key) if api_key.is_active => Ok(api_key),
+            Some(_) => Err(warp::reject::custom(ImageError::InactiveKey)),
+            None => Err(warp::reject::custom(ImageError::Unauthorized)),
         }
     }
 
     pub fn check_admin(&self, auth_header: Option<String>) -> Result<(), Rejection> {
-        match auth_header {
-            Some(header) if header.starts_with("Bearer ") => {
-                let key = header.trim_start_matches("Bearer ").trim();
-                if key == self.admin_key.as_str() {
-                    Ok(())
-                } else {
-                    Err(warp::reject::custom(ImageError::Unauthorized))
-                }
-            }
-            _ => Err(warp::reject::custom(ImageError::Unauthorized)),
+        if Self::bearer_key(auth_header.as_deref())? == self.admin_key.as_str() {
+            Ok(())
+        } else {
+            Err(warp::reject::custom(ImageError::Unauthorized))
         }
     }
 
@@ -101,47 +162,78 @@ impl Auth {
         warp::header::optional::<String>("authorization").and_then(move |header: Option<String>| {
             let auth = auth.clone();
             async move {
-                match header {
-                    Some(header) if header.starts_with("Bearer ") => {
-                        let key = header.trim_start_matches("Bearer ").trim();
-
-                        // Check if it's the admin key
-                        if key == auth.admin_key.as_str() {
-                            return Ok(ApiKey {
-                                key: key.to_string(),
-                                username: "admin".to_string(),
-                                created_at: OffsetDateTime::now_utc(),
-                                last_used_at: None,
-                                is_active: true,
-                                requests_per_second: None, // unlimited
-                                max_batch_size: None,      // unlimited
-                            });
-                        }
-
-                        // Check rate limit
-                        if !auth.rate_limiter.check_rate_limit(key).await {
-                            return Err(warp::reject::custom(ImageError::RateLimitExceeded));
-                        }
-
-                        // Update last used timestamp
-                        if let Err(e) = auth.store.update_key_last_used(key) {
-                            warn!(
-                                api_key = %Self::truncate_key(key),
-                                error = %e,
-                                "Failed to update last_used_at timestamp"
-                            );
-                        }
-
-                        // Get API key info
-                        match auth.store.get_api_key(key) {
-                            Ok(api_key) if api_key.is_active => Ok(api_key),
-                            Ok(_) => Err(warp::reject::custom(ImageError::InactiveKey)),
-                            Err(_) => Err(warp::reject::custom(ImageError::Unauthorized)),
-                        }
-                    }
-                    _ => Err(warp::reject::custom(ImageError::Unauthorized)),
+                let key = Self::bearer_key(header.as_deref())?;
+
+                // Check if it's the admin key
+                if key == auth.admin_key.as_str() {
+                    return Ok(ApiKey {
+                        key: key.to_string(),
+                        username: "admin".to_string(),
+                        created_at: OffsetDateTime::now_utc(),
+                        last_used_at: None,
+                        is_active: true,
+                        requests_per_second: None, // unlimited
+                        max_batch_size: None,      // unlimited
+                        default_filters: None,
+                        scopes: ApiKeyScope::ALL.to_vec(),
+                        url_ingest_daily_bytes: None,
+                        allowed_tags: Vec::new(),
+                        is_admin: true,
+                    });
                 }
+
+                auth.authenticate(key).await
             }
         })
     }
+
+    /// Like `require_auth_info`, but the key must also hold `scope`.
+    pub fn require_scope(
+        &self,
+        scope: ApiKeyScope,
+    ) -> impl Filter<Extract = (ApiKey,), Error = Rejection> + Clone {
+        self.require_auth_info()
+            .and_then(move |api_key: ApiKey| async move {
+                if api_key.has_scope(scope) {
+                    Ok(api_key)
+                } else {
+                    Err(warp::reject::custom(ImageError::MissingScope(scope)))
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_of(auth_header: Option<&str>) -> Option<&'static str> {
+        Auth::bearer_key(auth_header)
+            .err()
+            .map(|rejection| match rejection.find::<ImageError>() {
+                Some(ImageError::InvalidAuthScheme) => "scheme",
+                Some(ImageError::Unauthorized) => "unauthorized",
+                other => panic!("unexpected rejection {:?}", other),
+            })
+    }
+
+    #[test]
+    fn bearer_keys_are_read_whatever_the_scheme_case() {
+        for header in ["Bearer key-1", "bearer key-1", "BEARER  key-1 "] {
+            assert_eq!(
+                Auth::bearer_key(Some(header)).ok(),
+                Some("key-1"),
+                "{}",
+                header
+            );
+        }
+    }
+
+    #[test]
+    fn other_schemes_get_their_own_error() {
+        assert_eq!(error_of(Some("Basic dXNlcjpwYXNz")), Some("scheme"));
+        assert_eq!(error_of(Some("key-1")), Some("scheme"));
+        assert_eq!(error_of(Some("Bearerkey-1")), Some("scheme"));
+        assert_eq!(error_of(None), Some("unauthorized"));
+    }
 }
@@ -0,0 +1,132 @@
+use serde::{Deserialize, Deserializer};
+
+const UNITS: [(&str, u64); 13] = [
+    ("b", 1),
+    ("k", 1000),
+    ("kb", 1000),
+    ("kib", 1 << 10),
+    ("m", 1000 * 1000),
+    ("mb", 1000 * 1000),
+    ("mib", 1 << 20),
+    ("g", 1000 * 1000 * 1000),
+    ("gb", 1000 * 1000 * 1000),
+    ("gib", 1 << 30),
+    ("t", 1000 * 1000 * 1000 * 1000),
+    ("tb", 1000 * 1000 * 1000 * 1000),
+    ("tib", 1 << 40),
+];
+
+/// Parses a byte count such as `1048576`, `500k`, `1.5MB` or `2GiB`. Units
+/// are case-insensitive; `k`/`kb` are decimal and `kib` is binary.
+pub fn parse(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    if let Ok(bytes) = value.parse::<u64>() {
+        return Ok(bytes);
+    }
+
+    let invalid = || {
+        format!(
+            "'{}' is not a size, use bytes or e.g. 500k, 1.5MB, 2GiB",
+            value
+        )
+    };
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .ok_or_else(invalid)?;
+    let (number, unit) = value.split_at(split);
+    let unit = unit.trim().to_lowercase();
+    let multiplier = UNITS
+        .iter()
+        .find(|(name, _)| *name == unit)
+        .map(|(_, multiplier)| *multiplier)
+        .ok_or_else(invalid)?;
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+
+    let bytes = (number * multiplier as f64).round();
+    // u64::MAX rounds up to 2^64 as an f64, which doesn't fit
+    if !bytes.is_finite() || bytes >= u64::MAX as f64 {
+        return Err(format!("'{}' is too large", value));
+    }
+    Ok(bytes as u64)
+}
+
+/// Renders a byte count with binary units, e.g. `1.5 MiB`.
+pub fn format(bytes: u64) -> String {
+    const STEPS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64;
+    let mut unit = "B";
+    for step in STEPS {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = step;
+    }
+    format!("{:.1} {}", value, unit)
+}
+
+/// For optional size fields in request bodies, which take either a number
+/// of bytes or a string accepted by [`parse`].
+pub fn deserialize_opt<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+
+    match Option::<Size>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Size::Bytes(bytes)) => Ok(Some(bytes)),
+        Some(Size::Text(text)) => parse(&text).map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+/// Like [`deserialize_opt`], for fields where an explicit `null` means
+/// something different from leaving the field out.
+pub fn deserialize_nullable<'de, D>(deserializer: D) -> Result<Option<Option<u64>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_opt(deserializer).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_plain_bytes_and_units() {
+        assert_eq!(parse("1048576"), Ok(1_048_576));
+        assert_eq!(parse("500k"), Ok(500_000));
+        assert_eq!(parse("1.5MB"), Ok(1_500_000));
+        assert_eq!(parse("2GiB"), Ok(2 << 30));
+        assert_eq!(parse(" 3 kib "), Ok(3 * 1024));
+        assert_eq!(parse("1.5mib"), Ok(3 << 19));
+    }
+
+    #[test]
+    fn refuses_unknown_units_and_junk() {
+        for value in ["", "10 bytes", "5x", "mb", "1.2.3k", "-1k", "k5"] {
+            let error = parse(value).unwrap_err();
+            assert!(error.contains("is not a size"), "{}: {}", value, error);
+        }
+    }
+
+    #[test]
+    fn refuses_sizes_past_u64() {
+        assert_eq!(parse("16777215TiB"), Ok(16_777_215 << 40));
+        let huge = format!("1{}k", "0".repeat(400));
+        for value in ["16777216TiB", "99999999999999TB", huge.as_str()] {
+            let error = parse(value).unwrap_err();
+            assert!(error.contains("too large"), "{}: {}", value, error);
+        }
+        assert!(parse("18446744073709551616").is_err());
+    }
+}
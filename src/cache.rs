@@ -1,30 +1,183 @@
 use crate::models::ImageResponse;
 use moka::future::Cache;
+use moka::Expiry;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+#[derive(Clone)]
+struct CachedImage {
+    response: ImageResponse,
+    ttl: Duration,
+}
+
+/// Expires each entry after the TTL it was inserted with.
+struct PerEntryTtl;
+
+impl Expiry<String, CachedImage> for PerEntryTtl {
+    fn expire_after_create(
+        &self,
+        _key: &String,
+        value: &CachedImage,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        Some(value.ttl)
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &String,
+        value: &CachedImage,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(value.ttl)
+    }
+}
 
 #[derive(Clone)]
 pub struct ImageCache {
-    cache: Arc<Cache<String, ImageResponse>>,
+    cache: Arc<Cache<String, CachedImage>>,
+    ttl: Duration,
+    random_ttl: Duration,
 }
 
 impl ImageCache {
-    pub fn new(max_capacity: usize, ttl: Duration) -> Self {
+    /// `random_ttl` applies to entries warmed by the random endpoints; zero
+    /// keeps random results out of the cache entirely.
+    pub fn new(max_capacity: usize, ttl: Duration, random_ttl: Duration) -> Self {
         let cache = Cache::builder()
             .max_capacity(max_capacity as u64)
-            .time_to_live(ttl)
+            .expire_after(PerEntryTtl)
             .build();
 
         Self {
             cache: Arc::new(cache),
+            ttl,
+            random_ttl,
         }
     }
 
     pub async fn get(&self, key: &str) -> Option<ImageResponse> {
-        self.cache.get(key).await
+        self.cache.get(key).await.map(|cached| cached.response)
     }
 
     pub async fn insert(&self, key: String, value: ImageResponse) {
-        self.cache.insert(key, value).await;
+        self.insert_with_ttl(key, value, self.ttl).await;
+    }
+
+    pub async fn insert_random(&self, key: String, value: ImageResponse) {
+        self.insert_with_ttl(key, value, self.random_ttl).await;
+    }
+
+    async fn insert_with_ttl(&self, key: String, value: ImageResponse, ttl: Duration) {
+        if ttl.is_zero() {
+            return;
+        }
+        self.cache
+            .insert(
+                key,
+                CachedImage {
+                    response: value,
+                    ttl,
+                },
+            )
+            .await;
+    }
+
+    pub async fn invalidate(&self, key: &str) {
+        self.cache.invalidate(key).await;
+    }
+
+    /// For changes that touch more images than are worth tracking one by one.
+    pub fn invalidate_all(&self) {
+        self.cache.invalidate_all();
+    }
+}
+
+/// Tag lists per image hash, so building image responses doesn't join
+/// `image_tags` and `tags` on every read. The store invalidates a hash
+/// whenever that image's tags change.
+#[derive(Clone)]
+pub struct TagCache {
+    cache: Option<moka::sync::Cache<String, Vec<String>>>,
+}
+
+impl TagCache {
+    /// A `max_capacity` of zero disables the cache.
+    pub fn new(max_capacity: usize, ttl: Duration) -> Self {
+        let cache = (max_capacity > 0).then(|| {
+            moka::sync::Cache::builder()
+                .max_capacity(max_capacity as u64)
+                .time_to_live(ttl)
+                .build()
+        });
+        Self { cache }
+    }
+
+    pub fn get(&self, hash: &str) -> Option<Vec<String>> {
+        self.cache.as_ref()?.get(hash)
+    }
+
+    pub fn insert(&self, hash: &str, tags: &[String]) {
+        if let Some(cache) = &self.cache {
+            cache.insert(hash.to_string(), tags.to_vec());
+        }
+    }
+
+    pub fn invalidate(&self, hash: &str) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{add_png, config, temp_store};
+
+    #[test]
+    fn random_cache_ttl_defaults_to_cache_ttl() {
+        let config_with = |args: &[&str]| config(args).random_cache_ttl();
+        assert_eq!(
+            config_with(&["--cache-ttl-secs", "300"]),
+            Duration::from_secs(300)
+        );
+        assert_eq!(
+            config_with(&["--cache-ttl-secs", "300", "--random-cache-ttl-secs", "5"]),
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            config_with(&["--random-cache-ttl-secs", "0"]),
+            Duration::ZERO
+        );
+    }
+
+    #[tokio::test]
+    async fn random_entries_expire_on_their_own_ttl() {
+        let (_dir, store) = temp_store();
+        let image = store
+            .get_image_by_hash(&add_png(&store, 1).await)
+            .unwrap()
+            .unwrap();
+
+        let cache = ImageCache::new(10, Duration::from_secs(60), Duration::from_millis(50));
+        cache.insert("fetched".to_string(), image.clone()).await;
+        cache
+            .insert_random("drawn".to_string(), image.clone())
+            .await;
+        assert!(cache.get("drawn").await.is_some());
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        assert!(cache.get("drawn").await.is_none());
+        assert!(cache.get("fetched").await.is_some());
+
+        // a later plain lookup caches it for the full TTL again
+        cache.insert("drawn".to_string(), image.clone()).await;
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        assert!(cache.get("drawn").await.is_some());
+
+        let uncached = ImageCache::new(10, Duration::from_secs(60), Duration::ZERO);
+        uncached.insert_random("drawn".to_string(), image).await;
+        assert!(uncached.get("drawn").await.is_none());
     }
 }
@@ -0,0 +1,85 @@
+use anyhow::{anyhow, Result};
+use image::codecs::gif::GifDecoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::{AnimationDecoder, DynamicImage, ImageEncoder, ImageFormat};
+
+/// Format every ingested image is re-encoded to, when configured.
+#[derive(Clone, Copy, Debug)]
+pub struct CanonicalFormat {
+    pub format: ImageFormat,
+    /// JPEG quality (1-100). PNG and WebP output is lossless and ignores this.
+    pub quality: u8,
+}
+
+impl CanonicalFormat {
+    pub fn from_config(format: &str, quality: u8) -> Result<Self> {
+        let format = match format.to_lowercase().as_str() {
+            "jpeg" | "jpg" => ImageFormat::Jpeg,
+            "png" => ImageFormat::Png,
+            "webp" => ImageFormat::WebP,
+            other => return Err(anyhow!("Unsupported canonical format: {}", other)),
+        };
+        Ok(Self {
+            format,
+            quality: quality.clamp(1, 100),
+        })
+    }
+}
+
+/// Animated images can't be re-encoded without losing frames, so they are
+/// stored as uploaded.
+pub fn is_animated(data: &[u8], format: ImageFormat) -> bool {
+    match format {
+        ImageFormat::Gif => GifDecoder::new(std::io::Cursor::new(data))
+            .map(|decoder| decoder.into_frames().take(2).count() > 1)
+            .unwrap_or(false),
+        // VP8X header with the animation flag set
+        ImageFormat::WebP => data.len() > 20 && &data[12..16] == b"VP8X" && data[20] & 0x02 != 0,
+        // APNG declares an acTL chunk before the first IDAT
+        ImageFormat::Png => {
+            let find = |chunk: &[u8]| data.windows(4).position(|w| w == chunk);
+            match (find(b"acTL"), find(b"IDAT")) {
+                (Some(actl), Some(idat)) => actl < idat,
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+pub fn encode(img: &DynamicImage, format: ImageFormat, quality: u8) -> Result<Vec<u8>> {
+    let mut encoded = Vec::new();
+    match format {
+        ImageFormat::WebP => {
+            let rgba = img.to_rgba8();
+            WebPEncoder::new_lossless(&mut encoded).encode(
+                &rgba,
+                rgba.width(),
+                rgba.height(),
+                image::ColorType::Rgba8,
+            )?;
+        }
+        ImageFormat::Png => {
+            let rgba = img.to_rgba8();
+            PngEncoder::new(&mut encoded).write_image(
+                &rgba,
+                rgba.width(),
+                rgba.height(),
+                image::ColorType::Rgba8,
+            )?;
+        }
+        _ => {
+            let rgb = img.to_rgb8();
+            JpegEncoder::new_with_quality(&mut encoded, quality).encode(
+                &rgb,
+                rgb.width(),
+                rgb.height(),
+                image::ColorType::Rgb8,
+            )?;
+        }
+    }
+
+    Ok(encoded)
+}
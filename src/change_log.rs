@@ -0,0 +1,52 @@
+use crate::store::ImageStore;
+use std::time::Duration;
+use tracing::{info, warn};
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Kinds of entries in the change feed served by `GET /sync/changes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeEvent {
+    ImageAdded,
+    ImageRemoved,
+    TagsChanged,
+    MetadataChanged,
+}
+
+impl ChangeEvent {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChangeEvent::ImageAdded => "image_added",
+            ChangeEvent::ImageRemoved => "image_removed",
+            ChangeEvent::TagsChanged => "tags_changed",
+            ChangeEvent::MetadataChanged => "metadata_changed",
+        }
+    }
+
+    /// Events that only say "go re-read this image". Only the latest one per
+    /// image is kept, since consumers fetch the current state anyway.
+    pub fn collapses(self) -> bool {
+        matches!(
+            self,
+            ChangeEvent::TagsChanged | ChangeEvent::MetadataChanged
+        )
+    }
+}
+
+/// Periodically drops change log entries older than `retention`.
+pub fn spawn_pruner(store: ImageStore, retention: Duration) {
+    tokio::spawn(async move {
+        let mut ticker =
+            tokio::time::interval(PRUNE_INTERVAL.min(retention.max(Duration::from_secs(1))));
+        loop {
+            ticker.tick().await;
+            let store = store.clone();
+            match tokio::task::spawn_blocking(move || store.prune_change_log(retention)).await {
+                Ok(Ok(0)) => {}
+                Ok(Ok(pruned)) => info!("Pruned {} change log entries", pruned),
+                Ok(Err(e)) => warn!("Failed to prune change log: {}", e),
+                Err(e) => warn!("Change log prune task failed: {}", e),
+            }
+        }
+    });
+}
@@ -1,6 +1,11 @@
+use crate::limiter::MAX_DEBUG_HISTORY;
+use crate::models::{RequestLimits, UrlStyle};
+use crate::presign::{hmac_sha256, to_hex};
 use anyhow::{anyhow, Result};
 use clap::Parser;
 use std::time::Duration;
+use tracing::warn;
+use url::Url;
 
 #[derive(Parser, Clone)]
 #[command(author, version, about, long_about = None)]
@@ -14,23 +19,266 @@ pub struct Config {
     #[arg(long, env = "IMAGES_PATH", default_value = "/images")]
     pub images_path: String,
 
+    /// Public URL image links are built from, e.g. https://img.example.com
     #[arg(long, env = "BASE_URL")]
     pub base_url: Option<String>,
 
+    /// Build image URLs from the request's Host and X-Forwarded-Proto headers
+    #[arg(long, env = "PUBLIC_URL_FROM_HEADERS", default_value = "false")]
+    pub public_url_from_headers: bool,
+
+    /// Comma-separated hosts accepted by PUBLIC_URL_FROM_HEADERS
+    #[arg(long, env = "PUBLIC_HOSTS", value_delimiter = ',')]
+    pub public_hosts: Vec<String>,
+
+    /// What image URLs are built from: filename, hash or id
+    #[arg(long, env = "URL_STYLE", default_value = "filename")]
+    pub url_style: String,
+
     #[arg(long, env = "RATE_LIMIT_REQUESTS", default_value = "2")]
     pub rate_limit_requests: u32,
 
     #[arg(long, env = "RATE_LIMIT_WINDOW_SECS", default_value = "1")]
     pub rate_limit_window_secs: u64,
 
+    /// Rate-limit decisions kept per key for debugging, 0 disables
+    #[arg(long, env = "RATE_LIMIT_DEBUG_HISTORY", default_value = "0")]
+    pub rate_limit_debug_history: usize,
+
     #[arg(long, env = "CACHE_SIZE", default_value = "100")]
     pub cache_size: usize,
 
     #[arg(long, env = "CACHE_TTL_SECS", default_value = "300")]
     pub cache_ttl_secs: u64,
 
+    /// Images whose tag lists are kept in memory, 0 disables
+    #[arg(long, env = "TAG_CACHE_SIZE", default_value = "10000")]
+    pub tag_cache_size: usize,
+
+    /// Serve a generated placeholder instead of 410 Gone for broken images
+    #[arg(long, env = "FALLBACK_PLACEHOLDER", default_value = "false")]
+    pub fallback_placeholder: bool,
+
+    /// TTL for entries cached from /random results, 0 disables. Defaults to
+    /// CACHE_TTL_SECS
+    #[arg(long, env = "RANDOM_CACHE_TTL_SECS")]
+    pub random_cache_ttl_secs: Option<u64>,
+
     #[arg(long, env = "ADMIN_KEY")]
     pub admin_key: String,
+
+    #[arg(long, env = "MAX_CONCURRENT_REQUESTS", default_value = "1024")]
+    pub max_concurrent_requests: usize,
+
+    /// JSON or TOML file overriding client-facing error messages per locale
+    #[arg(long, env = "ERROR_MESSAGES_FILE")]
+    pub error_messages_file: Option<String>,
+
+    #[arg(long, env = "ENABLE_REQUEST_DEDUP", default_value = "false")]
+    pub enable_request_dedup: bool,
+
+    /// Defaults to the number of available cores
+    #[arg(long, env = "UPLOAD_CONCURRENCY")]
+    pub upload_concurrency: Option<usize>,
+
+    /// Threads used to scan new image files at startup. Defaults to the
+    /// number of available cores
+    #[arg(long, env = "SYNC_CONCURRENCY")]
+    pub sync_concurrency: Option<usize>,
+
+    /// Most URL downloads running against one host at a time
+    #[arg(long, env = "DOWNLOAD_HOST_CONCURRENCY", default_value = "4")]
+    pub download_host_concurrency: usize,
+
+    /// Most redirects a URL download follows. 0 refuses any redirect
+    #[arg(long, env = "MAX_REDIRECTS", default_value = "5")]
+    pub max_redirects: u32,
+
+    /// Minimum gap between starting downloads from the same host
+    #[arg(long, env = "DOWNLOAD_HOST_INTERVAL_MS", default_value = "0")]
+    pub download_host_interval_ms: u64,
+
+    #[arg(long, env = "UPLOAD_QUEUE_DEPTH", default_value = "32")]
+    pub upload_queue_depth: usize,
+
+    /// Maximum filenames/hashes per POST /images/exists request
+    #[arg(long, env = "EXISTS_BATCH_MAX", default_value = "1000")]
+    pub exists_batch_max: usize,
+
+    /// Maximum keys per POST /api-keys/batch request
+    #[arg(long, env = "API_KEY_BATCH_MAX", default_value = "100")]
+    pub api_key_batch_max: usize,
+
+    /// Maximum tags a single random or list filter may use
+    #[arg(long, env = "MAX_FILTER_TAGS", default_value = "30")]
+    pub max_filter_tags: usize,
+
+    /// Maximum metadata keys (`has_metadata` plus `metadata.<key>`) a single
+    /// random or list filter may use
+    #[arg(long, env = "MAX_FILTER_METADATA", default_value = "32")]
+    pub max_filter_metadata: usize,
+
+    /// Reject `GET /random` requests with query parameters it doesn't know
+    #[arg(long, env = "STRICT_QUERY_PARAMS", default_value = "false")]
+    pub strict_query_params: bool,
+
+    #[arg(long, env = "LOOKUP_MAX_DISTANCE", default_value = "10")]
+    pub lookup_max_distance: u32,
+
+    /// Key presigned upload URLs are signed with. Defaults to one derived
+    /// from ADMIN_KEY
+    #[arg(long, env = "PRESIGN_SECRET")]
+    pub presign_secret: Option<String>,
+
+    /// Longest lifetime a presigned upload URL may be given
+    #[arg(long, env = "PRESIGN_MAX_TTL_SECS", default_value = "900")]
+    pub presign_max_ttl_secs: u64,
+
+    /// RFC 7662 endpoint that bearer tokens which aren't local API keys are
+    /// validated against
+    #[arg(long, env = "AUTH_INTROSPECTION_URL")]
+    pub auth_introspection_url: Option<String>,
+
+    /// Client credentials sent to the introspection endpoint as HTTP Basic
+    #[arg(long, env = "AUTH_INTROSPECTION_CLIENT_ID")]
+    pub auth_introspection_client_id: Option<String>,
+
+    #[arg(long, env = "AUTH_INTROSPECTION_CLIENT_SECRET")]
+    pub auth_introspection_client_secret: Option<String>,
+
+    /// How long an introspection answer is reused for the same token
+    #[arg(long, env = "AUTH_INTROSPECTION_CACHE_SECS", default_value = "60")]
+    pub auth_introspection_cache_secs: u64,
+
+    /// Format HEIC uploads are converted to: jpeg or webp
+    #[arg(long, env = "HEIC_CONVERT_FORMAT", default_value = "jpeg")]
+    pub heic_convert_format: String,
+
+    /// JPEG quality for converted HEIC images. WebP output is always
+    /// lossless, as the image crate can't encode lossy WebP, and ignores it
+    #[arg(long, env = "HEIC_QUALITY", default_value = "85")]
+    pub heic_quality: u8,
+
+    /// Re-encode every ingested image to this format: jpeg, png or webp
+    #[arg(long, env = "CANONICAL_FORMAT")]
+    pub canonical_format: Option<String>,
+
+    #[arg(long, env = "CANONICAL_QUALITY", default_value = "85")]
+    pub canonical_quality: u8,
+
+    /// Consecutive read-only/disk-full write errors before going read-only
+    #[arg(long, env = "STORAGE_FAILURE_THRESHOLD", default_value = "3")]
+    pub storage_failure_threshold: usize,
+
+    #[arg(long, env = "STORAGE_FAILURE_WINDOW_SECS", default_value = "60")]
+    pub storage_failure_window_secs: u64,
+
+    #[arg(long, env = "STORAGE_PROBE_INTERVAL_SECS", default_value = "30")]
+    pub storage_probe_interval_secs: u64,
+
+    /// How often leftover temp download files are swept
+    #[arg(long, env = "TEMP_SWEEP_INTERVAL_SECS", default_value = "300")]
+    pub temp_sweep_interval_secs: u64,
+
+    /// How long in-flight requests get to finish after SIGINT or SIGTERM
+    /// before the server exits anyway
+    #[arg(long, env = "SHUTDOWN_TIMEOUT_SECS", default_value = "30")]
+    pub shutdown_timeout_secs: u64,
+
+    /// URL that receives a JSON POST when storage degrades or recovers
+    #[arg(long, env = "STORAGE_ALERT_WEBHOOK")]
+    pub storage_alert_webhook: Option<String>,
+
+    /// Comma-separated domains URL downloads are restricted to (subdomains
+    /// included). Empty allows any host not otherwise blocked.
+    #[arg(long, env = "URL_ALLOWLIST", value_delimiter = ',')]
+    pub url_allowlist: Vec<String>,
+
+    /// Comma-separated domains (subdomains included) whose pages may embed
+    /// images. Empty disables hotlink protection.
+    #[arg(long, env = "ALLOWED_REFERERS", value_delimiter = ',')]
+    pub allowed_referers: Vec<String>,
+
+    /// Serve image files to requests without a `Referer` while
+    /// `ALLOWED_REFERERS` is set
+    #[arg(long, env = "ALLOW_EMPTY_REFERER", default_value = "true", action = clap::ArgAction::Set)]
+    pub allow_empty_referer: bool,
+
+    /// Comma-separated tags that images may never be tagged with
+    #[arg(long, env = "BLOCKED_TAGS", value_delimiter = ',')]
+    pub blocked_tags: Vec<String>,
+
+    /// Comma-separated `tag=seconds` pairs. Images carrying one of the tags
+    /// are removed once they are older than its age. Empty disables it.
+    #[arg(long, env = "TAG_TTLS", value_delimiter = ',')]
+    pub tag_ttls: Vec<String>,
+
+    /// How often images past a `TAG_TTLS` age are looked for
+    #[arg(long, env = "TAG_TTL_SWEEP_INTERVAL_SECS", default_value = "60")]
+    pub tag_ttl_sweep_interval_secs: u64,
+
+    /// Largest file embedded as base64 for `inline=true`, e.g. 512KiB
+    #[arg(long, env = "INLINE_MAX_BYTES", default_value = "1MiB", value_parser = crate::byte_size::parse)]
+    pub inline_max_bytes: u64,
+
+    /// Disk budget for files generated from images, e.g. 500MB or 2GiB.
+    /// The least recently served ones are evicted past it.
+    #[arg(long, env = "DERIVED_CACHE_MAX_BYTES", default_value = "1GiB", value_parser = crate::byte_size::parse)]
+    pub derived_cache_max_bytes: u64,
+
+    /// How often the derived file cache is brought back under its budget
+    #[arg(long, env = "DERIVED_CACHE_SWEEP_INTERVAL_SECS", default_value = "60")]
+    pub derived_cache_sweep_interval_secs: u64,
+
+    /// Fully decode images when adding or checking them, rejecting truncated
+    /// files. Turned off, a readable header is enough.
+    #[arg(long, env = "STRICT_DECODE", default_value = "true", action = clap::ArgAction::Set)]
+    pub strict_decode: bool,
+
+    /// Comma-separated origins allowed to make cross-origin GET requests.
+    /// Empty allows any origin
+    #[arg(long, env = "CORS_ORIGINS", value_delimiter = ',')]
+    pub cors_origins: Vec<String>,
+
+    /// Comma-separated origins allowed to make cross-origin POST, PUT and
+    /// DELETE requests. Empty allows none
+    #[arg(long, env = "CORS_WRITE_ORIGINS", value_delimiter = ',')]
+    pub cors_write_origins: Vec<String>,
+
+    /// Start even if the database's tables don't match the expected schema
+    #[arg(long, env = "SKIP_SCHEMA_CHECK", default_value = "false")]
+    pub skip_schema_check: bool,
+
+    /// Most distinct tags the server holds. Past it, only existing tags can
+    /// be applied. Unset is unlimited
+    #[arg(long, env = "MAX_DISTINCT_TAGS")]
+    pub max_distinct_tags: Option<u64>,
+
+    /// Keep tags in the database after their last image is removed
+    #[arg(long, env = "KEEP_EMPTY_TAGS", default_value = "false")]
+    pub keep_empty_tags: bool,
+
+    /// Keep a `<filename>.json` sidecar with tags and metadata next to each
+    /// image, and restore missing database rows from them at startup
+    #[arg(long, env = "WRITE_SIDECARS", default_value = "false")]
+    pub write_sidecars: bool,
+
+    /// How long entries stay in the /sync/changes feed
+    #[arg(long, env = "CHANGE_LOG_RETENTION_DAYS", default_value = "30")]
+    pub change_log_retention_days: u64,
+
+    /// How long responses to requests with an Idempotency-Key are replayed
+    #[arg(long, env = "IDEMPOTENCY_TTL_SECS", default_value = "86400")]
+    pub idempotency_ttl_secs: u64,
+
+    /// Log a warning for requests and image filter queries taking at least
+    /// this many milliseconds. Unset disables the warnings.
+    #[arg(long, env = "SLOW_MS")]
+    pub slow_ms: Option<u64>,
+
+    /// Log JSON request bodies at TRACE level (debugging only)
+    #[arg(long, env = "LOG_REQUEST_BODIES", default_value = "false")]
+    pub log_request_bodies: bool,
 }
 
 impl Config {
@@ -39,16 +287,309 @@ impl Config {
         if config.admin_key.is_empty() {
             return Err(anyhow!("ADMIN_KEY must be provided"));
         }
+        Self::normalize(config)
+    }
+
+    /// Settings for command line tools such as `waifu import-dir`, which
+    /// serve no requests and so need no `ADMIN_KEY`.
+    pub fn for_tools() -> Result<Self> {
+        Self::normalize(Self::try_parse_from(["waifu", "--admin-key", "unused"])?)
+    }
+
+    fn normalize(mut config: Self) -> Result<Self> {
+        config.base_url = config
+            .base_url
+            .as_deref()
+            .map(normalize_base_url)
+            .transpose()?;
+        config.public_hosts = config
+            .public_hosts
+            .iter()
+            .map(|host| host.trim().to_lowercase())
+            .filter(|host| !host.is_empty())
+            .collect();
+        config.url_allowlist = config
+            .url_allowlist
+            .iter()
+            .map(|domain| domain.trim().trim_matches('.').to_lowercase())
+            .filter(|domain| !domain.is_empty())
+            .collect();
+        config.allowed_referers = config
+            .allowed_referers
+            .iter()
+            .map(|domain| domain.trim().trim_matches('.').to_lowercase())
+            .filter(|domain| !domain.is_empty())
+            .collect();
+        config.blocked_tags = config
+            .blocked_tags
+            .iter()
+            .map(|tag| tag.trim().to_lowercase().replace(' ', "_"))
+            .filter(|tag| !tag.is_empty())
+            .collect();
+        config.tag_ttls = config
+            .tag_ttls
+            .iter()
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                parse_tag_ttl(entry)
+                    .map(|(tag, ttl)| format!("{}={}", tag, ttl.as_secs()))
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "TAG_TTLS entries must be tag=seconds with a positive age: {:?}",
+                            entry
+                        )
+                    })
+            })
+            .collect::<Result<_>>()?;
+        config.auth_introspection_url = config
+            .auth_introspection_url
+            .filter(|url| !url.trim().is_empty());
+        if let Some(url) = &config.auth_introspection_url {
+            Url::parse(url)
+                .map_err(|e| anyhow!("AUTH_INTROSPECTION_URL is not a valid URL: {}", e))?;
+        }
+        if config.url_style.parse::<UrlStyle>().is_err() {
+            return Err(anyhow!(
+                "URL_STYLE must be filename, hash or id: {}",
+                config.url_style
+            ));
+        }
+        if config.rate_limit_debug_history > MAX_DEBUG_HISTORY {
+            return Err(anyhow!(
+                "RATE_LIMIT_DEBUG_HISTORY must be at most {}",
+                MAX_DEBUG_HISTORY
+            ));
+        }
+        if config.public_url_from_headers && config.public_hosts.is_empty() {
+            return Err(anyhow!(
+                "PUBLIC_HOSTS must list the accepted hosts when PUBLIC_URL_FROM_HEADERS is enabled"
+            ));
+        }
         Ok(config)
     }
 
+    /// Image URLs pointing at a loopback or wildcard address only work on
+    /// this machine, which is rarely intended when listening on all
+    /// interfaces.
+    pub fn warn_on_local_base_url(&self) {
+        let base_url = self.get_base_url();
+        let wildcard_host = matches!(self.host.as_str(), "0.0.0.0" | "::" | "[::]");
+        if wildcard_host && (base_url.contains("0.0.0.0") || base_url.contains("127.0.0.1")) {
+            warn!(
+                "Image URLs will use {} which clients cannot reach. Set BASE_URL to the public address of this server",
+                base_url
+            );
+        }
+    }
+
+    /// The introspection client credentials, when both are set.
+    pub fn auth_introspection_credentials(&self) -> Option<(String, String)> {
+        self.auth_introspection_client_id
+            .clone()
+            .zip(self.auth_introspection_client_secret.clone())
+    }
+
+    pub fn auth_introspection_cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.auth_introspection_cache_secs)
+    }
+
+    /// `PRESIGN_SECRET`, or else HMAC(ADMIN_KEY, "presign"), so the admin
+    /// key itself never signs tokens handed to browsers.
+    pub fn presign_secret(&self) -> String {
+        match self.presign_secret.as_deref() {
+            Some(secret) if !secret.is_empty() => secret.to_string(),
+            _ => to_hex(&hmac_sha256(self.admin_key.as_bytes(), b"presign")),
+        }
+    }
+
+    pub fn presign_max_ttl(&self) -> Duration {
+        Duration::from_secs(self.presign_max_ttl_secs)
+    }
+
     pub fn cache_ttl(&self) -> Duration {
         Duration::from_secs(self.cache_ttl_secs)
     }
 
+    pub fn random_cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.random_cache_ttl_secs.unwrap_or(self.cache_ttl_secs))
+    }
+
+    pub fn idempotency_ttl(&self) -> Duration {
+        Duration::from_secs(self.idempotency_ttl_secs)
+    }
+
+    pub fn change_log_retention(&self) -> Duration {
+        Duration::from_secs(self.change_log_retention_days * 24 * 60 * 60)
+    }
+
+    pub fn storage_failure_window(&self) -> Duration {
+        Duration::from_secs(self.storage_failure_window_secs)
+    }
+
+    pub fn storage_probe_interval(&self) -> Duration {
+        Duration::from_secs(self.storage_probe_interval_secs.max(1))
+    }
+
+    pub fn temp_sweep_interval(&self) -> Duration {
+        Duration::from_secs(self.temp_sweep_interval_secs.max(1))
+    }
+
+    pub fn request_limits(&self) -> RequestLimits {
+        RequestLimits {
+            max_filter_tags: self.max_filter_tags,
+            max_filter_metadata: self.max_filter_metadata,
+            exists_batch_max: self.exists_batch_max,
+            api_key_batch_max: self.api_key_batch_max,
+            max_redirects: self.max_redirects,
+            inline_max_bytes: self.inline_max_bytes,
+        }
+    }
+
+    pub fn tag_ttls(&self) -> Vec<(String, Duration)> {
+        self.tag_ttls
+            .iter()
+            .filter_map(|entry| parse_tag_ttl(entry))
+            .collect()
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
+    pub fn tag_ttl_sweep_interval(&self) -> Duration {
+        Duration::from_secs(self.tag_ttl_sweep_interval_secs.max(1))
+    }
+
+    pub fn derived_cache_sweep_interval(&self) -> Duration {
+        Duration::from_secs(self.derived_cache_sweep_interval_secs.max(1))
+    }
+
+    pub fn upload_concurrency(&self) -> usize {
+        self.upload_concurrency.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+        })
+    }
+
+    pub fn sync_concurrency(&self) -> usize {
+        self.sync_concurrency
+            .unwrap_or_else(|| {
+                std::thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(1)
+            })
+            .max(1)
+    }
+
+    pub fn download_host_interval(&self) -> Duration {
+        Duration::from_millis(self.download_host_interval_ms)
+    }
+
+    pub fn url_style(&self) -> UrlStyle {
+        self.url_style.parse().unwrap_or_default()
+    }
+
     pub fn get_base_url(&self) -> String {
         self.base_url
             .clone()
             .unwrap_or_else(|| format!("http://{}:{}", self.host, self.port))
     }
 }
+
+fn parse_tag_ttl(entry: &str) -> Option<(String, Duration)> {
+    let (tag, secs) = entry.split_once('=')?;
+    let tag = tag.trim().to_lowercase().replace(' ', "_");
+    let secs = secs.trim().parse::<u64>().ok().filter(|secs| *secs > 0)?;
+    (!tag.is_empty()).then(|| (tag, Duration::from_secs(secs)))
+}
+
+/// Requires an http(s) scheme and a host, and strips trailing slashes so
+/// `{base_url}/images/{filename}` never has a double slash.
+fn normalize_base_url(raw: &str) -> Result<String> {
+    if raw.chars().any(char::is_whitespace) {
+        return Err(anyhow!("BASE_URL must not contain whitespace: {:?}", raw));
+    }
+
+    let (scheme, rest) = raw.split_once("://").ok_or_else(|| {
+        anyhow!(
+            "BASE_URL must include a scheme, e.g. https://{}",
+            raw.trim_end_matches('/')
+        )
+    })?;
+    let scheme = scheme.to_lowercase();
+    if scheme != "http" && scheme != "https" {
+        return Err(anyhow!("BASE_URL scheme must be http or https: {}", raw));
+    }
+
+    let rest = rest.trim_end_matches('/');
+    if rest.is_empty() || rest.starts_with('/') {
+        return Err(anyhow!("BASE_URL must include a host: {}", raw));
+    }
+
+    Ok(format!("{}://{}", scheme, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::config;
+
+    #[test]
+    fn base_url_loses_trailing_slashes() {
+        assert_eq!(
+            normalize_base_url("https://img.example.com//").unwrap(),
+            "https://img.example.com"
+        );
+        assert_eq!(
+            normalize_base_url("HTTP://img.example.com/waifu/").unwrap(),
+            "http://img.example.com/waifu"
+        );
+    }
+
+    #[test]
+    fn base_url_without_scheme_or_host_is_rejected() {
+        let err = normalize_base_url("img.example.com/").unwrap_err();
+        assert!(
+            err.to_string().contains("https://img.example.com"),
+            "{}",
+            err
+        );
+        assert!(normalize_base_url("ftp://img.example.com").is_err());
+        assert!(normalize_base_url("https:///images").is_err());
+        assert!(normalize_base_url("https://").is_err());
+    }
+
+    #[test]
+    fn base_url_with_whitespace_is_rejected() {
+        assert!(normalize_base_url("https://img.example.com /").is_err());
+        assert!(normalize_base_url(" https://img.example.com").is_err());
+    }
+
+    #[test]
+    fn config_is_normalized_on_load() {
+        let config = Config::normalize(config(&[
+            "--base-url",
+            "https://img.example.com/",
+            "--public-hosts",
+            " IMG.example.com ,,cdn.example.com",
+        ]))
+        .unwrap();
+        assert_eq!(config.get_base_url(), "https://img.example.com");
+        assert_eq!(config.public_hosts, ["img.example.com", "cdn.example.com"]);
+    }
+
+    #[test]
+    fn unset_base_url_falls_back_to_the_listen_address() {
+        let config = Config::normalize(config(&["--host", "0.0.0.0", "--port", "9000"])).unwrap();
+        assert_eq!(config.get_base_url(), "http://0.0.0.0:9000");
+    }
+
+    #[test]
+    fn presign_secret_is_derived_from_but_not_the_admin_key() {
+        let derived = config(&[]).presign_secret();
+        assert_ne!(derived, "test-admin");
+        assert_eq!(derived, config(&[]).presign_secret());
+        assert_eq!(config(&["--presign-secret", "own"]).presign_secret(), "own");
+    }
+}
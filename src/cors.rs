@@ -0,0 +1,284 @@
+//! CORS per route group. warp's `Cors` wrapper answers preflights itself,
+//! before the routes it wraps see the request, so it can't tell which
+//! group a preflight's path belongs to. Routes in a CORS-enabled group use
+//! the method filters here instead of warp's: on a preflight asking for
+//! their method they reject with `Preflight`, which `with_cors` takes as the
+//! group claiming the preflight.
+
+use warp::cors::Cors;
+use warp::filters::BoxedFilter;
+use warp::http::Method;
+use warp::reject::Reject;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+/// Allows `methods` from `origins`, or from any origin when it is empty.
+pub fn policy(origins: &[String], methods: &[Method]) -> Cors {
+    let cors = warp::cors();
+    let cors = if origins.is_empty() {
+        cors.allow_any_origin()
+    } else {
+        cors.allow_origins(origins.iter().map(String::as_str))
+    };
+    cors.allow_headers(vec![
+        "Authorization",
+        "Content-Type",
+        "Idempotency-Key",
+        "Want-Digest",
+        "Accept-Version",
+        "User-Agent",
+        "Sec-Fetch-Mode",
+        "Referer",
+        "Origin",
+        "Access-Control-Request-Method",
+        "Access-Control-Request-Headers",
+    ])
+    .allow_methods(methods.to_vec())
+    .max_age(3600)
+    .build()
+}
+
+/// A route matched a preflight's path and requested method.
+#[derive(Debug)]
+struct Preflight;
+
+impl Reject for Preflight {}
+
+pub fn get() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::get().or(preflight_for(Method::GET)).unify()
+}
+
+pub fn post() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::post().or(preflight_for(Method::POST)).unify()
+}
+
+pub fn put() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::put().or(preflight_for(Method::PUT)).unify()
+}
+
+pub fn delete() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::delete().or(preflight_for(Method::DELETE)).unify()
+}
+
+fn preflight_for(method: Method) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::options()
+        .and(warp::header::<String>("access-control-request-method"))
+        .and_then(move |requested: String| {
+            let claimed = Method::from_bytes(requested.as_bytes()).is_ok_and(|m| m == method);
+            async move {
+                if claimed {
+                    Err::<(), _>(warp::reject::custom(Preflight))
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Gives `routes` the `cors` policy, including preflights for their paths.
+/// Other requests only reach `routes` when they use one of `methods`, the
+/// ones the group answers to, so an origin the policy refuses doesn't turn
+/// another group's answer into a 403. Without a policy the routes get no
+/// CORS headers and their preflights are left to the catch-all `OPTIONS`
+/// route.
+pub fn with_cors<F, T>(
+    routes: F,
+    cors: Option<Cors>,
+    methods: &'static [Method],
+) -> BoxedFilter<(Response,)>
+where
+    F: Filter<Extract = (T,), Error = Rejection> + Clone + Send + Sync + 'static,
+    T: Reply + 'static,
+{
+    let routes = routes.map(Reply::into_response);
+    let Some(cors) = cors else {
+        return routes.boxed();
+    };
+
+    // handlers never run here, the method filters reject every preflight
+    let claimed =
+        routes
+            .clone()
+            .map(|_| ())
+            .untuple_one()
+            .or_else(|rejection: Rejection| async move {
+                match rejection.find::<Preflight>() {
+                    Some(_) => Ok(()),
+                    None => Err(rejection),
+                }
+            });
+    let preflight = warp::options()
+        .and(claimed)
+        .and(warp::any().map(warp::reply).with(cors.clone()))
+        .map(Reply::into_response);
+
+    // the wrapper would answer any preflight itself, and OPTIONS is never
+    // one of `methods`
+    let group_method = warp::method()
+        .and_then(move |method: Method| async move {
+            if methods.contains(&method) {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one();
+    preflight
+        .or(group_method.and(routes.with(cors).map(Reply::into_response)))
+        .unify()
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::http::StatusCode;
+
+    const READ_ORIGIN: &str = "https://read.example";
+    const WRITE_ORIGIN: &str = "https://write.example";
+
+    /// The groups as `main` mounts them, with an admin-only route outside
+    /// `/admin` and a read and a write route sharing a path.
+    fn api(write_origins: &[&str]) -> BoxedFilter<(Response,)> {
+        let origins = |list: &[&str]| list.iter().map(|o| o.to_string()).collect::<Vec<_>>();
+        let read_routes = warp::path!("images" / String)
+            .and(get())
+            .map(|_| "image")
+            .or(warp::path!("images").and(get()).map(|| "list"));
+        let write_routes = warp::path!("images").and(post()).map(|| "added");
+        let admin_routes = warp::path!("images" / String / "fileinfo")
+            .and(warp::get())
+            .map(|_| "fileinfo".into_response());
+
+        let write_cors =
+            (!write_origins.is_empty()).then(|| policy(&origins(write_origins), &[Method::POST]));
+        with_cors(
+            read_routes,
+            Some(policy(&origins(&[READ_ORIGIN]), &[Method::GET])),
+            &[Method::GET, Method::HEAD],
+        )
+        .or(with_cors(write_routes, write_cors, &[Method::POST]))
+        .unify()
+        .or(admin_routes)
+        .unify()
+        .or(warp::options().map(|| warp::reply().into_response()))
+        .unify()
+        .boxed()
+    }
+
+    async fn request(
+        api: &BoxedFilter<(Response,)>,
+        method: &str,
+        path: &str,
+        origin: &str,
+    ) -> (StatusCode, Option<String>) {
+        let response = warp::test::request()
+            .method(method)
+            .path(path)
+            .header("origin", origin)
+            .reply(api)
+            .await;
+        let allowed = response
+            .headers()
+            .get("access-control-allow-origin")
+            .map(|v| v.to_str().unwrap().to_string());
+        (response.status(), allowed)
+    }
+
+    async fn preflight(
+        api: &BoxedFilter<(Response,)>,
+        method: &str,
+        path: &str,
+        origin: &str,
+    ) -> (StatusCode, Option<String>) {
+        let response = warp::test::request()
+            .method("OPTIONS")
+            .path(path)
+            .header("origin", origin)
+            .header("access-control-request-method", method)
+            .header("access-control-request-headers", "authorization")
+            .reply(api)
+            .await;
+        let allowed = response
+            .headers()
+            .get("access-control-allow-origin")
+            .map(|v| v.to_str().unwrap().to_string());
+        (response.status(), allowed)
+    }
+
+    #[tokio::test]
+    async fn reads_follow_the_read_origins() {
+        let api = api(&[WRITE_ORIGIN]);
+        let read = Some(READ_ORIGIN.to_string());
+        assert_eq!(
+            request(&api, "GET", "/images/a", READ_ORIGIN).await,
+            (StatusCode::OK, read.clone())
+        );
+        assert_eq!(
+            preflight(&api, "GET", "/images", READ_ORIGIN).await,
+            (StatusCode::OK, read)
+        );
+        assert_eq!(
+            request(&api, "GET", "/images/a", WRITE_ORIGIN).await.0,
+            StatusCode::FORBIDDEN
+        );
+        // the catch-all answers, without an allowed origin
+        assert_eq!(
+            preflight(&api, "GET", "/images", WRITE_ORIGIN).await,
+            (StatusCode::OK, None)
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_follow_the_write_origins() {
+        let api = api(&[WRITE_ORIGIN]);
+        let write = Some(WRITE_ORIGIN.to_string());
+        assert_eq!(
+            request(&api, "POST", "/images", WRITE_ORIGIN).await,
+            (StatusCode::OK, write.clone())
+        );
+        // the read group shares the path, but the preflight is for a write
+        assert_eq!(
+            preflight(&api, "POST", "/images", WRITE_ORIGIN).await,
+            (StatusCode::OK, write)
+        );
+        assert_eq!(
+            request(&api, "POST", "/images", READ_ORIGIN).await.0,
+            StatusCode::FORBIDDEN
+        );
+        // the catch-all answers, without an allowed origin
+        assert_eq!(
+            preflight(&api, "POST", "/images", READ_ORIGIN).await,
+            (StatusCode::OK, None)
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_get_no_cors_without_write_origins() {
+        let api = api(&[]);
+        assert_eq!(
+            request(&api, "POST", "/images", READ_ORIGIN).await,
+            (StatusCode::OK, None)
+        );
+        assert_eq!(
+            preflight(&api, "POST", "/images", READ_ORIGIN).await,
+            (StatusCode::OK, None)
+        );
+    }
+
+    #[tokio::test]
+    async fn admin_routes_outside_admin_paths_get_no_cors() {
+        let api = api(&[WRITE_ORIGIN]);
+        for origin in [READ_ORIGIN, WRITE_ORIGIN] {
+            assert_eq!(
+                request(&api, "GET", "/images/a/fileinfo", origin).await,
+                (StatusCode::OK, None)
+            );
+            assert_eq!(
+                preflight(&api, "GET", "/images/a/fileinfo", origin).await,
+                (StatusCode::OK, None)
+            );
+        }
+    }
+}
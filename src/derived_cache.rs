@@ -0,0 +1,80 @@
+use crate::store::ImageStore;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::{info, warn};
+
+/// Files generated from an image, e.g. the image in another format, are
+/// written to `derived/` next to the images directory and tracked in the
+/// `derived_files` table. Past `DERIVED_CACHE_MAX_BYTES` the least recently
+/// served ones are evicted; they can always be generated again.
+pub struct DerivedFiles {
+    pub dir: PathBuf,
+    pub max_bytes: u64,
+    touches: Mutex<HashMap<(String, String), String>>,
+    evictions: AtomicU64,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct DerivedCacheStats {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl DerivedFiles {
+    pub fn new(dir: PathBuf, max_bytes: u64) -> Self {
+        Self {
+            dir,
+            max_bytes,
+            touches: Mutex::new(HashMap::new()),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Notes that a variant was served. Kept in memory and written to the
+    /// table in one go before each eviction pass.
+    pub fn touch(&self, parent_hash: &str, variant: &str) {
+        let Ok(now) = OffsetDateTime::now_utc().format(&Rfc3339) else {
+            return;
+        };
+        self.touches
+            .lock()
+            .unwrap()
+            .insert((parent_hash.to_string(), variant.to_string()), now);
+    }
+
+    pub fn take_touches(&self) -> HashMap<(String, String), String> {
+        std::mem::take(&mut *self.touches.lock().unwrap())
+    }
+
+    pub fn record_evictions(&self, count: u64) {
+        self.evictions.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+}
+
+/// Periodically writes pending access times and evicts the least recently
+/// served variants until the cache fits its budget.
+pub fn spawn_evictor(store: ImageStore, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let evictor_store = store.clone();
+            match tokio::task::spawn_blocking(move || evictor_store.evict_derived_files()).await {
+                Ok(Ok(0)) => {}
+                Ok(Ok(evicted)) => info!("Evicted {} derived files over budget", evicted),
+                Ok(Err(e)) => warn!("Failed to evict derived files: {}", e),
+                Err(e) => warn!("Derived file eviction task failed: {}", e),
+            }
+        }
+    });
+}
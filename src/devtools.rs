@@ -0,0 +1,370 @@
+//! Developer tooling for performance work, built with `--features devtools`:
+//!
+//! ```sh
+//! waifu devtools seed --images 100000 --tags 5000 --skew zipf
+//! waifu devtools bench
+//! ```
+//!
+//! `seed` fills a database and images directory with a synthetic library
+//! that is identical for a given `--seed`, so benchmarks are comparable
+//! across branches. `bench` times the common read queries against it.
+
+use crate::config::Config;
+use crate::models::{ImageFilters, ImageSort, SelectionStrategy, SortOrder, TagMatch};
+use crate::phash::compute_phash;
+use crate::store::ImageStore;
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use rusqlite::params;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+const MIN_DIMENSION: u32 = 16;
+const MAX_DIMENSION: u32 = 512;
+const MAX_TAGS_PER_IMAGE: usize = 8;
+const SEED_BATCH_SIZE: usize = 1000;
+
+#[derive(Parser)]
+#[command(name = "waifu devtools")]
+struct DevtoolsCli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Generate a synthetic image library
+    Seed {
+        #[arg(long, default_value = "images.db")]
+        db: String,
+        #[arg(long, default_value = "images")]
+        images_dir: PathBuf,
+        #[arg(long, default_value = "10000")]
+        images: usize,
+        #[arg(long, default_value = "500")]
+        tags: usize,
+        #[arg(long, value_enum, default_value = "zipf")]
+        skew: Skew,
+        #[arg(long, default_value = "42")]
+        seed: u64,
+    },
+    /// Time random, filter and count queries against a library
+    Bench {
+        #[arg(long, default_value = "images.db")]
+        db: String,
+        #[arg(long, default_value = "images")]
+        images_dir: PathBuf,
+        #[arg(long, default_value = "200")]
+        iterations: usize,
+        #[arg(long, default_value = "42")]
+        seed: u64,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Skew {
+    /// A few tags are on most images, like real libraries
+    Zipf,
+    Uniform,
+}
+
+/// Entry point for `waifu devtools ...`. `args` starts at `devtools`.
+pub fn run(args: impl Iterator<Item = String>) -> Result<()> {
+    match DevtoolsCli::parse_from(args).command {
+        Command::Seed {
+            db,
+            images_dir,
+            images,
+            tags,
+            skew,
+            seed,
+        } => run_seed(&db, &images_dir, images, tags, skew, seed),
+        Command::Bench {
+            db,
+            images_dir,
+            iterations,
+            seed,
+        } => run_bench(&db, &images_dir, iterations, seed),
+    }
+}
+
+/// The store needs a config, but nothing devtools touches depends on it.
+fn open_store(db: &str, images_dir: &Path) -> Result<ImageStore> {
+    let config = Config::try_parse_from(["waifu", "--admin-key", "devtools"])?;
+    ImageStore::new(db, images_dir.to_path_buf(), &config)
+}
+
+fn run_seed(
+    db: &str,
+    images_dir: &Path,
+    image_count: usize,
+    tag_count: usize,
+    skew: Skew,
+    seed: u64,
+) -> Result<()> {
+    if tag_count == 0 {
+        return Err(anyhow!("--tags must be at least 1"));
+    }
+    open_store(db, images_dir)?;
+
+    let mut rng = SplitMix64::new(seed);
+    let tag_picker = TagPicker::new(tag_count, skew);
+    let mut conn = rusqlite::Connection::open(db)?;
+    let started = Instant::now();
+
+    {
+        let tx = conn.transaction()?;
+        for i in 0..tag_count {
+            tx.execute(
+                "INSERT OR IGNORE INTO tags (name) VALUES (?)",
+                [tag_name(i)],
+            )?;
+        }
+        tx.commit()?;
+    }
+
+    let now = OffsetDateTime::now_utc();
+    let mut inserted = 0;
+    for batch_start in (0..image_count).step_by(SEED_BATCH_SIZE) {
+        let tx = conn.transaction()?;
+        for i in batch_start..(batch_start + SEED_BATCH_SIZE).min(image_count) {
+            let width = rng.range(MIN_DIMENSION, MAX_DIMENSION);
+            let height = rng.range(MIN_DIMENSION, MAX_DIMENSION);
+            let color = Rgb([rng.next() as u8, rng.next() as u8, rng.next() as u8]);
+            let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(width, height, color));
+
+            let mut data = Vec::new();
+            img.write_to(&mut Cursor::new(&mut data), ImageFormat::Png)?;
+            let hash = format!("{:x}", Sha256::digest(&data));
+            let filename = format!("{}.png", hash);
+
+            // five minutes apart, oldest first
+            let created_at =
+                (now - time::Duration::minutes((image_count - i) as i64 * 5)).format(&Rfc3339)?;
+            let added = tx.execute(
+                "INSERT OR IGNORE INTO images (filename, hash, created_at, modified_at, width, height, size_bytes, phash)
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+                params![
+                    filename,
+                    hash,
+                    created_at,
+                    created_at,
+                    width,
+                    height,
+                    data.len() as i64,
+                    compute_phash(&img) as i64
+                ],
+            )?;
+            if added == 0 {
+                continue;
+            }
+            std::fs::write(images_dir.join(&filename), &data)?;
+
+            let tags_on_image = rng.range(1, MAX_TAGS_PER_IMAGE as u32) as usize;
+            for _ in 0..tags_on_image {
+                tx.execute(
+                    "INSERT OR IGNORE INTO image_tags (image_hash, tag_id)
+                     SELECT ?, id FROM tags WHERE name = ?",
+                    params![hash, tag_name(tag_picker.pick(&mut rng))],
+                )?;
+            }
+            inserted += 1;
+        }
+        tx.commit()?;
+        println!("seeded {}/{} images", inserted, image_count);
+    }
+
+    println!(
+        "Seeded {} images and {} tags in {:.1}s (seed {})",
+        inserted,
+        tag_count,
+        started.elapsed().as_secs_f64(),
+        seed
+    );
+    Ok(())
+}
+
+fn run_bench(db: &str, images_dir: &Path, iterations: usize, seed: u64) -> Result<()> {
+    let store = open_store(db, images_dir)?;
+    let conn = rusqlite::Connection::open(db)?;
+    let mut rng = SplitMix64::new(seed);
+
+    // the most and least used tags bound what real filters hit
+    let tags: Vec<String> = conn
+        .prepare(
+            "SELECT t.name FROM tags t JOIN image_tags it ON t.id = it.tag_id
+             GROUP BY t.id ORDER BY COUNT(*) DESC",
+        )?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    if tags.len() < 2 {
+        return Err(anyhow!("Library has fewer than 2 tags, run seed first"));
+    }
+    let popular = tags[0].clone();
+    let rare = tags[tags.len() - 1].clone();
+
+    let mut results = Vec::new();
+    results.push(time_query("random", iterations, || {
+        store.get_random_image_with_strategy(&filters(None), SelectionStrategy::Uniform)?;
+        Ok(())
+    })?);
+    results.push(time_query("random, popular tag", iterations, || {
+        store.get_random_image_with_strategy(
+            &filters(Some(vec![popular.clone()])),
+            SelectionStrategy::Uniform,
+        )?;
+        Ok(())
+    })?);
+    results.push(time_query("random, 2 tags", iterations, || {
+        let second = tags[rng.range(1, (tags.len() - 1).min(20) as u32) as usize].clone();
+        // no image may carry both, which is a valid (and slow) case too
+        let _ = store.get_random_image_with_strategy(
+            &filters(Some(vec![popular.clone(), second])),
+            SelectionStrategy::Uniform,
+        );
+        Ok(())
+    })?);
+    results.push(time_query("list page, rare tag", iterations, || {
+        store.list_images_with_filters(
+            &filters(Some(vec![rare.clone()])),
+            ImageSort::Created,
+            SortOrder::Desc,
+            50,
+            0,
+        )?;
+        Ok(())
+    })?);
+    results.push(time_query("count all", iterations, || {
+        conn.query_row("SELECT COUNT(*) FROM images", [], |row| {
+            row.get::<_, i64>(0)
+        })?;
+        Ok(())
+    })?);
+    results.push(time_query("count, popular tag", iterations, || {
+        conn.query_row(
+            "SELECT COUNT(*) FROM image_tags it JOIN tags t ON t.id = it.tag_id WHERE t.name = ?",
+            [&popular],
+            |row| row.get::<_, i64>(0),
+        )?;
+        Ok(())
+    })?);
+
+    println!(
+        "{:<22} {:>10} {:>10} {:>10} {:>10}",
+        "query", "min", "median", "p95", "max"
+    );
+    for (name, mut samples) in results {
+        samples.sort();
+        let at = |q: f64| samples[((samples.len() - 1) as f64 * q) as usize];
+        println!(
+            "{:<22} {:>10} {:>10} {:>10} {:>10}",
+            name,
+            format_duration(samples[0]),
+            format_duration(at(0.5)),
+            format_duration(at(0.95)),
+            format_duration(samples[samples.len() - 1])
+        );
+    }
+    Ok(())
+}
+
+fn filters(tags: Option<Vec<String>>) -> ImageFilters {
+    ImageFilters {
+        tags,
+        tag_match: TagMatch::All,
+        width: None,
+        height: None,
+        size: None,
+        has_metadata: Vec::new(),
+        metadata: BTreeMap::new(),
+        bbox: None,
+        any_tags: Vec::new(),
+        exclude_tags: Vec::new(),
+        owner: None,
+        collection: None,
+    }
+}
+
+fn time_query(
+    name: &str,
+    iterations: usize,
+    mut query: impl FnMut() -> Result<()>,
+) -> Result<(String, Vec<Duration>)> {
+    let mut samples = Vec::with_capacity(iterations.max(1));
+    for _ in 0..iterations.max(1) {
+        let started = Instant::now();
+        query().map_err(|e| anyhow!("{} failed: {}", name, e))?;
+        samples.push(started.elapsed());
+    }
+    Ok((name.to_string(), samples))
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!("{:.2}ms", duration.as_secs_f64() * 1000.0)
+}
+
+fn tag_name(i: usize) -> String {
+    format!("tag_{:05}", i)
+}
+
+/// Small, fast and, unlike `rand`, guaranteed stable across versions, which
+/// is what keeps seeded libraries identical.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[min, max]`.
+    fn range(&mut self, min: u32, max: u32) -> u32 {
+        min + (self.next() % (max - min + 1) as u64) as u32
+    }
+
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Samples tag indices, either uniformly or with Zipf weights (s = 1) so
+/// tag `k` is used about `1/k` as often as the most popular one.
+struct TagPicker {
+    cumulative: Vec<f64>,
+}
+
+impl TagPicker {
+    fn new(tag_count: usize, skew: Skew) -> Self {
+        let mut total = 0.0;
+        let cumulative = (1..=tag_count)
+            .map(|k| {
+                total += match skew {
+                    Skew::Zipf => 1.0 / k as f64,
+                    Skew::Uniform => 1.0,
+                };
+                total
+            })
+            .collect();
+        Self { cumulative }
+    }
+
+    fn pick(&self, rng: &mut SplitMix64) -> usize {
+        let target = rng.unit() * self.cumulative[self.cumulative.len() - 1];
+        self.cumulative
+            .partition_point(|&c| c < target)
+            .min(self.cumulative.len() - 1)
+    }
+}
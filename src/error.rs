@@ -1,9 +1,20 @@
+use crate::messages::MessageCatalog;
+use crate::models::ApiKeyScope;
+use crate::quota::QuotaExceeded;
+use crate::store::HashMismatch;
+use crate::versioning::SUPPORTED_VERSIONS;
 use serde::Serialize;
 use std::fmt;
+use time::format_description::well_known::Rfc3339;
 use tracing::error;
 use uuid::Uuid;
+use warp::http::header::{CONTENT_LANGUAGE, RETRY_AFTER};
+use warp::http::HeaderValue;
+use warp::reply::Response;
 use warp::{http::StatusCode, reject::Reject, Rejection, Reply};
 
+const RETRY_AFTER_SECS: &str = "1";
+
 #[derive(Debug)]
 pub enum ImageError {
     PathNotFound(String),
@@ -13,11 +24,35 @@ pub enum ImageError {
     RateLimitExceeded,
     UsernameExists(String),
     Unauthorized,
+    InvalidAuthScheme,
     InactiveKey,
+    AuthUnavailable,
     UsernameNotFound(String),
     DuplicateImage(String),
     MissingTags,
     BatchSizeExceeded(u32),
+    UploadBusy,
+    ServerBusy,
+    InvalidParameter(String),
+    StorageDegraded,
+    InvalidBody(String),
+    IdempotencyConflict(String),
+    BlockedTag(String),
+    TagLimitReached(u64),
+    CursorExpired(i64),
+    InvalidPresign(String),
+    TooManyFilterTags(usize),
+    TooManyMetadataFilters(usize),
+    MissingScope(ApiKeyScope),
+    OwnerForbidden(String),
+    HotlinkForbidden(String),
+    QuotaExceeded(QuotaExceeded),
+    MissingAllowedTag(Vec<String>),
+    DryRunUnsupported,
+    PreconditionFailed(String),
+    ImageBroken(String),
+    HashMismatch(HashMismatch),
+    UnsupportedVersion(String),
 }
 
 impl fmt::Display for ImageError {
@@ -32,13 +67,63 @@ impl fmt::Display for ImageError {
                 write!(f, "Username already exists: {}", username)
             }
             ImageError::Unauthorized => write!(f, "Unauthorized"),
+            ImageError::InvalidAuthScheme => {
+                write!(f, "Authorization header is not a Bearer token")
+            }
             ImageError::InactiveKey => write!(f, "API key is inactive"),
+            ImageError::AuthUnavailable => write!(f, "Token introspection failed"),
             ImageError::UsernameNotFound(username) => write!(f, "Username not found: {}", username),
             ImageError::DuplicateImage(msg) => write!(f, "Duplicate image: {}", msg),
             ImageError::MissingTags => write!(f, "Missing tags"),
             ImageError::BatchSizeExceeded(max) => {
                 write!(f, "Batch size exceeds maximum of {}", max)
             }
+            ImageError::UploadBusy => write!(f, "Too many uploads in progress"),
+            ImageError::ServerBusy => write!(f, "Server is at capacity"),
+            ImageError::InvalidParameter(msg) => write!(f, "Invalid parameter: {}", msg),
+            ImageError::StorageDegraded => write!(f, "Storage is read-only"),
+            ImageError::InvalidBody(msg) => write!(f, "Invalid request body: {}", msg),
+            ImageError::IdempotencyConflict(msg) => {
+                write!(f, "Idempotency key conflict: {}", msg)
+            }
+            ImageError::BlockedTag(tag) => write!(f, "Tag is blocked: {}", tag),
+            ImageError::TagLimitReached(limit) => {
+                write!(f, "Distinct tag limit of {} reached", limit)
+            }
+            ImageError::InvalidPresign(msg) => write!(f, "Invalid presigned URL: {}", msg),
+            ImageError::TooManyFilterTags(max) => {
+                write!(f, "Filter uses more than {} tags", max)
+            }
+            ImageError::TooManyMetadataFilters(max) => {
+                write!(f, "Filter uses more than {} metadata keys", max)
+            }
+            ImageError::MissingScope(scope) => {
+                write!(f, "API key lacks the {} scope", scope.as_str())
+            }
+            ImageError::OwnerForbidden(owner) => {
+                write!(f, "API key may not filter by owner {}", owner)
+            }
+            ImageError::HotlinkForbidden(referer) => {
+                write!(f, "Images may not be embedded from {}", referer)
+            }
+            ImageError::QuotaExceeded(quota) => write!(f, "{}", quota),
+            ImageError::MissingAllowedTag(tags) => {
+                write!(f, "Image must carry one of: {}", tags.join(", "))
+            }
+            ImageError::DryRunUnsupported => write!(f, "Endpoint does not support dry runs"),
+            ImageError::PreconditionFailed(msg) => write!(f, "{}", msg),
+            ImageError::ImageBroken(filename) => write!(f, "Image is broken: {}", filename),
+            ImageError::HashMismatch(mismatch) => write!(f, "{}", mismatch),
+            ImageError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported API version: {}", version)
+            }
+            ImageError::CursorExpired(oldest) => {
+                write!(
+                    f,
+                    "Cursor is older than the change log, oldest is {}",
+                    oldest
+                )
+            }
         }
     }
 }
@@ -46,100 +131,269 @@ impl fmt::Display for ImageError {
 #[derive(Serialize)]
 struct ErrorResponse {
     code: u16,
+    error: &'static str,
     message: String,
     request_id: String,
 }
 
-impl Reject for ImageError {}
+/// Attached to error responses so the message can be rendered in the
+/// client's language once the request headers are available again.
+#[derive(Debug, Clone)]
+pub struct ErrorDetails {
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub params: Vec<(&'static str, String)>,
+    pub request_id: String,
+}
 
-pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, std::convert::Infallible> {
-    let request_id = Uuid::new_v4().to_string();
-    error!(request_id = %request_id, "Request rejected: {:?}", err);
+impl Reject for ImageError {}
 
-    let (code, message) = if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
-        if e.to_string().contains("missing field `tags`") {
-            (
-                StatusCode::BAD_REQUEST,
-                "The 'tags' field is required when uploading an image".to_string(),
-            )
-        } else {
-            (
-                StatusCode::BAD_REQUEST,
-                "Invalid request format. Please check the API documentation for required fields."
-                    .to_string(),
-            )
-        }
-    } else if let Some(e) = err.find::<ImageError>() {
-        match e {
-            ImageError::PathNotFound(msg) => (StatusCode::NOT_FOUND, msg.to_string()),
+impl ImageError {
+    fn details(&self) -> (StatusCode, &'static str, Vec<(&'static str, String)>) {
+        match self {
+            ImageError::PathNotFound(msg) => (
+                StatusCode::NOT_FOUND,
+                "path_not_found",
+                vec![("message", msg.clone())],
+            ),
             ImageError::DatabaseError(msg) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Database error: {}", msg),
+                "database_error",
+                vec![("message", msg.clone())],
             ),
             ImageError::InvalidImage(msg) => (
                 StatusCode::BAD_REQUEST,
-                format!("The provided file is not a valid image: {}", msg),
+                "invalid_image",
+                vec![("message", msg.clone())],
             ),
             ImageError::FileTooLarge(msg) => (
                 StatusCode::PAYLOAD_TOO_LARGE,
-                format!("The image file exceeds the maximum allowed size: {}", msg),
-            ),
-            ImageError::RateLimitExceeded => (
-                StatusCode::TOO_MANY_REQUESTS,
-                "Rate limit exceeded. Please try again later.".to_string(),
+                "file_too_large",
+                vec![("message", msg.clone())],
             ),
+            ImageError::RateLimitExceeded => {
+                (StatusCode::TOO_MANY_REQUESTS, "rate_limit_exceeded", vec![])
+            }
             ImageError::UsernameExists(username) => (
                 StatusCode::CONFLICT,
-                format!("The username '{}' is already in use", username),
-            ),
-            ImageError::Unauthorized => (
-                StatusCode::UNAUTHORIZED,
-                "Invalid or missing API key".to_string(),
-            ),
-            ImageError::InactiveKey => (
-                StatusCode::UNAUTHORIZED,
-                "This API key has been deactivated. Please contact the administrator.".to_string(),
+                "username_exists",
+                vec![("username", username.clone())],
             ),
+            ImageError::Unauthorized => (StatusCode::UNAUTHORIZED, "unauthorized", vec![]),
+            ImageError::InvalidAuthScheme => {
+                (StatusCode::UNAUTHORIZED, "invalid_auth_scheme", vec![])
+            }
+            ImageError::InactiveKey => (StatusCode::UNAUTHORIZED, "inactive_key", vec![]),
+            ImageError::AuthUnavailable => (StatusCode::UNAUTHORIZED, "auth_unavailable", vec![]),
             ImageError::UsernameNotFound(username) => (
                 StatusCode::NOT_FOUND,
-                format!("The username '{}' was not found", username),
+                "username_not_found",
+                vec![("username", username.clone())],
             ),
             ImageError::DuplicateImage(msg) => (
                 StatusCode::CONFLICT,
-                format!("This image has already been uploaded: {}", msg),
+                "duplicate_image",
+                vec![("message", msg.clone())],
             ),
-            ImageError::MissingTags => (
+            ImageError::MissingTags => (StatusCode::BAD_REQUEST, "missing_tags", vec![]),
+            ImageError::BatchSizeExceeded(max) => (
                 StatusCode::BAD_REQUEST,
-                "At least one tag is required when uploading an image".to_string(),
+                "batch_size_exceeded",
+                vec![("limit", max.to_string())],
             ),
-            ImageError::BatchSizeExceeded(max) => (
+            ImageError::UploadBusy => (StatusCode::SERVICE_UNAVAILABLE, "upload_busy", vec![]),
+            ImageError::ServerBusy => (StatusCode::SERVICE_UNAVAILABLE, "server_busy", vec![]),
+            ImageError::InvalidParameter(msg) => (
+                StatusCode::BAD_REQUEST,
+                "invalid_parameter",
+                vec![("message", msg.clone())],
+            ),
+            ImageError::StorageDegraded => {
+                (StatusCode::SERVICE_UNAVAILABLE, "storage_degraded", vec![])
+            }
+            ImageError::InvalidBody(msg) => body_error_details(msg),
+            ImageError::IdempotencyConflict(msg) => (
+                StatusCode::CONFLICT,
+                "idempotency_conflict",
+                vec![("message", msg.clone())],
+            ),
+            ImageError::BlockedTag(tag) => (
+                StatusCode::BAD_REQUEST,
+                "blocked_tag",
+                vec![("tag", tag.clone())],
+            ),
+            ImageError::TagLimitReached(limit) => (
                 StatusCode::BAD_REQUEST,
-                format!("Batch size exceeds maximum allowed size of {}", max),
+                "tag_limit_reached",
+                vec![("limit", limit.to_string())],
+            ),
+            ImageError::InvalidPresign(msg) => (
+                StatusCode::FORBIDDEN,
+                "invalid_presign",
+                vec![("message", msg.clone())],
+            ),
+            ImageError::CursorExpired(oldest) => (
+                StatusCode::GONE,
+                "cursor_expired",
+                vec![("cursor", oldest.to_string())],
+            ),
+            ImageError::TooManyFilterTags(max) => (
+                StatusCode::BAD_REQUEST,
+                "too_many_filter_tags",
+                vec![("limit", max.to_string())],
+            ),
+            ImageError::TooManyMetadataFilters(max) => (
+                StatusCode::BAD_REQUEST,
+                "too_many_metadata_filters",
+                vec![("limit", max.to_string())],
+            ),
+            ImageError::MissingScope(scope) => (
+                StatusCode::FORBIDDEN,
+                "missing_scope",
+                vec![("scope", scope.as_str().to_string())],
+            ),
+            ImageError::OwnerForbidden(owner) => (
+                StatusCode::FORBIDDEN,
+                "owner_forbidden",
+                vec![("username", owner.clone())],
+            ),
+            ImageError::HotlinkForbidden(referer) => (
+                StatusCode::FORBIDDEN,
+                "hotlink_forbidden",
+                vec![("referer", referer.clone())],
+            ),
+            ImageError::QuotaExceeded(quota) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "quota_exceeded",
+                vec![
+                    ("remaining", quota.remaining.to_string()),
+                    (
+                        "resets_at",
+                        quota.resets_at.format(&Rfc3339).unwrap_or_default(),
+                    ),
+                ],
+            ),
+            ImageError::MissingAllowedTag(tags) => (
+                StatusCode::FORBIDDEN,
+                "missing_allowed_tag",
+                vec![("tag", tags.join(", "))],
+            ),
+            ImageError::DryRunUnsupported => {
+                (StatusCode::BAD_REQUEST, "dry_run_unsupported", vec![])
+            }
+            ImageError::PreconditionFailed(msg) => (
+                StatusCode::PRECONDITION_FAILED,
+                "precondition_failed",
+                vec![("message", msg.clone())],
+            ),
+            ImageError::ImageBroken(filename) => (
+                StatusCode::GONE,
+                "image_broken",
+                vec![("filename", filename.clone())],
+            ),
+            ImageError::HashMismatch(mismatch) => (
+                StatusCode::BAD_REQUEST,
+                "hash_mismatch",
+                vec![
+                    ("expected", mismatch.expected.clone()),
+                    ("actual", mismatch.actual.clone()),
+                ],
+            ),
+            ImageError::UnsupportedVersion(version) => (
+                StatusCode::BAD_REQUEST,
+                "unsupported_version",
+                vec![
+                    ("version", version.clone()),
+                    (
+                        "supported",
+                        SUPPORTED_VERSIONS
+                            .iter()
+                            .map(u32::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                    ),
+                ],
             ),
         }
-    } else if err.is_not_found() {
-        (
-            StatusCode::NOT_FOUND,
-            "The requested resource was not found".to_string(),
-        )
-    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
-        (
-            StatusCode::METHOD_NOT_ALLOWED,
-            "This method is not allowed for this endpoint".to_string(),
-        )
+    }
+}
+
+fn body_error_details(message: &str) -> (StatusCode, &'static str, Vec<(&'static str, String)>) {
+    if message.contains("missing field `tags`") {
+        (StatusCode::BAD_REQUEST, "missing_tags_field", vec![])
     } else {
-        error!(request_id = %request_id, "Unhandled rejection: {:?}", err);
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "An internal error occurred".to_string(),
-        )
+        (StatusCode::BAD_REQUEST, "invalid_request", vec![])
+    }
+}
+
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, std::convert::Infallible> {
+    let request_id = Uuid::new_v4().to_string();
+    error!(request_id = %request_id, "Request rejected: {:?}", err);
+
+    let (status, code, params) =
+        if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+            body_error_details(&e.to_string())
+        } else if let Some(e) = err.find::<ImageError>() {
+            e.details()
+        } else if err.find::<warp::cors::CorsForbidden>().is_some() {
+            (StatusCode::FORBIDDEN, "cors_forbidden", vec![])
+        } else if err.is_not_found() {
+            (StatusCode::NOT_FOUND, "not_found", vec![])
+        } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+            (StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", vec![])
+        } else {
+            error!(request_id = %request_id, "Unhandled rejection: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "internal_error", vec![])
+        };
+
+    let details = ErrorDetails {
+        status,
+        code,
+        params,
+        request_id,
     };
+    let mut response = error_response(&details, &MessageCatalog::default(), "en");
+    response.extensions_mut().insert(details);
+
+    Ok(response)
+}
 
+fn error_response(details: &ErrorDetails, catalog: &MessageCatalog, locale: &str) -> Response {
     let json = warp::reply::json(&ErrorResponse {
-        code: code.as_u16(),
-        message,
-        request_id,
+        code: details.status.as_u16(),
+        error: details.code,
+        message: catalog.render(details.code, locale, &details.params),
+        request_id: details.request_id.clone(),
     });
 
-    Ok(warp::reply::with_status(json, code))
+    let mut response = warp::reply::with_status(json, details.status).into_response();
+    if matches!(details.code, "upload_busy" | "server_busy") {
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from_static(RETRY_AFTER_SECS));
+    }
+    if locale != "en" {
+        if let Ok(value) = HeaderValue::from_str(locale) {
+            response.headers_mut().insert(CONTENT_LANGUAGE, value);
+        }
+    }
+
+    response
+}
+
+/// Re-renders error responses produced by `handle_rejection` using the
+/// configured message catalog and the request's `Accept-Language`.
+pub fn localize_error<T: Reply>(
+    reply: T,
+    accept_language: Option<String>,
+    catalog: &MessageCatalog,
+) -> Response {
+    let response = reply.into_response();
+    match response.extensions().get::<ErrorDetails>() {
+        Some(details) => {
+            let locale = catalog.select_locale(accept_language.as_deref());
+            error_response(details, catalog, &locale)
+        }
+        None => response,
+    }
 }
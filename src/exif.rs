@@ -0,0 +1,237 @@
+//! Just enough EXIF reading to find where a photo was taken. The TIFF block
+//! that holds EXIF is located in JPEG, PNG and WebP files, or read directly
+//! from TIFF files.
+
+const GPS_IFD_TAG: u16 = 0x8825;
+const GPS_LATITUDE_REF: u16 = 0x0001;
+const GPS_LATITUDE: u16 = 0x0002;
+const GPS_LONGITUDE_REF: u16 = 0x0003;
+const GPS_LONGITUDE: u16 = 0x0004;
+const TYPE_RATIONAL: u16 = 5;
+
+/// Latitude and longitude in decimal degrees, `None` when the image has no
+/// (usable) GPS data.
+pub fn gps_location(data: &[u8]) -> Option<(f64, f64)> {
+    let tiff = Tiff::new(find_tiff(data)?)?;
+    let gps_ifd = tiff
+        .entries(tiff.u32_at(4)? as usize)?
+        .find(|entry| entry.tag == GPS_IFD_TAG)
+        .and_then(|entry| tiff.u32_at(entry.value_pos))? as usize;
+
+    let mut lat_ref = None;
+    let mut lat = None;
+    let mut lon_ref = None;
+    let mut lon = None;
+    for entry in tiff.entries(gps_ifd)? {
+        match entry.tag {
+            GPS_LATITUDE_REF => lat_ref = tiff.data.get(entry.value_pos).copied(),
+            GPS_LATITUDE => lat = tiff.degrees(&entry),
+            GPS_LONGITUDE_REF => lon_ref = tiff.data.get(entry.value_pos).copied(),
+            GPS_LONGITUDE => lon = tiff.degrees(&entry),
+            _ => {}
+        }
+    }
+
+    let lat = match lat_ref? {
+        b'N' => lat?,
+        b'S' => -lat?,
+        _ => return None,
+    };
+    let lon = match lon_ref? {
+        b'E' => lon?,
+        b'W' => -lon?,
+        _ => return None,
+    };
+    ((-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon)).then_some((lat, lon))
+}
+
+fn find_tiff(data: &[u8]) -> Option<&[u8]> {
+    if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+        Some(data)
+    } else if data.starts_with(&[0xFF, 0xD8]) {
+        find_jpeg_exif(data)
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        find_png_exif(data)
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        find_webp_exif(data)
+    } else {
+        None
+    }
+}
+
+fn find_jpeg_exif(data: &[u8]) -> Option<&[u8]> {
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            return None;
+        }
+        let marker = data[pos + 1];
+        if marker == 0xFF {
+            // fill byte
+            pos += 1;
+            continue;
+        }
+        // start of scan or end of image, the metadata segments come first
+        if marker == 0xDA || marker == 0xD9 {
+            return None;
+        }
+        let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let segment = data.get(pos + 4..pos + 2 + len)?;
+        if marker == 0xE1 {
+            if let Some(tiff) = segment.strip_prefix(b"Exif\0\0") {
+                return Some(tiff);
+            }
+        }
+        pos += 2 + len;
+    }
+    None
+}
+
+fn find_png_exif(data: &[u8]) -> Option<&[u8]> {
+    let mut pos = 8;
+    while pos + 8 <= data.len() {
+        let len = u32::from_be_bytes(data[pos..pos + 4].try_into().ok()?) as usize;
+        let kind = &data[pos + 4..pos + 8];
+        let chunk = data.get(pos + 8..(pos + 8).checked_add(len)?)?;
+        match kind {
+            b"eXIf" => return Some(chunk),
+            b"IDAT" | b"IEND" => return None,
+            _ => pos += 12 + len,
+        }
+    }
+    None
+}
+
+fn find_webp_exif(data: &[u8]) -> Option<&[u8]> {
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let kind = &data[pos..pos + 4];
+        let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().ok()?) as usize;
+        let chunk = data.get(pos + 8..(pos + 8).checked_add(len)?)?;
+        if kind == b"EXIF" {
+            // some encoders keep the JPEG-style prefix
+            return Some(chunk.strip_prefix(b"Exif\0\0").unwrap_or(chunk));
+        }
+        pos += 8 + len + (len & 1);
+    }
+    None
+}
+
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    /// Where the value is, or its offset when it doesn't fit in 4 bytes
+    value_pos: usize,
+}
+
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let big_endian = match data.get(..2)? {
+            b"II" => false,
+            b"MM" => true,
+            _ => return None,
+        };
+        Some(Self { data, big_endian })
+    }
+
+    fn u16_at(&self, pos: usize) -> Option<u16> {
+        let bytes = self.data.get(pos..pos + 2)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32_at(&self, pos: usize) -> Option<u32> {
+        let bytes = self.data.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn entries(&self, ifd: usize) -> Option<impl Iterator<Item = Entry> + '_> {
+        let count = self.u16_at(ifd)? as usize;
+        Some((0..count).map_while(move |i| {
+            let pos = ifd + 2 + i * 12;
+            Some(Entry {
+                tag: self.u16_at(pos)?,
+                kind: self.u16_at(pos + 2)?,
+                count: self.u32_at(pos + 4)?,
+                value_pos: pos + 8,
+            })
+        }))
+    }
+
+    /// Degrees, minutes and seconds stored as three rationals.
+    fn degrees(&self, entry: &Entry) -> Option<f64> {
+        if entry.kind != TYPE_RATIONAL || entry.count != 3 {
+            return None;
+        }
+        let offset = self.u32_at(entry.value_pos)? as usize;
+        let rational = |i: usize| {
+            let numerator = self.u32_at(offset + i * 8)?;
+            let denominator = self.u32_at(offset + i * 8 + 4)?;
+            (denominator != 0).then(|| numerator as f64 / denominator as f64)
+        };
+        Some(rational(0)? + rational(1)? / 60.0 + rational(2)? / 3600.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{gps_jpeg, gps_tiff};
+
+    #[test]
+    fn reads_degrees_minutes_and_seconds_in_either_byte_order() {
+        // 35°39'29.16"N 139°42'1.8"E
+        let lat = (b'N', [(35, 1), (39, 1), (2916, 100)]);
+        let lon = (b'E', [(139, 1), (42, 1), (18, 10)]);
+        for big_endian in [false, true] {
+            let (lat, lon) = gps_location(&gps_tiff(big_endian, lat, lon)).unwrap();
+            assert!((lat - 35.6581).abs() < 1e-6, "{}", lat);
+            assert!((lon - 139.7005).abs() < 1e-6, "{}", lon);
+        }
+    }
+
+    #[test]
+    fn south_and_west_are_negative() {
+        let lat = (b'S', [(33, 1), (30, 1), (0, 1)]);
+        let lon = (b'W', [(70, 1), (15, 1), (0, 1)]);
+        assert_eq!(
+            gps_location(&gps_tiff(false, lat, lon)),
+            Some((-33.5, -70.25))
+        );
+    }
+
+    #[test]
+    fn finds_the_exif_block_in_a_jpeg() {
+        let (lat, lon) = gps_location(&gps_jpeg(1, -12.5, 45.25)).unwrap();
+        assert!((lat + 12.5).abs() < 1e-6 && (lon - 45.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn unusable_gps_data_is_none() {
+        let degrees = [(10, 1), (0, 1), (0, 1)];
+        // unknown reference, zero denominator, out of range
+        let cases = [
+            gps_tiff(false, (b'X', degrees), (b'E', degrees)),
+            gps_tiff(false, (b'N', [(10, 0), (0, 1), (0, 1)]), (b'E', degrees)),
+            gps_tiff(false, (b'N', [(91, 1), (0, 1), (0, 1)]), (b'E', degrees)),
+        ];
+        for tiff in cases {
+            assert_eq!(gps_location(&tiff), None);
+        }
+        assert_eq!(gps_location(b"II*\0"), None);
+        assert_eq!(gps_location(&crate::test_support::png(4, 4, 1)), None);
+    }
+}
@@ -1,26 +1,117 @@
 use crate::cache::ImageCache;
-use crate::error::ImageError;
-use crate::models::ApiKey;
+use crate::derived_cache::DerivedCacheStats;
+use crate::error::{handle_rejection, ImageError};
+use crate::inflight::InFlightCache;
+use crate::limiter::{ApiKeyRateLimiter, UploadGate};
+use crate::migrations;
 use crate::models::{
-    AddImageRequest, BatchAddImageRequest, BatchImageResponse, BatchRandomRequest,
-    GenerateApiKeyRequest, RemoveApiKeyRequest, UpdateApiKeyRequest, UpdateApiKeyStatusRequest,
+    AddImageRequest, AutocompleteQuery, BatchAddImageRequest, BatchGenerateApiKeysRequest,
+    BatchImageResponse, BatchRandomRequest, ChangedSinceQuery, CloneApiKeyRequest,
+    CollectionImagesRequest, CopyTagsFromQuery, CopyTagsRequest, ExistsBatchRequest, ExportQuery,
+    GenerateApiKeyRequest, HashTagChange, InlineQuery, LookupMatch, PresignUploadRequest,
+    RemoveApiKeyRequest, SetWebhookRequest, SizeDistributionQuery, SlowQueriesQuery,
+    SyncChangesQuery, TagListQuery, TagMapping, TagRemapRequest, UpdateApiKeyRequest,
+    UpdateApiKeyStatusRequest,
 };
-use crate::store::ImageStore;
+use crate::models::{
+    ApiKey, ApiKeyScope, DefaultFilters, ExplainedImage, FeatureFlags, ImageCursor, ImageFilters,
+    ImageKey, ImageResponse, ImageSort, PathType, RequestLimits, SearchFilters, SortOrder,
+};
+use crate::placeholder::Placeholders;
+use crate::presign::{from_hex, PresignClaims, Presigner};
+use crate::quota::{self, QuotaExceeded};
+use crate::store::{self, BrokenImage, DuplicateImage, HashMismatch, ImageStore, TagLimitReached};
+use crate::temp_files::{self, TempFileStats};
+use crate::versioning::{BatchItemResults, DEFAULT_VERSION, SUPPORTED_VERSIONS};
+use crate::webhooks::{KeyWebhooks, KEY_WEBHOOK_EVENTS};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use bytes::{Buf, Bytes};
 use futures_util::future::join_all;
 use futures_util::TryStreamExt;
+use image::ImageFormat;
 use serde_json::json;
-use tracing::{error, info, warn};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use time::format_description::well_known::{Rfc2822, Rfc3339};
+use time::format_description::FormatItem;
+use time::macros::format_description;
+use time::{OffsetDateTime, UtcOffset};
+use tracing::{debug, error, info, warn};
+use warp::http::header::{
+    CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+};
+use warp::http::{HeaderValue, StatusCode};
 use warp::multipart::FormData;
+use warp::reply::Response;
 use warp::{http::HeaderMap, Rejection, Reply};
 
+const DEFAULT_AUTOCOMPLETE_LIMIT: usize = 10;
+const MAX_AUTOCOMPLETE_LIMIT: usize = 100;
+const MAX_HAMMING_DISTANCE: u32 = 64;
+const DEFAULT_SLOW_QUERY_LIMIT: usize = 20;
+const DEFAULT_SIZE_BOUNDARIES: [u64; 6] = [
+    0,
+    10 * 1024,
+    100 * 1024,
+    500 * 1024,
+    1024 * 1024,
+    5 * 1024 * 1024,
+];
+const DEFAULT_SYNC_LIMIT: usize = 100;
+const MAX_SYNC_LIMIT: usize = 1000;
+const DEFAULT_PRESIGN_TTL_SECS: u64 = 300;
+pub const MAX_UPLOAD_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_LIST_LIMIT: u32 = 50;
+const MAX_LIST_LIMIT: u32 = 500;
+const DEFAULT_EXPORT_LIMIT: usize = 100;
+const MAX_EXPORT_LIMIT: usize = 1000;
+const FIRST_KEY_HINT: &str = "No API keys exist yet. Create the first one with POST /api-keys, authenticated with the admin key, e.g. {\"username\": \"user1\"}";
+
+/// Applies a request-derived public base URL (see `PUBLIC_URL_FROM_HEADERS`).
+/// Cached responses keep the configured `BASE_URL`, so call this after caching.
+fn rebase_urls(images: &mut [ImageResponse], base_url: Option<&str>) {
+    if let Some(base_url) = base_url {
+        for image in images {
+            image.rebase(base_url);
+        }
+    }
+}
+
+/// With `STRICT_QUERY_PARAMS` on, rejects `GET /random` queries carrying
+/// parameters it would otherwise silently ignore, such as typos.
+pub async fn check_random_params(
+    params: std::collections::HashMap<String, String>,
+    strict: bool,
+) -> Result<std::collections::HashMap<String, String>, Rejection> {
+    if strict {
+        let unknown = ImageFilters::unknown_random_params(&params);
+        if !unknown.is_empty() {
+            return Err(warp::reject::custom(ImageError::InvalidParameter(format!(
+                "unknown query parameters: {}",
+                unknown.join(", ")
+            ))));
+        }
+    }
+    Ok(params)
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn get_random_image_handler(
     store: ImageStore,
     cache: ImageCache,
+    dedup: Option<InFlightCache<String, Option<ImageResponse>>>,
     params: std::collections::HashMap<String, String>,
-    _headers: HeaderMap,
-    _auth_info: ApiKey,
+    base_url: Option<String>,
+    limits: RequestLimits,
+    auth_info: ApiKey,
+    flags: FeatureFlags,
 ) -> Result<impl Reply, Rejection> {
+    let (has_metadata, metadata) = ImageFilters::parse_metadata(&params);
+    let size_param = |name| {
+        ImageFilters::size_param(&params, name)
+            .map_err(|e| warp::reject::custom(ImageError::InvalidParameter(e)))
+    };
     let request = BatchRandomRequest {
         count: 1,
         tags: params
@@ -33,99 +124,819 @@ pub async fn get_random_image_handler(
         height: params.get("height").and_then(|w| w.parse().ok()),
         height_min: params.get("height_min").and_then(|w| w.parse().ok()),
         height_max: params.get("height_max").and_then(|w| w.parse().ok()),
-        size: params.get("size").and_then(|w| w.parse().ok()),
-        size_min: params.get("size_min").and_then(|w| w.parse().ok()),
-        size_max: params.get("size_max").and_then(|w| w.parse().ok()),
+        size: size_param("size")?,
+        size_min: size_param("size_min")?,
+        size_max: size_param("size_max")?,
+        tag_match: params
+            .get("tag_match")
+            .and_then(|m| m.parse().ok())
+            .unwrap_or_default(),
+        has_metadata,
+        metadata,
+        bbox: params
+            .get("bbox")
+            .map(|b| b.parse())
+            .transpose()
+            .map_err(|e| warp::reject::custom(ImageError::InvalidParameter(e)))?,
+        owner: params.get("owner").cloned(),
+        exclude_tags: ImageFilters::parse_exclude_tags(&params),
+        collection: params.get("collection").cloned(),
+    };
+    check_owner_filter(&auth_info, request.owner.as_deref())?;
+    check_filter_limits(
+        request.tags.len() + request.exclude_tags.len(),
+        request.has_metadata.len() + request.metadata.len(),
+        limits,
+    )?;
+    let explain = params
+        .get("explain")
+        .map(|v| v.parse::<bool>())
+        .transpose()
+        .map_err(|_| {
+            warp::reject::custom(ImageError::InvalidParameter(
+                "explain must be true or false".to_string(),
+            ))
+        })?
+        .unwrap_or(false);
+    let inline = params
+        .get("inline")
+        .map(|v| v.parse::<bool>())
+        .transpose()
+        .map_err(|_| {
+            warp::reject::custom(ImageError::InvalidParameter(
+                "inline must be true or false".to_string(),
+            ))
+        })?
+        .unwrap_or(false);
+
+    let filters = request
+        .to_filters()
+        .with_defaults(auth_info.default_filters.as_ref())
+        .restrict_to(&auth_info.allowed_tags);
+    let fetch = || async {
+        store
+            .get_random_image_with_strategy(&filters, flags.strategy)
+            .ok()
+    };
+    let result = match &dedup {
+        Some(dedup) => {
+            let key = format!("{}|{}", filters.fingerprint(), flags.strategy.as_str());
+            dedup.run(key, fetch).await
+        }
+        None => fetch().await,
     };
 
-    let filters = request.to_filters();
-    match store.get_random_image_with_filters(&filters) {
-        Ok(image) => {
-            cache.insert(image.filename.clone(), image.clone()).await;
+    match result {
+        Some(mut image) => {
+            cache
+                .insert_random(image.filename.clone(), image.clone())
+                .await;
+            rebase_urls(std::slice::from_mut(&mut image), base_url.as_deref());
+            if inline {
+                inline_image_data(&store, &mut image, limits.inline_max_bytes).await?;
+            }
+            if explain {
+                let matched_tags = filters.matched_tags(&image.tags);
+                return Ok(warp::reply::json(&ExplainedImage {
+                    image,
+                    matched_tags,
+                }));
+            }
             Ok(warp::reply::json(&image))
         }
-        Err(_) => Err(warp::reject::not_found()),
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+/// Only the admin key may draw from another user's uploads.
+fn check_owner_filter(auth_info: &ApiKey, owner: Option<&str>) -> Result<(), Rejection> {
+    match owner {
+        Some(owner) if !auth_info.is_admin && owner != auth_info.username => Err(
+            warp::reject::custom(ImageError::OwnerForbidden(owner.to_string())),
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Every filter tag and metadata key adds bound parameters and a condition
+/// or join, so the number a single request may use is capped. Included and
+/// excluded tags count together.
+fn check_filter_limits(
+    tags: usize,
+    metadata_keys: usize,
+    limits: RequestLimits,
+) -> Result<(), Rejection> {
+    if tags > limits.max_filter_tags {
+        warn!(
+            "Rejected filter with {} tags (max {})",
+            tags, limits.max_filter_tags
+        );
+        return Err(warp::reject::custom(ImageError::TooManyFilterTags(
+            limits.max_filter_tags,
+        )));
     }
+    if metadata_keys > limits.max_filter_metadata {
+        warn!(
+            "Rejected filter with {} metadata keys (max {})",
+            metadata_keys, limits.max_filter_metadata
+        );
+        return Err(warp::reject::custom(ImageError::TooManyMetadataFilters(
+            limits.max_filter_metadata,
+        )));
+    }
+    Ok(())
+}
+
+/// Whether a key with `allowed_tags` may see an image tagged `tags`.
+fn can_see(auth_info: &ApiKey, tags: &[String]) -> bool {
+    auth_info.allowed_tags.is_empty() || auth_info.allowed_tags.iter().any(|tag| tags.contains(tag))
+}
+
+/// Keys with `allowed_tags` may only add images carrying one of them, so
+/// everything they add stays visible to them.
+fn check_allowed_tags(auth_info: &ApiKey, tags: &[String]) -> Result<(), ImageError> {
+    if auth_info.allowed_tags.is_empty() {
+        return Ok(());
+    }
+    if can_see(auth_info, &ImageStore::normalize_tag_list(tags)) {
+        Ok(())
+    } else {
+        Err(ImageError::MissingAllowedTag(
+            auth_info.allowed_tags.clone(),
+        ))
+    }
+}
+
+/// Uploader tracking only drives webhooks, so a failure here doesn't fail
+/// the upload.
+fn record_uploader(store: &ImageStore, hash: &str, username: &str) {
+    if let Err(e) = store.set_image_uploader(hash, username) {
+        warn!("Failed to record uploader of {}: {}", hash, e);
+    }
+}
+
+/// The existing image's hash when `merge_tags` lets an add that duplicates
+/// it tag that image instead. Only its uploader and the admin key may.
+fn mergeable_duplicate(e: &anyhow::Error, merge_tags: bool, auth_info: &ApiKey) -> Option<String> {
+    let duplicate = e.downcast_ref::<DuplicateImage>()?;
+    let allowed =
+        auth_info.is_admin || duplicate.uploaded_by.as_deref() == Some(auth_info.username.as_str());
+    (merge_tags && allowed).then(|| duplicate.hash.clone())
+}
+
+/// Validates an `expected_hash` and lowercases it to match stored hashes.
+fn parse_expected_hash(expected_hash: Option<&str>) -> Result<Option<String>, ImageError> {
+    let Some(hash) = expected_hash.map(str::trim) else {
+        return Ok(None);
+    };
+    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(ImageError::InvalidParameter(
+            "expected_hash must be a hex SHA-256".to_string(),
+        ));
+    }
+    Ok(Some(hash.to_lowercase()))
 }
 
 pub async fn add_image_handler(
     store: ImageStore,
     body: AddImageRequest,
+    auth_info: ApiKey,
 ) -> Result<impl Reply, Rejection> {
+    if matches!(body.path_type, PathType::Url) && !auth_info.has_scope(ApiKeyScope::IngestUrl) {
+        return Err(warp::reject::custom(ImageError::MissingScope(
+            ApiKeyScope::IngestUrl,
+        )));
+    }
     if body.tags.is_empty() {
         error!("Attempt to upload image without tags");
         return Err(warp::reject::custom(ImageError::MissingTags));
     }
+    if let Some(tag) = store.find_blocked_tag(&body.tags) {
+        warn!("Rejected image with blocked tag: {}", tag);
+        return Err(warp::reject::custom(ImageError::BlockedTag(tag)));
+    }
+    store
+        .check_tag_limit(&body.tags)
+        .map_err(|e| warp::reject::custom(tag_write_error(e)))?;
+    check_allowed_tags(&auth_info, &body.tags).map_err(warp::reject::custom)?;
+    let expected_hash =
+        parse_expected_hash(body.expected_hash.as_deref()).map_err(warp::reject::custom)?;
 
     info!(
         "Adding new image from {} with tags: {:?}",
         body.path, body.tags
     );
-    match store.add_image(&body.path, body.path_type).await {
+    let added = store
+        .add_image(
+            &body.path,
+            body.path_type,
+            expected_hash.as_deref(),
+            Some(&auth_info),
+        )
+        .await;
+    let (hash, merged) = match added {
         Ok(hash) => {
-            match store.add_tags(&hash, &body.tags) {
-                Ok(_) => info!("Successfully added tags: {:?}", body.tags),
-                Err(e) => {
-                    error!("Failed to add tags: {}", e);
-                    return Err(warp::reject::custom(ImageError::DatabaseError(format!(
-                        "Failed to add tags: {}",
-                        e
-                    ))));
-                }
-            }
-            info!("Successfully added image from {}", body.path);
-            Ok(warp::reply::with_status(
-                warp::reply::json(&json!({
-                    "message": "Image added successfully",
-                    "hash": hash,
-                    "tags": body.tags
-                })),
-                warp::http::StatusCode::CREATED,
-            ))
+            record_uploader(&store, &hash, &auth_info.username);
+            (hash, false)
         }
         Err(e) => {
-            error!("Failed to add image: {}", e);
-            let err = if e.to_string().contains("not found") {
-                ImageError::PathNotFound(e.to_string())
-            } else if e.to_string().contains("too large") {
-                ImageError::FileTooLarge(e.to_string())
-            } else if e.to_string().contains("Invalid image")
-                || e.to_string().contains("Unsupported image format")
-            {
-                ImageError::InvalidImage(e.to_string())
-            } else if e.to_string().contains("already exists") {
-                ImageError::DuplicateImage(e.to_string())
+            if let Some(hash) = mergeable_duplicate(&e, body.merge_tags, &auth_info) {
+                info!("Merging tags onto existing image {}", hash);
+                (hash, true)
             } else {
-                error!("Unexpected error: {}", e);
-                ImageError::DatabaseError(e.to_string())
-            };
-            Err(warp::reject::custom(err))
+                return Err(warp::reject::custom(add_image_error(e)));
+            }
+        }
+    };
+    match store.add_tags(&hash, &body.tags) {
+        Ok(_) => info!("Successfully added tags: {:?}", body.tags),
+        Err(e) => {
+            error!("Failed to add tags: {}", e);
+            return Err(warp::reject::custom(tag_write_error(e)));
         }
     }
+    let (message, status) = if merged {
+        ("Image already exists, tags merged", StatusCode::OK)
+    } else {
+        info!("Successfully added image from {}", body.path);
+        ("Image added successfully", StatusCode::CREATED)
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({
+            "message": message,
+            "url": store.url_for_hash(&hash).ok(),
+            "hash": hash,
+            "tags": body.tags
+        })),
+        status,
+    ))
+}
+
+fn add_image_error(e: anyhow::Error) -> ImageError {
+    error!("Failed to add image: {}", e);
+    if let Some(quota) = e.downcast_ref::<QuotaExceeded>() {
+        ImageError::QuotaExceeded(quota.clone())
+    } else if let Some(mismatch) = e.downcast_ref::<HashMismatch>() {
+        ImageError::HashMismatch(mismatch.clone())
+    } else if e.to_string().contains("not found") {
+        ImageError::PathNotFound(e.to_string())
+    } else if e.to_string().contains("too large") {
+        ImageError::FileTooLarge(e.to_string())
+    } else if e.to_string().contains("Invalid image")
+        || e.to_string().contains("Unsupported image format")
+    {
+        ImageError::InvalidImage(e.to_string())
+    } else if e.to_string().contains("already exists") {
+        ImageError::DuplicateImage(e.to_string())
+    } else if e.to_string().contains("not allowed") {
+        ImageError::InvalidParameter(e.to_string())
+    } else {
+        error!("Unexpected error: {}", e);
+        ImageError::DatabaseError(e.to_string())
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn get_image_by_filename_handler(
     filename: String,
     store: ImageStore,
     cache: ImageCache,
-    _headers: HeaderMap,
-) -> Result<impl Reply, Rejection> {
-    if let Some(cached) = cache.get(&filename).await {
-        info!("Cache hit for image: {}", filename);
-        return Ok(warp::reply::json(&cached));
-    }
-
-    match store.get_image_by_filename(&filename) {
-        Ok(response) => {
+    headers: HeaderMap,
+    base_url: Option<String>,
+    query: InlineQuery,
+    limits: RequestLimits,
+    auth_info: ApiKey,
+) -> Result<Response, Rejection> {
+    let mut response = match cache.get(&filename).await {
+        Some(cached) => {
+            info!("Cache hit for image: {}", filename);
+            cached
+        }
+        None => {
+            let response = store.get_image_by_filename(&filename).map_err(|e| {
+                error!("Failed to get image {}: {}", filename, e);
+                image_lookup_rejection(&filename, &e)
+            })?;
             info!(
                 "Retrieved image: {} ({}x{} pixels, {} bytes)",
                 response.filename, response.width, response.height, response.size_bytes
             );
             cache.insert(filename, response.clone()).await;
-            Ok(warp::reply::json(&response))
+            response
+        }
+    };
+    if !can_see(&auth_info, &response.tags) {
+        return Err(warp::reject::not_found());
+    }
+    let etag = HeaderValue::from_str(&metadata_etag(
+        &response.hash,
+        &response.modified_at,
+        query.inline,
+    ))
+    .map_err(|e| warp::reject::custom(ImageError::DatabaseError(e.to_string())))?;
+    // the JSON is only for key holders, so shared caches mustn't keep it
+    let cache_control = HeaderValue::from_static("private, no-cache");
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+    };
+    if is_not_modified(
+        header(IF_NONE_MATCH),
+        header(IF_MODIFIED_SINCE),
+        etag.to_str().unwrap_or_default(),
+        &response.modified_at,
+    ) {
+        return Ok(not_modified(etag, &response.modified_at, cache_control));
+    }
+
+    rebase_urls(std::slice::from_mut(&mut response), base_url.as_deref());
+    if query.inline {
+        inline_image_data(&store, &mut response, limits.inline_max_bytes).await?;
+    }
+    let modified_at = response.modified_at.clone();
+    let mut reply = warp::reply::json(&response).into_response();
+    insert_validators(reply.headers_mut(), etag, &modified_at, cache_control);
+    Ok(reply)
+}
+
+/// Embeds the file as base64 for `inline=true`. Files over `max_bytes` are
+/// left out, their `url` still works.
+async fn inline_image_data(
+    store: &ImageStore,
+    image: &mut ImageResponse,
+    max_bytes: u64,
+) -> Result<(), Rejection> {
+    if image.size_bytes > max_bytes {
+        return Ok(());
+    }
+    // reading and encoding up to INLINE_MAX_BYTES would stall the runtime
+    let reader = store.clone();
+    let filename = image.filename.clone();
+    let read = tokio::task::spawn_blocking(move || {
+        reader
+            .read_image_file(&filename)
+            .map(|(data, content_type)| (BASE64.encode(&data), content_type))
+    })
+    .await
+    .map_err(|e| warp::reject::custom(ImageError::DatabaseError(e.to_string())))?;
+    let (data, content_type) = read.map_err(|e| {
+        error!("Failed to read image file {}: {}", image.filename, e);
+        image_lookup_rejection(&image.filename, &e)
+    })?;
+    image.data = Some(data);
+    image.content_type = Some(content_type.to_string());
+    Ok(())
+}
+
+/// Broken images answer 410 Gone; anything else is treated as missing.
+fn image_lookup_rejection(filename: &str, e: &anyhow::Error) -> Rejection {
+    if e.downcast_ref::<BrokenImage>().is_some() {
+        warp::reject::custom(ImageError::ImageBroken(filename.to_string()))
+    } else {
+        warp::reject::not_found()
+    }
+}
+
+/// Answers a request for a broken image's bytes with its placeholder when
+/// `FALLBACK_PLACEHOLDER` is on, and 410 Gone otherwise.
+async fn broken_image_reply(
+    filename: &str,
+    broken: &BrokenImage,
+    placeholders: &Placeholders,
+) -> Result<Response, Rejection> {
+    let Some(png) = placeholders.get(&broken.hash, broken.width, broken.height) else {
+        // answered here, or the authenticated metadata route behind the
+        // file route would turn it into a 401
+        let rejection = warp::reject::custom(ImageError::ImageBroken(filename.to_string()));
+        return match handle_rejection(rejection).await {
+            Ok(reply) => Ok(reply.into_response()),
+            Err(never) => match never {},
+        };
+    };
+    let mut response = Response::new(png.into());
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/png"));
+    // the real image may come back once the file is replaced
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    headers.insert("X-Image-Broken", HeaderValue::from_static("true"));
+    Ok(response)
+}
+
+/// Every URL style hands out the same entity tag, so caches can revalidate
+/// an image no matter which URL they fetched it through.
+fn image_etag(hash: &str) -> String {
+    format!("\"{}\"", hash)
+}
+
+/// Weak comparison, as `If-None-Match` calls for.
+fn etag_matches(if_none_match: Option<&str>, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match.is_some_and(|header| {
+        header
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag)
+    })
+}
+
+/// Adds `X-Content-SHA256` and, unless `Want-Digest` rules SHA-256 out, an
+/// RFC 3230 `Digest`. Both describe the whole file, range responses included.
+fn insert_digest_headers(headers: &mut HeaderMap, hash: &str, want_digest: Option<&str>) {
+    if let Ok(value) = HeaderValue::from_str(hash) {
+        headers.insert("X-Content-SHA256", value);
+    }
+    if !want_digest.is_none_or(wants_sha256) {
+        return;
+    }
+    if let Some(bytes) = from_hex(hash) {
+        if let Ok(value) = HeaderValue::from_str(&format!("sha-256={}", BASE64.encode(bytes))) {
+            headers.insert("Digest", value);
+        }
+    }
+}
+
+/// Whether a `Want-Digest` header (e.g. `sha-256;q=1, sha;q=0.1`) accepts
+/// SHA-256.
+fn wants_sha256(want_digest: &str) -> bool {
+    want_digest.split(',').any(|entry| {
+        let mut parts = entry.split(';').map(str::trim);
+        let algorithm = parts.next().unwrap_or_default();
+        let quality = parts
+            .find_map(|param| param.strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        algorithm.eq_ignore_ascii_case("sha-256") && quality > 0.0
+    })
+}
+
+/// The JSON also changes when tags or metadata do, so its tag follows
+/// `modified_at` as well as the file, and `inline=true` adds the file's
+/// bytes, so it gets a tag of its own.
+fn metadata_etag(hash: &str, modified_at: &str, inline: bool) -> String {
+    let modified = OffsetDateTime::parse(modified_at, &Rfc3339)
+        .map(|t| t.unix_timestamp_nanos())
+        .unwrap_or_default();
+    let variant = if inline { "-inline" } else { "" };
+    format!("W/\"{}-{:x}{}\"", hash, modified, variant)
+}
+
+const HTTP_DATE: &[FormatItem<'static>] = format_description!(
+    "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT"
+);
+
+fn http_date(modified_at: &str) -> Option<HeaderValue> {
+    let modified = OffsetDateTime::parse(modified_at, &Rfc3339)
+        .ok()?
+        .to_offset(UtcOffset::UTC);
+    HeaderValue::from_str(&modified.format(HTTP_DATE).ok()?).ok()
+}
+
+/// `If-Modified-Since` is only looked at without `If-None-Match`, and has
+/// second precision, so a change within the given second counts as older.
+fn is_not_modified(
+    if_none_match: Option<&str>,
+    if_modified_since: Option<&str>,
+    etag: &str,
+    modified_at: &str,
+) -> bool {
+    if if_none_match.is_some() {
+        return etag_matches(if_none_match, etag);
+    }
+    let since =
+        if_modified_since.and_then(|since| OffsetDateTime::parse(since.trim(), &Rfc2822).ok());
+    match (since, OffsetDateTime::parse(modified_at, &Rfc3339)) {
+        (Some(since), Ok(modified)) => modified.unix_timestamp() <= since.unix_timestamp(),
+        _ => false,
+    }
+}
+
+/// Content under `/images/h/{hash}` can never change, so it may be cached
+/// for good. Everything else is revalidated with its ETag on each use.
+fn cache_control(key: Option<ImageKey>) -> HeaderValue {
+    match key {
+        Some(ImageKey::Hash) => HeaderValue::from_static("public, max-age=31536000, immutable"),
+        _ => HeaderValue::from_static("public, no-cache"),
+    }
+}
+
+fn insert_validators(
+    headers: &mut HeaderMap,
+    etag: HeaderValue,
+    modified_at: &str,
+    cache: HeaderValue,
+) {
+    headers.insert(ETAG, etag);
+    if let Some(last_modified) = http_date(modified_at) {
+        headers.insert(LAST_MODIFIED, last_modified);
+    }
+    headers.insert(CACHE_CONTROL, cache);
+}
+
+fn not_modified(etag: HeaderValue, modified_at: &str, cache: HeaderValue) -> Response {
+    let mut response = StatusCode::NOT_MODIFIED.into_response();
+    insert_validators(response.headers_mut(), etag, modified_at, cache);
+    response
+}
+
+/// Serves the bytes behind `/images/h/{hash}` and `/images/id/{id}`,
+/// wherever the file currently lives.
+pub async fn serve_image_handler(
+    value: String,
+    key: ImageKey,
+    store: ImageStore,
+    placeholders: Placeholders,
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+    want_digest: Option<String>,
+) -> Result<Response, Rejection> {
+    let (filename, hash, modified_at) = match store.resolve_image_file(key, &value) {
+        Ok(Some(found)) => found,
+        Ok(None) => return Err(warp::reject::not_found()),
+        Err(e) => {
+            error!("Failed to resolve image {}: {}", value, e);
+            return Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )));
         }
+    };
+    let etag = HeaderValue::from_str(&image_etag(&hash))
+        .map_err(|e| warp::reject::custom(ImageError::DatabaseError(e.to_string())))?;
+    if is_not_modified(
+        if_none_match.as_deref(),
+        if_modified_since.as_deref(),
+        etag.to_str().unwrap_or_default(),
+        &modified_at,
+    ) {
+        return Ok(not_modified(etag, &modified_at, cache_control(Some(key))));
+    }
+
+    let (data, content_type) = match store.read_image_file(&filename) {
+        Ok(file) => file,
         Err(e) => {
-            error!("Failed to get image {}: {}", filename, e);
+            error!("Failed to read image file {}: {}", filename, e);
+            return match e.downcast_ref::<BrokenImage>() {
+                Some(broken) => broken_image_reply(&filename, broken, &placeholders).await,
+                None => Err(warp::reject::not_found()),
+            };
+        }
+    };
+    let mut response = Response::new(data.into());
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    insert_validators(headers, etag, &modified_at, cache_control(Some(key)));
+    insert_digest_headers(headers, &hash, want_digest.as_deref());
+    Ok(response)
+}
+
+/// Output formats `?format=` accepts on the image routes.
+fn conversion_target(format: &str) -> Option<ImageFormat> {
+    match format {
+        "webp" => Some(ImageFormat::WebP),
+        "png" => Some(ImageFormat::Png),
+        "jpeg" => Some(ImageFormat::Jpeg),
+        _ => None,
+    }
+}
+
+/// Serves an image re-encoded as `?format=`, or the stored file when it
+/// already is that format. Each format gets its own ETag.
+#[allow(clippy::too_many_arguments)]
+pub async fn convert_image_handler(
+    value: String,
+    key: ImageKey,
+    format: String,
+    store: ImageStore,
+    placeholders: Placeholders,
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+    want_digest: Option<String>,
+) -> Result<Response, Rejection> {
+    let target = conversion_target(&format).ok_or_else(|| {
+        warp::reject::custom(ImageError::InvalidParameter(format!(
+            "format must be one of webp, png, jpeg (got '{}')",
+            format
+        )))
+    })?;
+    let (filename, hash, modified_at) = match store.resolve_image_file(key, &value) {
+        Ok(Some(found)) => found,
+        Ok(None) => return Err(warp::reject::not_found()),
+        Err(e) => {
+            error!("Failed to resolve image {}: {}", value, e);
+            return Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )));
+        }
+    };
+    let etag = HeaderValue::from_str(&format!("\"{}.{}\"", hash, format))
+        .map_err(|e| warp::reject::custom(ImageError::DatabaseError(e.to_string())))?;
+    if is_not_modified(
+        if_none_match.as_deref(),
+        if_modified_since.as_deref(),
+        etag.to_str().unwrap_or_default(),
+        &modified_at,
+    ) {
+        return Ok(not_modified(etag, &modified_at, cache_control(Some(key))));
+    }
+
+    let converter = store.clone();
+    let source = filename.clone();
+    let converted =
+        tokio::task::spawn_blocking(move || converter.converted_image(&source, &hash, target))
+            .await
+            .map_err(|e| warp::reject::custom(ImageError::DatabaseError(e.to_string())))?;
+    let data = match converted {
+        Ok(Some(data)) => data,
+        Ok(None) => {
+            return serve_image_handler(
+                value,
+                key,
+                store,
+                placeholders,
+                if_none_match,
+                if_modified_since,
+                want_digest,
+            )
+            .await
+        }
+        Err(e) => {
+            error!("Failed to convert image {} to {}: {}", filename, format, e);
+            return match e.downcast_ref::<BrokenImage>() {
+                Some(broken) => broken_image_reply(&filename, broken, &placeholders).await,
+                None => Err(warp::reject::custom(ImageError::InvalidImage(
+                    e.to_string(),
+                ))),
+            };
+        }
+    };
+    let mut response = Response::new(data.into());
+    let headers = response.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(target.to_mime_type()),
+    );
+    insert_validators(headers, etag, &modified_at, cache_control(Some(key)));
+    Ok(response)
+}
+
+/// Gives files served by filename the same ETag as the hash and id routes.
+pub async fn tag_image_file(
+    file: warp::fs::File,
+    store: ImageStore,
+    placeholders: Placeholders,
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+    want_digest: Option<String>,
+) -> Result<Response, Rejection> {
+    let filename = file
+        .path()
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+        .to_string();
+    let hash = match store.check_image_file(&filename) {
+        Ok(hash) => hash,
+        Err(e) => match e.downcast_ref::<BrokenImage>() {
+            Some(broken) => return broken_image_reply(&filename, broken, &placeholders).await,
+            None => None,
+        },
+    };
+    let Some((hash, etag, modified_at)) = hash.and_then(|hash| {
+        let etag = HeaderValue::from_str(&image_etag(&hash)).ok()?;
+        let modified_at = store.image_modified_at(&hash).ok()??;
+        Some((hash, etag, modified_at))
+    }) else {
+        return Ok(file.into_response());
+    };
+    if is_not_modified(
+        if_none_match.as_deref(),
+        if_modified_since.as_deref(),
+        etag.to_str().unwrap_or_default(),
+        &modified_at,
+    ) {
+        return Ok(not_modified(etag, &modified_at, cache_control(None)));
+    }
+
+    let mut response = file.into_response();
+    let headers = response.headers_mut();
+    insert_validators(headers, etag, &modified_at, cache_control(None));
+    insert_digest_headers(headers, &hash, want_digest.as_deref());
+    Ok(response)
+}
+
+/// Returns the image metadata and its bytes as a two-part multipart/mixed body.
+pub async fn get_image_full_handler(
+    filename: String,
+    store: ImageStore,
+    base_url: Option<String>,
+    auth_info: ApiKey,
+) -> Result<impl Reply, Rejection> {
+    let mut response = store.get_image_by_filename(&filename).map_err(|e| {
+        error!("Failed to get image {}: {}", filename, e);
+        image_lookup_rejection(&filename, &e)
+    })?;
+    if !can_see(&auth_info, &response.tags) {
+        return Err(warp::reject::not_found());
+    }
+    rebase_urls(std::slice::from_mut(&mut response), base_url.as_deref());
+    let (data, content_type) = store.read_image_file(&filename).map_err(|e| {
+        error!("Failed to read image file {}: {}", filename, e);
+        image_lookup_rejection(&filename, &e)
+    })?;
+    let metadata = serde_json::to_vec(&response).map_err(|e| {
+        error!("Failed to serialize image {}: {}", filename, e);
+        warp::reject::custom(ImageError::DatabaseError(e.to_string()))
+    })?;
+
+    let boundary = format!("waifu-{}", uuid::Uuid::new_v4().simple());
+    let mut body = Vec::with_capacity(metadata.len() + data.len() + 512);
+    body.extend_from_slice(
+        format!("--{boundary}\r\nContent-Type: application/json\r\n\r\n").as_bytes(),
+    );
+    body.extend_from_slice(&metadata);
+    body.extend_from_slice(
+        format!(
+            "\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Disposition: attachment; filename=\"{filename}\"\r\nContent-Length: {}\r\n\r\n",
+            data.len()
+        )
+        .as_bytes(),
+    );
+    body.extend_from_slice(&data);
+    body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+    info!(
+        "Retrieved image with data: {} ({} bytes)",
+        filename,
+        data.len()
+    );
+
+    Ok(warp::reply::with_header(
+        body,
+        "Content-Type",
+        format!("multipart/mixed; boundary={boundary}"),
+    ))
+}
+
+/// Cheap existence check for sync tooling: one indexed row, no file access.
+pub async fn image_exists_handler(
+    filename: String,
+    store: ImageStore,
+    auth_info: ApiKey,
+) -> Result<impl Reply, Rejection> {
+    match store.images_exist(std::slice::from_ref(&filename), &auth_info.allowed_tags) {
+        Ok(mut results) => {
+            let exists = results.remove(&filename);
+            Ok(warp::reply::json(&exists))
+        }
+        Err(e) => {
+            error!("Failed to check whether {} exists: {}", filename, e);
+            Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )))
+        }
+    }
+}
+
+pub async fn images_exist_handler(
+    store: ImageStore,
+    max_items: usize,
+    body: ExistsBatchRequest,
+    auth_info: ApiKey,
+) -> Result<impl Reply, Rejection> {
+    if body.images.len() > max_items {
+        return Err(warp::reject::custom(ImageError::BatchSizeExceeded(
+            max_items as u32,
+        )));
+    }
+
+    match store.images_exist(&body.images, &auth_info.allowed_tags) {
+        Ok(results) => {
+            debug!("Checked existence of {} images", results.len());
+            Ok(warp::reply::json(&json!({ "results": results })))
+        }
+        Err(e) => {
+            error!("Failed to check image existence: {}", e);
+            Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )))
+        }
+    }
+}
+
+pub async fn get_file_info_handler(
+    filename: String,
+    store: ImageStore,
+    _: (), // Admin auth result
+) -> Result<impl Reply, Rejection> {
+    match store.get_file_info(&filename) {
+        Ok(info) => {
+            if !info.in_sync {
+                warn!("Filesystem and database disagree for image: {}", filename);
+            }
+            Ok(warp::reply::json(&info))
+        }
+        Err(e) => {
+            error!("Failed to get file info for {}: {}", filename, e);
             Err(warp::reject::not_found())
         }
     }
@@ -136,11 +947,9 @@ pub async fn generate_api_key_handler(
     store: ImageStore,
     body: GenerateApiKeyRequest,
 ) -> Result<impl Reply, Rejection> {
-    match store.generate_api_key(
-        &body.username,
-        body.requests_per_second,
-        body.max_batch_size,
-    ) {
+    validate_default_filters(body.default_filters.as_ref())?;
+    let scopes = requested_scopes(&body);
+    match store.generate_api_key(&body, &scopes) {
         Ok(api_key) => {
             info!(
                 username = %body.username,
@@ -149,14 +958,7 @@ pub async fn generate_api_key_handler(
                 "Generated new API key"
             );
             Ok(warp::reply::with_status(
-                warp::reply::json(&json!({
-                    "username": body.username,
-                    "api_key": api_key,
-                    "rate_limit": body.requests_per_second.map(|r| format!("{} requests/second", r))
-                        .unwrap_or_else(|| "unlimited".to_string()),
-                    "max_batch_size": body.max_batch_size.map(|s| s.to_string())
-                        .unwrap_or_else(|| "1".to_string())
-                })),
+                warp::reply::json(&created_api_key_json(&body, &scopes, &api_key)),
                 warp::http::StatusCode::CREATED,
             ))
         }
@@ -175,29 +977,279 @@ pub async fn generate_api_key_handler(
     }
 }
 
-pub async fn remove_api_key_handler(
-    _: (),
+fn requested_scopes(request: &GenerateApiKeyRequest) -> Vec<ApiKeyScope> {
+    request
+        .scopes
+        .clone()
+        .unwrap_or_else(|| vec![ApiKeyScope::Upload])
+}
+
+fn created_api_key_json(
+    request: &GenerateApiKeyRequest,
+    scopes: &[ApiKeyScope],
+    api_key: &str,
+) -> serde_json::Value {
+    json!({
+        "username": request.username,
+        "api_key": api_key,
+        "rate_limit": request.requests_per_second.map(|r| format!("{} requests/second", r))
+            .unwrap_or_else(|| "unlimited".to_string()),
+        "max_batch_size": request.max_batch_size.map(|s| s.to_string())
+            .unwrap_or_else(|| "1".to_string()),
+        "default_filters": request.default_filters.clone().unwrap_or_default(),
+        "scopes": scopes,
+        "url_ingest_daily_bytes": request.url_ingest_daily_bytes,
+        "allowed_tags": ImageStore::normalize_tag_list(&request.allowed_tags)
+    })
+}
+
+/// Creates keys for a group of users at once. Every entry is checked before
+/// anything is written, and the keys are created in one transaction, so a
+/// failed batch leaves no keys behind.
+pub async fn batch_generate_api_keys_handler(
     store: ImageStore,
-    body: RemoveApiKeyRequest,
+    max_keys: usize,
+    body: BatchGenerateApiKeysRequest,
+    _: (), // Admin auth result
 ) -> Result<impl Reply, Rejection> {
-    match store.remove_api_key(&body.username) {
-        Ok(true) => Ok(warp::reply::json(&serde_json::json!({
-            "message": format!("API key for user '{}' was successfully removed", body.username)
-        }))),
-        Ok(false) => Err(warp::reject::custom(ImageError::UsernameNotFound(
-            body.username,
-        ))),
-        Err(e) => Err(warp::reject::custom(ImageError::DatabaseError(
-            e.to_string(),
-        ))),
+    if body.keys.len() > max_keys {
+        return Err(warp::reject::custom(ImageError::BatchSizeExceeded(
+            max_keys as u32,
+        )));
     }
+    let mut seen = std::collections::HashSet::new();
+    for request in &body.keys {
+        let username = &request.username;
+        if username.is_empty() || username.chars().any(|c| c.is_whitespace() || c == '/') {
+            return Err(warp::reject::custom(ImageError::InvalidParameter(format!(
+                "invalid username '{}': must be non-empty without whitespace or '/'",
+                username
+            ))));
+        }
+        if !seen.insert(username.as_str()) {
+            return Err(warp::reject::custom(ImageError::InvalidParameter(format!(
+                "username '{}' appears more than once",
+                username
+            ))));
+        }
+        validate_default_filters(request.default_filters.as_ref())?;
+    }
+
+    let usernames: Vec<&str> = body.keys.iter().map(|r| r.username.as_str()).collect();
+    let existing = store.existing_usernames(&usernames).map_err(|e| {
+        error!("Failed to look up existing usernames: {}", e);
+        warp::reject::custom(ImageError::DatabaseError(e.to_string()))
+    })?;
+    if let Some(username) = existing.first().filter(|_| !body.skip_existing) {
+        return Err(warp::reject::custom(ImageError::UsernameExists(
+            username.clone(),
+        )));
+    }
+
+    let requests: Vec<_> = body
+        .keys
+        .iter()
+        .filter(|request| !existing.contains(&request.username))
+        .map(|request| (request, requested_scopes(request)))
+        .collect();
+    match store.generate_api_keys(&requests) {
+        Ok(api_keys) => {
+            let created: Vec<&str> = requests
+                .iter()
+                .map(|(request, _)| request.username.as_str())
+                .collect();
+            info!(usernames = ?created, skipped = ?existing, "Generated API key batch");
+            let keys: Vec<_> = requests
+                .iter()
+                .zip(api_keys)
+                .map(|((request, scopes), api_key)| created_api_key_json(request, scopes, &api_key))
+                .collect();
+            Ok(warp::reply::with_status(
+                warp::reply::json(&json!({
+                    "keys": keys,
+                    "skipped": existing
+                })),
+                warp::http::StatusCode::CREATED,
+            ))
+        }
+        // another request took one of the names after the check above
+        Err(e) if e.to_string().contains("UNIQUE constraint failed") => {
+            let taken = store
+                .existing_usernames(&usernames)
+                .ok()
+                .and_then(|taken| taken.into_iter().find(|u| !existing.contains(u)))
+                .unwrap_or_default();
+            error!("Username already exists: {}", taken);
+            Err(warp::reject::custom(ImageError::UsernameExists(taken)))
+        }
+        Err(e) => {
+            error!("Failed to generate API key batch: {}", e);
+            Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )))
+        }
+    }
+}
+
+pub async fn clone_api_key_handler(
+    username: String,
+    _: (), // Admin auth result
+    store: ImageStore,
+    body: CloneApiKeyRequest,
+) -> Result<impl Reply, Rejection> {
+    match store.clone_api_key(&username, &body.new_username) {
+        Ok(api_key) => {
+            info!(
+                source = %username,
+                username = %body.new_username,
+                "Cloned API key"
+            );
+            Ok(warp::reply::with_status(
+                warp::reply::json(&json!({
+                    "username": body.new_username,
+                    "api_key": api_key,
+                    "cloned_from": username
+                })),
+                warp::http::StatusCode::CREATED,
+            ))
+        }
+        Err(e) if e.to_string().contains("UNIQUE constraint failed") => {
+            error!("Username already exists: {}", body.new_username);
+            Err(warp::reject::custom(ImageError::UsernameExists(
+                body.new_username,
+            )))
+        }
+        Err(e) if e.to_string().contains("No API key found") => {
+            error!("Failed to clone API key: {}", e);
+            Err(warp::reject::custom(ImageError::UsernameNotFound(username)))
+        }
+        Err(e) => {
+            error!("Failed to clone API key: {}", e);
+            Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )))
+        }
+    }
+}
+
+pub async fn reset_rate_limit_handler(
+    username: String,
+    _: (), // Admin auth result
+    store: ImageStore,
+    rate_limiter: ApiKeyRateLimiter,
+) -> Result<impl Reply, Rejection> {
+    let api_key = match store.get_api_key_for_username(&username) {
+        Ok(Some(api_key)) => api_key,
+        Ok(None) => return Err(warp::reject::custom(ImageError::UsernameNotFound(username))),
+        Err(e) => {
+            error!("Failed to look up API key for {}: {}", username, e);
+            return Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )));
+        }
+    };
+
+    let was_tracked = rate_limiter.reset(&api_key).await;
+    info!(username = %username, was_tracked, "Reset rate limit");
+
+    Ok(warp::reply::json(&json!({
+        "message": "Rate limit reset successfully",
+        "username": username
+    })))
 }
 
-pub async fn list_api_keys_handler(_: (), store: ImageStore) -> Result<impl Reply, Rejection> {
+pub async fn rate_limit_debug_handler(
+    username: String,
+    _: (), // Admin auth result
+    store: ImageStore,
+    rate_limiter: ApiKeyRateLimiter,
+) -> Result<impl Reply, Rejection> {
+    let api_key = match store
+        .get_api_key_for_username(&username)
+        .and_then(|key| key.map(|key| store.get_api_key(&key)).transpose())
+    {
+        Ok(Some(api_key)) => api_key,
+        Ok(None) => return Err(warp::reject::custom(ImageError::UsernameNotFound(username))),
+        Err(e) => {
+            error!("Failed to look up API key for {}: {}", username, e);
+            return Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )));
+        }
+    };
+
+    let debug = rate_limiter
+        .debug(&api_key.key, api_key.requests_per_second)
+        .await;
+    Ok(warp::reply::json(&json!({
+        "username": username,
+        "rate_limit": debug
+    })))
+}
+
+pub async fn rate_limits_handler(
+    _: (), // Admin auth result
+    rate_limiter: ApiKeyRateLimiter,
+) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&rate_limiter.snapshot().await))
+}
+
+pub async fn remove_api_key_handler(
+    _: (),
+    store: ImageStore,
+    body: RemoveApiKeyRequest,
+    dry_run: bool,
+) -> Result<impl Reply, Rejection> {
+    match store.remove_api_key(&body.username, dry_run) {
+        Ok(true) if dry_run => Ok(warp::reply::json(&serde_json::json!({
+            "message": format!("API key for user '{}' would be removed", body.username),
+            "dry_run": true
+        }))),
+        Ok(true) => Ok(warp::reply::json(&serde_json::json!({
+            "message": format!("API key for user '{}' was successfully removed", body.username),
+            "dry_run": false
+        }))),
+        Ok(false) => Err(warp::reject::custom(ImageError::UsernameNotFound(
+            body.username,
+        ))),
+        Err(e) => Err(warp::reject::custom(ImageError::DatabaseError(
+            e.to_string(),
+        ))),
+    }
+}
+
+/// Landing response for `GET /`. Until the first API key exists it also
+/// tells a new operator how to create one.
+pub async fn info_handler(
+    store: ImageStore,
+    limits: RequestLimits,
+) -> Result<impl Reply, Rejection> {
+    let mut info = json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "api_versions": SUPPORTED_VERSIONS,
+        "default_api_version": DEFAULT_VERSION,
+        "limits": limits
+    });
+    match store.count_api_keys() {
+        Ok(0) => info["setup"] = json!(FIRST_KEY_HINT),
+        Ok(_) => {}
+        Err(e) => warn!("Failed to count API keys: {}", e),
+    }
+    Ok(warp::reply::json(&info))
+}
+
+pub async fn list_api_keys_handler(_: (), store: ImageStore) -> Result<Response, Rejection> {
     match store.list_api_keys() {
         Ok(keys) => {
             info!("Listed {} API keys", keys.len());
-            Ok(warp::reply::json(&keys))
+            let mut response = warp::reply::json(&keys).into_response();
+            if keys.is_empty() {
+                response
+                    .headers_mut()
+                    .insert("X-Setup-Hint", HeaderValue::from_static(FIRST_KEY_HINT));
+            }
+            Ok(response)
         }
         Err(e) => {
             error!("Failed to list API keys: {}", e);
@@ -214,12 +1266,17 @@ pub async fn update_api_key_handler(
     store: ImageStore,
     body: UpdateApiKeyRequest,
 ) -> Result<impl Reply, Rejection> {
-    match store.update_api_key_rate_limit(&username, body.requests_per_second) {
+    validate_default_filters(body.default_filters.as_ref())?;
+    match store.update_api_key(&username, &body) {
         Ok(()) => {
             info!(
                 username = %username,
                 new_rate_limit = ?body.requests_per_second,
-                "Updated API key rate limit"
+                default_filters_changed = body.default_filters.is_some(),
+                scopes = ?body.scopes,
+                url_ingest_daily_bytes = ?body.url_ingest_daily_bytes,
+                allowed_tags = ?body.allowed_tags,
+                "Updated API key"
             );
             Ok(warp::reply::with_status(
                 warp::reply::json(&json!({
@@ -240,6 +1297,283 @@ pub async fn update_api_key_handler(
     }
 }
 
+fn validate_default_filters(filters: Option<&DefaultFilters>) -> Result<(), Rejection> {
+    match filters.map(DefaultFilters::validate) {
+        Some(Err(e)) => Err(warp::reject::custom(ImageError::InvalidParameter(format!(
+            "default_filters: {}",
+            e
+        )))),
+        _ => Ok(()),
+    }
+}
+
+pub async fn me_handler(auth_info: ApiKey, store: ImageStore) -> Result<impl Reply, Rejection> {
+    let url_ingest = match auth_info.url_ingest_daily_bytes {
+        Some(limit) => {
+            let used = store
+                .url_ingest_used_today(&auth_info.key)
+                .map_err(|e| warp::reject::custom(ImageError::DatabaseError(e.to_string())))?;
+            Some(json!({
+                "daily_bytes": limit,
+                "used_today": used,
+                "resets_at": quota::next_reset().format(&Rfc3339).ok()
+            }))
+        }
+        None => None,
+    };
+
+    Ok(warp::reply::json(&json!({
+        "username": auth_info.username,
+        "created_at": auth_info.created_at.format(&Rfc3339).ok(),
+        "requests_per_second": auth_info.requests_per_second,
+        "max_batch_size": auth_info.max_batch_size,
+        "default_filters": auth_info.default_filters.unwrap_or_default(),
+        "scopes": auth_info.scopes,
+        "url_ingest": url_ingest,
+        "allowed_tags": auth_info.allowed_tags
+    })))
+}
+
+/// Registers (or replaces) the caller's webhook once it passes the
+/// challenge. The secret is only shown here.
+pub async fn set_my_webhook_handler(
+    store: ImageStore,
+    webhooks: KeyWebhooks,
+    body: SetWebhookRequest,
+    auth_info: ApiKey,
+) -> Result<impl Reply, Rejection> {
+    // URL_ALLOWLIST is about where images come from, not where events go
+    let url = store::validate_public_url(&body.url)
+        .await
+        .map_err(|e| warp::reject::custom(ImageError::InvalidParameter(format!("url: {}", e))))?;
+
+    let secret = KeyWebhooks::new_secret();
+    if let Err(e) = webhooks.verify(&url, &secret).await {
+        warn!(
+            "Webhook verification for {} failed: {}",
+            auth_info.username, e
+        );
+        return Err(warp::reject::custom(ImageError::InvalidParameter(format!(
+            "webhook verification failed: {}",
+            e
+        ))));
+    }
+
+    let webhook = store
+        .set_key_webhook(&auth_info.username, url.as_str(), &secret)
+        .map_err(|e| warp::reject::custom(ImageError::DatabaseError(e.to_string())))?;
+    info!("Registered webhook for {}", auth_info.username);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({
+            "url": webhook.url,
+            "secret": webhook.secret,
+            "events": KEY_WEBHOOK_EVENTS,
+            "created_at": webhook.created_at
+        })),
+        StatusCode::CREATED,
+    ))
+}
+
+pub async fn get_my_webhook_handler(
+    auth_info: ApiKey,
+    store: ImageStore,
+) -> Result<impl Reply, Rejection> {
+    match store.get_key_webhook(&auth_info.username) {
+        Ok(Some(webhook)) => Ok(warp::reply::json(&json!({
+            "url": webhook.url,
+            "events": KEY_WEBHOOK_EVENTS,
+            "created_at": webhook.created_at
+        }))),
+        Ok(None) => Err(warp::reject::custom(ImageError::PathNotFound(
+            "No webhook registered".to_string(),
+        ))),
+        Err(e) => Err(warp::reject::custom(ImageError::DatabaseError(
+            e.to_string(),
+        ))),
+    }
+}
+
+pub async fn remove_my_webhook_handler(
+    store: ImageStore,
+    auth_info: ApiKey,
+) -> Result<impl Reply, Rejection> {
+    remove_key_webhook(&store, &auth_info.username)
+}
+
+pub async fn list_key_webhooks_handler(
+    _: (), // Admin auth result
+    store: ImageStore,
+) -> Result<impl Reply, Rejection> {
+    store
+        .list_key_webhooks()
+        .map(|webhooks| warp::reply::json(&webhooks))
+        .map_err(|e| warp::reject::custom(ImageError::DatabaseError(e.to_string())))
+}
+
+pub async fn remove_key_webhook_handler(
+    username: String,
+    _: (), // Admin auth result
+    store: ImageStore,
+) -> Result<impl Reply, Rejection> {
+    remove_key_webhook(&store, &username)
+}
+
+fn remove_key_webhook(store: &ImageStore, username: &str) -> Result<warp::reply::Json, Rejection> {
+    match store.remove_key_webhook(username) {
+        Ok(true) => {
+            info!("Removed webhook for {}", username);
+            Ok(warp::reply::json(&json!({
+                "message": format!("Webhook for '{}' was removed", username)
+            })))
+        }
+        Ok(false) => Err(warp::reject::custom(ImageError::PathNotFound(format!(
+            "No webhook registered for {}",
+            username
+        )))),
+        Err(e) => Err(warp::reject::custom(ImageError::DatabaseError(
+            e.to_string(),
+        ))),
+    }
+}
+
+const MAX_COLLECTION_NAME_LEN: usize = 64;
+
+fn check_collection_name(name: &str) -> Result<(), Rejection> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_COLLECTION_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(warp::reject::custom(ImageError::InvalidParameter(format!(
+            "Collection names are 1-{} letters, digits, '-' or '_'",
+            MAX_COLLECTION_NAME_LEN
+        ))))
+    }
+}
+
+fn collection_error(e: anyhow::Error) -> Rejection {
+    if e.to_string().starts_with("Collection not found") {
+        warp::reject::custom(ImageError::PathNotFound(e.to_string()))
+    } else {
+        warp::reject::custom(ImageError::DatabaseError(e.to_string()))
+    }
+}
+
+pub async fn list_collections_handler(
+    _: (), // Admin auth result
+    store: ImageStore,
+) -> Result<impl Reply, Rejection> {
+    store
+        .list_collections()
+        .map(|collections| warp::reply::json(&collections))
+        .map_err(|e| warp::reject::custom(ImageError::DatabaseError(e.to_string())))
+}
+
+pub async fn get_collection_handler(
+    name: String,
+    _: (), // Admin auth result
+    store: ImageStore,
+) -> Result<impl Reply, Rejection> {
+    match store.collection_hashes(&name) {
+        Ok(Some(hashes)) => Ok(warp::reply::json(&json!({
+            "name": name,
+            "hashes": hashes,
+        }))),
+        Ok(None) => Err(warp::reject::custom(ImageError::PathNotFound(format!(
+            "Collection not found: {}",
+            name
+        )))),
+        Err(e) => Err(warp::reject::custom(ImageError::DatabaseError(
+            e.to_string(),
+        ))),
+    }
+}
+
+pub async fn create_collection_handler(
+    name: String,
+    _: (), // Admin auth result
+    store: ImageStore,
+) -> Result<impl Reply, Rejection> {
+    check_collection_name(&name)?;
+    match store.create_collection(&name) {
+        Ok(created) => {
+            if created {
+                info!("Created collection {}", name);
+            }
+            let status = if created {
+                StatusCode::CREATED
+            } else {
+                StatusCode::OK
+            };
+            Ok(warp::reply::with_status(
+                warp::reply::json(&json!({ "name": name, "created": created })),
+                status,
+            ))
+        }
+        Err(e) => Err(warp::reject::custom(ImageError::DatabaseError(
+            e.to_string(),
+        ))),
+    }
+}
+
+pub async fn delete_collection_handler(
+    name: String,
+    _: (), // Admin auth result
+    store: ImageStore,
+) -> Result<impl Reply, Rejection> {
+    match store.delete_collection(&name) {
+        Ok(true) => {
+            info!("Deleted collection {}", name);
+            Ok(warp::reply::json(&json!({
+                "message": format!("Collection '{}' was deleted", name)
+            })))
+        }
+        Ok(false) => Err(warp::reject::custom(ImageError::PathNotFound(format!(
+            "Collection not found: {}",
+            name
+        )))),
+        Err(e) => Err(warp::reject::custom(ImageError::DatabaseError(
+            e.to_string(),
+        ))),
+    }
+}
+
+pub async fn add_collection_images_handler(
+    name: String,
+    _: (), // Admin auth result
+    store: ImageStore,
+    body: CollectionImagesRequest,
+) -> Result<impl Reply, Rejection> {
+    let (added, not_found) = store
+        .add_to_collection(&name, &body.hashes)
+        .map_err(collection_error)?;
+    if !added.is_empty() {
+        info!("Added {} images to collection {}", added.len(), name);
+    }
+    Ok(warp::reply::json(&json!({
+        "added": added,
+        "not_found": not_found,
+    })))
+}
+
+pub async fn remove_collection_images_handler(
+    name: String,
+    _: (), // Admin auth result
+    store: ImageStore,
+    body: CollectionImagesRequest,
+) -> Result<impl Reply, Rejection> {
+    let removed = store
+        .remove_from_collection(&name, &body.hashes)
+        .map_err(collection_error)?;
+    if !removed.is_empty() {
+        info!("Removed {} images from collection {}", removed.len(), name);
+    }
+    Ok(warp::reply::json(&json!({ "removed": removed })))
+}
+
 pub async fn update_api_key_status_handler(
     username: String,
     _: (), // Admin auth result
@@ -278,14 +1612,41 @@ pub async fn update_api_key_status_handler(
 pub async fn remove_image_handler(
     filename: String,
     store: ImageStore,
+    webhooks: KeyWebhooks,
+    dry_run: bool,
     _: (), // Admin auth result
 ) -> Result<impl Reply, Rejection> {
-    match store.remove_image(&filename) {
-        Ok(()) => {
+    // looked up first, the uploader is gone with the row
+    let subscriber = match store.uploader_webhook(&filename) {
+        Ok(webhook) => webhook,
+        Err(e) => {
+            warn!("Failed to look up uploader webhook for {}: {}", filename, e);
+            None
+        }
+    };
+    match store.remove_image(&filename, dry_run) {
+        Ok(hash) if dry_run => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({
+                "message": format!("Image '{}' would be removed", filename),
+                "hash": hash,
+                "dry_run": true
+            })),
+            warp::http::StatusCode::OK,
+        )),
+        Ok(hash) => {
             info!("Successfully removed image: {}", filename);
+            if let Some(webhook) = subscriber {
+                webhooks.deliver(
+                    webhook,
+                    "image.deleted",
+                    json!({ "hash": hash, "filename": filename }),
+                );
+            }
             Ok(warp::reply::with_status(
                 warp::reply::json(&json!({
-                    "message": format!("Image '{}' was successfully removed", filename)
+                    "message": format!("Image '{}' was successfully removed", filename),
+                    "hash": hash,
+                    "dry_run": false
                 })),
                 warp::http::StatusCode::OK,
             ))
@@ -299,53 +1660,149 @@ pub async fn remove_image_handler(
     }
 }
 
-pub async fn remove_image_tags_handler(
+/// Replaces an image's file in place. `If-Match` takes the image's ETag so
+/// a stale client can't overwrite someone else's replacement.
+pub async fn replace_image_handler(
     filename: String,
+    content_type: Option<String>,
+    if_match: Option<String>,
+    data: Bytes,
     store: ImageStore,
-    tags: Vec<String>,
+    cache: ImageCache,
     _: (), // Admin auth result
-) -> Result<impl Reply, Rejection> {
-    let image = match store.get_image_by_filename(&filename) {
-        Ok(img) => img,
-        Err(e) => {
-            error!("Failed to get image {}: {}", filename, e);
-            return Err(warp::reject::not_found());
-        }
-    };
+) -> Result<Response, Rejection> {
+    let content_type = content_type.unwrap_or_default().trim().to_lowercase();
+    // If-Match compares strongly, so a weak `W/` tag is left to never match
+    let if_match = if_match.map(|value| value.trim().trim_matches('"').to_string());
 
-    match store.remove_tags(&image.hash, &tags) {
-        Ok(()) => {
+    match store.replace_image(&filename, &data, &content_type, if_match.as_deref()) {
+        Ok(response) => {
+            cache.invalidate(&filename).await;
             info!(
-                "Successfully removed tags {:?} from image: {}",
-                tags, filename
+                "Replaced image: {} ({}x{} pixels, {} bytes)",
+                response.filename, response.width, response.height, response.size_bytes
             );
-            Ok(warp::reply::with_status(
-                warp::reply::json(&serde_json::json!({
-                    "message": format!("Tags removed successfully from image '{}'", filename),
-                    "removed_tags": tags
-                })),
-                warp::http::StatusCode::OK,
-            ))
+            let etag = image_etag(&response.hash);
+            let mut reply = warp::reply::json(&response).into_response();
+            if let Ok(etag) = HeaderValue::from_str(&etag) {
+                reply.headers_mut().insert(ETAG, etag);
+            }
+            Ok(reply)
         }
         Err(e) => {
-            error!("Failed to remove tags from image {}: {}", filename, e);
-            Err(warp::reject::custom(ImageError::DatabaseError(
-                e.to_string(),
-            )))
+            error!("Failed to replace image {}: {}", filename, e);
+            let msg = e.to_string();
+            let err = if msg.starts_with("Precondition failed") {
+                ImageError::PreconditionFailed(msg)
+            } else if msg.contains("not found") {
+                ImageError::PathNotFound(msg)
+            } else if msg.contains("Invalid image") || msg.contains("Unsupported content type") {
+                ImageError::InvalidImage(msg)
+            } else if msg.contains("already exists") {
+                ImageError::DuplicateImage(msg)
+            } else {
+                ImageError::DatabaseError(msg)
+            };
+            Err(warp::reject::custom(err))
         }
     }
 }
 
-pub async fn add_image_tags_handler(
+pub async fn refresh_image_handler(
     filename: String,
     store: ImageStore,
-    tags: Vec<String>,
+    cache: ImageCache,
+    _: (), // Admin auth result
+) -> Result<impl Reply, Rejection> {
+    match store.refresh_image(&filename) {
+        Ok(response) => {
+            cache.invalidate(&filename).await;
+            info!(
+                "Refreshed image: {} ({}x{} pixels, {} bytes)",
+                response.filename, response.width, response.height, response.size_bytes
+            );
+            Ok(warp::reply::json(&response))
+        }
+        Err(e) => {
+            error!("Failed to refresh image {}: {}", filename, e);
+            let msg = e.to_string();
+            if msg.contains("not found") {
+                Err(warp::reject::custom(ImageError::PathNotFound(msg)))
+            } else if msg.contains("Invalid image") {
+                Err(warp::reject::custom(ImageError::InvalidImage(msg)))
+            } else if msg.contains("already exists") {
+                Err(warp::reject::custom(ImageError::DuplicateImage(msg)))
+            } else {
+                Err(warp::reject::custom(ImageError::DatabaseError(msg)))
+            }
+        }
+    }
+}
+
+pub async fn remove_image_tags_handler(
+    filename: String,
+    store: ImageStore,
+    cache: ImageCache,
+    tags: Vec<String>,
+    dry_run: bool,
+    _: (), // Admin auth result
+) -> Result<impl Reply, Rejection> {
+    let image = match store.get_image_by_filename(&filename) {
+        Ok(img) => img,
+        Err(e) => {
+            error!("Failed to get image {}: {}", filename, e);
+            return Err(warp::reject::not_found());
+        }
+    };
+
+    match store.remove_tags(&image.hash, &tags, dry_run) {
+        Ok(removed) if dry_run => Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({
+                "message": format!("Tags would be removed from image '{}'", filename),
+                "removed_tags": removed,
+                "dry_run": true
+            })),
+            warp::http::StatusCode::OK,
+        )),
+        Ok(removed) => {
+            cache.invalidate(&filename).await;
+            info!(
+                "Successfully removed tags {:?} from image: {}",
+                removed, filename
+            );
+            Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({
+                    "message": format!("Tags removed successfully from image '{}'", filename),
+                    "removed_tags": removed,
+                    "dry_run": false
+                })),
+                warp::http::StatusCode::OK,
+            ))
+        }
+        Err(e) => {
+            error!("Failed to remove tags from image {}: {}", filename, e);
+            Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )))
+        }
+    }
+}
+
+pub async fn add_image_tags_handler(
+    filename: String,
+    store: ImageStore,
+    cache: ImageCache,
+    tags: Vec<String>,
     _: (), // Admin auth result
 ) -> Result<impl Reply, Rejection> {
     if tags.is_empty() {
         error!("Attempt to add empty tags list");
         return Err(warp::reject::custom(ImageError::MissingTags));
     }
+    if let Some(tag) = store.find_blocked_tag(&tags) {
+        warn!("Rejected blocked tag {} for image: {}", tag, filename);
+        return Err(warp::reject::custom(ImageError::BlockedTag(tag)));
+    }
 
     let image = match store.get_image_by_filename(&filename) {
         Ok(img) => img,
@@ -357,6 +1814,7 @@ pub async fn add_image_tags_handler(
 
     match store.add_tags(&image.hash, &tags) {
         Ok(()) => {
+            cache.invalidate(&filename).await;
             info!("Successfully added tags {:?} to image: {}", tags, filename);
             Ok(warp::reply::with_status(
                 warp::reply::json(&serde_json::json!({
@@ -368,18 +1826,173 @@ pub async fn add_image_tags_handler(
         }
         Err(e) => {
             error!("Failed to add tags to image {}: {}", filename, e);
-            Err(warp::reject::custom(ImageError::DatabaseError(
-                e.to_string(),
-            )))
+            Err(warp::reject::custom(tag_write_error(e)))
+        }
+    }
+}
+
+/// `add_tags` failures: `TagLimitReached` keeps its own code, anything
+/// else is a database error.
+fn tag_write_error(e: anyhow::Error) -> ImageError {
+    match e.downcast_ref::<TagLimitReached>() {
+        Some(limit) => ImageError::TagLimitReached(limit.limit),
+        None => ImageError::DatabaseError(format!("Failed to add tags: {}", e)),
+    }
+}
+
+pub async fn copy_image_tags_handler(
+    filename: String,
+    store: ImageStore,
+    cache: ImageCache,
+    body: CopyTagsRequest,
+    _: (), // Admin auth result
+) -> Result<impl Reply, Rejection> {
+    copy_tags(
+        &store,
+        &cache,
+        &filename,
+        &body.from,
+        false,
+        body.include_metadata,
+    )
+    .await
+}
+
+pub async fn copy_tags_from_handler(
+    filename: String,
+    source: String,
+    store: ImageStore,
+    cache: ImageCache,
+    query: CopyTagsFromQuery,
+    _: (), // Admin auth result
+) -> Result<impl Reply, Rejection> {
+    copy_tags(&store, &cache, &filename, &source, !query.merge, false).await
+}
+
+async fn copy_tags(
+    store: &ImageStore,
+    cache: &ImageCache,
+    filename: &str,
+    source: &str,
+    replace: bool,
+    include_metadata: bool,
+) -> Result<warp::reply::Json, Rejection> {
+    match store.copy_tags(filename, source, replace, include_metadata) {
+        Ok(result) => {
+            cache.invalidate(filename).await;
+            info!(
+                source = %source,
+                added = ?result.added,
+                removed = ?result.removed,
+                include_metadata,
+                "Copied tags to image: {}", filename
+            );
+            Ok(warp::reply::json(&result))
+        }
+        Err(e) => {
+            error!(
+                "Failed to copy tags from {} to image {}: {}",
+                source, filename, e
+            );
+            let msg = e.to_string();
+            if msg.contains("not found") {
+                Err(warp::reject::custom(ImageError::PathNotFound(msg)))
+            } else if msg.contains("to itself") {
+                Err(warp::reject::custom(ImageError::InvalidParameter(msg)))
+            } else {
+                Err(warp::reject::custom(ImageError::DatabaseError(msg)))
+            }
+        }
+    }
+}
+
+pub async fn change_tags_by_hash_handler(
+    store: ImageStore,
+    cache: ImageCache,
+    changes: Vec<HashTagChange>,
+    dry_run: bool,
+    _: (), // Admin auth result
+) -> Result<impl Reply, Rejection> {
+    if changes.is_empty() {
+        return Err(warp::reject::custom(ImageError::InvalidBody(
+            "expected a non-empty list of changes".to_string(),
+        )));
+    }
+    let added: Vec<String> = changes.iter().flat_map(|c| c.add.clone()).collect();
+    if let Some(tag) = store.find_blocked_tag(&added) {
+        warn!("Rejected blocked tag {} in tag changes by hash", tag);
+        return Err(warp::reject::custom(ImageError::BlockedTag(tag)));
+    }
+
+    match store.change_tags_by_hash(&changes, dry_run) {
+        Ok(results) => {
+            if !dry_run {
+                for result in &results {
+                    cache.invalidate(&result.filename).await;
+                }
+                info!("Changed tags of {} images by hash", results.len());
+            }
+            Ok(warp::reply::json(
+                &json!({ "results": results, "dry_run": dry_run }),
+            ))
+        }
+        Err(e) => {
+            error!("Failed to change tags by hash: {}", e);
+            let msg = e.to_string();
+            if let Some(limit) = e.downcast_ref::<TagLimitReached>() {
+                Err(warp::reject::custom(ImageError::TagLimitReached(
+                    limit.limit,
+                )))
+            } else if msg.contains("not found") {
+                Err(warp::reject::custom(ImageError::PathNotFound(msg)))
+            } else {
+                Err(warp::reject::custom(ImageError::DatabaseError(msg)))
+            }
+        }
+    }
+}
+
+pub async fn set_image_metadata_handler(
+    filename: String,
+    store: ImageStore,
+    metadata: BTreeMap<String, String>,
+    _: (), // Admin auth result
+) -> Result<impl Reply, Rejection> {
+    if metadata.is_empty() || metadata.keys().any(|k| k.trim().is_empty()) {
+        return Err(warp::reject::custom(ImageError::InvalidParameter(
+            "metadata must be a non-empty object with non-empty keys".to_string(),
+        )));
+    }
+
+    match store.set_image_metadata(&filename, &metadata) {
+        Ok(metadata) => {
+            info!("Updated metadata for image: {}", filename);
+            Ok(warp::reply::json(&json!({
+                "filename": filename,
+                "metadata": metadata
+            })))
+        }
+        Err(e) => {
+            error!("Failed to set metadata on image {}: {}", filename, e);
+            if e.to_string().contains("Image not found") {
+                Err(warp::reject::custom(ImageError::PathNotFound(
+                    e.to_string(),
+                )))
+            } else {
+                Err(warp::reject::custom(ImageError::DatabaseError(
+                    e.to_string(),
+                )))
+            }
         }
     }
 }
 
 pub async fn get_all_tags_handler(
+    query: TagListQuery,
     store: ImageStore,
     _: (), // Auth result
 ) -> Result<impl Reply, Rejection> {
-    match store.get_all_tags() {
+    match store.get_all_tags(query.include_empty) {
         Ok(tags) => {
             info!("Retrieved {} unique tags", tags.len());
             let tag_objects: Vec<_> = tags
@@ -406,148 +2019,593 @@ pub async fn get_all_tags_handler(
     }
 }
 
-pub async fn batch_random_images_handler(
-    store: ImageStore,
-    cache: ImageCache,
-    _headers: HeaderMap,
-    auth_info: ApiKey,
-    body: BatchRandomRequest,
-) -> Result<impl Reply, Rejection> {
-    let max_batch = auth_info.max_batch_size.unwrap_or(1);
-    if body.count > max_batch {
-        return Err(warp::reject::custom(ImageError::BatchSizeExceeded(
-            max_batch,
-        )));
-    }
+/// The listing URL with the request's query parameters, paging ones
+/// replaced by `paging`.
+fn listing_link(
+    listing_url: &str,
+    params: &std::collections::HashMap<String, String>,
+    paging: &[(&str, &str)],
+) -> String {
+    // sorted so the same page always gets the same URL
+    let params: std::collections::BTreeMap<&str, &str> = params
+        .iter()
+        .filter(|(key, _)| !matches!(key.as_str(), "limit" | "offset" | "after"))
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect();
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(params)
+        .extend_pairs(paging)
+        .finish();
+    format!("{}?{}", listing_url, query)
+}
 
-    let filters = body.to_filters();
-    let mut images = Vec::new();
-    let mut errors = Vec::new();
+/// Links to the pages either side of `offset`, keeping the request's other
+/// query parameters. `None` past either end of the listing.
+fn page_links(
+    listing_url: &str,
+    params: &std::collections::HashMap<String, String>,
+    limit: u32,
+    offset: u32,
+    total: u64,
+) -> (Option<String>, Option<String>) {
+    let link = |offset: u32| {
+        listing_link(
+            listing_url,
+            params,
+            &[
+                ("limit", &limit.to_string()),
+                ("offset", &offset.to_string()),
+            ],
+        )
+    };
+    let next = (limit > 0 && u64::from(offset) + u64::from(limit) < total)
+        .then(|| link(offset.saturating_add(limit)));
+    let prev = (offset > 0).then(|| link(offset.saturating_sub(limit)));
+    (next, prev)
+}
 
-    for _ in 0..body.count {
-        match store.get_random_image_with_filters(&filters) {
-            Ok(response) => {
-                info!(
-                    "Retrieved random image: {} ({}x{} pixels, {} bytes)",
-                    response.filename, response.width, response.height, response.size_bytes
-                );
-                cache
-                    .insert(response.filename.clone(), response.clone())
-                    .await;
-                images.push(response);
-            }
-            Err(e) => {
-                error!("Failed to get random image: {}", e);
-                errors.push(e.to_string());
-            }
+/// Resolves `after` to a cursor: a `next` link's token, the position of
+/// the image with that filename, or else the instant if it's an RFC 3339
+/// timestamp.
+fn listing_cursor(store: &ImageStore, after: &str) -> Result<ImageCursor, Rejection> {
+    if let Some(cursor) = ImageCursor::from_token(after) {
+        return Ok(cursor);
+    }
+    match store.image_cursor(after) {
+        Ok(Some(cursor)) => return Ok(cursor),
+        Ok(None) => {}
+        Err(e) => {
+            error!("Failed to look up listing cursor: {}", e);
+            return Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )));
         }
     }
+    // stored timestamps are UTC, so compare like with like
+    let created_at = OffsetDateTime::parse(after, &Rfc3339)
+        .ok()
+        .and_then(|instant| instant.to_offset(UtcOffset::UTC).format(&Rfc3339).ok());
+    match created_at {
+        Some(created_at) => Ok(ImageCursor {
+            created_at,
+            hash: None,
+        }),
+        None => Err(warp::reject::custom(ImageError::InvalidParameter(format!(
+            "after must be an image filename or an RFC 3339 timestamp (got '{}')",
+            after
+        )))),
+    }
+}
 
-    let total = body.count as usize;
-    let successful = images.len();
-    let failed = errors.len();
-
-    Ok(warp::reply::json(&BatchImageResponse {
-        images,
-        total,
-        successful,
-        failed,
-        errors,
-    }))
+/// The `after=` flavour of `GET /images`: one page following the cursor and
+/// a `next` link continuing from its last image.
+fn list_images_after(
+    store: ImageStore,
+    params: std::collections::HashMap<String, String>,
+    filters: ImageFilters,
+    cursor: ImageCursor,
+    order: SortOrder,
+    limit: u32,
+    base_url: Option<String>,
+) -> Result<warp::reply::Json, Rejection> {
+    let listing = store.count_images_with_filters(&filters).and_then(|total| {
+        store
+            .list_images_after(&filters, &cursor, order, limit)
+            .map(|(images, next)| (total, images, next))
+    });
+    match listing {
+        Ok((total, mut images, next)) => {
+            info!("Listed {} of {} images after cursor", images.len(), total);
+            rebase_urls(&mut images, base_url.as_deref());
+            let listing_url = match &base_url {
+                Some(base_url) => format!("{}/images", base_url),
+                None => store.images_url().to_string(),
+            };
+            let next = next.map(|after| {
+                listing_link(
+                    &listing_url,
+                    &params,
+                    &[("limit", &limit.to_string()), ("after", &after.token())],
+                )
+            });
+            Ok(warp::reply::json(&json!({
+                "images": images,
+                "count": images.len(),
+                "total": total,
+                "limit": limit,
+                "after": params.get("after"),
+                "sort": ImageSort::Created,
+                "order": order,
+                "next": next,
+                "prev": null
+            })))
+        }
+        Err(e) => {
+            error!("Failed to list images: {}", e);
+            Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )))
+        }
+    }
 }
 
-pub async fn batch_add_images_handler(
+pub async fn list_images_handler(
+    params: std::collections::HashMap<String, String>,
     store: ImageStore,
-    body: BatchAddImageRequest,
+    base_url: Option<String>,
+    limits: RequestLimits,
     auth_info: ApiKey,
 ) -> Result<impl Reply, Rejection> {
-    let max_batch = auth_info.max_batch_size.unwrap_or(1);
-    if body.images.len() > max_batch as usize {
-        return Err(warp::reject::custom(ImageError::BatchSizeExceeded(
-            max_batch,
-        )));
-    }
-
-    let mut successful = Vec::new();
-    let mut errors = Vec::new();
-
-    let futures: Vec<_> = body
-        .images
-        .into_iter()
-        .map(|req| {
-            let store = store.clone();
-            async move {
-                if req.tags.is_empty() {
-                    return Err(ImageError::MissingTags);
-                }
-
-                match store.add_image(&req.path, req.path_type).await {
-                    Ok(hash) => match store.add_tags(&hash, &req.tags) {
-                        Ok(_) => Ok((hash, req.tags)),
-                        Err(e) => {
-                            error!("Failed to add tags: {}", e);
-                            Err(ImageError::DatabaseError(format!(
-                                "Failed to add tags: {}",
-                                e
-                            )))
-                        }
-                    },
-                    Err(e) => {
-                        error!("Failed to add image: {}", e);
-                        Err(if e.to_string().contains("not found") {
-                            ImageError::PathNotFound(e.to_string())
-                        } else if e.to_string().contains("too large") {
-                            ImageError::FileTooLarge(e.to_string())
-                        } else if e.to_string().contains("Invalid image")
-                            || e.to_string().contains("Unsupported image format")
-                        {
-                            ImageError::InvalidImage(e.to_string())
-                        } else if e.to_string().contains("already exists") {
-                            ImageError::DuplicateImage(e.to_string())
-                        } else {
-                            ImageError::DatabaseError(e.to_string())
-                        })
-                    }
-                }
-            }
-        })
-        .collect();
-
-    let results = join_all(futures).await;
+    let filters = ImageFilters::from_query(&params)
+        .map_err(|e| warp::reject::custom(ImageError::InvalidParameter(e)))?;
+    check_filter_limits(
+        filters.tags.as_ref().map_or(0, Vec::len) + filters.exclude_tags.len(),
+        filters.has_metadata.len() + filters.metadata.len(),
+        limits,
+    )?;
+    let filters = filters
+        .with_defaults(auth_info.default_filters.as_ref())
+        .restrict_to(&auth_info.allowed_tags);
+    let limit = params
+        .get("limit")
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .min(MAX_LIST_LIMIT);
+    let offset = params
+        .get("offset")
+        .and_then(|o| o.parse().ok())
+        .unwrap_or(0);
+    let sort = match params.get("sort") {
+        Some(sort) => sort.parse::<ImageSort>().map_err(|_| {
+            warp::reject::custom(ImageError::InvalidParameter(format!(
+                "sort must be one of width, height, size, created (got '{}')",
+                sort
+            )))
+        })?,
+        None => ImageSort::default(),
+    };
+    let order = match params.get("order") {
+        Some(order) => order.parse::<SortOrder>().map_err(|_| {
+            warp::reject::custom(ImageError::InvalidParameter(format!(
+                "order must be asc or desc (got '{}')",
+                order
+            )))
+        })?,
+        None => SortOrder::default(),
+    };
 
-    for result in results {
-        match result {
-            Ok((hash, tags)) => {
-                successful.push(serde_json::json!({
-                    "hash": hash,
-                    "tags": tags
-                }));
-            }
-            Err(e) => {
-                errors.push(e.to_string());
-            }
+    if let Some(after) = params.get("after") {
+        if params.contains_key("offset") || sort != ImageSort::Created {
+            return Err(warp::reject::custom(ImageError::InvalidParameter(
+                "after can't be combined with offset or a sort other than created".to_string(),
+            )));
         }
+        let cursor = listing_cursor(&store, after)?;
+        return list_images_after(store, params, filters, cursor, order, limit, base_url);
     }
 
-    let response = serde_json::json!({
-        "message": "Batch processing completed",
-        "total": successful.len() + errors.len(),
-        "successful": successful.len(),
-        "failed": errors.len(),
-        "results": successful,
-        "errors": errors
+    let listing = store.count_images_with_filters(&filters).and_then(|total| {
+        store
+            .list_images_with_filters(&filters, sort, order, limit, offset)
+            .map(|images| (total, images))
     });
-
-    Ok(warp::reply::with_status(
-        warp::reply::json(&response),
-        warp::http::StatusCode::CREATED,
-    ))
+    match listing {
+        Ok((total, mut images)) => {
+            info!("Listed {} of {} images", images.len(), total);
+            rebase_urls(&mut images, base_url.as_deref());
+            let listing_url = match &base_url {
+                Some(base_url) => format!("{}/images", base_url),
+                None => store.images_url().to_string(),
+            };
+            let (next, prev) = page_links(&listing_url, &params, limit, offset, total);
+            Ok(warp::reply::json(&json!({
+                "images": images,
+                "count": images.len(),
+                "total": total,
+                "limit": limit,
+                "offset": offset,
+                "sort": sort,
+                "order": order,
+                "next": next,
+                "prev": prev
+            })))
+        }
+        Err(e) => {
+            error!("Failed to list images: {}", e);
+            Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )))
+        }
+    }
 }
 
-pub async fn upload_image_handler(
-    mut form: FormData,
+pub async fn search_images_handler(
+    params: std::collections::HashMap<String, String>,
+    store: ImageStore,
+    base_url: Option<String>,
+    limits: RequestLimits,
+    auth_info: ApiKey,
+) -> Result<impl Reply, Rejection> {
+    let filters = ImageFilters::from_query(&params)
+        .map_err(|e| warp::reject::custom(ImageError::InvalidParameter(e)))?;
+    check_filter_limits(
+        filters.tags.as_ref().map_or(0, Vec::len) + filters.exclude_tags.len(),
+        filters.has_metadata.len() + filters.metadata.len(),
+        limits,
+    )?;
+    let filters = filters
+        .with_defaults(auth_info.default_filters.as_ref())
+        .restrict_to(&auth_info.allowed_tags);
+    let search = SearchFilters::from_query(&params, filters, DEFAULT_LIST_LIMIT, MAX_LIST_LIMIT)
+        .map_err(|e| warp::reject::custom(ImageError::InvalidParameter(e)))?;
+
+    match store.search_images(&search) {
+        Ok((mut images, total)) => {
+            info!(
+                "Search matched {} images, returning {}",
+                total,
+                images.len()
+            );
+            rebase_urls(&mut images, base_url.as_deref());
+            let search_url = format!(
+                "{}/search",
+                base_url.as_deref().unwrap_or_else(|| store.server_url())
+            );
+            let (next, prev) = page_links(&search_url, &params, search.limit, search.offset, total);
+            Ok(warp::reply::json(&json!({
+                "images": images,
+                "count": images.len(),
+                "total": total,
+                "limit": search.limit,
+                "offset": search.offset,
+                "sort": search.sort.search_field(),
+                "order": search.order,
+                "next": next,
+                "prev": prev
+            })))
+        }
+        Err(e) => {
+            error!("Failed to search images: {}", e);
+            Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )))
+        }
+    }
+}
+
+pub async fn changed_since_handler(
+    query: ChangedSinceQuery,
+    store: ImageStore,
+    auth_info: ApiKey,
+) -> Result<impl Reply, Rejection> {
+    let since = OffsetDateTime::parse(&query.timestamp, &Rfc3339).map_err(|e| {
+        warp::reject::custom(ImageError::InvalidParameter(format!(
+            "timestamp must be RFC3339: {}",
+            e
+        )))
+    })?;
+
+    match store.get_changed_since(since, &auth_info.allowed_tags) {
+        Ok(images) => {
+            let images: Vec<_> = images
+                .into_iter()
+                .map(|(filename, modified_at)| {
+                    json!({
+                        "filename": filename,
+                        "modified_at": modified_at
+                    })
+                })
+                .collect();
+            Ok(warp::reply::json(&json!({
+                "since": query.timestamp,
+                "total": images.len(),
+                "images": images
+            })))
+        }
+        Err(e) => {
+            error!("Failed to list changed images: {}", e);
+            Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )))
+        }
+    }
+}
+
+pub async fn sync_changes_handler(
+    query: SyncChangesQuery,
+    store: ImageStore,
+    auth_info: ApiKey,
+) -> Result<impl Reply, Rejection> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SYNC_LIMIT)
+        .clamp(1, MAX_SYNC_LIMIT);
+
+    // one extra row tells whether another page follows
+    match store.get_changes(query.since, limit + 1, &auth_info.allowed_tags) {
+        Ok((_, pruned_through)) if query.since < pruned_through => Err(warp::reject::custom(
+            ImageError::CursorExpired(pruned_through),
+        )),
+        Ok((mut changes, _)) => {
+            let has_more = changes.len() > limit;
+            changes.truncate(limit);
+            let next_cursor = changes.last().map_or(query.since, |c| c.cursor);
+            Ok(warp::reply::json(&json!({
+                "changes": changes,
+                "next_cursor": next_cursor,
+                "has_more": has_more
+            })))
+        }
+        Err(e) => {
+            error!("Failed to read change log: {}", e);
+            Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )))
+        }
+    }
+}
+
+pub async fn tag_namespaces_handler(
+    store: ImageStore,
+    _: (), // Auth result
+) -> Result<impl Reply, Rejection> {
+    match store.list_namespaces() {
+        Ok(namespaces) => {
+            let namespaces: Vec<_> = namespaces
+                .into_iter()
+                .map(|(namespace, tag_count)| {
+                    json!({
+                        "namespace": namespace,
+                        "tag_count": tag_count
+                    })
+                })
+                .collect();
+            Ok(warp::reply::json(&json!({ "namespaces": namespaces })))
+        }
+        Err(e) => {
+            error!("Failed to list tag namespaces: {}", e);
+            Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )))
+        }
+    }
+}
+
+pub async fn autocomplete_tags_handler(
+    query: AutocompleteQuery,
     store: ImageStore,
     _: (), // Auth result
+) -> Result<impl Reply, Rejection> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_AUTOCOMPLETE_LIMIT)
+        .min(MAX_AUTOCOMPLETE_LIMIT);
+
+    match store.autocomplete_tags(&query.q, limit) {
+        Ok(tags) => Ok(warp::reply::json(&json!({
+            "prefix": query.q,
+            "tags": tags
+        }))),
+        Err(e) => {
+            error!("Failed to autocomplete tags for '{}': {}", query.q, e);
+            Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )))
+        }
+    }
+}
+
+pub async fn batch_random_images_handler(
+    store: ImageStore,
+    cache: ImageCache,
+    flags: FeatureFlags,
+    auth_info: ApiKey,
+    body: BatchRandomRequest,
+    base_url: Option<String>,
+    limits: RequestLimits,
+) -> Result<impl Reply, Rejection> {
+    let max_batch = auth_info.max_batch_size.unwrap_or(1);
+    if body.count > max_batch {
+        return Err(warp::reject::custom(ImageError::BatchSizeExceeded(
+            max_batch,
+        )));
+    }
+    check_owner_filter(&auth_info, body.owner.as_deref())?;
+    check_filter_limits(
+        body.tags.len() + body.exclude_tags.len(),
+        body.has_metadata.len() + body.metadata.len(),
+        limits,
+    )?;
+
+    let filters = body
+        .to_filters()
+        .with_defaults(auth_info.default_filters.as_ref())
+        .restrict_to(&auth_info.allowed_tags);
+    let mut images = Vec::new();
+    let mut errors = Vec::new();
+    let mut reason = None;
+
+    for _ in 0..body.count {
+        match store.get_random_image_with_strategy(&filters, flags.strategy) {
+            Ok(response) => {
+                info!(
+                    "Retrieved random image: {} ({}x{} pixels, {} bytes)",
+                    response.filename, response.width, response.height, response.size_bytes
+                );
+                cache
+                    .insert_random(response.filename.clone(), response.clone())
+                    .await;
+                images.push(response);
+            }
+            // every further draw would come up empty too
+            Err(e)
+                if matches!(
+                    e.downcast_ref::<rusqlite::Error>(),
+                    Some(rusqlite::Error::QueryReturnedNoRows)
+                ) =>
+            {
+                reason = Some("no_matches".to_string());
+                break;
+            }
+            Err(e) => {
+                error!("Failed to get random image: {}", e);
+                errors.push(e.to_string());
+            }
+        }
+    }
+
+    rebase_urls(&mut images, base_url.as_deref());
+    let total = body.count as usize;
+    let successful = images.len();
+    let failed = errors.len();
+
+    Ok(warp::reply::json(&BatchImageResponse {
+        images,
+        total,
+        successful,
+        failed,
+        errors,
+        reason,
+    }))
+}
+
+pub async fn batch_add_images_handler(
+    store: ImageStore,
+    gate: UploadGate,
+    body: BatchAddImageRequest,
+    auth_info: ApiKey,
+) -> Result<impl Reply, Rejection> {
+    let max_batch = auth_info.max_batch_size.unwrap_or(1);
+    if body.images.len() > max_batch as usize {
+        return Err(warp::reject::custom(ImageError::BatchSizeExceeded(
+            max_batch,
+        )));
+    }
+    if !auth_info.has_scope(ApiKeyScope::IngestUrl)
+        && body
+            .images
+            .iter()
+            .any(|req| matches!(req.path_type, PathType::Url))
+    {
+        return Err(warp::reject::custom(ImageError::MissingScope(
+            ApiKeyScope::IngestUrl,
+        )));
+    }
+
+    let mut successful = Vec::new();
+    let mut errors = Vec::new();
+    let auth_info = &auth_info;
+
+    let futures: Vec<_> = body
+        .images
+        .into_iter()
+        .map(|req| {
+            let store = store.clone();
+            let gate = gate.clone();
+            async move {
+                if req.tags.is_empty() {
+                    return Err(ImageError::MissingTags);
+                }
+                if let Some(tag) = store.find_blocked_tag(&req.tags) {
+                    return Err(ImageError::BlockedTag(tag));
+                }
+                store.check_tag_limit(&req.tags).map_err(tag_write_error)?;
+                check_allowed_tags(auth_info, &req.tags)?;
+                let expected_hash = parse_expected_hash(req.expected_hash.as_deref())?;
+
+                let _permit = gate.acquire().await?;
+                let added = store
+                    .add_image(
+                        &req.path,
+                        req.path_type,
+                        expected_hash.as_deref(),
+                        Some(auth_info),
+                    )
+                    .await;
+                let hash = match added {
+                    Ok(hash) => {
+                        record_uploader(&store, &hash, &auth_info.username);
+                        hash
+                    }
+                    Err(e) => match mergeable_duplicate(&e, req.merge_tags, auth_info) {
+                        Some(hash) => hash,
+                        None => return Err(add_image_error(e)),
+                    },
+                };
+                match store.add_tags(&hash, &req.tags) {
+                    Ok(_) => Ok((hash, req.tags)),
+                    Err(e) => {
+                        error!("Failed to add tags: {}", e);
+                        Err(tag_write_error(e))
+                    }
+                }
+            }
+        })
+        .collect();
+
+    let results = join_all(futures).await;
+
+    let mut items = Vec::with_capacity(results.len());
+    for result in results {
+        match result {
+            Ok((hash, tags)) => {
+                let added = serde_json::json!({
+                    "url": store.url_for_hash(&hash).ok(),
+                    "hash": hash,
+                    "tags": tags
+                });
+                let mut item = added.clone();
+                item["status"] = json!("ok");
+                items.push(item);
+                successful.push(added);
+            }
+            Err(e) => {
+                items.push(json!({ "status": "error", "error": e.to_string() }));
+                errors.push(e.to_string());
+            }
+        }
+    }
+
+    let response = serde_json::json!({
+        "message": "Batch processing completed",
+        "total": successful.len() + errors.len(),
+        "successful": successful.len(),
+        "failed": errors.len(),
+        "results": successful,
+        "errors": errors
+    });
+
+    let mut reply =
+        warp::reply::with_status(warp::reply::json(&response), StatusCode::CREATED).into_response();
+    reply.extensions_mut().insert(BatchItemResults(items));
+    Ok(reply)
+}
+
+pub async fn upload_image_handler(
+    mut form: FormData,
+    store: ImageStore,
+    gate: UploadGate,
+    auth_info: ApiKey,
 ) -> Result<impl Reply, Rejection> {
     let mut tags: Vec<String> = Vec::new();
     let mut file_data: Option<(String, String, Bytes)> = None;
@@ -566,7 +2624,7 @@ pub async fn upload_image_handler(
                 let data = part
                     .stream()
                     .try_fold(Vec::new(), |mut vec, data| async move {
-                        vec.extend_from_slice(&data.chunk());
+                        vec.extend_from_slice(data.chunk());
                         Ok(vec)
                     })
                     .await
@@ -615,6 +2673,14 @@ pub async fn upload_image_handler(
     if tags.is_empty() {
         return Err(warp::reject::custom(ImageError::MissingTags));
     }
+    if let Some(tag) = store.find_blocked_tag(&tags) {
+        warn!("Rejected upload with blocked tag: {}", tag);
+        return Err(warp::reject::custom(ImageError::BlockedTag(tag)));
+    }
+    store
+        .check_tag_limit(&tags)
+        .map_err(|e| warp::reject::custom(tag_write_error(e)))?;
+    check_allowed_tags(&auth_info, &tags).map_err(warp::reject::custom)?;
 
     info!(
         "Processing file upload: {} ({} bytes) with tags: {:?}",
@@ -623,13 +2689,16 @@ pub async fn upload_image_handler(
         tags
     );
 
+    let _permit = gate.acquire().await.map_err(warp::reject::custom)?;
     match store.add_image_data(&data, &filename, &content_type).await {
         Ok(hash) => match store.add_tags(&hash, &tags) {
             Ok(_) => {
+                record_uploader(&store, &hash, &auth_info.username);
                 info!("Successfully added image with tags: {:?}", tags);
                 Ok(warp::reply::with_status(
                     warp::reply::json(&json!({
                         "message": "Image uploaded successfully",
+                        "url": store.url_for_hash(&hash).ok(),
                         "hash": hash,
                         "tags": tags
                     })),
@@ -638,17 +2707,2905 @@ pub async fn upload_image_handler(
             }
             Err(e) => {
                 error!("Failed to add tags: {}", e);
-                Err(warp::reject::custom(ImageError::DatabaseError(format!(
-                    "Failed to add tags: {}",
-                    e
-                ))))
+                Err(warp::reject::custom(tag_write_error(e)))
             }
         },
         Err(e) => {
             error!("Failed to add image: {}", e);
-            Err(warp::reject::custom(ImageError::InvalidImage(
-                e.to_string(),
-            )))
+            Err(warp::reject::custom(
+                if e.downcast_ref::<DuplicateImage>().is_some() {
+                    ImageError::DuplicateImage(e.to_string())
+                } else {
+                    ImageError::InvalidImage(e.to_string())
+                },
+            ))
+        }
+    }
+}
+
+/// Issues a short-lived URL that accepts a single image `PUT` without an
+/// `Authorization` header, for uploads straight from a browser.
+pub async fn presign_upload_handler(
+    presigner: Presigner,
+    max_ttl_secs: u64,
+    base_url: String,
+    public_url: Option<String>,
+    auth_info: ApiKey,
+    body: PresignUploadRequest,
+    store: ImageStore,
+) -> Result<impl Reply, Rejection> {
+    if body.tags.is_empty() {
+        return Err(warp::reject::custom(ImageError::MissingTags));
+    }
+    if let Some(tag) = store.find_blocked_tag(&body.tags) {
+        return Err(warp::reject::custom(ImageError::BlockedTag(tag)));
+    }
+    store
+        .check_tag_limit(&body.tags)
+        .map_err(|e| warp::reject::custom(tag_write_error(e)))?;
+    check_allowed_tags(&auth_info, &body.tags).map_err(warp::reject::custom)?;
+    let content_type = body.content_type.trim().to_lowercase();
+    if !content_type.starts_with("image/") {
+        return Err(warp::reject::custom(ImageError::InvalidParameter(format!(
+            "content_type must be an image type (got '{}')",
+            body.content_type
+        ))));
+    }
+
+    let max_size_bytes = body
+        .max_size_bytes
+        .unwrap_or(MAX_UPLOAD_BYTES)
+        .min(MAX_UPLOAD_BYTES);
+    let expires_in = body
+        .expires_in_secs
+        .unwrap_or(DEFAULT_PRESIGN_TTL_SECS)
+        .clamp(1, max_ttl_secs.max(1));
+    let expires_at = OffsetDateTime::now_utc() + time::Duration::seconds(expires_in as i64);
+
+    let token = presigner.sign(&PresignClaims {
+        username: auth_info.username.clone(),
+        content_type: content_type.clone(),
+        max_size_bytes,
+        expires_at: expires_at.unix_timestamp(),
+        tags: body.tags,
+        is_admin: auth_info.is_admin,
+        nonce: uuid::Uuid::new_v4().simple().to_string(),
+    });
+    info!(
+        username = %auth_info.username,
+        content_type = %content_type,
+        expires_in,
+        "Issued presigned upload URL"
+    );
+
+    let base_url = public_url.unwrap_or(base_url);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({
+            "url": format!("{}/upload/presigned/{}", base_url, token),
+            "method": "PUT",
+            "content_type": content_type,
+            "max_size_bytes": max_size_bytes,
+            "expires_at": expires_at.format(&Rfc3339).unwrap_or_default()
+        })),
+        warp::http::StatusCode::CREATED,
+    ))
+}
+
+pub async fn upload_presigned_handler(
+    token: String,
+    content_type: Option<String>,
+    data: Bytes,
+    presigner: Presigner,
+    store: ImageStore,
+    gate: UploadGate,
+    rate_limiter: ApiKeyRateLimiter,
+) -> Result<impl Reply, Rejection> {
+    let claims = presigner.verify(&token).map_err(|e| {
+        warn!("Rejected presigned upload: {}", e);
+        warp::reject::custom(e)
+    })?;
+
+    // the key that issued the URL must still be usable, and its rate
+    // limit covers the uploads it presigned. The admin key has neither.
+    if !claims.is_admin {
+        let key = match store.get_api_key_for_username(&claims.username) {
+            Ok(Some(key)) if !matches!(store.validate_api_key(&key), Ok(true)) => {
+                return Err(warp::reject::custom(ImageError::InactiveKey));
+            }
+            Ok(Some(key)) => key,
+            Ok(None) => return Err(warp::reject::custom(ImageError::Unauthorized)),
+            Err(e) => {
+                return Err(warp::reject::custom(ImageError::DatabaseError(
+                    e.to_string(),
+                )))
+            }
+        };
+        let key_info = store.get_api_key(&key).ok();
+        if !rate_limiter.check_rate_limit(&key, key_info.as_ref()).await {
+            return Err(warp::reject::custom(ImageError::RateLimitExceeded));
+        }
+    }
+
+    let content_type = content_type.unwrap_or_default().trim().to_lowercase();
+    if content_type != claims.content_type {
+        return Err(warp::reject::custom(ImageError::InvalidPresign(format!(
+            "Content-Type must be {}",
+            claims.content_type
+        ))));
+    }
+    if data.len() as u64 > claims.max_size_bytes {
+        return Err(warp::reject::custom(ImageError::FileTooLarge(format!(
+            "{} bytes (max {} bytes)",
+            data.len(),
+            claims.max_size_bytes
+        ))));
+    }
+
+    info!(
+        username = %claims.username,
+        "Processing presigned upload ({} bytes) with tags: {:?}",
+        data.len(),
+        claims.tags
+    );
+
+    // a busy server turns the upload away without using up the URL
+    let _permit = gate.acquire().await.map_err(warp::reject::custom)?;
+    presigner.consume(&claims).map_err(warp::reject::custom)?;
+    let hash = store
+        .add_image_data(&data, "presigned", &content_type)
+        .await
+        .map_err(|e| {
+            error!("Failed to add presigned upload: {}", e);
+            if e.to_string().contains("already exists") {
+                warp::reject::custom(ImageError::DuplicateImage(e.to_string()))
+            } else {
+                warp::reject::custom(ImageError::InvalidImage(e.to_string()))
+            }
+        })?;
+    record_uploader(&store, &hash, &claims.username);
+    store.add_tags(&hash, &claims.tags).map_err(|e| {
+        error!("Failed to add tags: {}", e);
+        warp::reject::custom(tag_write_error(e))
+    })?;
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({
+            "message": "Image uploaded successfully",
+            "url": store.url_for_hash(&hash).ok(),
+            "hash": hash,
+            "tags": claims.tags
+        })),
+        warp::http::StatusCode::CREATED,
+    ))
+}
+
+pub async fn metrics_handler(
+    gate: UploadGate,
+    images_dir: PathBuf,
+    store: ImageStore,
+    _: (),
+) -> Result<impl Reply, Rejection> {
+    let temp_files = temp_files::stats(&images_dir).unwrap_or_else(|e| {
+        warn!("Failed to count temp files: {}", e);
+        TempFileStats::default()
+    });
+    let broken_images = store.count_broken_images().unwrap_or_else(|e| {
+        warn!("Failed to count broken images: {}", e);
+        0
+    });
+    let derived = store.derived_cache_stats().unwrap_or_else(|e| {
+        warn!("Failed to measure the derived file cache: {}", e);
+        DerivedCacheStats::default()
+    });
+    Ok(warp::reply::json(&json!({
+        "upload": gate.stats(),
+        "temp_files": temp_files,
+        "broken_images": broken_images,
+        "derived_cache": {
+            "files": derived.files,
+            "bytes": derived.bytes,
+            "max_bytes": store.derived_cache_budget(),
+            "evictions": store.derived_cache_evictions()
+        }
+    })))
+}
+
+pub async fn purge_derived_cache_handler(
+    _: (), // Admin auth result
+    store: ImageStore,
+) -> Result<impl Reply, Rejection> {
+    match store.purge_derived_files() {
+        Ok(removed) => {
+            info!(
+                "Purged {} derived files ({} bytes)",
+                removed.files, removed.bytes
+            );
+            Ok(warp::reply::json(&json!({
+                "removed_files": removed.files,
+                "removed_bytes": removed.bytes
+            })))
+        }
+        Err(e) => {
+            error!("Failed to purge derived files: {}", e);
+            Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )))
+        }
+    }
+}
+
+pub async fn broken_images_handler(store: ImageStore, _: ()) -> Result<impl Reply, Rejection> {
+    match store.list_broken_images() {
+        Ok(images) => Ok(warp::reply::json(&json!({
+            "total": images.len(),
+            "images": images
+        }))),
+        Err(e) => {
+            error!("Failed to list broken images: {}", e);
+            Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )))
+        }
+    }
+}
+
+pub async fn db_version_handler(store: ImageStore, _: ()) -> Result<impl Reply, Rejection> {
+    match store.schema_migrations() {
+        Ok((version, applied)) => Ok(warp::reply::json(&json!({
+            "version": version,
+            "latest": migrations::latest_version(),
+            "migrations": applied
+        }))),
+        Err(e) => {
+            error!("Failed to read schema version: {}", e);
+            Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )))
+        }
+    }
+}
+
+pub async fn backfill_handler(store: ImageStore, _: ()) -> Result<impl Reply, Rejection> {
+    match store.backfill_metadata() {
+        Ok(result) => {
+            info!("Backfilled {} of {} images", result.updated, result.scanned);
+            Ok(warp::reply::json(&result))
+        }
+        Err(e) => {
+            error!("Failed to backfill image metadata: {}", e);
+            Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )))
+        }
+    }
+}
+
+pub async fn write_sidecars_handler(store: ImageStore, _: ()) -> Result<impl Reply, Rejection> {
+    match store.write_all_sidecars() {
+        Ok(result) => Ok(warp::reply::json(&result)),
+        Err(e) => {
+            error!("Failed to write sidecars: {}", e);
+            Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )))
+        }
+    }
+}
+
+pub async fn lookup_image_handler(
+    mut form: FormData,
+    store: ImageStore,
+    default_max_distance: u32,
+    base_url: Option<String>,
+    auth_info: ApiKey,
+) -> Result<impl Reply, Rejection> {
+    let mut data: Option<Vec<u8>> = None;
+    let mut fuzzy = false;
+    let mut max_distance = default_max_distance;
+
+    while let Ok(Some(part)) = form.try_next().await {
+        let name = part.name().to_string();
+        let value = part
+            .stream()
+            .try_fold(Vec::new(), |mut vec, data| async move {
+                vec.extend_from_slice(data.chunk());
+                Ok(vec)
+            })
+            .await
+            .map_err(|e| {
+                error!("Failed to read form field {}: {}", name, e);
+                warp::reject::custom(ImageError::InvalidImage(e.to_string()))
+            })?;
+
+        match name.as_str() {
+            "file" => data = Some(value),
+            "fuzzy" => fuzzy = String::from_utf8_lossy(&value).trim() == "true",
+            "max_distance" => {
+                max_distance = String::from_utf8_lossy(&value)
+                    .trim()
+                    .parse::<u32>()
+                    .map_err(|e| {
+                        warp::reject::custom(ImageError::InvalidImage(format!(
+                            "Invalid max_distance: {}",
+                            e
+                        )))
+                    })?
+                    .min(MAX_HAMMING_DISTANCE);
+            }
+            _ => warn!("Unexpected form field: {}", name),
+        }
+    }
+
+    let data = data.ok_or_else(|| {
+        warp::reject::custom(ImageError::InvalidImage("No file provided".to_string()))
+    })?;
+
+    let matches = if fuzzy {
+        store
+            .lookup_similar_images(&data, max_distance)
+            .map(|results| {
+                results
+                    .into_iter()
+                    .map(|(image, distance)| LookupMatch { image, distance })
+                    .collect::<Vec<_>>()
+            })
+    } else {
+        store.lookup_image(&data).map(|image| {
+            image
+                .map(|image| LookupMatch { image, distance: 0 })
+                .into_iter()
+                .collect::<Vec<_>>()
+        })
+    };
+
+    match matches {
+        Ok(mut matches) => {
+            matches.retain(|m| can_see(&auth_info, &m.image.tags));
+            if let Some(base_url) = base_url.as_deref() {
+                for m in &mut matches {
+                    m.image.rebase(base_url);
+                }
+            }
+            info!(
+                "Image lookup (fuzzy: {}) found {} matches",
+                fuzzy,
+                matches.len()
+            );
+            Ok(warp::reply::json(&json!({
+                "fuzzy": fuzzy,
+                "matches": matches
+            })))
+        }
+        Err(e) if e.to_string().contains("Invalid image") => Err(warp::reject::custom(
+            ImageError::InvalidImage(e.to_string()),
+        )),
+        Err(e) => {
+            error!("Failed to look up image: {}", e);
+            Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )))
+        }
+    }
+}
+
+pub async fn export_handler(
+    query: ExportQuery,
+    store: ImageStore,
+    _: (), // Admin auth result
+) -> Result<impl Reply, Rejection> {
+    let invalid = |e: String| warp::reject::custom(ImageError::InvalidParameter(e));
+    let limit = query.limit.unwrap_or(DEFAULT_EXPORT_LIMIT);
+    if limit == 0 || limit > MAX_EXPORT_LIMIT {
+        return Err(invalid(format!(
+            "limit must be between 1 and {}",
+            MAX_EXPORT_LIMIT
+        )));
+    }
+    let snapshot_at = match query.snapshot_at {
+        Some(snapshot_at) => {
+            OffsetDateTime::parse(&snapshot_at, &Rfc3339)
+                .map_err(|e| invalid(format!("snapshot_at: {}", e)))?;
+            snapshot_at
+        }
+        None => OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .map_err(|e| invalid(e.to_string()))?,
+    };
+    let cursor = match &query.cursor {
+        Some(cursor) => Some(
+            cursor
+                .split_once(',')
+                .ok_or_else(|| invalid("cursor: not a next_cursor value".to_string()))?,
+        ),
+        None => None,
+    };
+
+    match store.export_zip(&snapshot_at, cursor, limit).await {
+        Ok((data, manifest)) => {
+            info!(
+                included = manifest.counts.included,
+                skipped = manifest.counts.skipped,
+                "Exported ZIP page"
+            );
+            let mut response =
+                warp::reply::with_header(data, "Content-Type", "application/zip").into_response();
+            let headers = response.headers_mut();
+            headers.insert(
+                "Content-Disposition",
+                warp::http::HeaderValue::from_static("attachment; filename=\"export.zip\""),
+            );
+            if let Ok(value) = warp::http::HeaderValue::from_str(&manifest.snapshot_at) {
+                headers.insert("X-Snapshot-At", value);
+            }
+            if let Some(next) = manifest
+                .next_cursor
+                .as_deref()
+                .and_then(|c| warp::http::HeaderValue::from_str(c).ok())
+            {
+                headers.insert("X-Next-Cursor", next);
+            }
+            Ok(response)
+        }
+        Err(e) => {
+            error!("Failed to export images: {}", e);
+            Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )))
+        }
+    }
+}
+
+pub async fn import_zip_catalog_handler(
+    mut form: FormData,
+    store: ImageStore,
+    _: (), // Admin auth result
+) -> Result<impl Reply, Rejection> {
+    let mut zip_data: Option<Vec<u8>> = None;
+    let mut apply_tags = true;
+
+    while let Ok(Some(part)) = form.try_next().await {
+        let name = part.name().to_string();
+        let value = part
+            .stream()
+            .try_fold(Vec::new(), |mut vec, data| async move {
+                vec.extend_from_slice(data.chunk());
+                Ok(vec)
+            })
+            .await
+            .map_err(|e| {
+                error!("Failed to read form field {}: {}", name, e);
+                warp::reject::custom(ImageError::InvalidImage(e.to_string()))
+            })?;
+
+        match name.as_str() {
+            "file" => zip_data = Some(value),
+            "apply_tags" => apply_tags = String::from_utf8_lossy(&value).trim() != "false",
+            _ => warn!("Unexpected form field: {}", name),
+        }
+    }
+
+    let zip_data = zip_data.ok_or_else(|| {
+        warp::reject::custom(ImageError::InvalidImage("No file provided".to_string()))
+    })?;
+
+    match store.import_zip_catalog(&zip_data, apply_tags).await {
+        Ok(result) => Ok(warp::reply::with_status(
+            warp::reply::json(&result),
+            warp::http::StatusCode::CREATED,
+        )),
+        Err(e) => {
+            error!("Failed to import ZIP catalog: {}", e);
+            let err = if e.to_string().contains("too large") {
+                ImageError::FileTooLarge(e.to_string())
+            } else if e.to_string().contains("Invalid") || e.to_string().contains("missing") {
+                ImageError::InvalidImage(e.to_string())
+            } else {
+                ImageError::DatabaseError(e.to_string())
+            };
+            Err(warp::reject::custom(err))
+        }
+    }
+}
+
+pub async fn size_distribution_handler(
+    query: SizeDistributionQuery,
+    store: ImageStore,
+    _: (), // Admin auth result
+) -> Result<impl Reply, Rejection> {
+    let boundaries = match query.boundaries {
+        Some(raw) => raw
+            .split(',')
+            .map(|b| b.trim().parse::<u64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| {
+                warp::reject::custom(ImageError::InvalidParameter(format!("boundaries: {}", e)))
+            })?,
+        None => DEFAULT_SIZE_BOUNDARIES.to_vec(),
+    };
+    if boundaries.is_empty() || boundaries.windows(2).any(|w| w[0] >= w[1]) {
+        return Err(warp::reject::custom(ImageError::InvalidParameter(
+            "boundaries must be strictly ascending".to_string(),
+        )));
+    }
+
+    let buckets: Vec<(u64, u64)> = boundaries
+        .iter()
+        .enumerate()
+        .map(|(i, &start)| (start, boundaries.get(i + 1).copied().unwrap_or(u64::MAX)))
+        .collect();
+
+    match store.size_distribution(&buckets) {
+        Ok(buckets) => Ok(warp::reply::json(&json!({ "buckets": buckets }))),
+        Err(e) => {
+            error!("Failed to compute size distribution: {}", e);
+            Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )))
+        }
+    }
+}
+
+pub async fn gc_tags_handler(
+    store: ImageStore,
+    dry_run: bool,
+    _: (),
+) -> Result<impl Reply, Rejection> {
+    match store.gc_tags(dry_run) {
+        Ok(removed) => {
+            if !dry_run {
+                info!("Tag GC removed {} empty tags", removed.len());
+            }
+            Ok(warp::reply::json(&json!({
+                "removed": removed.len(),
+                "tags": removed,
+                "dry_run": dry_run
+            })))
+        }
+        Err(e) => {
+            error!("Failed to garbage collect tags: {}", e);
+            Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )))
+        }
+    }
+}
+
+pub async fn remap_tags_handler(
+    content_type: Option<String>,
+    body: Bytes,
+    store: ImageStore,
+    cache: ImageCache,
+    dry_run: bool,
+    _: (), // Admin auth result
+) -> Result<impl Reply, Rejection> {
+    let is_csv = content_type.is_some_and(|ct| ct.starts_with("text/csv"));
+    let mappings = if is_csv {
+        parse_tag_mappings_csv(&body)
+    } else {
+        serde_json::from_slice::<TagRemapRequest>(&body)
+            .map(|request| request.mappings)
+            .map_err(|e| e.to_string())
+    }
+    .map_err(|e| warp::reject::custom(ImageError::InvalidBody(e)))?;
+
+    match store.remap_tags(&mappings, dry_run) {
+        Ok(results) => {
+            if !dry_run {
+                cache.invalidate_all();
+                info!("Remapped {} tags", results.len());
+            }
+            Ok(warp::reply::json(
+                &json!({ "results": results, "dry_run": dry_run }),
+            ))
+        }
+        Err(e) if e.to_string().contains("Tag is blocked") => {
+            let tag = e
+                .to_string()
+                .trim_start_matches("Tag is blocked: ")
+                .to_string();
+            Err(warp::reject::custom(ImageError::BlockedTag(tag)))
+        }
+        Err(e) if e.to_string().contains("Invalid mapping") => Err(warp::reject::custom(
+            ImageError::InvalidParameter(e.to_string()),
+        )),
+        Err(e) => {
+            error!("Failed to remap tags: {}", e);
+            Err(warp::reject::custom(ImageError::DatabaseError(
+                e.to_string(),
+            )))
+        }
+    }
+}
+
+/// One `old,new` pair per line. Blank lines and an `old,new` or
+/// `from,to` header are skipped.
+fn parse_tag_mappings_csv(body: &[u8]) -> Result<Vec<TagMapping>, String> {
+    let text = std::str::from_utf8(body).map_err(|e| e.to_string())?;
+    let mut mappings = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some((from, to)) = line.split_once(',') else {
+            return Err(format!("line {}: expected old,new", i + 1));
+        };
+        let (from, to) = (from.trim(), to.trim());
+        if i == 0 && matches!((from, to), ("old", "new") | ("from", "to")) {
+            continue;
+        }
+        mappings.push(TagMapping {
+            from: from.to_string(),
+            to: to.to_string(),
+        });
+    }
+    Ok(mappings)
+}
+
+pub async fn slow_queries_handler(
+    query: SlowQueriesQuery,
+    store: ImageStore,
+    _: (), // Admin auth result
+) -> Result<impl Reply, Rejection> {
+    let threshold_ms = query.threshold_ms.unwrap_or(0);
+    let queries = store.slow_queries(
+        threshold_ms,
+        query.limit.unwrap_or(DEFAULT_SLOW_QUERY_LIMIT),
+    );
+
+    Ok(warp::reply::json(&json!({
+        "threshold_ms": threshold_ms,
+        "queries": queries
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{add_png, api_key, config, png, temp_store};
+
+    async fn into_parts(reply: impl Reply) -> (StatusCode, HeaderMap, Bytes) {
+        let (parts, body) = reply.into_response().into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        (parts.status, parts.headers, body)
+    }
+
+    /// The headers and body of each part of a multipart body.
+    fn multipart_parts<'a>(body: &'a [u8], boundary: &str) -> Vec<(String, &'a [u8])> {
+        let delimiter = format!("--{}", boundary);
+        let mut parts = Vec::new();
+        let mut rest = body;
+        loop {
+            let start = find(rest, delimiter.as_bytes()).unwrap() + delimiter.len();
+            rest = &rest[start..];
+            if rest.starts_with(b"--") {
+                return parts;
+            }
+            let rest_body = &rest[2..];
+            let headers_end = find(rest_body, b"\r\n\r\n").unwrap();
+            let headers = String::from_utf8(rest_body[..headers_end].to_vec()).unwrap();
+            let content = &rest_body[headers_end + 4..];
+            let end = find(content, format!("\r\n{}", delimiter).as_bytes()).unwrap();
+            parts.push((headers, &content[..end]));
+            rest = &content[end + 2..];
+        }
+    }
+
+    fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+        haystack.windows(needle.len()).position(|w| w == needle)
+    }
+
+    #[tokio::test]
+    async fn full_response_carries_metadata_and_bytes() {
+        let (_dir, store) = temp_store();
+        let hash = add_png(&store, 1).await;
+        let filename = format!("{}.png", hash);
+
+        let reply = get_image_full_handler(filename.clone(), store, None, api_key("reader"))
+            .await
+            .unwrap();
+        let (status, headers, body) = into_parts(reply).await;
+        assert_eq!(status, StatusCode::OK);
+        let content_type = headers[CONTENT_TYPE].to_str().unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/mixed; boundary=")
+            .unwrap();
+
+        let parts = multipart_parts(&body, boundary);
+        assert_eq!(parts.len(), 2);
+        assert!(parts[0].0.contains("application/json"));
+        let metadata: serde_json::Value = serde_json::from_slice(parts[0].1).unwrap();
+        assert_eq!(metadata["filename"], filename);
+        assert_eq!(metadata["hash"], hash);
+        assert!(parts[1].0.contains("Content-Type: image/png"));
+        assert_eq!(parts[1].1, &png(4, 4, 1)[..]);
+    }
+
+    /// The metadata route's reply for `filename` with `headers`.
+    async fn get_metadata(
+        store: &ImageStore,
+        filename: &str,
+        headers: HeaderMap,
+        inline: bool,
+        limits: RequestLimits,
+    ) -> (StatusCode, HeaderMap, Bytes) {
+        let ttl = std::time::Duration::from_secs(60);
+        let reply = get_image_by_filename_handler(
+            filename.to_string(),
+            store.clone(),
+            ImageCache::new(10, ttl, ttl),
+            headers,
+            None,
+            InlineQuery { inline },
+            limits,
+            api_key("reader"),
+        )
+        .await
+        .unwrap();
+        into_parts(reply).await
+    }
+
+    #[tokio::test]
+    async fn inline_data_round_trips_through_base64() {
+        let (_dir, store) = temp_store();
+        let hash = add_png(&store, 1).await;
+        let filename = format!("{}.png", hash);
+
+        let limits = config(&[]).request_limits();
+        let (_, _, body) = get_metadata(&store, &filename, HeaderMap::new(), true, limits).await;
+        let metadata: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let data = BASE64.decode(metadata["data"].as_str().unwrap()).unwrap();
+        assert_eq!(data, &png(4, 4, 1)[..]);
+        assert_eq!(metadata["content_type"], "image/png");
+
+        // over the limit only the URL is given
+        let limits = config(&["--inline-max-bytes", "10"]).request_limits();
+        let (_, _, body) = get_metadata(&store, &filename, HeaderMap::new(), true, limits).await;
+        let metadata: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(metadata.get("data").is_none());
+        assert!(metadata["url"].is_string());
+    }
+
+    #[tokio::test]
+    async fn only_the_admin_key_draws_from_another_owner() {
+        let (_dir, store) = temp_store();
+        let alice_hash = add_png(&store, 1).await;
+        let bob_hash = add_png(&store, 2).await;
+        store.set_image_uploader(&alice_hash, "alice").unwrap();
+        store.set_image_uploader(&bob_hash, "bob").unwrap();
+
+        let limits = config(&[]).request_limits();
+        let ttl = std::time::Duration::from_secs(60);
+        let cache = ImageCache::new(10, ttl, ttl);
+        let random = |key: ApiKey| {
+            get_random_image_handler(
+                store.clone(),
+                cache.clone(),
+                None,
+                query(&[("owner", "bob")]),
+                None,
+                limits,
+                key,
+                FeatureFlags::default(),
+            )
+        };
+        let drawn = |reply| async move {
+            let (_, _, body) = into_parts(reply).await;
+            let image: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            image["hash"].as_str().unwrap().to_string()
+        };
+
+        assert_eq!(drawn(random(api_key("bob")).await.unwrap()).await, bob_hash);
+        let admin = ApiKey {
+            is_admin: true,
+            ..api_key("root")
+        };
+        assert_eq!(drawn(random(admin).await.unwrap()).await, bob_hash);
+
+        // a user called admin is still just a user
+        for username in ["alice", "admin"] {
+            let error = random(api_key(username)).await.err().unwrap();
+            assert!(
+                matches!(image_error(&error), ImageError::OwnerForbidden(owner) if owner == "bob"),
+                "{}",
+                username
+            );
+        }
+    }
+
+    fn conditional(name: warp::http::header::HeaderName, value: &HeaderValue) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.clone());
+        headers
+    }
+
+    #[tokio::test]
+    async fn metadata_revalidates_with_etag_and_last_modified() {
+        let (_dir, store) = temp_store();
+        let filename = format!("{}.png", add_png(&store, 1).await);
+        let limits = config(&[]).request_limits();
+        let get = |headers, inline| get_metadata(&store, &filename, headers, inline, limits);
+
+        let (status, headers, _) = get(HeaderMap::new(), false).await;
+        assert_eq!(status, StatusCode::OK);
+        let etag = headers[ETAG].clone();
+        let last_modified = headers[LAST_MODIFIED].clone();
+
+        let (status, headers, body) = get(conditional(IF_NONE_MATCH, &etag), false).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert_eq!(headers[ETAG], etag);
+        assert!(body.is_empty());
+
+        // the inline JSON holds the bytes too, so a plain tag doesn't match
+        let (status, headers, _) = get(conditional(IF_NONE_MATCH, &etag), true).await;
+        assert_eq!(status, StatusCode::OK);
+        let inline_etag = headers[ETAG].clone();
+        assert_ne!(inline_etag, etag);
+        let (status, _, _) = get(conditional(IF_NONE_MATCH, &inline_etag), true).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+
+        let (status, _, _) = get(conditional(IF_MODIFIED_SINCE, &last_modified), false).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        let long_ago = HeaderValue::from_static("Mon, 01 Jan 2001 00:00:00 GMT");
+        let (status, _, _) = get(conditional(IF_MODIFIED_SINCE, &long_ago), false).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // If-None-Match wins over If-Modified-Since
+        let mut headers = conditional(IF_MODIFIED_SINCE, &last_modified);
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_static("W/\"other\""));
+        let (status, _, _) = get(headers, false).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn image_bytes_revalidate_with_etag_and_last_modified() {
+        let (_dir, store) = temp_store();
+        let hash = add_png(&store, 1).await;
+        let serve = |if_none_match, if_modified_since| {
+            serve_image_handler(
+                hash.clone(),
+                ImageKey::Hash,
+                store.clone(),
+                Placeholders::new(false),
+                if_none_match,
+                if_modified_since,
+                None,
+            )
+        };
+
+        let (status, headers, body) = into_parts(serve(None, None).await.unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, png(4, 4, 1));
+        let etag = headers[ETAG].to_str().unwrap().to_string();
+        let last_modified = headers[LAST_MODIFIED].to_str().unwrap().to_string();
+
+        let (status, _, body) = into_parts(serve(Some(etag), None).await.unwrap()).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert!(body.is_empty());
+        let (status, _, _) = into_parts(serve(None, Some(last_modified)).await.unwrap()).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        let long_ago = "Mon, 01 Jan 2001 00:00:00 GMT".to_string();
+        let (status, _, _) = into_parts(serve(None, Some(long_ago)).await.unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn batch_random_reads_sizes_without_opening_files() {
+        let (dir, store) = temp_store();
+        let mut hashes = Vec::new();
+        for seed in 1..=20 {
+            hashes.push(add_png(&store, seed).await);
+        }
+        // a row missing its size is repaired from the file while it's there
+        let conn = rusqlite::Connection::open(dir.path().join("images.db")).unwrap();
+        conn.execute(
+            "UPDATE images SET width = NULL WHERE hash = ?",
+            [&hashes[0]],
+        )
+        .unwrap();
+        let repaired = store
+            .get_image_by_filename(&format!("{}.png", hashes[0]))
+            .unwrap();
+        assert_eq!(repaired.width, 4);
+        let stored: Option<u32> = conn
+            .query_row(
+                "SELECT width FROM images WHERE hash = ?",
+                [&hashes[0]],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(stored, Some(4));
+
+        // with the files gone, only the database can answer
+        for hash in &hashes {
+            std::fs::remove_file(dir.path().join("images").join(format!("{}.png", hash))).unwrap();
+        }
+        let key = ApiKey {
+            max_batch_size: Some(20),
+            ..api_key("alice")
+        };
+        let body: BatchRandomRequest = serde_json::from_value(json!({ "count": 20 })).unwrap();
+        let ttl = std::time::Duration::from_secs(60);
+        let reply = batch_random_images_handler(
+            store,
+            ImageCache::new(10, ttl, ttl),
+            FeatureFlags::default(),
+            key,
+            body,
+            None,
+            config(&[]).request_limits(),
+        )
+        .await
+        .unwrap();
+        let (_, _, body) = into_parts(reply).await;
+        let batch: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            (batch["successful"].as_u64(), batch["failed"].as_u64()),
+            (Some(20), Some(0))
+        );
+        for image in batch["images"].as_array().unwrap() {
+            assert_eq!(
+                (image["width"].as_u64(), image["height"].as_u64()),
+                (Some(4), Some(4))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn copied_tags_replace_unless_merged() {
+        let (_dir, store) = temp_store();
+        let tags = |list: &[&str]| list.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        let source = add_png(&store, 1).await;
+        store.add_tags(&source, &tags(&["neko", "maid"])).unwrap();
+        let ttl = std::time::Duration::from_secs(60);
+        let copy = |target: String, merge| {
+            copy_tags_from_handler(
+                format!("{}.png", target),
+                format!("{}.png", source),
+                store.clone(),
+                ImageCache::new(10, ttl, ttl),
+                CopyTagsFromQuery { merge },
+                (),
+            )
+        };
+        let result = |reply| async move {
+            let (_, _, body) = into_parts(reply).await;
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        let replaced = add_png(&store, 2).await;
+        store.add_tags(&replaced, &tags(&["old", "neko"])).unwrap();
+        let copied = result(copy(replaced.clone(), false).await.unwrap()).await;
+        assert_eq!(copied["tags"], json!(["maid", "neko"]));
+        assert_eq!(copied["removed"], json!(["old"]));
+
+        let merged = add_png(&store, 3).await;
+        store.add_tags(&merged, &tags(&["old", "neko"])).unwrap();
+        let copied = result(copy(merged.clone(), true).await.unwrap()).await;
+        assert_eq!(copied["tags"], json!(["maid", "neko", "old"]));
+        assert_eq!(copied["added"], json!(["maid"]));
+        assert_eq!(copied["already_present"], json!(["neko"]));
+        assert_eq!(copied["removed"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn search_rejects_an_unknown_sort_field() {
+        let (_dir, store) = temp_store();
+        add_png(&store, 1).await;
+        let limits = config(&[]).request_limits();
+        let search = |pairs: &[(&str, &str)]| {
+            search_images_handler(query(pairs), store.clone(), None, limits, api_key("alice"))
+        };
+
+        for pairs in [[("sort", "filename")], [("sort", "")]] {
+            let rejection = search(&pairs).await.err().unwrap();
+            assert!(matches!(
+                image_error(&rejection),
+                ImageError::InvalidParameter(message) if message.starts_with("sort must be")
+            ));
+            let (status, _, _) = into_parts(handle_rejection(rejection).await.unwrap()).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+
+        for field in ["created_at", "size_bytes", "width", "height"] {
+            assert!(search(&[("sort", field), ("order", "asc")]).await.is_ok());
+        }
+    }
+
+    /// The hashes on one `GET /images` page and the `after` of its `next`.
+    async fn list_page(store: &ImageStore, after: &str) -> (Vec<String>, Option<String>) {
+        let params = query(&[("order", "asc"), ("limit", "2"), ("after", after)]);
+        let limits = config(&[]).request_limits();
+        let reply = list_images_handler(params, store.clone(), None, limits, api_key("alice"))
+            .await
+            .unwrap();
+        let (_, _, body) = into_parts(reply).await;
+        let page: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let hashes = page["images"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|image| image["hash"].as_str().unwrap().to_string())
+            .collect();
+        let next = page["next"].as_str().map(|next| {
+            let next = url::Url::parse(next).unwrap();
+            let after = next.query_pairs().find(|(key, _)| key == "after").unwrap();
+            after.1.into_owned()
+        });
+        (hashes, next)
+    }
+
+    #[tokio::test]
+    async fn cursor_pages_survive_inserts_and_deletes() {
+        let (_dir, store) = temp_store();
+        let mut originals = Vec::new();
+        for seed in 1..=7 {
+            originals.push(add_png(&store, seed).await);
+        }
+
+        let mut seen = Vec::new();
+        let mut after = Some("1970-01-01T00:00:00Z".to_string());
+        let mut seed = 100;
+        while let Some(cursor) = after {
+            let (hashes, next) = list_page(&store, &cursor).await;
+            // the page's last image goes away and a new one arrives
+            if let Some(last) = hashes.last() {
+                store.remove_image(&format!("{}.png", last), false).unwrap();
+            }
+            add_png(&store, seed).await;
+            seed += 1;
+            seen.extend(hashes);
+            after = next;
+            assert!(seen.len() < 50, "paging doesn't end");
+        }
+
+        let mut unique = seen.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), seen.len(), "an image was listed twice");
+        for hash in &originals {
+            assert!(seen.contains(hash), "{} was skipped", hash);
+        }
+    }
+
+    #[tokio::test]
+    async fn an_unreadable_image_does_not_end_cursor_paging() {
+        let (dir, store) = temp_store();
+        let mut hashes = Vec::new();
+        for seed in 1..=5 {
+            hashes.push(add_png(&store, seed).await);
+        }
+        let (first_page, _) = list_page(&store, "1970-01-01T00:00:00Z").await;
+        rusqlite::Connection::open(dir.path().join("images.db"))
+            .unwrap()
+            .execute(
+                "UPDATE images SET broken_reason = 'truncated' WHERE hash = ?",
+                [&first_page[1]],
+            )
+            .unwrap();
+
+        let mut seen = Vec::new();
+        let mut after = Some("1970-01-01T00:00:00Z".to_string());
+        while let Some(cursor) = after {
+            let (page, next) = list_page(&store, &cursor).await;
+            seen.extend(page);
+            after = next;
+        }
+        hashes.retain(|hash| *hash != first_page[1]);
+        hashes.sort();
+        seen.sort();
+        assert_eq!(seen, hashes);
+    }
+
+    fn query(pairs: &[(&str, &str)]) -> std::collections::HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn image_error(rejection: &Rejection) -> &ImageError {
+        rejection
+            .find::<ImageError>()
+            .unwrap_or_else(|| panic!("not an ImageError: {:?}", rejection))
+    }
+
+    #[tokio::test]
+    async fn over_limit_filters_are_rejected_on_every_filtered_endpoint() {
+        let (_dir, store) = temp_store();
+        let limits =
+            config(&["--max-filter-tags", "2", "--max-filter-metadata", "1"]).request_limits();
+        let ttl = std::time::Duration::from_secs(60);
+        let cache = ImageCache::new(10, ttl, ttl);
+        let key = || api_key("alice");
+
+        // included and excluded tags count together
+        let too_many_tags = [("tags", "a,b"), ("exclude_tags", "c")];
+        let too_much_metadata = [("has_metadata", "source"), ("metadata.artist", "x")];
+        let cases = [
+            (&too_many_tags, ImageError::TooManyFilterTags(2)),
+            (&too_much_metadata, ImageError::TooManyMetadataFilters(1)),
+        ];
+
+        for (pairs, expected) in cases {
+            let params = query(&pairs[..]);
+            let errors = [
+                get_random_image_handler(
+                    store.clone(),
+                    cache.clone(),
+                    None,
+                    params.clone(),
+                    None,
+                    limits,
+                    key(),
+                    FeatureFlags::default(),
+                )
+                .await
+                .err(),
+                list_images_handler(params.clone(), store.clone(), None, limits, key())
+                    .await
+                    .err(),
+                search_images_handler(params.clone(), store.clone(), None, limits, key())
+                    .await
+                    .err(),
+            ];
+            for (endpoint, error) in ["random", "images", "search"].iter().zip(errors) {
+                let error = error.unwrap_or_else(|| panic!("{} accepted", endpoint));
+                let error = image_error(&error).to_string();
+                assert_eq!(error, expected.to_string(), "{}", endpoint);
+            }
+        }
+
+        let body: BatchRandomRequest = serde_json::from_value(json!({
+            "count": 1,
+            "tags": ["a", "b"],
+            "exclude_tags": ["c"]
+        }))
+        .unwrap();
+        let error = batch_random_images_handler(
+            store.clone(),
+            cache.clone(),
+            FeatureFlags::default(),
+            key(),
+            body,
+            None,
+            limits,
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(matches!(
+            image_error(&error),
+            ImageError::TooManyFilterTags(2)
+        ));
+
+        // at the limit is fine
+        let params = query(&[("tags", "a,b"), ("metadata.artist", "x")]);
+        assert!(list_images_handler(params, store, None, limits, key())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn tenant_keys_only_see_their_own_images() {
+        let (_dir, store) = temp_store();
+        let ours = add_png(&store, 1).await;
+        let theirs = add_png(&store, 2).await;
+        store.add_tags(&ours, &["tenant_a".to_string()]).unwrap();
+        store.add_tags(&theirs, &["tenant_b".to_string()]).unwrap();
+        let (ours_file, theirs_file) = (format!("{}.png", ours), format!("{}.png", theirs));
+        let tenant = || ApiKey {
+            allowed_tags: vec!["tenant_a".to_string()],
+            ..api_key("tenant")
+        };
+        let ttl = std::time::Duration::from_secs(60);
+        let cache = ImageCache::new(10, ttl, ttl);
+        let limits = config(&[]).request_limits();
+        let get = |filename: &String, auth_info| {
+            get_image_by_filename_handler(
+                filename.clone(),
+                store.clone(),
+                cache.clone(),
+                HeaderMap::new(),
+                None,
+                InlineQuery { inline: false },
+                limits,
+                auth_info,
+            )
+        };
+
+        assert!(get(&ours_file, tenant()).await.is_ok());
+        assert!(get(&theirs_file, tenant())
+            .await
+            .unwrap_err()
+            .is_not_found());
+        // a cached copy from an unrestricted key doesn't leak either
+        assert!(get(&theirs_file, api_key("other")).await.is_ok());
+        assert!(get(&theirs_file, tenant())
+            .await
+            .unwrap_err()
+            .is_not_found());
+        assert!(
+            get_image_full_handler(theirs_file.clone(), store.clone(), None, tenant())
+                .await
+                .err()
+                .unwrap()
+                .is_not_found()
+        );
+
+        let reply = image_exists_handler(theirs_file.clone(), store.clone(), tenant())
+            .await
+            .unwrap();
+        let (_, _, body) = into_parts(reply).await;
+        let exists: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(exists["exists"], false);
+        let body = ExistsBatchRequest {
+            images: vec![ours.clone(), theirs.clone()],
+        };
+        let reply = images_exist_handler(store.clone(), 10, body, tenant())
+            .await
+            .unwrap();
+        let (_, _, body) = into_parts(reply).await;
+        let results: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(results["results"][&ours]["exists"], true);
+        assert_eq!(results["results"][&theirs]["exists"], false);
+
+        let since = ChangedSinceQuery {
+            timestamp: "2000-01-01T00:00:00Z".to_string(),
+        };
+        let reply = changed_since_handler(since, store.clone(), tenant())
+            .await
+            .unwrap();
+        let (_, _, body) = into_parts(reply).await;
+        let changed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            changed["images"],
+            json!([{
+                "filename": ours_file,
+                "modified_at": changed["images"][0]["modified_at"]
+            }])
+        );
+
+        let sync = SyncChangesQuery {
+            since: 0,
+            limit: None,
+        };
+        let reply = sync_changes_handler(sync, store.clone(), tenant())
+            .await
+            .unwrap();
+        let (_, _, body) = into_parts(reply).await;
+        let feed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let hashes: std::collections::HashSet<&str> = feed["changes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|change| change["hash"].as_str().unwrap())
+            .collect();
+        assert_eq!(hashes, [ours.as_str()].into());
+
+        // once their image is gone, its removal is no secret
+        store.remove_image(&theirs_file, false).unwrap();
+        let sync = SyncChangesQuery {
+            since: feed["next_cursor"].as_i64().unwrap(),
+            limit: None,
+        };
+        let reply = sync_changes_handler(sync, store, tenant()).await.unwrap();
+        let (_, _, body) = into_parts(reply).await;
+        let feed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(feed["changes"][0]["event"], "image_removed");
+    }
+
+    #[tokio::test]
+    async fn strict_query_params_refuse_typos_but_not_metadata_keys() {
+        let typo = query(&[("tgas", "neko"), ("width_min", "10")]);
+        let error = check_random_params(typo.clone(), true).await.unwrap_err();
+        assert!(matches!(
+            image_error(&error),
+            ImageError::InvalidParameter(msg) if msg == "unknown query parameters: tgas"
+        ));
+        // off by default
+        assert!(check_random_params(typo, false).await.is_ok());
+
+        let known = query(&[
+            ("tags", "neko"),
+            ("metadata.artist", "x"),
+            ("explain", "true"),
+        ]);
+        assert_eq!(
+            check_random_params(known.clone(), true).await.unwrap(),
+            known
+        );
+        // a bare `metadata.` names no key
+        let error = check_random_params(query(&[("metadata.", "x")]), true)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            image_error(&error),
+            ImageError::InvalidParameter(_)
+        ));
+    }
+
+    /// Creates a key for `username` from the JSON fields of a key request.
+    fn create_key(store: &ImageStore, request: serde_json::Value) -> ApiKey {
+        let request: GenerateApiKeyRequest = serde_json::from_value(request).unwrap();
+        let key = store
+            .generate_api_key(&request, &[ApiKeyScope::Upload])
+            .unwrap();
+        store.get_api_key(&key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reset_rate_limit_lets_a_throttled_key_back_in() {
+        let (_dir, store) = temp_store();
+        let alice = create_key(
+            &store,
+            json!({"username": "alice", "requests_per_second": 2}),
+        );
+        let limiter = ApiKeyRateLimiter::new(store.clone(), 10, time::Duration::seconds(60), 10);
+        let check = || limiter.check_rate_limit(&alice.key, Some(&alice));
+
+        assert!(check().await);
+        assert!(check().await);
+        assert!(!check().await);
+
+        let reply =
+            reset_rate_limit_handler("alice".to_string(), (), store.clone(), limiter.clone())
+                .await
+                .unwrap();
+        let (status, _, body) = into_parts(reply).await;
+        assert_eq!(status, StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["username"], "alice");
+        assert!(check().await);
+
+        let error = reset_rate_limit_handler("nobody".to_string(), (), store, limiter)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            image_error(&error),
+            ImageError::UsernameNotFound(name) if name == "nobody"
+        ));
+    }
+
+    #[tokio::test]
+    async fn exists_checks_resolve_filenames_and_hashes() {
+        let (_dir, store) = temp_store();
+        let hash = add_png(&store, 1).await;
+        let filename = format!("{}.png", hash);
+        let json_of = |body: Bytes| serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+
+        let reply = image_exists_handler(filename.clone(), store.clone(), api_key("reader"))
+            .await
+            .unwrap();
+        let (status, _, body) = into_parts(reply).await;
+        assert_eq!(status, StatusCode::OK);
+        let exists = json_of(body);
+        assert_eq!(exists["exists"], true);
+        assert_eq!(exists["hash"], hash);
+        assert_eq!(exists["size_bytes"], png(4, 4, 1).len());
+
+        let reply =
+            image_exists_handler("missing.png".to_string(), store.clone(), api_key("reader"))
+                .await
+                .unwrap();
+        let (_, _, body) = into_parts(reply).await;
+        assert_eq!(json_of(body)["exists"], false);
+
+        let body = ExistsBatchRequest {
+            images: vec![filename.clone(), hash.clone(), "missing.png".to_string()],
+        };
+        let reply = images_exist_handler(store.clone(), 3, body, api_key("reader"))
+            .await
+            .unwrap();
+        let (_, _, body) = into_parts(reply).await;
+        let results = &json_of(body)["results"];
+        assert_eq!(results[&filename]["exists"], true);
+        assert_eq!(results[&hash]["exists"], true);
+        assert_eq!(results["missing.png"]["exists"], false);
+
+        // EXISTS_BATCH_MAX caps the batch
+        let body = ExistsBatchRequest {
+            images: vec![filename.clone(); 4],
+        };
+        let error = images_exist_handler(store, 3, body, api_key("reader"))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            image_error(&error),
+            ImageError::BatchSizeExceeded(3)
+        ));
+    }
+
+    #[tokio::test]
+    async fn listings_sort_by_each_field_both_ways() {
+        let (dir, store) = temp_store();
+        let limits = config(&[]).request_limits();
+        // created in one order, widest to narrowest the other way round
+        let mut images = Vec::new();
+        for (i, (width, height)) in [(2u32, 9u32), (5, 5), (9, 2)].into_iter().enumerate() {
+            let hash = store
+                .add_image_data(&png(width, height, i as u8), "test.png", "image/png")
+                .await
+                .unwrap();
+            images.push(store.get_image_by_hash(&hash).unwrap().unwrap());
+        }
+        let conn = rusqlite::Connection::open(dir.path().join("images.db")).unwrap();
+        // sizes in yet another order, since flat PNGs barely differ
+        for (i, (image, size)) in images.iter_mut().zip([200u64, 300, 100]).enumerate() {
+            conn.execute(
+                "UPDATE images SET created_at = ?, size_bytes = ? WHERE hash = ?",
+                rusqlite::params![format!("2024-01-0{}T00:00:00Z", i + 1), size, image.hash],
+            )
+            .unwrap();
+            image.size_bytes = size;
+        }
+
+        let listed = |sort: &'static str, order: &'static str| {
+            let store = store.clone();
+            async move {
+                let params = query(&[("sort", sort), ("order", order)]);
+                let reply = list_images_handler(params, store, None, limits, api_key("reader"))
+                    .await
+                    .unwrap();
+                let (_, _, body) = into_parts(reply).await;
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(
+                    (body["sort"].as_str(), body["order"].as_str()),
+                    (Some(sort), Some(order))
+                );
+                body["images"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|image| image["hash"].as_str().unwrap().to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+        let by = |key: fn(&ImageResponse) -> u64| {
+            let mut sorted = images.clone();
+            sorted.sort_by_key(key);
+            sorted
+                .into_iter()
+                .map(|image| image.hash)
+                .collect::<Vec<_>>()
+        };
+        let expectations = [
+            ("width", by(|image| image.width as u64)),
+            ("height", by(|image| image.height as u64)),
+            ("size", by(|image| image.size_bytes)),
+            (
+                "created",
+                images.iter().map(|image| image.hash.clone()).collect(),
+            ),
+        ];
+        for (sort, ascending) in expectations {
+            assert_eq!(listed(sort, "asc").await, ascending, "{} asc", sort);
+            let descending: Vec<String> = ascending.into_iter().rev().collect();
+            assert_eq!(listed(sort, "desc").await, descending, "{} desc", sort);
+        }
+
+        for params in [[("sort", "name")], [("order", "up")]] {
+            let error = list_images_handler(
+                query(&params),
+                store.clone(),
+                None,
+                limits,
+                api_key("reader"),
+            )
+            .await
+            .err()
+            .unwrap();
+            assert!(matches!(
+                image_error(&error),
+                ImageError::InvalidParameter(_)
+            ));
+            let reply = handle_rejection(error).await.unwrap().into_response();
+            assert_eq!(reply.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn blocked_tags_are_refused_on_every_ingest_path() {
+        let (dir, store) = crate::test_support::temp_store_with(&["--blocked-tags", "gore"]);
+        let gate = UploadGate::new(2, 2);
+        let uploader = || ApiKey {
+            max_batch_size: Some(5),
+            ..api_key("uploader")
+        };
+        let source = dir.path().join("source.png");
+        std::fs::write(&source, png(4, 4, 1)).unwrap();
+        let request = |tags: &[&str]| AddImageRequest {
+            path: source.to_str().unwrap().to_string(),
+            path_type: PathType::Local,
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            expected_hash: None,
+            merge_tags: false,
+        };
+        let is_blocked = |error: &Rejection| matches!(image_error(error), ImageError::BlockedTag(tag) if tag == "gore");
+
+        let error = add_image_handler(store.clone(), request(&["neko", "gore"]), uploader())
+            .await
+            .err()
+            .unwrap();
+        assert!(is_blocked(&error));
+
+        let body = BatchAddImageRequest {
+            images: vec![request(&["GORE"])],
+        };
+        let reply = batch_add_images_handler(store.clone(), gate.clone(), body, uploader())
+            .await
+            .unwrap();
+        let (_, _, body) = into_parts(reply).await;
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["failed"], 1);
+
+        let boundary = "XBOUNDARY";
+        let mut multipart = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"tags\"\r\n\r\n[\"gore\"]\r\n\
+             --{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.png\"\r\n\
+             Content-Type: image/png\r\n\r\n"
+        )
+        .into_bytes();
+        multipart.extend_from_slice(&png(4, 4, 1));
+        multipart.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+        let form = warp::test::request()
+            .method("POST")
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={boundary}"),
+            )
+            .body(multipart)
+            .filter(&warp::multipart::form())
+            .await
+            .unwrap();
+        let error = upload_image_handler(form, store.clone(), gate, uploader())
+            .await
+            .err()
+            .unwrap();
+        assert!(is_blocked(&error));
+
+        let presign: PresignUploadRequest = serde_json::from_value(json!({
+            "content_type": "image/png",
+            "tags": ["gore"]
+        }))
+        .unwrap();
+        let presigner = Presigner::new("secret", std::time::Duration::from_secs(60));
+        let error = presign_upload_handler(
+            presigner,
+            60,
+            "http://localhost".to_string(),
+            None,
+            uploader(),
+            presign,
+            store.clone(),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(is_blocked(&error));
+
+        use std::io::Write;
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default();
+        zip.start_file("catalog.json", options).unwrap();
+        zip.write_all(br#"{"images": [{"filename": "a.png", "tags": ["gore"]}]}"#)
+            .unwrap();
+        zip.start_file("a.png", options).unwrap();
+        zip.write_all(&png(4, 4, 1)).unwrap();
+        let zip = zip.finish().unwrap().into_inner();
+        let imported = store.import_zip_catalog(&zip, true).await.unwrap();
+        assert_eq!(imported.failed, 1);
+
+        // nothing got as far as the database or the images directory
+        let conn = rusqlite::Connection::open(dir.path().join("images.db")).unwrap();
+        let count = |sql: &str| conn.query_row(sql, [], |row| row.get::<_, i64>(0)).unwrap();
+        assert_eq!(count("SELECT COUNT(*) FROM images"), 0);
+        assert_eq!(count("SELECT COUNT(*) FROM tags"), 0);
+        assert_eq!(
+            std::fs::read_dir(dir.path().join("images"))
+                .unwrap()
+                .count(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn random_with_too_many_filter_tags_is_a_400_naming_the_cap() {
+        let (_dir, store) = temp_store();
+        let limits = config(&[]).request_limits();
+        let ttl = std::time::Duration::from_secs(60);
+        let random = |count: usize| {
+            let tags: Vec<String> = (0..count).map(|i| format!("tag{}", i)).collect();
+            get_random_image_handler(
+                store.clone(),
+                ImageCache::new(10, ttl, ttl),
+                None,
+                query(&[("tags", &tags.join(","))]),
+                None,
+                limits,
+                api_key("alice"),
+                FeatureFlags::default(),
+            )
+        };
+
+        let rejection = random(31).await.err().unwrap();
+        let (status, _, body) = into_parts(handle_rejection(rejection).await.unwrap()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "too_many_filter_tags");
+        assert!(body["message"].as_str().unwrap().contains("30"), "{}", body);
+
+        // at the cap the query runs and simply finds nothing
+        let rejection = random(30).await.err().unwrap();
+        assert!(rejection.is_not_found());
+    }
+
+    #[tokio::test]
+    async fn temp_file_sweep_keeps_fresh_files_and_metrics_count_them() {
+        let (dir, store) = temp_store();
+        let images_dir = dir.path().join("images");
+        let old = images_dir.join("temp_old.png");
+        let fresh = images_dir.join("temp_fresh.png");
+        let image = images_dir.join("kept.png");
+        for path in [&old, &fresh, &image] {
+            std::fs::write(path, b"partial").unwrap();
+        }
+        let hour_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+        std::fs::File::options()
+            .write(true)
+            .open(&old)
+            .unwrap()
+            .set_modified(hour_ago)
+            .unwrap();
+
+        let removed = temp_files::sweep(&images_dir, std::time::Duration::from_secs(600)).unwrap();
+        assert_eq!(removed, 1);
+        assert!(!old.exists());
+        assert!(fresh.exists() && image.exists());
+
+        let reply = metrics_handler(UploadGate::new(1, 1), images_dir, store, ())
+            .await
+            .unwrap();
+        let (_, _, body) = into_parts(reply).await;
+        let metrics: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(metrics["temp_files"], json!({"count": 1, "bytes": 7}));
+    }
+
+    #[tokio::test]
+    async fn url_ingest_needs_its_scope_and_stops_at_the_daily_quota() {
+        let (dir, store) = temp_store();
+        let url = "https://93.184.215.14/cat.png";
+        let request = || AddImageRequest {
+            path: url.to_string(),
+            path_type: PathType::Url,
+            tags: vec!["neko".to_string()],
+            expected_hash: None,
+            merge_tags: false,
+        };
+
+        let uploader = ApiKey {
+            scopes: vec![ApiKeyScope::Upload],
+            max_batch_size: Some(5),
+            ..api_key("uploader")
+        };
+        let error = add_image_handler(store.clone(), request(), uploader.clone())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            image_error(&error),
+            ImageError::MissingScope(ApiKeyScope::IngestUrl)
+        ));
+        let batch = BatchAddImageRequest {
+            images: vec![request()],
+        };
+        let error = batch_add_images_handler(store.clone(), UploadGate::new(1, 1), batch, uploader)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            image_error(&error),
+            ImageError::MissingScope(ApiKeyScope::IngestUrl)
+        ));
+
+        // only today's downloads count against the quota
+        let ingester = ApiKey {
+            url_ingest_daily_bytes: Some(1000),
+            ..api_key("ingester")
+        };
+        let yesterday = (OffsetDateTime::now_utc().date() - time::Duration::days(1)).to_string();
+        let conn = rusqlite::Connection::open(dir.path().join("images.db")).unwrap();
+        for (day, bytes) in [(yesterday, 5000), (quota::today(), 1000)] {
+            conn.execute(
+                "INSERT INTO api_key_usage (api_key, day, url_ingest_bytes) VALUES (?, ?, ?)",
+                rusqlite::params![ingester.key, day, bytes],
+            )
+            .unwrap();
+        }
+        assert_eq!(store.url_ingest_used_today(&ingester.key).unwrap(), 1000);
+        assert_eq!(store.url_ingest_used_today("someone-else").unwrap(), 0);
+
+        // the spent quota refuses the URL before anything is downloaded
+        let rejection = add_image_handler(store.clone(), request(), ingester.clone())
+            .await
+            .err()
+            .unwrap();
+        match image_error(&rejection) {
+            ImageError::QuotaExceeded(quota) => {
+                assert_eq!(quota.remaining, 0);
+                assert!(quota.resets_at > OffsetDateTime::now_utc());
+                assert_eq!(quota.resets_at.time(), time::Time::MIDNIGHT);
+            }
+            other => panic!("expected QuotaExceeded, got {:?}", other),
+        }
+        let (status, _, _) = into_parts(handle_rejection(rejection).await.unwrap()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+        let reply = me_handler(ingester, store).await.unwrap();
+        let (_, _, body) = into_parts(reply).await;
+        let me: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(me["url_ingest"]["used_today"], 1000);
+        assert_eq!(me["url_ingest"]["daily_bytes"], 1000);
+    }
+
+    #[tokio::test]
+    async fn remap_renames_merges_and_rolls_back_on_a_bad_mapping() {
+        let (_dir, store) = crate::test_support::temp_store_with(&["--blocked-tags", "gore"]);
+        let ttl = std::time::Duration::from_secs(60);
+        let cache = ImageCache::new(10, ttl, ttl);
+        let tags = |list: &[&str]| list.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        let both = add_png(&store, 1).await;
+        let kitty = add_png(&store, 2).await;
+        let dog = add_png(&store, 3).await;
+        store.add_tags(&both, &tags(&["cat", "kitty"])).unwrap();
+        store.add_tags(&kitty, &tags(&["kitty"])).unwrap();
+        store.add_tags(&dog, &tags(&["dog"])).unwrap();
+        let remap = |content_type: &str, body: &str| {
+            remap_tags_handler(
+                Some(content_type.to_string()),
+                Bytes::from(body.to_string()),
+                store.clone(),
+                cache.clone(),
+                false,
+                (),
+            )
+        };
+        let tag_names = || {
+            let mut names: Vec<String> = store
+                .get_all_tags(true)
+                .unwrap()
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect();
+            names.sort();
+            names
+        };
+
+        let reply = remap("text/csv", "from,to\n\nKitty,cat\ndog,canine\n")
+            .await
+            .unwrap();
+        let (_, _, body) = into_parts(reply).await;
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body["results"],
+            json!([
+                {"from": "kitty", "to": "cat", "status": "merged", "images": 2},
+                {"from": "dog", "to": "canine", "status": "renamed", "images": 1}
+            ])
+        );
+        assert_eq!(store.get_image_tags(&both).unwrap(), ["cat"]);
+        assert_eq!(store.get_image_tags(&kitty).unwrap(), ["cat"]);
+        assert_eq!(store.get_image_tags(&dog).unwrap(), ["canine"]);
+        assert_eq!(tag_names(), ["canine", "cat"]);
+
+        // a blocked target anywhere in the list stops every mapping
+        let blocked = json!({"mappings": [
+            {"from": "cat", "to": "feline"},
+            {"from": "canine", "to": "gore"}
+        ]});
+        let error = remap("application/json", &blocked.to_string())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(image_error(&error), ImageError::BlockedTag(tag) if tag == "gore"));
+        assert_eq!(tag_names(), ["canine", "cat"]);
+
+        // as does a bad mapping after one that was already applied
+        let error = remap("text/csv", "cat,feline\ncanine, \n")
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            image_error(&error),
+            ImageError::InvalidParameter(_)
+        ));
+        assert_eq!(tag_names(), ["canine", "cat"]);
+        assert_eq!(store.get_image_tags(&both).unwrap(), ["cat"]);
+
+        let error = remap("text/csv", "cat feline\n").await.err().unwrap();
+        assert!(matches!(image_error(&error), ImageError::InvalidBody(_)));
+    }
+
+    #[tokio::test]
+    async fn every_url_style_serves_and_etags_follow_the_content() {
+        for style in ["filename", "hash", "id"] {
+            let (_dir, store) = crate::test_support::temp_store_with(&["--url-style", style]);
+            let serve = |key: ImageKey, value: &str, if_none_match: Option<&str>| {
+                serve_image_handler(
+                    value.to_string(),
+                    key,
+                    store.clone(),
+                    Placeholders::new(false),
+                    if_none_match.map(str::to_string),
+                    None,
+                    None,
+                )
+            };
+            // the route an image URL lands on
+            let route = |url: &str| {
+                let path = url
+                    .strip_prefix(&format!("{}/", store.images_url()))
+                    .unwrap_or_else(|| panic!("{} isn't under /images", url))
+                    .to_string();
+                match path.split_once('/') {
+                    Some(("h", hash)) => (ImageKey::Hash, hash.to_string()),
+                    Some(("id", id)) => (ImageKey::Id, id.to_string()),
+                    _ => (ImageKey::Filename, path),
+                }
+            };
+
+            let hash = add_png(&store, 1).await;
+            let image = store.get_image_by_hash(&hash).unwrap().unwrap();
+            let url = store.url_for_hash(&hash).unwrap();
+            assert_eq!(url, image.url, "{}", style);
+            let (key, value) = route(&url);
+            let expected = match style {
+                "filename" => image.filename.clone(),
+                "hash" => hash.clone(),
+                _ => image.id.clone(),
+            };
+            assert_eq!(value, expected, "{}", style);
+
+            // every route to the image has the same ETag
+            let etag = format!("\"{}\"", hash);
+            for (key, value) in [
+                (key, value.as_str()),
+                (ImageKey::Filename, image.filename.as_str()),
+                (ImageKey::Hash, hash.as_str()),
+                (ImageKey::Id, image.id.as_str()),
+            ] {
+                let (status, headers, body) =
+                    into_parts(serve(key, value, None).await.unwrap()).await;
+                assert_eq!(status, StatusCode::OK, "{}", style);
+                assert_eq!(headers[ETAG], etag.as_str(), "{}", style);
+                assert_eq!(body, png(4, 4, 1));
+            }
+
+            let replaced = store
+                .replace_image(&image.filename, &png(4, 4, 2), "image/png", None)
+                .unwrap();
+            let new_etag = format!("\"{}\"", replaced.hash);
+            assert_ne!(new_etag, etag);
+            let new_url = store.url_for_hash(&replaced.hash).unwrap();
+            if style == "hash" {
+                // a hash URL names the content, so the old one is gone
+                assert!(serve(key, &value, None).await.is_err());
+                assert_ne!(new_url, url);
+            } else {
+                assert_eq!(new_url, url, "{}", style);
+            }
+
+            // the same URL now revalidates against the new content
+            let (key, value) = route(&new_url);
+            let (status, headers, body) =
+                into_parts(serve(key, &value, Some(&etag)).await.unwrap()).await;
+            assert_eq!(status, StatusCode::OK, "{}", style);
+            assert_eq!(headers[ETAG], new_etag.as_str(), "{}", style);
+            assert_eq!(body, png(4, 4, 2));
+            let (status, _, _) =
+                into_parts(serve(key, &value, Some(&new_etag)).await.unwrap()).await;
+            assert_eq!(status, StatusCode::NOT_MODIFIED, "{}", style);
+        }
+    }
+
+    /// Every row of every table, and the files in the images directory.
+    fn snapshot(dir: &std::path::Path) -> (Vec<String>, Vec<String>) {
+        let conn = rusqlite::Connection::open(dir.join("images.db")).unwrap();
+        let tables: Vec<String> = conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let mut rows = Vec::new();
+        for table in tables {
+            let mut stmt = conn.prepare(&format!("SELECT * FROM {}", table)).unwrap();
+            let columns = stmt.column_count();
+            let mut table_rows = stmt
+                .query_map([], |row| {
+                    let values: Vec<String> = (0..columns)
+                        .map(|i| format!("{:?}", row.get_ref(i).unwrap()))
+                        .collect();
+                    Ok(format!("{}: {}", table, values.join(", ")))
+                })
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            table_rows.sort();
+            rows.extend(table_rows);
+        }
+        let mut files: Vec<String> = std::fs::read_dir(dir.join("images"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        (rows, files)
+    }
+
+    #[tokio::test]
+    async fn dry_runs_report_without_changing_anything() {
+        let (dir, store) = temp_store();
+        let ttl = std::time::Duration::from_secs(60);
+        let cache = ImageCache::new(10, ttl, ttl);
+        let hash = add_png(&store, 1).await;
+        let filename = format!("{}.png", hash);
+        store
+            .add_tags(&hash, &["neko".to_string(), "maid".to_string()])
+            .unwrap();
+        create_key(&store, json!({"username": "alice"}));
+        rusqlite::Connection::open(dir.path().join("images.db"))
+            .unwrap()
+            .execute("INSERT INTO tags (name) VALUES ('orphan')", [])
+            .unwrap();
+        let before = snapshot(dir.path());
+
+        let mut replies = Vec::new();
+        replies.push(
+            into_parts(
+                remove_image_handler(
+                    filename.clone(),
+                    store.clone(),
+                    KeyWebhooks::new().unwrap(),
+                    true,
+                    (),
+                )
+                .await
+                .unwrap(),
+            )
+            .await,
+        );
+        replies.push(
+            into_parts(
+                remove_image_tags_handler(
+                    filename.clone(),
+                    store.clone(),
+                    cache.clone(),
+                    vec!["neko".to_string()],
+                    true,
+                    (),
+                )
+                .await
+                .unwrap(),
+            )
+            .await,
+        );
+        let changes = vec![HashTagChange {
+            hash: hash.clone(),
+            add: vec!["catgirl".to_string()],
+            remove: vec!["maid".to_string()],
+        }];
+        replies.push(
+            into_parts(
+                change_tags_by_hash_handler(store.clone(), cache.clone(), changes, true, ())
+                    .await
+                    .unwrap(),
+            )
+            .await,
+        );
+        replies.push(into_parts(gc_tags_handler(store.clone(), true, ()).await.unwrap()).await);
+        replies.push(
+            into_parts(
+                remap_tags_handler(
+                    Some("text/csv".to_string()),
+                    Bytes::from_static(b"neko,catgirl\nmaid,neko\n"),
+                    store.clone(),
+                    cache.clone(),
+                    true,
+                    (),
+                )
+                .await
+                .unwrap(),
+            )
+            .await,
+        );
+        let body = RemoveApiKeyRequest {
+            username: "alice".to_string(),
+        };
+        replies.push(
+            into_parts(
+                remove_api_key_handler((), store.clone(), body, true)
+                    .await
+                    .unwrap(),
+            )
+            .await,
+        );
+
+        for (status, _, body) in &replies {
+            assert_eq!(*status, StatusCode::OK);
+            let body: serde_json::Value = serde_json::from_slice(body).unwrap();
+            assert_eq!(body["dry_run"], true, "{}", body);
+        }
+        // each one still says what it would have done
+        let body = |i: usize| serde_json::from_slice::<serde_json::Value>(&replies[i].2).unwrap();
+        assert_eq!(body(0)["hash"], hash.as_str());
+        assert_eq!(body(3)["tags"], json!(["orphan"]));
+        assert_eq!(body(4)["results"][0]["images"], 1);
+        assert_eq!(snapshot(dir.path()), before);
+    }
+
+    #[tokio::test]
+    async fn explain_lists_the_tags_that_matched() {
+        let (_dir, store) = temp_store();
+        let hash = add_png(&store, 1).await;
+        store
+            .add_tags(
+                &hash,
+                &["smile".to_string(), "neko".to_string(), "maid".to_string()],
+            )
+            .unwrap();
+        let limits = config(&[]).request_limits();
+        let ttl = std::time::Duration::from_secs(60);
+        let random = |pairs: &[(&str, &str)], key: ApiKey| {
+            get_random_image_handler(
+                store.clone(),
+                ImageCache::new(10, ttl, ttl),
+                None,
+                query(pairs),
+                None,
+                limits,
+                key,
+                FeatureFlags::default(),
+            )
+        };
+        let body = |reply| async move {
+            let (_, _, body) = into_parts(reply).await;
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        let image = body(
+            random(
+                &[("tags", "Neko,maid"), ("explain", "true")],
+                api_key("alice"),
+            )
+            .await
+            .unwrap(),
+        )
+        .await;
+        assert_eq!(image["hash"], hash.as_str());
+        // in the image's tag order, however the request spelled them
+        assert_eq!(image["matched_tags"], json!(["maid", "neko"]));
+
+        let image = body(random(&[("tags", "neko")], api_key("alice")).await.unwrap()).await;
+        assert!(image.get("matched_tags").is_none());
+
+        // tags filled in from the key's defaults count too
+        let defaulted = ApiKey {
+            default_filters: Some(DefaultFilters {
+                tags: vec!["smile".to_string()],
+                ..Default::default()
+            }),
+            ..api_key("bob")
+        };
+        let image = body(random(&[("explain", "true")], defaulted).await.unwrap()).await;
+        assert_eq!(image["matched_tags"], json!(["smile"]));
+
+        let error = random(&[("explain", "yes")], api_key("alice"))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            image_error(&error),
+            ImageError::InvalidParameter(msg) if msg == "explain must be true or false"
+        ));
+    }
+
+    #[tokio::test]
+    async fn image_bytes_carry_their_sha256_and_digest_when_wanted() {
+        use sha2::{Digest, Sha256};
+
+        let (_dir, store) = temp_store();
+        let hash = add_png(&store, 1).await;
+        let serve = |want_digest: Option<&str>| {
+            serve_image_handler(
+                hash.clone(),
+                ImageKey::Hash,
+                store.clone(),
+                Placeholders::new(false),
+                None,
+                None,
+                want_digest.map(str::to_string),
+            )
+        };
+
+        let (_, headers, body) = into_parts(serve(None).await.unwrap()).await;
+        let sha256 = Sha256::digest(&body);
+        assert_eq!(
+            headers["X-Content-SHA256"],
+            format!("{:x}", sha256).as_str()
+        );
+        let digest = format!("sha-256={}", BASE64.encode(sha256));
+        assert_eq!(headers["Digest"], digest.as_str());
+
+        for (want_digest, sent) in [
+            ("sha-256", true),
+            ("SHA-256;q=0.5", true),
+            ("sha;q=1, sha-256;q=0.1", true),
+            ("sha;q=1", false),
+            ("sha-256;q=0", false),
+            ("md5", false),
+        ] {
+            let (_, headers, _) = into_parts(serve(Some(want_digest)).await.unwrap()).await;
+            assert_eq!(headers.get("Digest").is_some(), sent, "{}", want_digest);
+            // the plain header doesn't depend on Want-Digest
+            assert_eq!(headers["X-Content-SHA256"], hash.as_str());
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_files_answer_410_or_their_placeholder() {
+        for fallback in [false, true] {
+            let (dir, store) = temp_store();
+            let hash = store
+                .add_image_data(&png(64, 40, 1), "test.png", "image/png")
+                .await
+                .unwrap();
+            let filename = format!("{}.png", hash);
+            let path = dir.path().join("images").join(&filename);
+            let data = std::fs::read(&path).unwrap();
+            std::fs::write(&path, &data[..data.len() / 2]).unwrap();
+
+            let file = warp::test::request()
+                .path(&format!("/{}", filename))
+                .filter(&warp::fs::dir(dir.path().join("images")))
+                .await
+                .unwrap();
+            let reply = tag_image_file(
+                file,
+                store.clone(),
+                Placeholders::new(fallback),
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+            let (status, headers, body) = into_parts(reply).await;
+            if fallback {
+                assert_eq!(status, StatusCode::OK);
+                assert_eq!(headers[CONTENT_TYPE], "image/png");
+                assert_eq!(headers["X-Image-Broken"], "true");
+                assert_eq!(headers[CACHE_CONTROL], "no-store");
+                // the placeholder keeps the image's shape
+                let placeholder = image::load_from_memory(&body).unwrap();
+                assert_eq!((placeholder.width(), placeholder.height()), (64, 40));
+            } else {
+                assert_eq!(status, StatusCode::GONE);
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(body["error"], "image_broken");
+            }
+
+            // the image is flagged, so the hash route answers the same way
+            // without decoding again
+            assert!(store
+                .check_image_file(&filename)
+                .unwrap_err()
+                .downcast_ref::<BrokenImage>()
+                .is_some());
+            assert_eq!(store.count_broken_images().unwrap(), 1);
+            let reply = serve_image_handler(
+                hash.clone(),
+                ImageKey::Hash,
+                store.clone(),
+                Placeholders::new(fallback),
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+            let (hash_status, _, _) = into_parts(reply).await;
+            assert_eq!(hash_status, status);
+        }
+    }
+
+    #[tokio::test]
+    async fn the_first_key_hint_shows_only_until_a_key_exists() {
+        let (_dir, store) = temp_store();
+        let limits = config(&[]).request_limits();
+        let info = || async {
+            let reply = info_handler(store.clone(), limits).await.unwrap();
+            let (_, _, body) = into_parts(reply).await;
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+        let list_hint = || async {
+            let reply = list_api_keys_handler((), store.clone()).await.unwrap();
+            let (_, headers, _) = into_parts(reply).await;
+            headers.get("X-Setup-Hint").cloned()
+        };
+
+        assert_eq!(info().await["setup"], FIRST_KEY_HINT);
+        assert_eq!(list_hint().await.unwrap(), FIRST_KEY_HINT);
+
+        create_key(&store, json!({"username": "alice"}));
+        let info = info().await;
+        assert!(info.get("setup").is_none(), "{}", info);
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert!(list_hint().await.is_none());
+    }
+
+    #[test]
+    fn expected_hashes_are_checked_and_lowercased() {
+        let hash = "AB".repeat(32);
+        assert_eq!(parse_expected_hash(None).unwrap(), None);
+        assert_eq!(
+            parse_expected_hash(Some(&format!(" {} ", hash))).unwrap(),
+            Some("ab".repeat(32))
+        );
+        for bad in ["", "abc", &"g".repeat(64), &"a".repeat(65)] {
+            assert!(
+                matches!(
+                    parse_expected_hash(Some(bad)),
+                    Err(ImageError::InvalidParameter(_))
+                ),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn a_file_not_matching_its_expected_hash_is_not_added() {
+        use sha2::{Digest, Sha256};
+
+        let (dir, store) = temp_store();
+        let source = dir.path().join("source.png");
+        let data = png(4, 4, 1);
+        std::fs::write(&source, &data).unwrap();
+        let actual = format!("{:x}", Sha256::digest(&data));
+        let request = |expected_hash: &str| AddImageRequest {
+            path: source.to_str().unwrap().to_string(),
+            path_type: PathType::Local,
+            tags: vec!["neko".to_string()],
+            expected_hash: Some(expected_hash.to_string()),
+            merge_tags: false,
+        };
+
+        let wrong = "0".repeat(64);
+        let rejection = add_image_handler(store.clone(), request(&wrong), api_key("alice"))
+            .await
+            .err()
+            .unwrap();
+        match image_error(&rejection) {
+            ImageError::HashMismatch(mismatch) => {
+                assert_eq!(mismatch.expected, wrong);
+                assert_eq!(mismatch.actual, actual);
+            }
+            other => panic!("expected HashMismatch, got {:?}", other),
+        }
+        let (status, _, body) = into_parts(handle_rejection(rejection).await.unwrap()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "hash_mismatch");
+        let conn = rusqlite::Connection::open(dir.path().join("images.db")).unwrap();
+        let images: i64 = conn
+            .query_row("SELECT COUNT(*) FROM images", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(images, 0);
+        assert_eq!(
+            std::fs::read_dir(dir.path().join("images"))
+                .unwrap()
+                .count(),
+            0
+        );
+        assert!(source.exists());
+
+        let reply = add_image_handler(
+            store.clone(),
+            request(&actual.to_uppercase()),
+            api_key("alice"),
+        )
+        .await
+        .unwrap();
+        let (status, _, _) = into_parts(reply).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(store.get_image_by_hash(&actual).unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn rate_limit_debug_shows_a_keys_window_and_decisions() {
+        let (_dir, store) = temp_store();
+        let alice = create_key(
+            &store,
+            json!({"username": "alice", "requests_per_second": 2}),
+        );
+        let limiter = ApiKeyRateLimiter::new(store.clone(), 10, time::Duration::seconds(60), 10);
+        for _ in 0..3 {
+            limiter.check_rate_limit(&alice.key, Some(&alice)).await;
+        }
+
+        let reply =
+            rate_limit_debug_handler("alice".to_string(), (), store.clone(), limiter.clone())
+                .await
+                .unwrap();
+        let (status, _, body) = into_parts(reply).await;
+        assert_eq!(status, StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["username"], "alice");
+        let debug = &body["rate_limit"];
+        assert_eq!(debug["limit"], 2);
+        assert_eq!(debug["window_secs"], 60);
+        assert_eq!(debug["history_size"], 10);
+        assert_eq!(debug["requests_in_window"], 2);
+        assert!(debug["oldest_in_window"].is_string());
+        let decisions: Vec<(bool, u64)> = debug["decisions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|d| {
+                (
+                    d["allowed"].as_bool().unwrap(),
+                    d["remaining"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(decisions, vec![(true, 1), (true, 0), (false, 0)]);
+
+        let error = rate_limit_debug_handler("nobody".to_string(), (), store, limiter)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            image_error(&error),
+            ImageError::UsernameNotFound(name) if name == "nobody"
+        ));
+    }
+
+    #[tokio::test]
+    async fn offset_pages_link_to_their_neighbours() {
+        let (_dir, store) = temp_store();
+        for seed in 0..5 {
+            add_png(&store, seed).await;
+        }
+        let limits = config(&[]).request_limits();
+        let page = |offset: &'static str| {
+            let store = store.clone();
+            async move {
+                let params = query(&[("order", "asc"), ("limit", "2"), ("offset", offset)]);
+                let reply = list_images_handler(params, store, None, limits, api_key("alice"))
+                    .await
+                    .unwrap();
+                let (_, _, body) = into_parts(reply).await;
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+            }
+        };
+        // the other parameters are kept and the paging ones replaced
+        let link_of = |link: &serde_json::Value| {
+            link.as_str().map(|link| {
+                let url = url::Url::parse(link).unwrap();
+                let pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+                assert_eq!(pairs[0], ("order".to_string(), "asc".to_string()));
+                assert_eq!(pairs[1], ("limit".to_string(), "2".to_string()));
+                assert_eq!(pairs.len(), 3);
+                pairs[2].1.parse::<u32>().unwrap()
+            })
+        };
+
+        for (offset, next, prev) in [
+            ("0", Some(2), None),
+            ("2", Some(4), Some(0)),
+            ("4", None, Some(2)),
+            ("1", Some(3), Some(0)),
+        ] {
+            let page = page(offset).await;
+            assert_eq!(page["total"], 5);
+            assert_eq!(link_of(&page["next"]), next, "next of offset {}", offset);
+            assert_eq!(link_of(&page["prev"]), prev, "prev of offset {}", offset);
+        }
+
+        let params = query(&[]);
+        assert_eq!(
+            page_links("http://x/images", &params, 0, 0, 5),
+            (None, None)
+        );
+        assert_eq!(
+            page_links("http://x/images", &params, 10, 0, 0),
+            (None, None)
+        );
+    }
+
+    #[tokio::test]
+    async fn rate_limits_list_every_window_busiest_first() {
+        let (_dir, store) = temp_store();
+        let alice = create_key(
+            &store,
+            json!({"username": "alice", "requests_per_second": 2}),
+        );
+        let carol = create_key(
+            &store,
+            json!({"username": "carol", "requests_per_second": 5}),
+        );
+        let unlimited = create_key(&store, json!({"username": "bob"}));
+        let limiter = ApiKeyRateLimiter::new(store.clone(), 10, time::Duration::seconds(60), 10);
+        for _ in 0..3 {
+            limiter.check_rate_limit(&alice.key, Some(&alice)).await;
+        }
+        limiter.check_rate_limit(&carol.key, Some(&carol)).await;
+        limiter
+            .check_rate_limit(&unlimited.key, Some(&unlimited))
+            .await;
+        limiter.check_rate_limit("not-a-key", None).await;
+
+        let reply = rate_limits_handler((), limiter).await.unwrap();
+        let (status, _, body) = into_parts(reply).await;
+        assert_eq!(status, StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["window_secs"], 60);
+        assert_eq!(body["unknown_keys"], 1);
+        let keys: Vec<(&str, u64, u64, u64)> = body["keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|window| {
+                assert!(window["oldest_in_window"].is_string());
+                (
+                    window["username"].as_str().unwrap(),
+                    window["limit"].as_u64().unwrap(),
+                    window["requests_in_window"].as_u64().unwrap(),
+                    window["remaining"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        // the throttled request wasn't counted, and unlimited keys have no
+        // window to show
+        assert_eq!(keys, [("alice", 2, 2, 0), ("carol", 5, 1, 4)]);
+    }
+
+    #[tokio::test]
+    async fn batch_draws_matching_nothing_give_one_reason() {
+        let (_dir, store) = temp_store();
+        let hash = add_png(&store, 1).await;
+        store.add_tags(&hash, &["cat".to_string()]).unwrap();
+        let ttl = std::time::Duration::from_secs(60);
+        let draw = |tags: &[&str]| {
+            let store = store.clone();
+            let body: BatchRandomRequest =
+                serde_json::from_value(json!({ "count": 3, "tags": tags })).unwrap();
+            async move {
+                let key = ApiKey {
+                    max_batch_size: Some(3),
+                    ..api_key("alice")
+                };
+                let reply = batch_random_images_handler(
+                    store,
+                    ImageCache::new(10, ttl, ttl),
+                    FeatureFlags::default(),
+                    key,
+                    body,
+                    None,
+                    config(&[]).request_limits(),
+                )
+                .await
+                .unwrap();
+                let (status, _, body) = into_parts(reply).await;
+                assert_eq!(status, StatusCode::OK);
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+            }
+        };
+
+        let batch = draw(&["dog"]).await;
+        assert_eq!(
+            batch,
+            json!({
+                "images": [],
+                "total": 3,
+                "successful": 0,
+                "failed": 0,
+                "errors": [],
+                "reason": "no_matches"
+            })
+        );
+
+        let batch = draw(&["cat"]).await;
+        assert_eq!(batch["successful"], 3);
+        assert!(batch.get("reason").is_none());
+    }
+
+    #[tokio::test]
+    async fn tags_change_by_hash_all_together_or_not_at_all() {
+        let (_dir, store) = crate::test_support::temp_store_with(&["--blocked-tags", "nsfw"]);
+        let ttl = std::time::Duration::from_secs(60);
+        let cache = ImageCache::new(10, ttl, ttl);
+        let first = add_png(&store, 1).await;
+        let second = add_png(&store, 2).await;
+        store
+            .add_tags(&first, &["maid".to_string(), "cat".to_string()])
+            .unwrap();
+        let image = store.get_image_by_hash(&first).unwrap().unwrap();
+        cache.insert(image.filename.clone(), image).await;
+        let change = |hash: &str, add: &[&str], remove: &[&str]| HashTagChange {
+            hash: hash.to_string(),
+            add: add.iter().map(|t| t.to_string()).collect(),
+            remove: remove.iter().map(|t| t.to_string()).collect(),
+        };
+        let apply = |changes: Vec<HashTagChange>| {
+            change_tags_by_hash_handler(store.clone(), cache.clone(), changes, false, ())
+        };
+
+        let reply = apply(vec![
+            change(&first, &["Cat Girl", "cat"], &["maid", "dog"]),
+            change(&second, &["dog"], &[]),
+        ])
+        .await
+        .unwrap();
+        let (status, _, body) = into_parts(reply).await;
+        assert_eq!(status, StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body["results"],
+            json!([
+                {
+                    "hash": first,
+                    "filename": format!("{}.png", first),
+                    "added": ["cat_girl"],
+                    "removed": ["maid"]
+                },
+                {
+                    "hash": second,
+                    "filename": format!("{}.png", second),
+                    "added": ["dog"],
+                    "removed": []
+                }
+            ])
+        );
+        assert_eq!(store.get_image_tags(&first).unwrap(), ["cat", "cat_girl"]);
+        assert_eq!(store.get_image_tags(&second).unwrap(), ["dog"]);
+        assert!(cache.get(&format!("{}.png", first)).await.is_none());
+
+        // one unknown hash and the earlier changes in the batch are undone
+        let error = apply(vec![
+            change(&second, &["bird"], &["dog"]),
+            change(&"0".repeat(64), &["bird"], &[]),
+        ])
+        .await
+        .err()
+        .unwrap();
+        assert!(matches!(image_error(&error), ImageError::PathNotFound(_)));
+        assert_eq!(store.get_image_tags(&second).unwrap(), ["dog"]);
+
+        let error = apply(vec![change(&second, &["nsfw"], &[])])
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(image_error(&error), ImageError::BlockedTag(tag) if tag == "nsfw"));
+        let error = apply(Vec::new()).await.err().unwrap();
+        assert!(matches!(image_error(&error), ImageError::InvalidBody(_)));
+        assert_eq!(store.get_image_tags(&second).unwrap(), ["dog"]);
+    }
+
+    #[tokio::test]
+    async fn collection_draws_return_only_its_members() {
+        let (_dir, store) = temp_store();
+        let mut hashes = Vec::new();
+        for seed in 0..6 {
+            hashes.push(add_png(&store, seed).await);
+        }
+        let members = vec![hashes[1].clone(), hashes[4].clone()];
+        store.create_collection("faves").unwrap();
+        store.create_collection("empty").unwrap();
+        store.add_to_collection("faves", &members).unwrap();
+
+        let limits = config(&[]).request_limits();
+        let ttl = std::time::Duration::from_secs(60);
+        let cache = ImageCache::new(10, ttl, ttl);
+        let random = |collection: &str| {
+            get_random_image_handler(
+                store.clone(),
+                cache.clone(),
+                None,
+                query(&[("collection", collection)]),
+                None,
+                limits,
+                api_key("alice"),
+                FeatureFlags::default(),
+            )
+        };
+
+        let mut drawn = std::collections::HashSet::new();
+        for _ in 0..30 {
+            let (_, _, body) = into_parts(random("faves").await.unwrap()).await;
+            let image: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            drawn.insert(image["hash"].as_str().unwrap().to_string());
+        }
+        assert_eq!(drawn, members.iter().cloned().collect());
+
+        let key = ApiKey {
+            max_batch_size: Some(10),
+            ..api_key("alice")
+        };
+        let body: BatchRandomRequest =
+            serde_json::from_value(json!({ "count": 10, "collection": "faves" })).unwrap();
+        let reply = batch_random_images_handler(
+            store.clone(),
+            cache.clone(),
+            FeatureFlags::default(),
+            key,
+            body,
+            None,
+            limits,
+        )
+        .await
+        .unwrap();
+        let (_, _, body) = into_parts(reply).await;
+        let batch: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(batch["successful"], 10);
+        for image in batch["images"].as_array().unwrap() {
+            assert!(members.iter().any(|hash| image["hash"] == *hash));
+        }
+
+        // an empty or unknown collection has nothing to draw
+        for collection in ["empty", "missing"] {
+            let error = random(collection).await.err().unwrap();
+            assert!(error.is_not_found(), "{}", collection);
+        }
+    }
+
+    #[tokio::test]
+    async fn duplicates_conflict_unless_their_uploader_merges_tags() {
+        let (dir, store) = temp_store();
+        let images_dir = dir.path().join("images");
+        let source = dir.path().join("source.png");
+        std::fs::write(&source, png(4, 4, 1)).unwrap();
+        let other = dir.path().join("other.png");
+        std::fs::write(&other, png(4, 4, 2)).unwrap();
+        let request = |path: &std::path::Path, tags: &[&str], merge_tags| AddImageRequest {
+            path: path.to_str().unwrap().to_string(),
+            path_type: PathType::Local,
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            expected_hash: None,
+            merge_tags,
+        };
+        let files = || std::fs::read_dir(&images_dir).unwrap().count();
+        let body_of = |body: Bytes| serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+
+        let reply = add_image_handler(
+            store.clone(),
+            request(&source, &["neko"], false),
+            api_key("alice"),
+        )
+        .await
+        .unwrap();
+        let (status, _, body) = into_parts(reply).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let hash = body_of(body)["hash"].as_str().unwrap().to_string();
+
+        // without merge_tags, and for anyone but the uploader, it's a 409;
+        // the copy made to check it doesn't stay behind
+        for (username, merge_tags) in [("alice", false), ("bob", true)] {
+            let error = add_image_handler(
+                store.clone(),
+                request(&source, &["maid"], merge_tags),
+                api_key(username),
+            )
+            .await
+            .err()
+            .unwrap();
+            let (status, _, body) = into_parts(handle_rejection(error).await.unwrap()).await;
+            assert_eq!(status, StatusCode::CONFLICT, "{}", username);
+            assert_eq!(body_of(body)["error"], "duplicate_image");
+            assert_eq!(files(), 1);
+        }
+        assert_eq!(store.get_image_tags(&hash).unwrap(), ["neko"]);
+
+        let admin = ApiKey {
+            is_admin: true,
+            ..api_key("root")
+        };
+        for (key, tag) in [(api_key("alice"), "maid"), (admin, "catgirl")] {
+            let reply = add_image_handler(store.clone(), request(&source, &[tag], true), key)
+                .await
+                .unwrap();
+            let (status, _, body) = into_parts(reply).await;
+            assert_eq!(status, StatusCode::OK);
+            let body = body_of(body);
+            assert_eq!(body["hash"], hash);
+            assert_eq!(body["message"], "Image already exists, tags merged");
+        }
+        assert_eq!(
+            store.get_image_tags(&hash).unwrap(),
+            ["catgirl", "maid", "neko"]
+        );
+        assert_eq!(files(), 1);
+
+        // a batch reports the duplicate against its own item
+        let body = BatchAddImageRequest {
+            images: vec![
+                request(&source, &["dup"], false),
+                request(&source, &["merged"], true),
+                request(&other, &["new"], false),
+            ],
+        };
+        let key = ApiKey {
+            max_batch_size: Some(5),
+            ..api_key("alice")
+        };
+        let reply = batch_add_images_handler(store.clone(), UploadGate::new(2, 2), body, key)
+            .await
+            .unwrap();
+        let response = reply.into_response();
+        let items = response
+            .extensions()
+            .get::<BatchItemResults>()
+            .unwrap()
+            .0
+            .clone();
+        let statuses: Vec<&str> = items
+            .iter()
+            .map(|item| item["status"].as_str().unwrap())
+            .collect();
+        assert_eq!(statuses, ["error", "ok", "ok"]);
+        assert!(items[0]["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Duplicate image"));
+        assert_eq!(items[1]["hash"], hash);
+        let (_, _, body) = into_parts(response).await;
+        let body = body_of(body);
+        assert_eq!(
+            (body["successful"].as_u64(), body["failed"].as_u64()),
+            (Some(2), Some(1))
+        );
+        assert!(store
+            .get_image_tags(&hash)
+            .unwrap()
+            .contains(&"merged".to_string()));
+        assert!(!store
+            .get_image_tags(&hash)
+            .unwrap()
+            .contains(&"dup".to_string()));
+        assert_eq!(files(), 2);
+    }
+
+    #[tokio::test]
+    async fn the_strategy_header_picks_how_random_images_are_drawn() {
+        let (dir, store) = temp_store();
+        let mut hashes = Vec::new();
+        for seed in 0..3 {
+            hashes.push(add_png(&store, seed).await);
+        }
+        hashes.sort();
+        let limits = config(&[]).request_limits();
+        let ttl = std::time::Duration::from_secs(60);
+        let cache = ImageCache::new(10, ttl, ttl);
+        let flags_for = |strategy: Option<&'static str>| async move {
+            let mut request = warp::test::request();
+            if let Some(strategy) = strategy {
+                request = request.header("x-strategy", strategy);
+            }
+            request.filter(&crate::middleware::feature_flags()).await
+        };
+        let draw = |flags: FeatureFlags| {
+            let store = store.clone();
+            let cache = cache.clone();
+            async move {
+                let reply = get_random_image_handler(
+                    store,
+                    cache,
+                    None,
+                    query(&[]),
+                    None,
+                    limits,
+                    api_key("alice"),
+                    flags,
+                )
+                .await
+                .unwrap();
+                let (_, _, body) = into_parts(reply).await;
+                let image: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                image["hash"].as_str().unwrap().to_string()
+            }
+        };
+        let serve_counts = || -> Vec<i64> {
+            let conn = rusqlite::Connection::open(dir.path().join("images.db")).unwrap();
+            let mut stmt = conn
+                .prepare("SELECT serve_count FROM images ORDER BY hash")
+                .unwrap();
+            let counts = stmt.query_map([], |row| row.get(0)).unwrap();
+            counts.collect::<Result<_, _>>().unwrap()
+        };
+
+        let uniform = flags_for(None).await.unwrap();
+        assert_eq!(uniform.strategy, crate::models::SelectionStrategy::Uniform);
+        for _ in 0..5 {
+            draw(uniform).await;
+        }
+        assert_eq!(serve_counts(), [0, 0, 0]);
+
+        // least served goes round every image before repeating one
+        let least_served = flags_for(Some(" Least_Served ")).await.unwrap();
+        assert_eq!(
+            least_served.strategy,
+            crate::models::SelectionStrategy::LeastServed
+        );
+        for _ in 0..2 {
+            let mut round: Vec<String> = Vec::new();
+            for _ in 0..3 {
+                round.push(draw(least_served).await);
+            }
+            round.sort();
+            assert_eq!(round, hashes);
+        }
+        assert_eq!(serve_counts(), [2, 2, 2]);
+
+        let error = flags_for(Some("newest")).await.err().unwrap();
+        let (status, _, body) = into_parts(handle_rejection(error).await.unwrap()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body["message"],
+            "Invalid parameter: X-Strategy must be one of uniform, least_served, got 'newest'"
+        );
+    }
+
+    #[tokio::test]
+    async fn images_convert_to_each_format_or_pass_through() {
+        let (_dir, store) = temp_store();
+        let hash = add_png(&store, 1).await;
+        let convert = |format: &str, if_none_match: Option<&str>| {
+            convert_image_handler(
+                hash.clone(),
+                ImageKey::Hash,
+                format.to_string(),
+                store.clone(),
+                Placeholders::new(false),
+                if_none_match.map(str::to_string),
+                None,
+                None,
+            )
+        };
+
+        for (format, mime, expected) in [
+            ("webp", "image/webp", ImageFormat::WebP),
+            ("jpeg", "image/jpeg", ImageFormat::Jpeg),
+        ] {
+            let (status, headers, body) = into_parts(convert(format, None).await.unwrap()).await;
+            assert_eq!(status, StatusCode::OK, "{}", format);
+            assert_eq!(headers[CONTENT_TYPE], mime);
+            let etag = format!("\"{}.{}\"", hash, format);
+            assert_eq!(headers["etag"], etag.as_str());
+            assert_eq!(image::guess_format(&body).unwrap(), expected);
+            let decoded = image::load_from_memory(&body).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (4, 4));
+
+            // served again from the derived cache, and revalidated by its ETag
+            let (_, _, again) = into_parts(convert(format, None).await.unwrap()).await;
+            assert_eq!(again, body);
+            let (status, _, _) = into_parts(convert(format, Some(&etag)).await.unwrap()).await;
+            assert_eq!(status, StatusCode::NOT_MODIFIED);
+        }
+
+        // already a PNG, so the stored bytes go out untouched
+        let (status, headers, body) = into_parts(convert("png", None).await.unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[CONTENT_TYPE], "image/png");
+        assert_eq!(body, png(4, 4, 1));
+
+        for format in ["gif", "JPEG", ""] {
+            let error = convert(format, None).await.err().unwrap();
+            let (status, _, _) = into_parts(handle_rejection(error).await.unwrap()).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{:?}", format);
         }
     }
 }
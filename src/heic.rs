@@ -0,0 +1,103 @@
+use anyhow::{anyhow, Result};
+use image::{DynamicImage, ImageFormat};
+
+pub const HEIC_CONTENT_TYPES: [&str; 2] = ["image/heic", "image/heif"];
+
+// ISO-BMFF brands used by HEIC/HEIF stills and sequences
+const HEIF_BRANDS: [&[u8; 4]; 8] = [
+    b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"mif1", b"msf1",
+];
+
+/// Browsers can't display HEIC, so it is always re-encoded before storing.
+#[derive(Clone, Copy, Debug)]
+pub struct HeicConversion {
+    pub format: ImageFormat,
+    /// JPEG quality (1-100). WebP output is lossless and ignores this.
+    pub quality: u8,
+}
+
+impl HeicConversion {
+    pub fn from_config(format: &str, quality: u8) -> Result<Self> {
+        let format = match format.to_lowercase().as_str() {
+            "jpeg" | "jpg" => ImageFormat::Jpeg,
+            "webp" => ImageFormat::WebP,
+            other => return Err(anyhow!("Unsupported HEIC conversion format: {}", other)),
+        };
+        Ok(Self {
+            format,
+            quality: quality.clamp(1, 100),
+        })
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self.format {
+            ImageFormat::Png => "image/png",
+            ImageFormat::WebP => "image/webp",
+            _ => "image/jpeg",
+        }
+    }
+}
+
+pub fn is_heic_content_type(content_type: &str) -> bool {
+    HEIC_CONTENT_TYPES.contains(&content_type)
+}
+
+pub fn is_heif(data: &[u8]) -> bool {
+    data.len() >= 12 && &data[4..8] == b"ftyp" && HEIF_BRANDS.iter().any(|b| &data[8..12] == *b)
+}
+
+pub fn ensure_supported() -> Result<()> {
+    if cfg!(feature = "heic") {
+        Ok(())
+    } else {
+        Err(not_compiled_in())
+    }
+}
+
+fn not_compiled_in() -> anyhow::Error {
+    anyhow!(
+        "Unsupported image format: HEIC support is not compiled into this server (rebuild with `--features heic`)"
+    )
+}
+
+/// Decodes a HEIC/HEIF image and re-encodes it in the configured format.
+pub fn convert(data: &[u8], conversion: HeicConversion) -> Result<Vec<u8>> {
+    ensure_supported()?;
+    let img = decode(data)?;
+    crate::canonical::encode(&img, conversion.format, conversion.quality)
+}
+
+#[cfg(feature = "heic")]
+fn decode(data: &[u8]) -> Result<DynamicImage> {
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let lib_heif = LibHeif::new();
+    let ctx = HeifContext::read_from_bytes(data).map_err(|e| anyhow!("Invalid image: {}", e))?;
+    let handle = ctx
+        .primary_image_handle()
+        .map_err(|e| anyhow!("Invalid image: {}", e))?;
+    let decoded = lib_heif
+        .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None)
+        .map_err(|e| anyhow!("Invalid image: {}", e))?;
+
+    let planes = decoded.planes();
+    let plane = planes
+        .interleaved
+        .ok_or_else(|| anyhow!("Invalid image: HEIC decoder returned no pixel data"))?;
+
+    // rows may be padded, copy them out without the stride slack
+    let row_len = plane.width as usize * 3;
+    let mut pixels = Vec::with_capacity(row_len * plane.height as usize);
+    for row in plane.data.chunks(plane.stride).take(plane.height as usize) {
+        pixels.extend_from_slice(&row[..row_len]);
+    }
+
+    let buffer = image::RgbImage::from_raw(plane.width, plane.height, pixels)
+        .ok_or_else(|| anyhow!("Invalid image: HEIC pixel buffer has unexpected size"))?;
+    Ok(DynamicImage::ImageRgb8(buffer))
+}
+
+#[cfg(not(feature = "heic"))]
+fn decode(_data: &[u8]) -> Result<DynamicImage> {
+    Err(not_compiled_in())
+}
@@ -0,0 +1,156 @@
+use dashmap::DashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use tracing::debug;
+
+/// Idle hosts are dropped once the map grows past this many entries.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Longest pause taken for an origin's `Retry-After`.
+const MAX_HOST_PAUSE: Duration = Duration::from_secs(300);
+
+struct HostSlot {
+    permits: Arc<Semaphore>,
+    // earliest moment the next download from this host may start
+    next_start: Mutex<Instant>,
+}
+
+/// Caps in-flight URL downloads per destination host and spaces out the
+/// requests made to it, so a batch against one origin stays polite.
+#[derive(Clone)]
+pub struct HostThrottle {
+    hosts: Arc<DashMap<String, Arc<HostSlot>>>,
+    max_in_flight: usize,
+    min_interval: Duration,
+}
+
+impl HostThrottle {
+    pub fn new(max_in_flight: usize, min_interval: Duration) -> Self {
+        Self {
+            hosts: Arc::new(DashMap::new()),
+            max_in_flight: max_in_flight.max(1),
+            min_interval,
+        }
+    }
+
+    fn slot(&self, host: &str) -> Arc<HostSlot> {
+        if self.hosts.len() > PRUNE_THRESHOLD {
+            let now = Instant::now();
+            self.hosts.retain(|_, slot| {
+                Arc::strong_count(slot) > 1
+                    || *slot.next_start.lock().unwrap_or_else(|e| e.into_inner()) > now
+            });
+        }
+        self.hosts
+            .entry(host.to_string())
+            .or_insert_with(|| {
+                Arc::new(HostSlot {
+                    permits: Arc::new(Semaphore::new(self.max_in_flight)),
+                    next_start: Mutex::new(Instant::now()),
+                })
+            })
+            .clone()
+    }
+
+    /// Waits for a free slot for `host` and for its spacing or pause to
+    /// pass. The download holds the returned permit until it finishes.
+    pub async fn acquire(&self, host: &str) -> OwnedSemaphorePermit {
+        let slot = self.slot(host);
+        let queued = Instant::now();
+        let permit = slot
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("host semaphore is never closed");
+
+        let start = {
+            let mut next_start = slot.next_start.lock().unwrap_or_else(|e| e.into_inner());
+            let start = (*next_start).max(Instant::now());
+            *next_start = start + self.min_interval;
+            start
+        };
+        tokio::time::sleep_until(start).await;
+
+        debug!(
+            "Waited {:?} to download from {} ({} of {} slots in use)",
+            queued.elapsed(),
+            host,
+            self.max_in_flight - slot.permits.available_permits(),
+            self.max_in_flight
+        );
+        permit
+    }
+
+    /// Holds back further downloads from `host`, e.g. after it answered 429.
+    pub fn pause(&self, host: &str, duration: Duration) {
+        let duration = duration.min(MAX_HOST_PAUSE);
+        let slot = self.slot(host);
+        let mut next_start = slot.next_start.lock().unwrap_or_else(|e| e.into_inner());
+        *next_start = (*next_start).max(Instant::now() + duration);
+        debug!("Pausing downloads from {} for {:?}", host, duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHORT: Duration = Duration::from_millis(50);
+
+    #[tokio::test]
+    async fn caps_downloads_in_flight_per_host() {
+        let throttle = HostThrottle::new(2, Duration::ZERO);
+        let first = throttle.acquire("a.example").await;
+        let _second = throttle.acquire("a.example").await;
+
+        assert!(tokio::time::timeout(SHORT, throttle.acquire("a.example"))
+            .await
+            .is_err());
+        // other hosts have their own slots
+        assert!(tokio::time::timeout(SHORT, throttle.acquire("b.example"))
+            .await
+            .is_ok());
+
+        drop(first);
+        assert!(tokio::time::timeout(SHORT, throttle.acquire("a.example"))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn spaces_out_starts_to_one_host() {
+        let interval = Duration::from_millis(100);
+        let throttle = HostThrottle::new(4, interval);
+        let started = Instant::now();
+        for _ in 0..3 {
+            drop(throttle.acquire("a.example").await);
+        }
+        assert!(started.elapsed() >= interval * 2, "{:?}", started.elapsed());
+
+        let started = Instant::now();
+        drop(throttle.acquire("b.example").await);
+        assert!(started.elapsed() < interval);
+    }
+
+    #[tokio::test]
+    async fn a_pause_holds_back_only_that_host() {
+        let throttle = HostThrottle::new(4, Duration::ZERO);
+        let pause = Duration::from_millis(200);
+        throttle.pause("a.example", pause);
+
+        let started = Instant::now();
+        drop(throttle.acquire("b.example").await);
+        assert!(started.elapsed() < pause);
+        drop(throttle.acquire("a.example").await);
+        assert!(started.elapsed() >= pause, "{:?}", started.elapsed());
+
+        // an hour-long Retry-After is cut down to the longest pause
+        throttle.pause("a.example", Duration::from_secs(3600));
+        let next_start = *throttle.slot("a.example").next_start.lock().unwrap();
+        assert!(next_start <= Instant::now() + MAX_HOST_PAUSE);
+        assert!(next_start > Instant::now() + MAX_HOST_PAUSE - Duration::from_secs(5));
+    }
+}
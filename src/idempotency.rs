@@ -0,0 +1,202 @@
+use crate::error::ImageError;
+use crate::models::IdempotentResponse;
+use crate::store::ImageStore;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use warp::http::header::CONTENT_TYPE;
+use warp::http::{HeaderValue, StatusCode};
+use warp::hyper::body::to_bytes;
+use warp::hyper::Body;
+use warp::reply::Response;
+
+const MAX_KEY_LEN: usize = 255;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(600);
+
+type ScopedKey = (String, String);
+
+/// Remembers the outcome of mutating requests sent with an `Idempotency-Key`
+/// so client retries get the original response instead of running twice.
+/// Keys are scoped to the API key that sent them.
+#[derive(Clone)]
+pub struct Idempotency {
+    store: ImageStore,
+    in_flight: Arc<DashMap<ScopedKey, ()>>,
+    ttl: Duration,
+}
+
+pub enum Claim {
+    /// First attempt: run the request and hand its response to `complete`.
+    Run(IdempotencyGuard),
+    /// The key already completed for this request.
+    Replay(IdempotentResponse),
+}
+
+// Releases the key even if the request is dropped midway, so a retry can
+// run it again instead of getting 409 until restart.
+pub struct IdempotencyGuard {
+    in_flight: Arc<DashMap<ScopedKey, ()>>,
+    key: ScopedKey,
+    method: String,
+    path: String,
+}
+
+impl Drop for IdempotencyGuard {
+    fn drop(&mut self) {
+        self.in_flight.remove(&self.key);
+    }
+}
+
+impl Idempotency {
+    pub fn new(store: ImageStore, ttl: Duration) -> Self {
+        Self {
+            store,
+            in_flight: Arc::new(DashMap::new()),
+            ttl,
+        }
+    }
+
+    pub fn begin(
+        &self,
+        api_key: &str,
+        idempotency_key: &str,
+        method: &str,
+        path: &str,
+    ) -> Result<Claim, ImageError> {
+        if idempotency_key.is_empty()
+            || idempotency_key.len() > MAX_KEY_LEN
+            || !idempotency_key.bytes().all(|b| b.is_ascii_graphic())
+        {
+            return Err(ImageError::InvalidParameter(format!(
+                "Idempotency-Key must be 1-{} visible ASCII characters",
+                MAX_KEY_LEN
+            )));
+        }
+
+        let key = (api_key.to_string(), idempotency_key.to_string());
+        match self.in_flight.entry(key.clone()) {
+            Entry::Occupied(_) => {
+                return Err(ImageError::IdempotencyConflict(
+                    "a request with this key is still in progress".to_string(),
+                ))
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(());
+            }
+        }
+        let guard = IdempotencyGuard {
+            in_flight: self.in_flight.clone(),
+            key,
+            method: method.to_string(),
+            path: path.to_string(),
+        };
+
+        // checked after claiming the key so a request that completes in
+        // between is replayed rather than run twice
+        let stored = self
+            .store
+            .get_idempotent_response(api_key, idempotency_key, self.ttl)
+            .map_err(|e| ImageError::DatabaseError(e.to_string()))?;
+        match stored {
+            Some(stored) if stored.method != method || stored.path != path => {
+                Err(ImageError::IdempotencyConflict(format!(
+                    "key was already used for {} {}",
+                    stored.method, stored.path
+                )))
+            }
+            Some(stored) => {
+                debug!("Replaying stored response for {} {}", method, path);
+                Ok(Claim::Replay(stored))
+            }
+            None => Ok(Claim::Run(guard)),
+        }
+    }
+
+    /// Stores `response` for the guarded key and returns it. Server errors,
+    /// auth failures and rate limiting aren't stored so a retry runs again.
+    pub async fn complete(&self, guard: IdempotencyGuard, response: Response) -> Response {
+        let status = response.status();
+        if !Self::is_final(status) {
+            return response;
+        }
+
+        let (parts, body) = response.into_parts();
+        let body = match to_bytes(body).await {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Failed to buffer response for idempotency key: {}", e);
+                return Response::from_parts(parts, Body::empty());
+            }
+        };
+
+        let stored = IdempotentResponse {
+            method: guard.method.clone(),
+            path: guard.path.clone(),
+            status: status.as_u16(),
+            content_type: parts
+                .headers
+                .get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+            body: body.to_vec(),
+        };
+        if let Err(e) = self
+            .store
+            .save_idempotent_response(&guard.key.0, &guard.key.1, &stored)
+        {
+            warn!("Failed to store response for idempotency key: {}", e);
+        }
+
+        Response::from_parts(parts, Body::from(body))
+    }
+
+    fn is_final(status: StatusCode) -> bool {
+        status.is_success()
+            || (status.is_client_error()
+                && !matches!(
+                    status,
+                    StatusCode::UNAUTHORIZED
+                        | StatusCode::FORBIDDEN
+                        | StatusCode::REQUEST_TIMEOUT
+                        | StatusCode::TOO_MANY_REQUESTS
+                ))
+    }
+
+    pub fn replay(stored: &IdempotentResponse) -> Response {
+        let mut response = Response::new(Body::from(stored.body.clone()));
+        *response.status_mut() = StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK);
+        if let Some(value) = stored
+            .content_type
+            .as_deref()
+            .and_then(|v| HeaderValue::from_str(v).ok())
+        {
+            response.headers_mut().insert(CONTENT_TYPE, value);
+        }
+        response
+            .headers_mut()
+            .insert("Idempotent-Replayed", HeaderValue::from_static("true"));
+        response
+    }
+
+    /// Periodically deletes stored responses older than the TTL.
+    pub fn spawn_sweeper(&self) {
+        let store = self.store.clone();
+        let ttl = self.ttl;
+        tokio::spawn(async move {
+            let mut ticker =
+                tokio::time::interval(SWEEP_INTERVAL.min(ttl.max(Duration::from_secs(1))));
+            loop {
+                ticker.tick().await;
+                let store = store.clone();
+                match tokio::task::spawn_blocking(move || store.purge_idempotency_keys(ttl)).await {
+                    Ok(Ok(0)) => {}
+                    Ok(Ok(purged)) => info!("Purged {} expired idempotency keys", purged),
+                    Ok(Err(e)) => warn!("Failed to purge idempotency keys: {}", e),
+                    Err(e) => warn!("Idempotency sweep task failed: {}", e),
+                }
+            }
+        });
+    }
+}
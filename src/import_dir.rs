@@ -0,0 +1,312 @@
+//! `waifu import-dir`, which adds every image under a directory as if each
+//! had been posted to `POST /image` as a local file:
+//!
+//! ```sh
+//! waifu import-dir ./collection --tags imported --tags-from both
+//! ```
+//!
+//! Besides the `--tags` baseline, `--tags-from` takes tags from each image's
+//! `.txt` sidecar (comma-separated, as dataset tools write them), from the
+//! name of the directory holding it, or both.
+
+use crate::config::Config;
+use crate::models::PathType;
+use crate::store::ImageStore;
+use anyhow::{anyhow, Result};
+use clap::{Parser, ValueEnum};
+use std::path::{Path, PathBuf};
+
+/// Files next to images that are never images themselves.
+const SKIPPED_EXTENSIONS: [&str; 2] = ["txt", "json"];
+
+#[derive(Parser)]
+#[command(name = "waifu import-dir")]
+struct ImportDirCli {
+    /// Directory to import, including subdirectories
+    dir: PathBuf,
+    /// Comma-separated tags given to every imported image
+    #[arg(long, value_delimiter = ',')]
+    tags: Vec<String>,
+    /// Where to take each image's own tags from, on top of `--tags`
+    #[arg(long, value_enum)]
+    tags_from: Option<TagSource>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum TagSource {
+    /// `<image>.txt` next to the image
+    Sidecar,
+    /// The name of the directory the image is in
+    Dirname,
+    Both,
+}
+
+impl TagSource {
+    fn sidecar(self) -> bool {
+        matches!(self, TagSource::Sidecar | TagSource::Both)
+    }
+
+    fn dirname(self) -> bool {
+        matches!(self, TagSource::Dirname | TagSource::Both)
+    }
+}
+
+#[derive(Default)]
+struct Summary {
+    imported: usize,
+    failed: Vec<(PathBuf, String)>,
+    unreadable_sidecars: Vec<PathBuf>,
+    skipped_tags: Vec<(PathBuf, String)>,
+}
+
+/// Entry point for `waifu import-dir ...`. `args` starts at `import-dir`.
+pub async fn run(args: impl Iterator<Item = String>) -> Result<()> {
+    let cli = ImportDirCli::parse_from(args);
+    let config = Config::for_tools()?;
+    let store = ImageStore::new("images.db", PathBuf::from("images"), &config)?;
+
+    let baseline = ImageStore::normalize_tag_list(&cli.tags);
+    if let Some(tag) = store.find_blocked_tag(&baseline) {
+        return Err(anyhow!("--tags includes a blocked tag: {}", tag));
+    }
+
+    let summary = import_all(&store, &cli.dir, &baseline, cli.tags_from).await?;
+    print_summary(&summary);
+    Ok(())
+}
+
+/// Imports every file under `dir`, in path order, with `baseline` plus the
+/// tags `tags_from` finds for it.
+async fn import_all(
+    store: &ImageStore,
+    dir: &Path,
+    baseline: &[String],
+    tags_from: Option<TagSource>,
+) -> Result<Summary> {
+    let mut files = Vec::new();
+    collect_files(dir, &mut files)?;
+    files.sort();
+
+    let mut summary = Summary::default();
+    for file in files {
+        let tags = tags_for(store, &file, baseline, tags_from, &mut summary)
+            .unwrap_or_else(|| baseline.to_vec());
+        if tags.is_empty() {
+            summary
+                .failed
+                .push((file, "no tags; pass --tags or --tags-from".to_string()));
+            continue;
+        }
+        match import(store, &file, &tags).await {
+            Ok(()) => {
+                println!("imported {} with tags {}", file.display(), tags.join(", "));
+                summary.imported += 1;
+            }
+            Err(e) => summary.failed.push((file, e.to_string())),
+        }
+    }
+    Ok(summary)
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let entries =
+        std::fs::read_dir(dir).map_err(|e| anyhow!("Failed to read {}: {}", dir.display(), e))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+            continue;
+        }
+        let skipped = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| SKIPPED_EXTENSIONS.contains(&ext.to_lowercase().as_str()));
+        if !skipped {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The baseline plus the image's own tags, or `None` when its sidecar can't
+/// be read, in which case only the baseline applies. Blocked tags from the
+/// sidecar or directory name are dropped and noted in the summary.
+fn tags_for(
+    store: &ImageStore,
+    file: &Path,
+    baseline: &[String],
+    source: Option<TagSource>,
+    summary: &mut Summary,
+) -> Option<Vec<String>> {
+    let mut tags = baseline.to_vec();
+    let Some(source) = source else {
+        return Some(tags);
+    };
+    if source.sidecar() {
+        if let Some(sidecar) = find_sidecar(file) {
+            match std::fs::read_to_string(&sidecar) {
+                Ok(contents) => tags.extend(contents.split(',').map(str::to_string)),
+                Err(e) => {
+                    eprintln!("failed to read {}: {}", sidecar.display(), e);
+                    summary.unreadable_sidecars.push(sidecar);
+                    return None;
+                }
+            }
+        }
+    }
+    if source.dirname() {
+        if let Some(name) = file
+            .parent()
+            .and_then(|dir| dir.file_name())
+            .and_then(|name| name.to_str())
+        {
+            tags.push(name.to_string());
+        }
+    }
+
+    let mut tags = ImageStore::normalize_tag_list(&tags);
+    tags.retain(|tag| {
+        let blocked = store.find_blocked_tag(std::slice::from_ref(tag)).is_some();
+        if blocked {
+            summary.skipped_tags.push((file.to_path_buf(), tag.clone()));
+        }
+        !blocked
+    });
+    Some(tags)
+}
+
+/// `image.txt` as most dataset tools write it, or `image.png.txt`.
+fn find_sidecar(file: &Path) -> Option<PathBuf> {
+    let mut appended = file.as_os_str().to_owned();
+    appended.push(".txt");
+    [file.with_extension("txt"), PathBuf::from(appended)]
+        .into_iter()
+        .find(|path| path.exists())
+}
+
+async fn import(store: &ImageStore, file: &Path, tags: &[String]) -> Result<()> {
+    let path = file
+        .to_str()
+        .ok_or_else(|| anyhow!("path is not valid UTF-8"))?;
+    let hash = store.add_image(path, PathType::Local, None, None).await?;
+    store.add_tags(&hash, tags)
+}
+
+fn print_summary(summary: &Summary) {
+    println!(
+        "Imported {} images, {} failed",
+        summary.imported,
+        summary.failed.len()
+    );
+    for (file, reason) in &summary.failed {
+        println!("  failed {}: {}", file.display(), reason);
+    }
+    if !summary.unreadable_sidecars.is_empty() {
+        println!("Unreadable sidecars, imported with --tags only:");
+        for sidecar in &summary.unreadable_sidecars {
+            println!("  {}", sidecar.display());
+        }
+    }
+    if !summary.skipped_tags.is_empty() {
+        println!("Blocked tags left off:");
+        for (file, tag) in &summary.skipped_tags {
+            println!("  {} on {}", tag, file.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{png, temp_store_with};
+    use sha2::{Digest, Sha256};
+
+    fn write(path: &Path, contents: &[u8]) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    /// Imports the tree under `root` into a fresh store, returning the
+    /// summary and each seed's sorted tags.
+    async fn import(root: &Path, tags_from: TagSource) -> (Summary, Vec<Vec<String>>) {
+        let (_dir, store) = temp_store_with(&["--blocked-tags", "nsfw"]);
+        let summary = import_all(&store, root, &["imported".to_string()], Some(tags_from))
+            .await
+            .unwrap();
+        let tags = (1..=3)
+            .map(|seed| {
+                let hash = format!("{:x}", Sha256::digest(png(4, 4, seed)));
+                let mut tags = store.get_image_by_hash(&hash).unwrap().unwrap().tags;
+                tags.sort();
+                tags
+            })
+            .collect();
+        (summary, tags)
+    }
+
+    #[tokio::test]
+    async fn tags_come_from_sidecars_and_directory_names() {
+        let source = tempfile::tempdir().unwrap();
+        let root = source.path();
+        write(&root.join("Cats/a.png"), &png(4, 4, 1));
+        write(&root.join("Cats/a.txt"), b" Fluffy, orange cat,nsfw\n");
+        write(&root.join("dogs/b.png"), &png(4, 4, 2));
+        write(&root.join("dogs/b.png.txt"), b"good boy");
+        write(&root.join("misc/c.png"), &png(4, 4, 3));
+        write(&root.join("misc/c.json"), b"{}");
+
+        let (summary, tags) = import(root, TagSource::Both).await;
+        assert_eq!(summary.imported, 3);
+        assert!(summary.failed.is_empty());
+        assert_eq!(
+            tags,
+            vec![
+                vec!["cats", "fluffy", "imported", "orange_cat"],
+                vec!["dogs", "good_boy", "imported"],
+                vec!["imported", "misc"],
+            ]
+        );
+        assert_eq!(
+            summary.skipped_tags,
+            vec![(root.join("Cats/a.png"), "nsfw".to_string())]
+        );
+
+        let (_, tags) = import(root, TagSource::Sidecar).await;
+        assert_eq!(
+            tags,
+            vec![
+                vec!["fluffy", "imported", "orange_cat"],
+                vec!["good_boy", "imported"],
+                vec!["imported"],
+            ]
+        );
+
+        let (summary, tags) = import(root, TagSource::Dirname).await;
+        assert_eq!(
+            tags,
+            vec![
+                vec!["cats", "imported"],
+                vec!["dogs", "imported"],
+                vec!["imported", "misc"],
+            ]
+        );
+        assert!(summary.skipped_tags.is_empty());
+    }
+
+    #[tokio::test]
+    async fn images_without_any_tags_are_not_imported() {
+        let (_dir, store) = temp_store_with(&[]);
+        let source = tempfile::tempdir().unwrap();
+        write(&source.path().join("a.png"), &png(4, 4, 1));
+        write(&source.path().join("b.png"), &png(4, 4, 2));
+        write(&source.path().join("b.txt"), b"tagged");
+
+        let summary = import_all(&store, source.path(), &[], Some(TagSource::Sidecar))
+            .await
+            .unwrap();
+        assert_eq!(summary.imported, 1);
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.failed[0].0, source.path().join("a.png"));
+        assert!(summary.failed[0].1.contains("no tags"));
+    }
+}
@@ -0,0 +1,136 @@
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tracing::debug;
+
+/// Coalesces identical concurrent requests so only the first one does the work
+/// and the rest receive a copy of its result.
+#[derive(Clone)]
+pub struct InFlightCache<K, V> {
+    inflight: Arc<DashMap<K, broadcast::Sender<V>>>,
+}
+
+// Removes the in-flight entry even if the leading request is dropped midway,
+// so waiters see a closed channel instead of hanging forever.
+struct InFlightGuard<'a, K: Eq + Hash, V> {
+    inflight: &'a DashMap<K, broadcast::Sender<V>>,
+    key: &'a K,
+}
+
+impl<K: Eq + Hash, V> Drop for InFlightGuard<'_, K, V> {
+    fn drop(&mut self) {
+        self.inflight.remove(self.key);
+    }
+}
+
+impl<K, V> InFlightCache<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    pub fn new() -> Self {
+        Self {
+            inflight: Arc::new(DashMap::new()),
+        }
+    }
+
+    pub async fn run<F, Fut>(&self, key: K, f: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let sender = match self.inflight.entry(key.clone()) {
+            Entry::Occupied(entry) => {
+                let mut receiver = entry.get().subscribe();
+                drop(entry);
+                debug!("Joining in-flight request");
+                if let Ok(value) = receiver.recv().await {
+                    return value;
+                }
+                // the leading request went away without a result, do the work ourselves
+                return f().await;
+            }
+            Entry::Vacant(entry) => {
+                let (sender, _) = broadcast::channel(1);
+                entry.insert(sender.clone());
+                sender
+            }
+        };
+
+        let guard = InFlightGuard {
+            inflight: &self.inflight,
+            key: &key,
+        };
+        let value = f().await;
+        drop(guard);
+
+        // no receivers is fine, it just means nobody piggybacked on this request
+        let _ = sender.send(value.clone());
+        value
+    }
+}
+
+impl<K, V> Default for InFlightCache<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::Notify;
+
+    #[tokio::test]
+    async fn identical_concurrent_calls_share_one_run() {
+        let cache: InFlightCache<String, u32> = InFlightCache::new();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let release = Arc::new(Notify::new());
+
+        let call = |key: &str| {
+            let (cache, runs, release) = (cache.clone(), runs.clone(), release.clone());
+            let key = key.to_string();
+            tokio::spawn(async move {
+                cache
+                    .run(key, || async move {
+                        runs.fetch_add(1, Ordering::SeqCst);
+                        release.notified().await;
+                        7
+                    })
+                    .await
+            })
+        };
+        let leader = call("random");
+        while cache.inflight.get("random").is_none() {
+            tokio::task::yield_now().await;
+        }
+        let followers: Vec<_> = (0..4).map(|_| call("random")).collect();
+        while cache.inflight.get("random").unwrap().receiver_count() < 4 {
+            tokio::task::yield_now().await;
+        }
+
+        release.notify_one();
+        assert_eq!(leader.await.unwrap(), 7);
+        for follower in followers {
+            assert_eq!(follower.await.unwrap(), 7);
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert!(cache.inflight.is_empty());
+
+        // a different request, or the same one afterwards, does its own work
+        let other = call("other");
+        while runs.load(Ordering::SeqCst) < 2 {
+            tokio::task::yield_now().await;
+        }
+        release.notify_one();
+        assert_eq!(other.await.unwrap(), 7);
+    }
+}
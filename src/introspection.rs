@@ -0,0 +1,321 @@
+use crate::auth::KeyValidator;
+use crate::error::ImageError;
+use crate::limiter::ApiKeyRateLimiter;
+use crate::models::{ApiKey, ApiKeyScope};
+use futures_util::future::BoxFuture;
+use moka::future::Cache;
+use moka::Expiry;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+use tracing::warn;
+
+const INTROSPECTION_TIMEOUT: Duration = Duration::from_secs(5);
+const CACHE_CAPACITY: u64 = 10_000;
+/// Longest a token the provider didn't accept is remembered, so one it
+/// issues or reactivates soon after works without a long wait
+const NEGATIVE_TTL: Duration = Duration::from_secs(10);
+
+/// The parts of an RFC 7662 introspection response that map onto an
+/// `ApiKey`. `rate_limit` and `max_batch_size` are custom claims; `scope`
+/// values other than ours are ignored.
+#[derive(Debug, Deserialize)]
+struct IntrospectionResponse {
+    active: bool,
+    username: Option<String>,
+    sub: Option<String>,
+    scope: Option<String>,
+    rate_limit: Option<u32>,
+    max_batch_size: Option<u32>,
+    iat: Option<i64>,
+    exp: Option<i64>,
+}
+
+impl IntrospectionResponse {
+    /// `None` for inactive tokens and ones naming no subject. Usernames get
+    /// an `idp:` prefix so they can't pass for a local key's user, and a
+    /// token without a `rate_limit` claim gets `default_rate_limit`.
+    fn into_api_key(self, token: &str, default_rate_limit: u32) -> Option<ApiKey> {
+        if !self.active {
+            return None;
+        }
+        let subject = self.sub.or(self.username)?;
+        let subject = subject.trim();
+        if subject.is_empty() {
+            warn!("Introspection accepted a token without a subject");
+            return None;
+        }
+        let scopes = self
+            .scope
+            .as_deref()
+            .unwrap_or_default()
+            .split_whitespace()
+            .filter_map(ApiKeyScope::parse)
+            .collect();
+        Some(ApiKey {
+            key: token.to_string(),
+            username: format!("idp:{}", subject),
+            created_at: self
+                .iat
+                .and_then(|iat| OffsetDateTime::from_unix_timestamp(iat).ok())
+                .unwrap_or_else(OffsetDateTime::now_utc),
+            last_used_at: None,
+            is_active: true,
+            requests_per_second: Some(self.rate_limit.unwrap_or(default_rate_limit)),
+            max_batch_size: self.max_batch_size,
+            default_filters: None,
+            scopes,
+            url_ingest_daily_bytes: None,
+            allowed_tags: Vec::new(),
+            is_admin: false,
+        })
+    }
+}
+
+/// An introspection answer and how long it may be reused.
+#[derive(Clone)]
+struct Answer {
+    api_key: Option<ApiKey>,
+    ttl: Duration,
+}
+
+/// Expires each answer after the TTL it was inserted with.
+struct PerAnswerTtl;
+
+impl Expiry<String, Answer> for PerAnswerTtl {
+    fn expire_after_create(
+        &self,
+        _key: &String,
+        value: &Answer,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        Some(value.ttl)
+    }
+}
+
+/// Validates bearer tokens issued by an identity provider against its
+/// `AUTH_INTROSPECTION_URL`. Accepted tokens are reused for
+/// `AUTH_INTROSPECTION_CACHE_SECS`, but not past their `exp`; rejected ones
+/// for at most `NEGATIVE_TTL`. Failed calls are not cached. A token already
+/// over the default rate limit isn't sent to the provider at all.
+pub struct Introspection {
+    client: reqwest::Client,
+    url: String,
+    credentials: Option<(String, String)>,
+    ttl: Duration,
+    cache: Cache<String, Answer>,
+    rate_limiter: ApiKeyRateLimiter,
+}
+
+impl Introspection {
+    pub fn new(
+        url: String,
+        credentials: Option<(String, String)>,
+        ttl: Duration,
+        rate_limiter: ApiKeyRateLimiter,
+    ) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(INTROSPECTION_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        Self {
+            client,
+            url,
+            credentials,
+            ttl,
+            cache: Cache::builder()
+                .max_capacity(CACHE_CAPACITY)
+                .expire_after(PerAnswerTtl)
+                .build(),
+            rate_limiter,
+        }
+    }
+
+    async fn introspect(&self, token: &str) -> anyhow::Result<Answer> {
+        let mut request = self
+            .client
+            .post(&self.url)
+            .form(&[("token", token), ("token_type_hint", "access_token")]);
+        if let Some((client_id, client_secret)) = &self.credentials {
+            request = request.basic_auth(client_id, Some(client_secret));
+        }
+        let response = request.send().await?.error_for_status()?;
+        let body = response.bytes().await?;
+        let response: IntrospectionResponse = serde_json::from_slice(&body)?;
+
+        let until_exp = response.exp.map(|exp| {
+            let left = exp - OffsetDateTime::now_utc().unix_timestamp();
+            Duration::from_secs(left.max(0) as u64)
+        });
+        let api_key = response.into_api_key(token, self.rate_limiter.default_limit());
+        let ttl = match (&api_key, until_exp) {
+            (None, _) => self.ttl.min(NEGATIVE_TTL),
+            (Some(_), Some(until_exp)) => self.ttl.min(until_exp),
+            (Some(_), None) => self.ttl,
+        };
+        Ok(Answer { api_key, ttl })
+    }
+}
+
+impl KeyValidator for Introspection {
+    fn validate<'a>(&'a self, token: &'a str) -> BoxFuture<'a, Result<Option<ApiKey>, ImageError>> {
+        Box::pin(async move {
+            if let Some(answer) = self.cache.get(token).await {
+                return Ok(answer.api_key);
+            }
+            // an unknown token is held to the default limit, so one that
+            // keeps failing can't turn every request into a remote call
+            if !self.rate_limiter.within_default_limit(token).await {
+                return Err(ImageError::RateLimitExceeded);
+            }
+            match self.introspect(token).await {
+                Ok(answer) => {
+                    let api_key = answer.api_key.clone();
+                    if !answer.ttl.is_zero() {
+                        self.cache.insert(token.to_string(), answer).await;
+                    }
+                    Ok(api_key)
+                }
+                Err(e) => {
+                    warn!(error = %e, "Token introspection failed");
+                    Err(ImageError::AuthUnavailable)
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_store;
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+    use warp::Filter;
+
+    /// An introspection endpoint on localhost answering `answer(token)`,
+    /// and the number of calls it has had.
+    fn provider(answer: fn(&str) -> Value) -> (String, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let route =
+            warp::post()
+                .and(warp::body::form())
+                .map(move |form: HashMap<String, String>| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    warp::reply::json(&answer(&form["token"]))
+                });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}/introspect", addr), calls)
+    }
+
+    /// Default limit of 2 requests per minute.
+    fn introspection(url: String, ttl: Duration) -> (TempDir, ApiKeyRateLimiter, Introspection) {
+        let (dir, store) = temp_store();
+        let limiter = ApiKeyRateLimiter::new(store, 2, time::Duration::minutes(1), 0);
+        let introspection = Introspection::new(url, None, ttl, limiter.clone());
+        (dir, limiter, introspection)
+    }
+
+    fn answer(token: &str) -> Value {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        match token {
+            "full" => json!({
+                "active": true,
+                "sub": "alice",
+                "username": "Alice",
+                "scope": "openid upload",
+                "rate_limit": 7,
+                "max_batch_size": 3,
+            }),
+            "bare" => json!({ "active": true, "username": "bob" }),
+            "no-subject" => json!({ "active": true, "sub": " " }),
+            "expiring" => json!({ "active": true, "sub": "carol", "exp": now + 2 }),
+            _ => json!({ "active": false }),
+        }
+    }
+
+    #[tokio::test]
+    async fn claims_map_onto_a_prefixed_key() {
+        let (url, _calls) = provider(answer);
+        let (_dir, _limiter, introspection) = introspection(url, Duration::from_secs(60));
+
+        let full = introspection.validate("full").await.unwrap().unwrap();
+        assert_eq!(full.username, "idp:alice");
+        assert_eq!(full.scopes, vec![ApiKeyScope::Upload]);
+        assert_eq!(full.requests_per_second, Some(7));
+        assert_eq!(full.max_batch_size, Some(3));
+        assert!(full.is_active && !full.is_admin);
+
+        let bare = introspection.validate("bare").await.unwrap().unwrap();
+        assert_eq!(bare.username, "idp:bob");
+        assert!(bare.scopes.is_empty());
+        // no claim means the default limit, not unlimited
+        assert_eq!(bare.requests_per_second, Some(2));
+
+        assert!(introspection
+            .validate("no-subject")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(introspection.validate("revoked").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn answers_are_cached_until_exp() {
+        let (url, calls) = provider(answer);
+        let (_dir, _limiter, introspection) = introspection(url, Duration::from_secs(60));
+
+        for _ in 0..3 {
+            introspection.validate("full").await.unwrap();
+            introspection.validate("revoked").await.unwrap();
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        introspection.validate("expiring").await.unwrap();
+        introspection.validate("expiring").await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        tokio::time::sleep(Duration::from_millis(3100)).await;
+        introspection.validate("expiring").await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn rejected_tokens_are_cached_briefly() {
+        let (url, calls) = provider(answer);
+        let (_dir, _limiter, introspection) = introspection(url, Duration::from_secs(3600));
+
+        introspection.validate("revoked").await.unwrap();
+        let answer = introspection.cache.get("revoked").await.unwrap();
+        assert!(answer.api_key.is_none());
+        assert_eq!(answer.ttl, NEGATIVE_TTL);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn tokens_over_the_default_limit_are_not_introspected() {
+        let (url, calls) = provider(answer);
+        let (_dir, limiter, introspection) = introspection(url, Duration::from_secs(60));
+
+        for _ in 0..2 {
+            limiter.check_rate_limit("spam", None).await;
+        }
+        assert!(matches!(
+            introspection.validate("spam").await,
+            Err(ImageError::RateLimitExceeded)
+        ));
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        // a cached answer is still served
+        introspection.validate("full").await.unwrap();
+        for _ in 0..2 {
+            limiter.check_rate_limit("full", None).await;
+        }
+        assert!(introspection.validate("full").await.unwrap().is_some());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,64 +1,266 @@
+use crate::error::ImageError;
+use crate::models::ApiKey;
 use crate::store::ImageStore;
-use std::collections::HashMap;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use time::{Duration, OffsetDateTime};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
 use tracing::{debug, warn};
 
+/// Largest `RATE_LIMIT_DEBUG_HISTORY` accepted, bounding memory per key.
+pub const MAX_DEBUG_HISTORY: usize = 1000;
+
+/// One recorded outcome of `check_rate_limit`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RateLimitDecision {
+    #[serde(with = "time::serde::rfc3339")]
+    pub timestamp: OffsetDateTime,
+    pub allowed: bool,
+    /// Requests left in the window after this one, `None` for unlimited keys
+    pub remaining: Option<u32>,
+}
+
+/// What the limiter knows about one key, for diagnosing throttling.
+#[derive(Debug, Serialize)]
+pub struct RateLimitDebug {
+    /// Requests allowed per window, `None` for unlimited keys
+    pub limit: Option<u32>,
+    pub window_secs: i64,
+    /// Decisions kept per key, 0 when recording is disabled
+    pub history_size: usize,
+    /// Requests counted in the current window
+    pub requests_in_window: usize,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub oldest_in_window: Option<OffsetDateTime>,
+    /// Oldest first
+    pub decisions: Vec<RateLimitDecision>,
+}
+
+/// One key's current window in a `snapshot`.
+#[derive(Debug, Serialize)]
+pub struct RateLimitWindow {
+    pub username: String,
+    pub limit: u32,
+    pub requests_in_window: usize,
+    /// Requests the key may still make before the oldest one leaves the window
+    pub remaining: u32,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub oldest_in_window: Option<OffsetDateTime>,
+}
+
+/// Every key the limiter is currently counting requests for.
+#[derive(Debug, Serialize)]
+pub struct RateLimitSnapshot {
+    pub window_secs: i64,
+    /// Most requested first
+    pub keys: Vec<RateLimitWindow>,
+    /// Keys with requests in the window that no longer resolve to a user
+    pub unknown_keys: usize,
+}
+
 #[derive(Clone)]
 pub struct ApiKeyRateLimiter {
     requests: Arc<Mutex<HashMap<String, Vec<OffsetDateTime>>>>,
+    decisions: Arc<Mutex<HashMap<String, VecDeque<RateLimitDecision>>>>,
     store: ImageStore,
     default_max_requests: u32,
     window_size: Duration,
+    history_size: usize,
 }
 
 impl ApiKeyRateLimiter {
-    pub fn new(store: ImageStore, default_max_requests: u32, window_size: Duration) -> Self {
+    /// `history_size` decisions are kept per key for `debug`; 0 records none.
+    pub fn new(
+        store: ImageStore,
+        default_max_requests: u32,
+        window_size: Duration,
+        history_size: usize,
+    ) -> Self {
         Self {
             requests: Arc::new(Mutex::new(HashMap::new())),
+            decisions: Arc::new(Mutex::new(HashMap::new())),
             store,
             default_max_requests,
             window_size,
+            history_size: history_size.min(MAX_DEBUG_HISTORY),
         }
     }
 
-    pub async fn check_rate_limit(&self, api_key: &str) -> bool {
-        let rate_limit = match self.store.get_api_key(api_key) {
-            Ok(key_info) => {
-                if key_info.requests_per_second.is_none() {
+    /// The limit of keys that don't set their own.
+    pub fn default_limit(&self) -> u32 {
+        self.default_max_requests
+    }
+
+    /// Whether `api_key` may make another request under the default limit,
+    /// without counting one. Lets a key be turned away before the work of
+    /// resolving it.
+    pub async fn within_default_limit(&self, api_key: &str) -> bool {
+        let window_start = OffsetDateTime::now_utc() - self.window_size;
+        let in_window = self.requests.lock().await.get(api_key).map_or(0, |times| {
+            times.iter().filter(|&&time| time > window_start).count()
+        });
+        in_window < self.default_max_requests as usize
+    }
+
+    /// `key_info` is what the key resolved to, `None` for unknown keys,
+    /// which get the default limit.
+    pub async fn check_rate_limit(&self, api_key: &str, key_info: Option<&ApiKey>) -> bool {
+        let now = OffsetDateTime::now_utc();
+        // unknown keys are about to be rejected anyway, so only real keys
+        // get a decision history
+        let (rate_limit, known_key) = match key_info {
+            Some(key_info) => match key_info.requests_per_second {
+                Some(limit) => (limit, true),
+                None => {
                     debug!("API key has unlimited rate limit");
+                    self.record(api_key, now, true, None).await;
                     return true;
                 }
-                key_info
-                    .requests_per_second
-                    .unwrap_or(self.default_max_requests)
-            }
-            Err(e) => {
-                warn!("Failed to get API key info: {}, using default limit", e);
-                self.default_max_requests
+            },
+            None => {
+                warn!("Unknown API key, using default limit");
+                (self.default_max_requests, false)
             }
         };
 
-        let now = OffsetDateTime::now_utc();
         let window_start = now - self.window_size;
 
-        let mut requests = self.requests.lock().await;
-        let request_times = requests.entry(api_key.to_string()).or_default();
-        request_times.retain(|&time| time > window_start);
+        let (allowed, remaining) = {
+            let mut requests = self.requests.lock().await;
+            let request_times = requests.entry(api_key.to_string()).or_default();
+            request_times.retain(|&time| time > window_start);
 
-        if request_times.len() >= rate_limit as usize {
-            warn!(
-                "Rate limit exceeded: {}/{} requests in {:?}",
-                request_times.len(),
-                rate_limit,
-                self.window_size
-            );
-            return false;
+            if request_times.len() >= rate_limit as usize {
+                warn!(
+                    "Rate limit exceeded: {}/{} requests in {:?}",
+                    request_times.len(),
+                    rate_limit,
+                    self.window_size
+                );
+                (false, 0)
+            } else {
+                request_times.push(now);
+                (true, rate_limit - request_times.len() as u32)
+            }
+        };
+
+        if known_key {
+            self.record(api_key, now, allowed, Some(remaining)).await;
         }
+        allowed
+    }
 
-        request_times.push(now);
-        true
+    async fn record(
+        &self,
+        api_key: &str,
+        timestamp: OffsetDateTime,
+        allowed: bool,
+        remaining: Option<u32>,
+    ) {
+        if self.history_size == 0 {
+            return;
+        }
+        let mut decisions = self.decisions.lock().await;
+        let history = decisions.entry(api_key.to_string()).or_default();
+        if history.len() >= self.history_size {
+            history.pop_front();
+        }
+        history.push_back(RateLimitDecision {
+            timestamp,
+            allowed,
+            remaining,
+        });
+    }
+
+    /// The recorded decisions and current window for a key whose
+    /// configured limit is `limit`.
+    pub async fn debug(&self, api_key: &str, limit: Option<u32>) -> RateLimitDebug {
+        let window_start = OffsetDateTime::now_utc() - self.window_size;
+        let in_window: Vec<OffsetDateTime> = self
+            .requests
+            .lock()
+            .await
+            .get(api_key)
+            .map(|times| {
+                times
+                    .iter()
+                    .copied()
+                    .filter(|&time| time > window_start)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let decisions = self
+            .decisions
+            .lock()
+            .await
+            .get(api_key)
+            .map(|history| history.iter().cloned().collect())
+            .unwrap_or_default();
+
+        RateLimitDebug {
+            limit,
+            window_secs: self.window_size.whole_seconds(),
+            history_size: self.history_size,
+            requests_in_window: in_window.len(),
+            oldest_in_window: in_window.iter().min().copied(),
+            decisions,
+        }
+    }
+
+    /// The current window of every key with recent requests. Keys whose
+    /// requests have all left the window are dropped first.
+    pub async fn snapshot(&self) -> RateLimitSnapshot {
+        let window_start = OffsetDateTime::now_utc() - self.window_size;
+        let windows: Vec<(String, Vec<OffsetDateTime>)> = {
+            let mut requests = self.requests.lock().await;
+            requests.retain(|_, times| {
+                times.retain(|&time| time > window_start);
+                !times.is_empty()
+            });
+            requests
+                .iter()
+                .map(|(api_key, times)| (api_key.clone(), times.clone()))
+                .collect()
+        };
+
+        // looked up after unlocking so requests aren't held up on the store
+        let mut keys = Vec::with_capacity(windows.len());
+        let mut unknown_keys = 0;
+        for (api_key, times) in windows {
+            let Ok(key_info) = self.store.get_api_key(&api_key) else {
+                unknown_keys += 1;
+                continue;
+            };
+            let limit = key_info
+                .requests_per_second
+                .unwrap_or(self.default_max_requests);
+            keys.push(RateLimitWindow {
+                username: key_info.username,
+                limit,
+                requests_in_window: times.len(),
+                remaining: limit.saturating_sub(times.len() as u32),
+                oldest_in_window: times.iter().min().copied(),
+            });
+        }
+        keys.sort_by(|a, b| {
+            b.requests_in_window
+                .cmp(&a.requests_in_window)
+                .then_with(|| a.username.cmp(&b.username))
+        });
+
+        RateLimitSnapshot {
+            window_secs: self.window_size.whole_seconds(),
+            keys,
+            unknown_keys,
+        }
+    }
+
+    /// Forgets the recent requests recorded for a key so it is no longer
+    /// throttled. Returns whether the key had any tracked requests.
+    pub async fn reset(&self, api_key: &str) -> bool {
+        self.requests.lock().await.remove(api_key).is_some()
     }
 }
 
@@ -67,3 +269,164 @@ impl Default for ApiKeyRateLimiter {
         unimplemented!("ApiKeyRateLimiter requires store instance")
     }
 }
+
+/// Bounds how many uploads may decode and write at once. Requests that would
+/// have to wait behind a full queue are turned away instead of piling up.
+#[derive(Clone)]
+pub struct UploadGate {
+    semaphore: Arc<Semaphore>,
+    waiting: Arc<AtomicUsize>,
+    max_permits: usize,
+    max_queue_depth: usize,
+}
+
+/// One upload's place in the `UploadGate` queue.
+struct QueuedGuard<'a>(&'a AtomicUsize);
+
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct UploadGateStats {
+    pub permits_total: usize,
+    pub permits_in_use: usize,
+    pub queue_depth: usize,
+    pub max_queue_depth: usize,
+}
+
+impl UploadGate {
+    pub fn new(max_permits: usize, max_queue_depth: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max_permits)),
+            waiting: Arc::new(AtomicUsize::new(0)),
+            max_permits,
+            max_queue_depth,
+        }
+    }
+
+    pub async fn acquire(&self) -> Result<OwnedSemaphorePermit, ImageError> {
+        if let Ok(permit) = self.semaphore.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+
+        let queued = self.waiting.fetch_add(1, Ordering::SeqCst);
+        if queued >= self.max_queue_depth {
+            self.waiting.fetch_sub(1, Ordering::SeqCst);
+            warn!(
+                "Upload queue full: {}/{} waiting",
+                queued, self.max_queue_depth
+            );
+            return Err(ImageError::UploadBusy);
+        }
+
+        // leaves the queue even if the upload is dropped while waiting
+        let _queued = QueuedGuard(&self.waiting);
+        self.semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|_| ImageError::UploadBusy)
+    }
+
+    pub fn stats(&self) -> UploadGateStats {
+        UploadGateStats {
+            permits_total: self.max_permits,
+            permits_in_use: self.max_permits - self.semaphore.available_permits(),
+            queue_depth: self.waiting.load(Ordering::SeqCst),
+            max_queue_depth: self.max_queue_depth,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{add_png, temp_store};
+    use std::time::Duration as StdDuration;
+
+    /// Stands in for an upload whose decode takes `decode` to finish.
+    async fn slow_upload(gate: UploadGate, decode: StdDuration) -> Result<(), ImageError> {
+        let _permit = gate.acquire().await?;
+        tokio::time::sleep(decode).await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_uploads_past_the_queue_depth() {
+        let gate = UploadGate::new(2, 3);
+        let uploads: Vec<_> = (0..8)
+            .map(|_| tokio::spawn(slow_upload(gate.clone(), StdDuration::from_millis(200))))
+            .collect();
+        tokio::time::sleep(StdDuration::from_millis(50)).await;
+
+        let stats = gate.stats();
+        assert_eq!(stats.permits_in_use, 2);
+        assert_eq!(stats.queue_depth, 3);
+
+        let mut busy = 0;
+        for upload in uploads {
+            if let Err(ImageError::UploadBusy) = upload.await.unwrap() {
+                busy += 1;
+            }
+        }
+        assert_eq!(busy, 3);
+        assert_eq!(gate.stats().permits_in_use, 0);
+        assert_eq!(gate.stats().queue_depth, 0);
+    }
+
+    #[tokio::test]
+    async fn queued_uploads_run_once_a_permit_frees() {
+        let gate = UploadGate::new(1, 1);
+        let first = tokio::spawn(slow_upload(gate.clone(), StdDuration::from_millis(100)));
+        tokio::time::sleep(StdDuration::from_millis(20)).await;
+        let queued = tokio::spawn(slow_upload(gate.clone(), StdDuration::ZERO));
+
+        assert!(first.await.unwrap().is_ok());
+        assert!(queued.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn dropped_queued_uploads_leave_the_queue() {
+        let gate = UploadGate::new(1, 2);
+        let holder = gate.acquire().await.unwrap();
+        for _ in 0..5 {
+            let queued = tokio::spawn(slow_upload(gate.clone(), StdDuration::ZERO));
+            tokio::time::sleep(StdDuration::from_millis(20)).await;
+            assert_eq!(gate.stats().queue_depth, 1);
+            queued.abort();
+            assert!(queued.await.unwrap_err().is_cancelled());
+            assert_eq!(gate.stats().queue_depth, 0);
+        }
+
+        // the queue still has room once the permit frees
+        let queued = tokio::spawn(slow_upload(gate.clone(), StdDuration::ZERO));
+        tokio::time::sleep(StdDuration::from_millis(20)).await;
+        drop(holder);
+        assert!(queued.await.unwrap().is_ok());
+        assert_eq!(gate.stats().queue_depth, 0);
+    }
+
+    #[tokio::test]
+    async fn reads_stay_fast_while_uploads_hold_every_permit() {
+        let (_dir, store) = temp_store();
+        let hash = add_png(&store, 1).await;
+        let gate = UploadGate::new(1, 4);
+        let uploads: Vec<_> = (0..4)
+            .map(|_| tokio::spawn(slow_upload(gate.clone(), StdDuration::from_millis(500))))
+            .collect();
+        tokio::time::sleep(StdDuration::from_millis(20)).await;
+
+        let started = std::time::Instant::now();
+        assert!(store
+            .get_image_by_filename(&format!("{}.png", hash))
+            .is_ok());
+        assert!(started.elapsed() < StdDuration::from_millis(200));
+
+        for upload in uploads {
+            upload.abort();
+        }
+    }
+}
@@ -1,47 +1,109 @@
 mod auth;
+mod byte_size;
 mod cache;
+mod canonical;
+mod change_log;
 mod config;
+mod cors;
+mod derived_cache;
+#[cfg(feature = "devtools")]
+mod devtools;
 mod error;
+mod exif;
 mod handlers;
+mod heic;
+mod host_throttle;
+mod idempotency;
+mod import_dir;
+mod inflight;
+mod introspection;
 mod limiter;
+mod messages;
 mod middleware;
+mod migrations;
 mod models;
+mod phash;
+mod placeholder;
+mod presign;
+mod query_log;
+mod quota;
+mod shutdown;
+mod sidecar;
+mod storage_health;
 mod store;
+mod tag_ttl;
+mod temp_files;
+#[cfg(test)]
+mod test_support;
+mod url_guard;
+mod versioning;
+mod webhooks;
 
 use crate::cache::ImageCache;
-use crate::limiter::ApiKeyRateLimiter;
-use crate::models::{AddImageRequest, GenerateApiKeyRequest, RemoveApiKeyRequest};
+use crate::idempotency::Idempotency;
+use crate::inflight::InFlightCache;
+use crate::limiter::{ApiKeyRateLimiter, UploadGate};
+use crate::models::{
+    AddImageRequest, ApiKey, ApiKeyScope, AutocompleteQuery, ChangedSinceQuery, ConvertQuery,
+    CopyTagsFromQuery, ExportQuery, GenerateApiKeyRequest, ImageKey, InlineQuery,
+    RemoveApiKeyRequest, RequestLimits, SizeDistributionQuery, SlowQueriesQuery, SyncChangesQuery,
+    TagListQuery,
+};
+use crate::storage_health::StorageHealth;
 use crate::store::ImageStore;
 use anyhow::Result;
-use auth::Auth;
-use chrono;
-use middleware::{add_request_id_header, with_request_id};
-use serde_json;
+use auth::{Auth, KeyValidator, LocalKeys};
+use middleware::{
+    add_request_id_header, dry_run, feature_flags, json_body, no_dry_run, query_params,
+    reply_to_hotlinks, track_storage_writes, with_allowed_referer, with_body_logging,
+    with_concurrency_limit, with_idempotency, with_public_base_url, with_request_id,
+    with_slow_request_warning, with_writable_storage,
+};
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::Arc;
 use time::macros::format_description;
 use time::Duration;
-use tracing::info;
-use warp::cors::Cors;
+use tokio::sync::Semaphore;
+use tracing::{info, warn};
 use warp::http::HeaderMap;
+use warp::http::Method;
 use warp::multipart::form;
-use warp::Filter;
+use warp::{Filter, Reply};
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    #[cfg(feature = "devtools")]
+    if std::env::args().nth(1).as_deref() == Some("devtools") {
+        return devtools::run(std::env::args().skip(1));
+    }
+    if std::env::args().nth(1).as_deref() == Some("import-dir") {
+        return import_dir::run(std::env::args().skip(1)).await;
+    }
+
+    let config = config::Config::from_env()?;
+
+    let log_filter = if config.log_request_bodies {
+        "waifu=debug,waifu::middleware=trace,warp=info"
+    } else {
+        "waifu=debug,warp=info"
+    };
     tracing_subscriber::fmt()
-        .with_env_filter("waifu=debug,warp=info")
+        .with_env_filter(log_filter)
         .with_timer(tracing_subscriber::fmt::time::LocalTime::new(
             format_description!("[year]-[month]-[day] [hour]:[minute]:[second]"),
         ))
         .init();
 
     info!("Starting waifu server...");
-
-    let config = config::Config::from_env()?;
+    config.warn_on_local_base_url();
 
     let images_dir = PathBuf::from("images");
 
+    let messages = Arc::new(messages::MessageCatalog::load(
+        config.error_messages_file.as_deref(),
+    )?);
+
     info!("Initializing image store...");
     let store = store::ImageStore::new("images.db", images_dir.clone(), &config)?;
 
@@ -49,191 +111,941 @@ async fn main() -> Result<()> {
         store.clone(),
         config.rate_limit_requests,
         Duration::seconds(config.rate_limit_window_secs as i64),
+        config.rate_limit_debug_history,
+    );
+
+    let cache = ImageCache::new(
+        config.cache_size,
+        config.cache_ttl(),
+        config.random_cache_ttl(),
     );
 
-    let cache = ImageCache::new(config.cache_size, config.cache_ttl());
+    let request_limiter = Arc::new(Semaphore::new(config.max_concurrent_requests));
+    let draining = request_limiter.clone();
+
+    let upload_gate = UploadGate::new(config.upload_concurrency(), config.upload_queue_depth);
+
+    let dedup = if config.enable_request_dedup {
+        info!("Request deduplication enabled for /random");
+        Some(InFlightCache::new())
+    } else {
+        None
+    };
+
+    let storage_health = StorageHealth::new(
+        config.storage_failure_threshold,
+        config.storage_failure_window(),
+        config.storage_alert_webhook.clone(),
+    );
+    storage_health.spawn_recovery_probe(store.clone(), config.storage_probe_interval());
 
-    let auth = Auth::new(config.admin_key, store.clone(), rate_limiter);
+    let idempotency = Idempotency::new(store.clone(), config.idempotency_ttl());
+    idempotency.spawn_sweeper();
+
+    change_log::spawn_pruner(store.clone(), config.change_log_retention());
+
+    // nothing is downloading yet, so every temp file is a leftover
+    match temp_files::sweep(&images_dir, std::time::Duration::ZERO) {
+        Ok(0) => {}
+        Ok(removed) => info!("Removed {} leftover temp files", removed),
+        Err(e) => warn!("Failed to sweep temp files: {}", e),
+    }
+    temp_files::spawn_janitor(
+        images_dir.clone(),
+        config.temp_sweep_interval(),
+        store::DOWNLOAD_TIMEOUT * 2,
+    );
+
+    derived_cache::spawn_evictor(store.clone(), config.derived_cache_sweep_interval());
+
+    let presigner = presign::Presigner::new(&config.presign_secret(), config.presign_max_ttl());
+    let webhooks = webhooks::KeyWebhooks::new()?;
+    let placeholders = placeholder::Placeholders::new(config.fallback_placeholder);
+    tag_ttl::spawn_reaper(
+        store.clone(),
+        cache.clone(),
+        webhooks.clone(),
+        config.tag_ttls(),
+        config.tag_ttl_sweep_interval(),
+    );
+
+    let mut validators: Vec<Box<dyn KeyValidator>> = vec![Box::new(LocalKeys::new(store.clone()))];
+    if let Some(url) = &config.auth_introspection_url {
+        info!("Validating unknown bearer tokens against {}", url);
+        validators.push(Box::new(introspection::Introspection::new(
+            url.clone(),
+            config.auth_introspection_credentials(),
+            config.auth_introspection_cache_ttl(),
+            rate_limiter.clone(),
+        )));
+    }
+    let auth = Auth::new(
+        config.admin_key.clone(),
+        store.clone(),
+        rate_limiter.clone(),
+        validators,
+    );
 
     let store = warp::any().map(move || store.clone());
     let cache = warp::any().map(move || cache.clone());
-
-    fn cors() -> Cors {
-        warp::cors()
-            .allow_any_origin()
-            .allow_headers(vec![
-                "Authorization",
-                "Content-Type",
-                "User-Agent",
-                "Sec-Fetch-Mode",
-                "Referer",
-                "Origin",
-                "Access-Control-Request-Method",
-                "Access-Control-Request-Headers",
-            ])
-            .allow_methods(vec!["GET", "POST", "DELETE"])
-            .max_age(3600)
-            .build()
+    let dedup = warp::any().map(move || dedup.clone());
+    let upload_gate = warp::any().map(move || upload_gate.clone());
+    let rate_limiter = warp::any().map(move || rate_limiter.clone());
+    let webhooks = warp::any().map(move || webhooks.clone());
+    let placeholders = warp::any().map(move || placeholders.clone());
+    let writable = with_writable_storage(storage_health.clone());
+    let public_url = with_public_base_url(
+        config
+            .public_url_from_headers
+            .then(|| Arc::new(config.public_hosts.clone())),
+    );
+    let log_bodies = config.log_request_bodies;
+    if log_bodies {
+        warn!("Request body logging is enabled, bodies are logged at TRACE level");
     }
 
-    let health = warp::path("health").and(warp::get()).map(|| {
-        warp::reply::json(&serde_json::json!({
-            "status": "ok",
-            "timestamp": chrono::Utc::now().to_rfc3339()
-        }))
+    let read_cors = cors::policy(&config.cors_origins, &[Method::GET]);
+    let write_cors = (!config.cors_write_origins.is_empty()).then(|| {
+        cors::policy(
+            &config.cors_write_origins,
+            &[Method::POST, Method::PUT, Method::DELETE],
+        )
     });
 
+    let health_state = storage_health.clone();
+    let health = warp::path("health").and(
+        warp::get()
+            .map(move || {
+                warp::reply::json(&serde_json::json!({
+                    "status": "ok",
+                    "read_only": health_state.is_degraded(),
+                    "timestamp": chrono::Utc::now().to_rfc3339()
+                }))
+            })
+            .with(read_cors.clone()),
+    );
+
+    let limits = config.request_limits();
+    let limits = warp::any().map(move || limits);
+    let info = warp::path::end()
+        .and(cors::get())
+        .and(store.clone())
+        .and(limits)
+        .and_then(handlers::info_handler);
+
+    let strict_query_params = config.strict_query_params;
     let random_get = warp::path("random")
-        .and(warp::get())
+        .and(cors::get())
         .and(store.clone())
         .and(cache.clone())
-        .and(warp::query::<std::collections::HashMap<String, String>>())
-        .and(warp::filters::header::headers_cloned())
+        .and(dedup.clone())
+        .and(
+            warp::query::<std::collections::HashMap<String, String>>()
+                .and(warp::any().map(move || strict_query_params))
+                .and_then(handlers::check_random_params),
+        )
+        .and(public_url.clone())
+        .and(limits)
         .and(auth.require_auth_info())
+        .and(feature_flags())
         .and_then(handlers::get_random_image_handler);
 
     let random_post = warp::path("random")
-        .and(warp::post())
+        .and(cors::post())
         .and(store.clone())
         .and(cache.clone())
-        .and(warp::filters::header::headers_cloned())
+        .and(feature_flags())
         .and(auth.require_auth_info())
-        .and(warp::body::json())
+        .and(json_body(log_bodies))
+        .and(public_url.clone())
+        .and(limits)
         .and_then(handlers::batch_random_images_handler);
 
     let add_image = warp::path("image")
-        .and(warp::post())
-        .and(warp::body::json())
+        .and(cors::post())
+        .and(writable.clone())
+        .and(json_body(log_bodies))
         .and(store.clone())
-        .and(auth.require_auth())
-        .map(|body, store, ()| (store, body))
-        .and_then(|args: (ImageStore, AddImageRequest)| async move {
-            handlers::add_image_handler(args.0, args.1).await
+        .and(auth.require_scope(ApiKeyScope::Upload))
+        .map(|body, store, auth_info| (store, body, auth_info))
+        .and_then(|args: (ImageStore, AddImageRequest, ApiKey)| async move {
+            handlers::add_image_handler(args.0, args.1, args.2).await
         });
 
-    let batch_add_images = warp::path("images")
-        .and(warp::post())
+    let batch_add_images = warp::path!("images")
+        .and(cors::post())
+        .and(writable.clone())
         .and(store.clone())
-        .and(warp::body::json())
-        .and(auth.require_auth_info())
+        .and(upload_gate.clone())
+        .and(json_body(log_bodies))
+        .and(auth.require_scope(ApiKeyScope::Upload))
         .and_then(handlers::batch_add_images_handler);
 
+    let changed_since = warp::path!("images" / "changed-since")
+        .and(cors::get())
+        .and(warp::query::<ChangedSinceQuery>())
+        .and(store.clone())
+        .and(auth.require_auth_info())
+        .and_then(handlers::changed_since_handler);
+
+    let sync_changes = warp::path!("sync" / "changes")
+        .and(cors::get())
+        .and(warp::query::<SyncChangesQuery>())
+        .and(store.clone())
+        .and(auth.require_auth_info())
+        .and_then(handlers::sync_changes_handler);
+
+    let list_images = warp::path!("images")
+        .and(cors::get())
+        .and(warp::query::<std::collections::HashMap<String, String>>())
+        .and(store.clone())
+        .and(public_url.clone())
+        .and(limits)
+        .and(auth.require_auth_info())
+        .and_then(handlers::list_images_handler);
+
+    let search_images = warp::path!("search")
+        .and(cors::get())
+        .and(warp::query::<std::collections::HashMap<String, String>>())
+        .and(store.clone())
+        .and(public_url.clone())
+        .and(limits)
+        .and(auth.require_auth_info())
+        .and_then(handlers::search_images_handler);
+
+    let me = warp::path!("me")
+        .and(cors::get())
+        .and(auth.require_auth_info())
+        .and(store.clone())
+        .and_then(handlers::me_handler);
+
+    let get_my_webhook = warp::path!("me" / "webhook")
+        .and(cors::get())
+        .and(auth.require_auth_info())
+        .and(store.clone())
+        .and_then(handlers::get_my_webhook_handler);
+
+    let set_my_webhook = warp::path!("me" / "webhook")
+        .and(cors::post())
+        .and(writable.clone())
+        .and(store.clone())
+        .and(webhooks.clone())
+        .and(json_body(log_bodies))
+        .and(auth.require_auth_info())
+        .and_then(handlers::set_my_webhook_handler);
+
+    let remove_my_webhook = warp::path!("me" / "webhook")
+        .and(cors::delete())
+        .and(writable.clone())
+        .and(store.clone())
+        .and(auth.require_auth_info())
+        .and_then(handlers::remove_my_webhook_handler);
+
     let remove_image = warp::path!("images" / String)
         .and(warp::delete())
+        .and(writable.clone())
         .and(store.clone())
+        .and(webhooks.clone())
+        .and(dry_run())
         .and(auth.require_admin())
         .and_then(handlers::remove_image_handler);
 
     let remove_image_tags = warp::path!("images" / String / "tags")
         .and(warp::delete())
+        .and(writable.clone())
         .and(store.clone())
-        .and(warp::body::json())
+        .and(cache.clone())
+        .and(json_body(log_bodies))
+        .and(dry_run())
         .and(auth.require_admin())
         .and_then(handlers::remove_image_tags_handler);
 
     let add_image_tags = warp::path!("images" / String / "tags")
         .and(warp::post())
+        .and(writable.clone())
         .and(store.clone())
-        .and(warp::body::json())
+        .and(cache.clone())
+        .and(json_body(log_bodies))
         .and(auth.require_admin())
+        .and(no_dry_run())
         .and_then(handlers::add_image_tags_handler);
 
+    let replace_image = warp::path!("images" / String)
+        .and(warp::put())
+        .and(writable.clone())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::header::optional::<String>("if-match"))
+        .and(warp::body::content_length_limit(handlers::MAX_UPLOAD_BYTES))
+        .and(warp::body::bytes())
+        .and(store.clone())
+        .and(cache.clone())
+        .and(auth.require_admin())
+        .and(no_dry_run())
+        .and_then(handlers::replace_image_handler);
+
+    let refresh_image = warp::path!("images" / String / "refresh")
+        .and(warp::post())
+        .and(writable.clone())
+        .and(store.clone())
+        .and(cache.clone())
+        .and(auth.require_admin())
+        .and(no_dry_run())
+        .and_then(handlers::refresh_image_handler);
+
+    let copy_image_tags = warp::path!("images" / String / "copy-tags")
+        .and(warp::post())
+        .and(writable.clone())
+        .and(store.clone())
+        .and(cache.clone())
+        .and(json_body(log_bodies))
+        .and(auth.require_admin())
+        .and(no_dry_run())
+        .and_then(handlers::copy_image_tags_handler);
+
+    let copy_tags_from = warp::path!("images" / String / "copy-tags-from" / String)
+        .and(warp::post())
+        .and(writable.clone())
+        .and(store.clone())
+        .and(cache.clone())
+        .and(query_params::<CopyTagsFromQuery>())
+        .and(auth.require_admin())
+        .and(no_dry_run())
+        .and_then(handlers::copy_tags_from_handler);
+
+    let change_tags_by_hash = warp::path!("tags" / "by-hash")
+        .and(warp::post())
+        .and(writable.clone())
+        .and(store.clone())
+        .and(cache.clone())
+        .and(json_body(log_bodies))
+        .and(dry_run())
+        .and(auth.require_admin())
+        .and_then(handlers::change_tags_by_hash_handler);
+
+    let set_image_metadata = warp::path!("images" / String / "metadata")
+        .and(warp::post())
+        .and(writable.clone())
+        .and(store.clone())
+        .and(json_body(log_bodies))
+        .and(auth.require_admin())
+        .and(no_dry_run())
+        .and_then(handlers::set_image_metadata_handler);
+
+    let autocomplete_tags = warp::path!("tags" / "autocomplete")
+        .and(cors::get())
+        .and(warp::query::<AutocompleteQuery>())
+        .and(store.clone())
+        .and(auth.require_auth())
+        .and_then(handlers::autocomplete_tags_handler);
+
+    let tag_namespaces = warp::path!("tags" / "namespaces")
+        .and(cors::get())
+        .and(store.clone())
+        .and(auth.require_auth())
+        .and_then(handlers::tag_namespaces_handler);
+
     let get_all_tags = warp::path("tags")
-        .and(warp::get())
+        .and(cors::get())
+        .and(warp::query::<TagListQuery>())
         .and(store.clone())
         .and(auth.require_auth())
         .and_then(handlers::get_all_tags_handler);
 
-    let images = warp::path("images").and(warp::fs::dir("images"));
+    // sidecars carry metadata and temp files are half-written, so neither
+    // is served with the images
+    let file_query = warp::query::<ConvertQuery>()
+        .or(warp::any().map(ConvertQuery::default))
+        .unify();
+    // `?format=` requests are `converted_image`'s, so a bad format gets a
+    // 400 instead of falling through to the stored file
+    let stored_file = file_query
+        .and_then(|query: ConvertQuery| async move {
+            match query.format {
+                Some(_) => Err(warp::reject::not_found()),
+                None => Ok(()),
+            }
+        })
+        .untuple_one();
+    let referer_check = with_allowed_referer(
+        Arc::new(config.allowed_referers.clone()),
+        config.allow_empty_referer,
+    );
+    let images = warp::path("images")
+        .and(warp::path::peek())
+        .and(file_query)
+        .and_then(|peek: warp::path::Peek, query: ConvertQuery| async move {
+            // inline requests get the image JSON from the metadata route
+            if sidecar::is_sidecar(peek.as_str())
+                || temp_files::is_temp(peek.as_str())
+                || query.inline
+                || query.format.is_some()
+            {
+                Err(warp::reject::not_found())
+            } else {
+                Ok(())
+            }
+        })
+        .untuple_one()
+        .and(referer_check.clone())
+        .and(warp::fs::dir("images"))
+        .and(store.clone())
+        .and(placeholders.clone())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(warp::header::optional::<String>("if-modified-since"))
+        .and(warp::header::optional::<String>("want-digest"))
+        .and_then(handlers::tag_image_file);
+
+    // `?format=` re-encodes the image; without it the routes below serve
+    // the stored file as is
+    let converted_image = warp::path!("images" / String)
+        .map(|value| (value, ImageKey::Filename))
+        .or(warp::path!("images" / "h" / String).map(|value| (value, ImageKey::Hash)))
+        .unify()
+        .or(warp::path!("images" / "id" / String).map(|value| (value, ImageKey::Id)))
+        .unify()
+        .untuple_one()
+        .and(cors::get())
+        .and(file_query.and_then(|query: ConvertQuery| async move {
+            match query.format {
+                Some(format) if !query.inline => Ok(format),
+                _ => Err(warp::reject::not_found()),
+            }
+        }))
+        .and(referer_check.clone())
+        .and(store.clone())
+        .and(placeholders.clone())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(warp::header::optional::<String>("if-modified-since"))
+        .and(warp::header::optional::<String>("want-digest"))
+        .and_then(handlers::convert_image_handler);
+
+    let image_by_hash = warp::path!("images" / "h" / String)
+        .and(cors::get())
+        .and(stored_file)
+        .and(referer_check.clone())
+        .and(warp::any().map(|| ImageKey::Hash))
+        .and(store.clone())
+        .and(placeholders.clone())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(warp::header::optional::<String>("if-modified-since"))
+        .and(warp::header::optional::<String>("want-digest"))
+        .and_then(handlers::serve_image_handler);
+
+    let image_by_id = warp::path!("images" / "id" / String)
+        .and(cors::get())
+        .and(stored_file)
+        .and(referer_check.clone())
+        .and(warp::any().map(|| ImageKey::Id))
+        .and(store.clone())
+        .and(placeholders.clone())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(warp::header::optional::<String>("if-modified-since"))
+        .and(warp::header::optional::<String>("want-digest"))
+        .and_then(handlers::serve_image_handler);
 
     let image = warp::path!("images" / String)
-        .and(warp::get())
+        .and(cors::get())
         .and(store.clone())
         .and(cache.clone())
         .and(warp::filters::header::headers_cloned())
-        .and(auth.require_auth())
-        .map(|filename, store, cache, headers, ()| (filename, store, cache, headers))
+        .and(public_url.clone())
+        .and(auth.require_auth_info())
+        .and(warp::query::<InlineQuery>())
+        .and(limits)
+        .map(
+            |filename, store, cache, headers, base_url, auth_info, query, limits| {
+                (
+                    filename, store, cache, headers, base_url, query, limits, auth_info,
+                )
+            },
+        )
         .and_then(
-            |args: (String, ImageStore, ImageCache, HeaderMap)| async move {
-                handlers::get_image_by_filename_handler(args.0, args.1, args.2, args.3).await
+            |args: (
+                String,
+                ImageStore,
+                ImageCache,
+                HeaderMap,
+                Option<String>,
+                InlineQuery,
+                RequestLimits,
+                ApiKey,
+            )| async move {
+                handlers::get_image_by_filename_handler(
+                    args.0, args.1, args.2, args.3, args.4, args.5, args.6, args.7,
+                )
+                .await
             },
         );
 
-    let api_key_routes = warp::path("api-keys")
+    let image_full = warp::path!("images" / String / "full")
+        .and(cors::get())
+        .and(store.clone())
+        .and(public_url.clone())
+        .and(auth.require_auth_info())
+        .and_then(handlers::get_image_full_handler);
+
+    let image_exists = warp::path!("images" / String / "exists")
+        .and(cors::get())
+        .and(store.clone())
+        .and(auth.require_auth_info())
+        .and_then(handlers::image_exists_handler);
+
+    let exists_batch_max = config.exists_batch_max;
+    let images_exist = warp::path!("images" / "exists")
+        .and(cors::post())
+        .and(store.clone())
+        .and(warp::any().map(move || exists_batch_max))
+        .and(json_body(log_bodies))
+        .and(auth.require_auth_info())
+        .and_then(handlers::images_exist_handler);
+
+    let file_info = warp::path!("images" / String / "fileinfo")
+        .and(warp::get())
+        .and(store.clone())
+        .and(auth.require_admin())
+        .and_then(handlers::get_file_info_handler);
+
+    let generate_api_key = warp::path!("api-keys")
         .and(warp::post())
+        .and(writable.clone())
         .and(store.clone())
-        .and(warp::body::json())
+        .and(json_body(log_bodies))
         .and(auth.require_admin())
+        .and(no_dry_run())
         .map(|store, body, ()| ((), store, body))
         .and_then(|args: ((), ImageStore, GenerateApiKeyRequest)| async move {
-            handlers::generate_api_key_handler(args.0, args.1, args.2).await
-        })
-        .or(warp::path("api-keys")
-            .and(warp::delete())
-            .and(store.clone())
-            .and(warp::body::json())
-            .and(auth.require_admin())
-            .map(|store, body, ()| ((), store, body))
-            .and_then(|args: ((), ImageStore, RemoveApiKeyRequest)| async move {
-                handlers::remove_api_key_handler(args.0, args.1, args.2).await
-            }))
-        .or(warp::path("api-keys")
-            .and(warp::get())
-            .and(store.clone())
-            .and(auth.require_admin())
-            .map(|store, ()| ((), store))
-            .and_then(|args: ((), ImageStore)| async move {
-                handlers::list_api_keys_handler(args.0, args.1).await
-            }));
+            handlers::generate_api_key_handler((), args.1, args.2).await
+        });
+
+    let api_key_batch_max = config.api_key_batch_max;
+    let batch_generate_api_keys = warp::path!("api-keys" / "batch")
+        .and(warp::post())
+        .and(writable.clone())
+        .and(store.clone())
+        .and(warp::any().map(move || api_key_batch_max))
+        .and(json_body(log_bodies))
+        .and(auth.require_admin())
+        .and(no_dry_run())
+        .and_then(handlers::batch_generate_api_keys_handler);
+
+    let remove_api_key = warp::path!("api-keys")
+        .and(warp::delete())
+        .and(writable.clone())
+        .and(store.clone())
+        .and(json_body(log_bodies))
+        .and(dry_run())
+        .and(auth.require_admin())
+        .map(|store, body, dry_run, ()| ((), store, body, dry_run))
+        .and_then(
+            |args: ((), ImageStore, RemoveApiKeyRequest, bool)| async move {
+                handlers::remove_api_key_handler((), args.1, args.2, args.3).await
+            },
+        );
+
+    let list_api_keys = warp::path!("api-keys")
+        .and(warp::get())
+        .and(store.clone())
+        .and(auth.require_admin())
+        .map(|store, ()| ((), store))
+        .and_then(|args: ((), ImageStore)| async move {
+            handlers::list_api_keys_handler((), args.1).await
+        });
+
+    let list_key_webhooks = warp::path!("admin" / "webhooks")
+        .and(warp::get())
+        .and(auth.require_admin())
+        .and(store.clone())
+        .and_then(handlers::list_key_webhooks_handler);
+
+    let remove_key_webhook = warp::path!("admin" / "webhooks" / String)
+        .and(warp::delete())
+        .and(writable.clone())
+        .and(auth.require_admin())
+        .and(no_dry_run())
+        .and(store.clone())
+        .and_then(handlers::remove_key_webhook_handler);
+
+    let list_collections = warp::path!("admin" / "collections")
+        .and(warp::get())
+        .and(auth.require_admin())
+        .and(store.clone())
+        .and_then(handlers::list_collections_handler);
+
+    let get_collection = warp::path!("admin" / "collections" / String)
+        .and(warp::get())
+        .and(auth.require_admin())
+        .and(store.clone())
+        .and_then(handlers::get_collection_handler);
+
+    let create_collection = warp::path!("admin" / "collections" / String)
+        .and(warp::put())
+        .and(writable.clone())
+        .and(auth.require_admin())
+        .and(no_dry_run())
+        .and(store.clone())
+        .and_then(handlers::create_collection_handler);
+
+    let delete_collection = warp::path!("admin" / "collections" / String)
+        .and(warp::delete())
+        .and(writable.clone())
+        .and(auth.require_admin())
+        .and(no_dry_run())
+        .and(store.clone())
+        .and_then(handlers::delete_collection_handler);
+
+    let add_collection_images = warp::path!("admin" / "collections" / String / "images")
+        .and(warp::post())
+        .and(writable.clone())
+        .and(auth.require_admin())
+        .and(no_dry_run())
+        .and(store.clone())
+        .and(json_body(log_bodies))
+        .and_then(handlers::add_collection_images_handler);
+
+    let remove_collection_images = warp::path!("admin" / "collections" / String / "images")
+        .and(warp::delete())
+        .and(writable.clone())
+        .and(auth.require_admin())
+        .and(no_dry_run())
+        .and(store.clone())
+        .and(json_body(log_bodies))
+        .and_then(handlers::remove_collection_images_handler);
 
     let update_api_key = warp::path!("api-keys" / String)
         .and(warp::put())
+        .and(writable.clone())
         .and(auth.require_admin())
+        .and(no_dry_run())
         .and(store.clone())
-        .and(warp::body::json())
+        .and(json_body(log_bodies))
         .and_then(handlers::update_api_key_handler);
 
+    let clone_api_key = warp::path!("api-keys" / String / "clone")
+        .and(warp::post())
+        .and(writable.clone())
+        .and(auth.require_admin())
+        .and(no_dry_run())
+        .and(store.clone())
+        .and(json_body(log_bodies))
+        .and_then(handlers::clone_api_key_handler);
+
+    let reset_rate_limit = warp::path!("api-keys" / String / "reset-limit")
+        .and(warp::post())
+        .and(auth.require_admin())
+        .and(no_dry_run())
+        .and(store.clone())
+        .and(rate_limiter.clone())
+        .and_then(handlers::reset_rate_limit_handler);
+
+    let rate_limits = warp::path!("admin" / "rate-limits")
+        .and(warp::get())
+        .and(auth.require_admin())
+        .and(rate_limiter.clone())
+        .and_then(handlers::rate_limits_handler);
+
+    let rate_limit_debug = warp::path!("api-keys" / String / "rate-limit-debug")
+        .and(warp::get())
+        .and(auth.require_admin())
+        .and(store.clone())
+        .and(rate_limiter.clone())
+        .and_then(handlers::rate_limit_debug_handler);
+
     let update_api_key_status = warp::path!("api-keys" / String / "status")
         .and(warp::patch())
+        .and(writable.clone())
         .and(auth.require_admin())
+        .and(no_dry_run())
         .and(store.clone())
-        .and(warp::body::json())
+        .and(json_body(log_bodies))
         .and_then(handlers::update_api_key_status_handler);
 
     let upload = warp::path("upload")
-        .and(warp::post())
+        .and(cors::post())
+        .and(writable.clone())
         .and(form().max_length(10 * 1024 * 1024)) // 10MB limit
         .and(store.clone())
-        .and(auth.require_auth())
+        .and(upload_gate.clone())
+        .and(auth.require_scope(ApiKeyScope::Upload))
         .and_then(handlers::upload_image_handler);
 
-    let api = health
+    let presigner = warp::any().map(move || presigner.clone());
+    let presign_max_ttl = config.presign_max_ttl_secs;
+    let default_base_url = config.get_base_url();
+    let presign_upload = warp::path!("upload" / "presign")
+        .and(cors::post())
+        .and(presigner.clone())
+        .and(warp::any().map(move || presign_max_ttl))
+        .and(warp::any().map(move || default_base_url.clone()))
+        .and(public_url.clone())
+        .and(auth.require_scope(ApiKeyScope::Upload))
+        .and(json_body(log_bodies))
+        .and(store.clone())
+        .and_then(handlers::presign_upload_handler);
+
+    // authorized by the signed token in the path instead of a header
+    let upload_presigned = warp::path!("upload" / "presigned" / String)
+        .and(cors::put())
+        .and(writable.clone())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::content_length_limit(handlers::MAX_UPLOAD_BYTES))
+        .and(warp::body::bytes())
+        .and(presigner.clone())
+        .and(store.clone())
+        .and(upload_gate.clone())
+        .and(rate_limiter.clone())
+        .and_then(handlers::upload_presigned_handler);
+
+    let lookup_max_distance = config.lookup_max_distance;
+    let lookup = warp::path!("images" / "lookup")
+        .and(cors::post())
+        .and(form().max_length(10 * 1024 * 1024)) // 10MB limit
+        .and(store.clone())
+        .and(warp::any().map(move || lookup_max_distance))
+        .and(public_url.clone())
+        .and(auth.require_auth_info())
+        .and_then(handlers::lookup_image_handler);
+
+    let import_zip_catalog = warp::path!("admin" / "import-zip-catalog")
+        .and(warp::post())
+        .and(writable.clone())
+        .and(form().max_length(50 * 1024 * 1024)) // 50MB limit
+        .and(store.clone())
+        .and(auth.require_admin())
+        .and(no_dry_run())
+        .and_then(handlers::import_zip_catalog_handler);
+
+    let export = warp::path!("admin" / "export")
+        .and(warp::get())
+        .and(warp::query::<ExportQuery>())
+        .and(store.clone())
+        .and(auth.require_admin())
+        .and_then(handlers::export_handler);
+
+    let db_version = warp::path!("admin" / "db" / "version")
+        .and(warp::get())
+        .and(store.clone())
+        .and(auth.require_admin())
+        .and_then(handlers::db_version_handler);
+
+    let metrics_images_dir = images_dir.clone();
+    let metrics = warp::path!("admin" / "metrics")
+        .and(warp::get())
+        .and(upload_gate.clone())
+        .and(warp::any().map(move || metrics_images_dir.clone()))
+        .and(store.clone())
+        .and(auth.require_admin())
+        .and_then(handlers::metrics_handler);
+
+    let purge_derived_cache = warp::path!("admin" / "derived-cache")
+        .and(warp::delete())
+        .and(writable.clone())
+        .and(auth.require_admin())
+        .and(no_dry_run())
+        .and(store.clone())
+        .and_then(handlers::purge_derived_cache_handler);
+
+    let broken_images = warp::path!("admin" / "images" / "broken")
+        .and(warp::get())
+        .and(store.clone())
+        .and(auth.require_admin())
+        .and_then(handlers::broken_images_handler);
+
+    let size_distribution = warp::path!("admin" / "images" / "size-distribution")
+        .and(warp::get())
+        .and(warp::query::<SizeDistributionQuery>())
+        .and(store.clone())
+        .and(auth.require_admin())
+        .and_then(handlers::size_distribution_handler);
+
+    let gc_tags = warp::path!("admin" / "tags" / "gc")
+        .and(warp::post())
+        .and(writable.clone())
+        .and(store.clone())
+        .and(dry_run())
+        .and(auth.require_admin())
+        .and_then(handlers::gc_tags_handler);
+
+    let remap_tags = warp::path!("admin" / "tags" / "remap")
+        .and(warp::post())
+        .and(writable.clone())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(with_body_logging(log_bodies))
+        .and(store.clone())
+        .and(cache.clone())
+        .and(dry_run())
+        .and(auth.require_admin())
+        .and_then(handlers::remap_tags_handler);
+
+    let slow_queries = warp::path!("admin" / "slow-queries")
+        .and(warp::get())
+        .and(warp::query::<SlowQueriesQuery>())
+        .and(store.clone())
+        .and(auth.require_admin())
+        .and_then(handlers::slow_queries_handler);
+
+    let backfill = warp::path!("admin" / "backfill")
+        .and(warp::post())
+        .and(writable.clone())
+        .and(store.clone())
+        .and(auth.require_admin())
+        .and(no_dry_run())
+        .and_then(handlers::backfill_handler);
+
+    let write_sidecars = warp::path!("admin" / "sidecars" / "write")
+        .and(warp::post())
+        .and(store.clone())
+        .and(auth.require_admin())
+        .and(no_dry_run())
+        .and_then(handlers::write_sidecars_handler);
+
+    // routes are grouped by who may call them from a browser: reads follow
+    // CORS_ORIGINS, key writes CORS_WRITE_ORIGINS, and admin routes never
+    // get CORS headers. Groups are boxed to keep the combined filter type
+    // (and the futures it produces) shallow.
+    let read_routes = info
         .or(random_get)
-        .or(random_post)
-        .or(add_image)
-        .or(batch_add_images)
-        .or(remove_image)
-        .or(remove_image_tags)
-        .or(add_image_tags)
+        .or(autocomplete_tags)
+        .or(tag_namespaces)
         .or(get_all_tags)
-        .or(images)
+        .or(changed_since)
+        .or(sync_changes)
+        .or(list_images)
+        .or(search_images)
+        .or(me)
+        .or(get_my_webhook)
+        .or(reply_to_hotlinks(
+            converted_image.or(image_by_hash).or(image_by_id).or(images),
+        ))
         .or(image)
-        .or(api_key_routes)
+        .or(image_full)
+        .or(image_exists)
+        .boxed();
+
+    // everything that writes to the database is refused while storage is
+    // read-only, and its failures decide when that happens. Uploads and key
+    // management are retried by importers, so a repeated Idempotency-Key
+    // replays the first response instead of running again.
+    let idempotent_writes = add_image
+        .or(batch_add_images)
+        .or(upload)
+        .or(presign_upload)
+        .or(upload_presigned)
+        .boxed();
+    let write_routes = random_post
+        .or(lookup)
+        .or(images_exist)
+        .or(with_idempotency(
+            idempotency.clone(),
+            track_storage_writes(storage_health.clone(), idempotent_writes),
+        ))
+        .or(track_storage_writes(
+            storage_health.clone(),
+            set_my_webhook.or(remove_my_webhook),
+        ))
+        .boxed();
+
+    let idempotent_admin_writes = generate_api_key
+        .or(batch_generate_api_keys)
+        .or(remove_api_key)
+        .or(clone_api_key)
         .or(update_api_key)
         .or(update_api_key_status)
-        .or(upload)
+        .boxed();
+    let admin_writes = remove_image
+        .or(remove_key_webhook)
+        .or(create_collection)
+        .or(delete_collection)
+        .or(add_collection_images)
+        .or(remove_collection_images)
+        .or(remove_image_tags)
+        .or(add_image_tags)
+        .or(copy_image_tags)
+        .or(copy_tags_from)
+        .or(change_tags_by_hash)
+        .or(refresh_image)
+        .or(replace_image)
+        .or(set_image_metadata)
+        .or(import_zip_catalog)
+        .or(backfill)
+        .or(write_sidecars)
+        .or(gc_tags)
+        .or(remap_tags)
+        .boxed();
+    let admin_routes = file_info
+        .or(list_api_keys)
+        .or(reset_rate_limit)
+        .or(rate_limit_debug)
+        .or(metrics)
+        .or(list_key_webhooks)
+        .or(list_collections)
+        .or(get_collection)
+        .or(db_version)
+        .or(export)
+        .or(slow_queries)
+        .or(size_distribution)
+        .or(broken_images)
+        .or(rate_limits)
+        .or(purge_derived_cache)
+        .or(with_idempotency(
+            idempotency,
+            track_storage_writes(storage_health.clone(), idempotent_admin_writes),
+        ))
+        .or(track_storage_writes(storage_health, admin_writes))
+        .map(Reply::into_response)
+        .boxed();
+
+    let routes = cors::with_cors(read_routes, Some(read_cors), &[Method::GET, Method::HEAD])
+        .or(cors::with_cors(
+            write_routes,
+            write_cors,
+            &[Method::POST, Method::PUT, Method::DELETE],
+        ))
+        .unify()
+        .or(admin_routes)
+        .unify()
         .or(warp::options()
             .and(warp::path::full())
             .map(|_| warp::reply()))
+        .boxed();
+
+    // health checks bypass the limiter so probes keep working under load
+    let api = health
+        .or(with_concurrency_limit(request_limiter)
+            .and(versioning::with_api_version(routes))
+            .map(|_permit, reply| reply))
         .and(with_request_id())
-        .map(|reply, request_id| add_request_id_header(reply, request_id))
-        .recover(error::handle_rejection)
-        .with(cors());
+        .map(add_request_id_header)
+        .recover(error::handle_rejection);
+    let api = with_slow_request_warning(api, config.slow_ms)
+        .and(warp::header::optional::<String>("accept-language"))
+        .map(move |reply, accept_language| error::localize_error(reply, accept_language, &messages))
+        .and(warp::header::optional::<String>("accept-version"))
+        .map(versioning::add_version_header);
 
     let addr: SocketAddr = format!("{}:{}", config.host, config.port).parse()?;
 
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let (_, server) = warp::serve(api).try_bind_with_graceful_shutdown(addr, async {
+        stopped.await.ok();
+    })?;
+    let server = tokio::spawn(server);
     info!("Server started at http://{}:{}", config.host, config.port);
-    warp::serve(api).run(addr).await;
+
+    shutdown::signal().await;
+    // every request outside /health holds a limiter permit while it runs
+    let in_flight = |limiter: &Semaphore| {
+        config
+            .max_concurrent_requests
+            .saturating_sub(limiter.available_permits())
+    };
+    shutdown::drain(
+        stop,
+        server,
+        config.shutdown_timeout(),
+        || in_flight(&draining),
+        &images_dir,
+    )
+    .await;
 
     Ok(())
 }
@@ -0,0 +1,351 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use tracing::info;
+
+const DEFAULT_LOCALE: &str = "en";
+
+/// Built-in English templates keyed by machine error code. Placeholders are
+/// written as `{name}` and filled from the error's parameters.
+const DEFAULT_MESSAGES: &[(&str, &str)] = &[
+    (
+        "invalid_request",
+        "Invalid request format. Please check the API documentation for required fields.",
+    ),
+    (
+        "missing_tags_field",
+        "The 'tags' field is required when uploading an image",
+    ),
+    ("path_not_found", "{message}"),
+    ("database_error", "Database error: {message}"),
+    (
+        "invalid_image",
+        "The provided file is not a valid image: {message}",
+    ),
+    (
+        "file_too_large",
+        "The image file exceeds the maximum allowed size: {message}",
+    ),
+    (
+        "rate_limit_exceeded",
+        "Rate limit exceeded. Please try again later.",
+    ),
+    (
+        "username_exists",
+        "The username '{username}' is already in use",
+    ),
+    ("unauthorized", "Invalid or missing API key"),
+    (
+        "invalid_auth_scheme",
+        "Authorization must use the Bearer scheme: Authorization: Bearer <api_key>",
+    ),
+    (
+        "inactive_key",
+        "This API key has been deactivated. Please contact the administrator.",
+    ),
+    (
+        "auth_unavailable",
+        "The token could not be validated because the identity provider is unavailable. Please try again later.",
+    ),
+    (
+        "username_not_found",
+        "The username '{username}' was not found",
+    ),
+    (
+        "duplicate_image",
+        "This image has already been uploaded: {message}",
+    ),
+    (
+        "missing_tags",
+        "At least one tag is required when uploading an image",
+    ),
+    (
+        "batch_size_exceeded",
+        "Batch size exceeds maximum allowed size of {limit}",
+    ),
+    (
+        "upload_busy",
+        "Too many uploads in progress, please retry later",
+    ),
+    (
+        "server_busy",
+        "The server is at capacity, please retry later",
+    ),
+    ("invalid_parameter", "Invalid parameter: {message}"),
+    (
+        "storage_degraded",
+        "Storage is currently read-only. Writes are disabled until it recovers.",
+    ),
+    (
+        "idempotency_conflict",
+        "Idempotency-Key conflict: {message}",
+    ),
+    (
+        "blocked_tag",
+        "The tag '{tag}' is not allowed on this server",
+    ),
+    (
+        "tag_limit_reached",
+        "This server already holds its limit of {limit} distinct tags. Only existing tags can be added",
+    ),
+    (
+        "cursor_expired",
+        "The cursor has been pruned from the change log. Resync from a full listing and continue from cursor {cursor}",
+    ),
+    ("invalid_presign", "The presigned upload URL is not valid: {message}"),
+    (
+        "too_many_filter_tags",
+        "Filters may use at most {limit} tags",
+    ),
+    (
+        "too_many_metadata_filters",
+        "Filters may use at most {limit} metadata keys",
+    ),
+    (
+        "missing_scope",
+        "This API key does not have the '{scope}' scope",
+    ),
+    (
+        "owner_forbidden",
+        "This API key can only filter by its own uploads, not those of '{username}'",
+    ),
+    (
+        "hotlink_forbidden",
+        "Images on this server may not be embedded from '{referer}'",
+    ),
+    (
+        "quota_exceeded",
+        "Daily URL download quota exceeded: {remaining} bytes remaining, resets at {resets_at}",
+    ),
+    (
+        "missing_allowed_tag",
+        "Images added with this API key must carry one of these tags: {tag}",
+    ),
+    (
+        "dry_run_unsupported",
+        "This endpoint cannot preview its changes, retry without X-Dry-Run",
+    ),
+    ("precondition_failed", "{message}"),
+    (
+        "image_broken",
+        "The file for image {filename} is broken and can no longer be served",
+    ),
+    (
+        "hash_mismatch",
+        "The fetched file's SHA-256 is {actual}, not the expected {expected}",
+    ),
+    (
+        "unsupported_version",
+        "API version '{version}' is not supported. Supported versions: {supported}",
+    ),
+    ("not_found", "The requested resource was not found"),
+    (
+        "cors_forbidden",
+        "This origin is not allowed to make this cross-origin request",
+    ),
+    (
+        "method_not_allowed",
+        "This method is not allowed for this endpoint",
+    ),
+    ("internal_error", "An internal error occurred"),
+];
+
+/// Client-facing error messages per locale. Locales loaded from
+/// `ERROR_MESSAGES_FILE` override any subset of the built-in English text.
+#[derive(Debug, Default)]
+pub struct MessageCatalog {
+    locales: HashMap<String, HashMap<String, String>>,
+}
+
+impl MessageCatalog {
+    /// Loads overrides from a JSON or TOML file (picked by extension) mapping
+    /// locale to code to template, e.g. `{"ja": {"unauthorized": "..."}}`.
+    pub fn load(path: Option<&str>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read error messages file {}: {}", path, e))?;
+        let locales: HashMap<String, HashMap<String, String>> = if path.ends_with(".toml") {
+            toml::from_str(&contents)
+                .map_err(|e| anyhow!("Invalid error messages file {}: {}", path, e))?
+        } else {
+            serde_json::from_str(&contents)
+                .map_err(|e| anyhow!("Invalid error messages file {}: {}", path, e))?
+        };
+
+        let locales = locales
+            .into_iter()
+            .map(|(locale, messages)| (locale.to_lowercase(), messages))
+            .collect::<HashMap<_, _>>();
+        info!(
+            "Loaded error messages for locales: {:?}",
+            locales.keys().collect::<Vec<_>>()
+        );
+
+        Ok(Self { locales })
+    }
+
+    /// Picks the best configured locale for an `Accept-Language` header.
+    pub fn select_locale(&self, accept_language: Option<&str>) -> String {
+        let Some(header) = accept_language else {
+            return DEFAULT_LOCALE.to_string();
+        };
+
+        let mut ranges: Vec<(String, f32)> = header
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.trim().split(';');
+                let tag = parts.next()?.trim().to_lowercase();
+                let quality = parts
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .and_then(|q| q.parse().ok())
+                    .unwrap_or(1.0);
+                (!tag.is_empty() && quality > 0.0).then_some((tag, quality))
+            })
+            .collect();
+        ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+
+        for (tag, _) in ranges {
+            if tag == "*" || tag == DEFAULT_LOCALE {
+                return DEFAULT_LOCALE.to_string();
+            }
+            if self.locales.contains_key(&tag) {
+                return tag;
+            }
+            let primary = tag.split('-').next().unwrap_or(&tag);
+            if primary == DEFAULT_LOCALE || self.locales.contains_key(primary) {
+                return primary.to_string();
+            }
+        }
+
+        DEFAULT_LOCALE.to_string()
+    }
+
+    pub fn render(&self, code: &str, locale: &str, params: &[(&str, String)]) -> String {
+        let template = self
+            .locales
+            .get(locale)
+            .and_then(|messages| messages.get(code))
+            .or_else(|| {
+                self.locales
+                    .get(DEFAULT_LOCALE)
+                    .and_then(|messages| messages.get(code))
+            })
+            .map(String::as_str)
+            .or_else(|| {
+                DEFAULT_MESSAGES
+                    .iter()
+                    .find(|(c, _)| *c == code)
+                    .map(|(_, m)| *m)
+            })
+            .unwrap_or("An internal error occurred");
+
+        render_template(template, params)
+    }
+}
+
+/// Substitutes `{name}` placeholders. Unknown placeholders are left as-is.
+fn render_template(template: &str, params: &[(&str, String)]) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after.find('}') {
+            Some(end) => {
+                let name = &after[..end];
+                match params.iter().find(|(key, _)| *key == name) {
+                    Some((_, value)) => rendered.push_str(value),
+                    None => {
+                        rendered.push('{');
+                        rendered.push_str(name);
+                        rendered.push('}');
+                    }
+                }
+                rest = &after[end + 1..];
+            }
+            None => {
+                rendered.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+
+    rendered.push_str(rest);
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catalog(file: &str, contents: &str) -> MessageCatalog {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(file);
+        std::fs::write(&path, contents).unwrap();
+        MessageCatalog::load(path.to_str()).unwrap()
+    }
+
+    #[test]
+    fn overrides_replace_only_the_codes_they_name() {
+        let messages = catalog(
+            "messages.json",
+            r#"{"JA": {"unauthorized": "APIキーが無効です"}}"#,
+        );
+        assert_eq!(
+            messages.render("unauthorized", "ja", &[]),
+            "APIキーが無効です"
+        );
+        // codes the locale doesn't cover fall back to English
+        assert_eq!(
+            messages.render("batch_size_exceeded", "ja", &[("limit", "5".to_string())]),
+            "Batch size exceeds maximum allowed size of 5"
+        );
+
+        let messages = catalog("messages.toml", "[en]\nnot_found = \"Nothing here\"\n");
+        assert_eq!(messages.render("not_found", "en", &[]), "Nothing here");
+        assert_eq!(messages.render("not_found", "fr", &[]), "Nothing here");
+
+        assert!(MessageCatalog::load(Some("/nonexistent/messages.json")).is_err());
+        let dir = tempfile::tempdir().unwrap();
+        let broken = dir.path().join("messages.json");
+        std::fs::write(&broken, "{not json").unwrap();
+        assert!(MessageCatalog::load(broken.to_str()).is_err());
+    }
+
+    #[test]
+    fn locale_follows_the_highest_quality_configured_range() {
+        let messages = catalog("messages.json", r#"{"ja": {}, "de": {}}"#);
+        let select = |header| messages.select_locale(Some(header));
+
+        assert_eq!(select("fr;q=0.9, de;q=0.5, ja;q=0.8"), "ja");
+        assert_eq!(select("de-AT"), "de");
+        // q=0 means "not acceptable"
+        assert_eq!(select("ja;q=0, de;q=0.1"), "de");
+        // English or a wildcard ranked above a configured locale wins
+        assert_eq!(select("en;q=1, ja;q=0.9"), "en");
+        assert_eq!(select("*, ja;q=0.5"), "en");
+        assert_eq!(select("fr, zh"), "en");
+        assert_eq!(messages.select_locale(None), "en");
+    }
+
+    #[test]
+    fn missing_placeholders_are_left_in_place() {
+        let messages = MessageCatalog::default();
+        assert_eq!(
+            messages.render("username_exists", "en", &[]),
+            "The username '{username}' is already in use"
+        );
+        assert_eq!(
+            messages.render("quota_exceeded", "en", &[("remaining", "10".to_string())]),
+            "Daily URL download quota exceeded: 10 bytes remaining, resets at {resets_at}"
+        );
+        assert_eq!(render_template("open {brace", &[]), "open {brace");
+        assert_eq!(
+            messages.render("no_such_code", "en", &[]),
+            "An internal error occurred"
+        );
+    }
+}
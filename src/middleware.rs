@@ -1,17 +1,49 @@
-use tracing::info;
+use crate::auth::bearer_token;
+use crate::error::{handle_rejection, ImageError};
+use crate::idempotency::{Claim, Idempotency, IdempotencyGuard};
+use crate::models::{FeatureFlags, IdempotentResponse, SelectionStrategy};
+use crate::storage_health::StorageHealth;
+use crate::store::host_allowed;
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, info, trace, warn};
 use uuid::Uuid;
-use warp::http::HeaderValue;
+use warp::http::{HeaderMap, HeaderValue, Method};
+use warp::path::FullPath;
+use warp::reject::Reject;
+use warp::reply::Response;
 use warp::{Filter, Rejection, Reply};
 
+const MAX_LOGGED_BODY_BYTES: usize = 1024;
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Reuses a well-formed `X-Request-ID` sent by the client or a proxy so logs
+/// can be correlated across hops, otherwise generates a new one.
 pub fn with_request_id() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
-    warp::any()
-        .map(|| Uuid::new_v4().to_string())
+    warp::header::optional::<String>("x-request-id")
+        .map(|incoming: Option<String>| {
+            incoming
+                .filter(|id| valid_request_id(id))
+                .unwrap_or_else(|| Uuid::new_v4().to_string())
+        })
         .and_then(|request_id: String| async move {
             info!(request_id = %request_id, "Processing request");
             Ok::<String, Rejection>(request_id)
         })
 }
 
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
 pub fn add_request_id_header<T: Reply>(reply: T, request_id: String) -> impl Reply {
     let mut response = reply.into_response();
     response.headers_mut().insert(
@@ -20,3 +52,677 @@ pub fn add_request_id_header<T: Reply>(reply: T, request_id: String) -> impl Rep
     );
     response
 }
+
+/// Logs a warning for requests `api` took at least `slow_ms` to answer,
+/// errors included. Does nothing when `slow_ms` is unset.
+pub fn with_slow_request_warning<F, T>(
+    api: F,
+    slow_ms: Option<u64>,
+) -> impl Filter<Extract = (Response,), Error = Infallible> + Clone
+where
+    F: Filter<Extract = (T,), Error = Infallible> + Clone + Send + Sync + 'static,
+    T: Reply,
+{
+    warp::method()
+        .and(warp::path::full())
+        .map(|method: Method, path: FullPath| (method, path, Instant::now()))
+        .and(api)
+        .map(
+            move |(method, path, started): (Method, FullPath, Instant), reply: T| {
+                let response = reply.into_response();
+                let elapsed_ms = started.elapsed().as_millis() as u64;
+                if slow_ms.is_some_and(|slow_ms| elapsed_ms >= slow_ms) {
+                    warn!(
+                        "Slow request: {} {} took {}ms ({})",
+                        method,
+                        path.as_str(),
+                        elapsed_ms,
+                        response.status()
+                    );
+                }
+                response
+            },
+        )
+}
+
+/// Reads `X-Dry-Run`, which asks an admin write endpoint to report what it
+/// would change without changing it.
+pub fn dry_run() -> impl Filter<Extract = (bool,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("x-dry-run").and_then(|value: Option<String>| async move {
+        parse_dry_run(value.as_deref()).map_err(warp::reject::custom)
+    })
+}
+
+/// For admin write endpoints that can't preview their changes, so a dry run
+/// is refused rather than carried out for real.
+pub fn no_dry_run() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    dry_run()
+        .and_then(|dry_run: bool| async move {
+            if dry_run {
+                Err(warp::reject::custom(ImageError::DryRunUnsupported))
+            } else {
+                Ok(())
+            }
+        })
+        .untuple_one()
+}
+
+fn parse_dry_run(value: Option<&str>) -> Result<bool, ImageError> {
+    match value.map(|v| v.trim().to_ascii_lowercase()).as_deref() {
+        None | Some("false") | Some("0") => Ok(false),
+        Some("true") | Some("1") => Ok(true),
+        Some(other) => Err(ImageError::InvalidParameter(format!(
+            "X-Dry-Run must be true or false, got '{}'",
+            other
+        ))),
+    }
+}
+
+/// Reads the feature headers, currently just `X-Strategy`. A missing header
+/// leaves the default behaviour; a value off the allowlist is a 400 rather
+/// than silently falling back, so a misconfigured experiment shows up.
+pub fn feature_flags() -> impl Filter<Extract = (FeatureFlags,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("x-strategy").and_then(|value: Option<String>| async move {
+        parse_strategy(value.as_deref())
+            .map(|strategy| FeatureFlags { strategy })
+            .map_err(warp::reject::custom)
+    })
+}
+
+fn parse_strategy(value: Option<&str>) -> Result<SelectionStrategy, ImageError> {
+    let Some(value) = value else {
+        return Ok(SelectionStrategy::default());
+    };
+    value.trim().to_ascii_lowercase().parse().map_err(|_| {
+        let allowed: Vec<&str> = SelectionStrategy::ALL.iter().map(|s| s.as_str()).collect();
+        ImageError::InvalidParameter(format!(
+            "X-Strategy must be one of {}, got '{}'",
+            allowed.join(", "),
+            value
+        ))
+    })
+}
+
+/// Hotlink protection for the image file routes. With `allowed` set, a
+/// `Referer` whose host isn't one of its domains or their subdomains gets
+/// 403, as does a missing one unless `allow_empty`.
+pub fn with_allowed_referer(
+    allowed: Arc<Vec<String>>,
+    allow_empty: bool,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("referer")
+        .and_then(move |referer: Option<String>| {
+            let allowed = allowed.clone();
+            async move {
+                if allowed.is_empty() {
+                    return Ok(());
+                }
+                let Some(referer) = referer.filter(|r| !r.trim().is_empty()) else {
+                    return if allow_empty {
+                        Ok(())
+                    } else {
+                        Err(warp::reject::custom(ImageError::HotlinkForbidden(
+                            String::new(),
+                        )))
+                    };
+                };
+                let host = url::Url::parse(referer.trim())
+                    .ok()
+                    .and_then(|url| url.host_str().map(str::to_string));
+                match host {
+                    Some(host) if host_allowed(&allowed, &host) => Ok(()),
+                    _ => {
+                        debug!("Refusing image hotlinked from {}", referer);
+                        Err(warp::reject::custom(ImageError::HotlinkForbidden(referer)))
+                    }
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Answers `with_allowed_referer` rejections from `routes` with their 403
+/// right away. Left as rejections they would lose to the 401 of the
+/// metadata route that shares `/images/{filename}`.
+pub fn reply_to_hotlinks<F, T>(
+    routes: F,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (T,), Error = Rejection> + Clone,
+    T: Reply,
+{
+    routes
+        .map(Reply::into_response)
+        .or_else(|rejection: Rejection| async move {
+            match rejection.find::<ImageError>() {
+                Some(ImageError::HotlinkForbidden(_)) => {
+                    let reply = handle_rejection(rejection)
+                        .await
+                        .unwrap_or_else(|never| match never {});
+                    Ok((reply.into_response(),))
+                }
+                _ => Err(rejection),
+            }
+        })
+}
+
+/// Sheds load once `semaphore` runs out of permits. The permit is held until
+/// the wrapped route has produced its reply.
+pub fn with_concurrency_limit(
+    semaphore: Arc<Semaphore>,
+) -> impl Filter<Extract = (OwnedSemaphorePermit,), Error = Rejection> + Clone {
+    warp::any().and_then(move || {
+        let semaphore = semaphore.clone();
+        async move {
+            semaphore.try_acquire_owned().map_err(|_| {
+                warn!("Server saturated, rejecting request");
+                warp::reject::custom(ImageError::ServerBusy)
+            })
+        }
+    })
+}
+
+/// Turns write requests away with 503 while storage is in read-only mode.
+pub fn with_writable_storage(
+    health: StorageHealth,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::any()
+        .and_then(move || {
+            let health = health.clone();
+            async move {
+                if health.is_degraded() {
+                    Err(warp::reject::custom(ImageError::StorageDegraded))
+                } else {
+                    Ok(())
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Feeds the outcome of write routes into `health` so persistent storage
+/// errors can switch the server to read-only mode.
+pub fn track_storage_writes<F, T>(
+    health: StorageHealth,
+    routes: F,
+) -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (T,), Error = Rejection> + Clone,
+    T: Reply,
+{
+    let on_success = health.clone();
+    routes
+        .map(move |reply: T| {
+            on_success.record_success();
+            reply
+        })
+        .or_else(move |rejection: Rejection| {
+            if let Some(e) = rejection.find::<ImageError>() {
+                health.record_failure(&e.to_string());
+            }
+            async move { Err::<(T,), _>(rejection) }
+        })
+}
+
+/// With `PUBLIC_URL_FROM_HEADERS`, derives `scheme://host` from the request's
+/// `Host` and `X-Forwarded-Proto` headers. Hosts missing from `allowed_hosts`
+/// yield `None`, meaning the configured `BASE_URL` is used.
+pub fn with_public_base_url(
+    allowed_hosts: Option<Arc<Vec<String>>>,
+) -> impl Filter<Extract = (Option<String>,), Error = Infallible> + Clone {
+    warp::header::headers_cloned().map(move |headers: HeaderMap| {
+        let allowed_hosts = allowed_hosts.as_ref()?;
+        let host = headers
+            .get("host")
+            .and_then(|v| v.to_str().ok())
+            .map(|h| h.trim().to_lowercase())?;
+        if !allowed_hosts.contains(&host) {
+            debug!("Host {} is not in PUBLIC_HOSTS, using BASE_URL", host);
+            return None;
+        }
+
+        let proto = headers
+            .get("x-forwarded-proto")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(',').next())
+            .map(|p| p.trim().to_lowercase())
+            .filter(|p| p == "http" || p == "https")
+            .unwrap_or_else(|| "http".to_string());
+
+        Some(format!("{}://{}", proto, host))
+    })
+}
+
+/// Extracts the raw request body, logging it at TRACE level first when
+/// `enabled`. Bodies longer than 1 KiB are truncated in the log.
+///
+/// The server-generated request ID is only assigned once routing is done, so
+/// the ID logged here is the client-supplied `X-Request-ID`, if any.
+pub fn with_body_logging(
+    enabled: bool,
+) -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>("x-request-id"))
+        .and(warp::body::bytes())
+        .map(
+            move |method: Method, path: FullPath, request_id: Option<String>, body: Bytes| {
+                if enabled {
+                    let request_id = request_id
+                        .filter(|id| valid_request_id(id))
+                        .unwrap_or_else(|| "-".to_string());
+                    let shown = body.len().min(MAX_LOGGED_BODY_BYTES);
+                    let suffix = if body.len() > shown {
+                        " [truncated]"
+                    } else {
+                        ""
+                    };
+                    trace!(
+                        request_id = %request_id,
+                        "{} {} body ({} bytes): {}{}",
+                        method,
+                        path.as_str(),
+                        body.len(),
+                        String::from_utf8_lossy(&body[..shown]),
+                        suffix
+                    );
+                }
+                body
+            },
+        )
+}
+
+/// Drop-in for `warp::body::json()` that goes through `with_body_logging`.
+pub fn json_body<T>(log_bodies: bool) -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Send,
+{
+    with_body_logging(log_bodies).and_then(|body: Bytes| async move {
+        serde_json::from_slice::<T>(&body)
+            .map_err(|e| warp::reject::custom(ImageError::InvalidBody(e.to_string())))
+    })
+}
+
+/// Drop-in for `warp::query()` that answers a query string it can't parse
+/// with 400 instead of a 500.
+pub fn query_params<T>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    warp::query::<T>().or_else(|_| async {
+        Err(warp::reject::custom(ImageError::InvalidParameter(
+            "malformed query string".to_string(),
+        )))
+    })
+}
+
+#[derive(Debug)]
+struct IdempotentReplay(IdempotentResponse);
+
+impl Reject for IdempotentReplay {}
+
+/// Makes `routes` safe to retry: a request carrying an `Idempotency-Key`
+/// that already completed gets the stored response back instead of running
+/// again, and a concurrent duplicate is turned away with 409.
+pub fn with_idempotency<F, T>(
+    idempotency: Idempotency,
+    routes: F,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (T,), Error = Rejection> + Clone + Send + Sync + 'static,
+    T: Reply,
+{
+    let claim_state = idempotency.clone();
+    // dry runs change nothing, so they are neither stored nor replayed
+    let claim = warp::header::optional::<String>("idempotency-key")
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>("x-dry-run"))
+        .and_then(
+            move |key: Option<String>,
+                  auth: Option<String>,
+                  method: Method,
+                  path: FullPath,
+                  dry_run: Option<String>| {
+                let idempotency = claim_state.clone();
+                async move {
+                    let Some(key) = key else {
+                        return Ok(None);
+                    };
+                    if parse_dry_run(dry_run.as_deref()).unwrap_or(false) {
+                        return Ok(None);
+                    }
+                    let api_key = auth.as_deref().and_then(bearer_token).unwrap_or_default();
+                    match idempotency.begin(api_key, &key, method.as_str(), path.as_str()) {
+                        Ok(Claim::Run(guard)) => Ok(Some(guard)),
+                        Ok(Claim::Replay(stored)) => {
+                            Err(warp::reject::custom(IdempotentReplay(stored)))
+                        }
+                        Err(e) => Err(warp::reject::custom(e)),
+                    }
+                }
+            },
+        );
+
+    // keep handler rejections as values so a failed first attempt can be
+    // stored and replayed too
+    let outcome = routes
+        .map(|reply: T| Ok::<Response, Rejection>(reply.into_response()))
+        .or_else(|rejection: Rejection| async move {
+            Ok::<(Result<Response, Rejection>,), Rejection>((Err(rejection),))
+        });
+
+    claim
+        .and(outcome)
+        .and_then(
+            move |guard: Option<IdempotencyGuard>, outcome: Result<Response, Rejection>| {
+                let idempotency = idempotency.clone();
+                async move {
+                    let Some(guard) = guard else {
+                        return outcome;
+                    };
+                    let response = match outcome {
+                        Ok(response) => response,
+                        // routing misses fall through to the other routes
+                        Err(rejection) if rejection.find::<ImageError>().is_none() => {
+                            return Err(rejection)
+                        }
+                        Err(rejection) => match crate::error::handle_rejection(rejection).await {
+                            Ok(reply) => reply.into_response(),
+                            Err(never) => match never {},
+                        },
+                    };
+                    Ok(idempotency.complete(guard, response).await)
+                }
+            },
+        )
+        .or_else(|rejection: Rejection| async move {
+            match rejection.find::<IdempotentReplay>() {
+                Some(IdempotentReplay(stored)) => Ok((Idempotency::replay(stored),)),
+                None => Err(rejection),
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::CopyTagsFromQuery;
+    use crate::test_support::capture_logs;
+    use warp::http::StatusCode;
+
+    /// `/health` outside the limiter and everything else inside it, as in
+    /// `main`.
+    fn limited_api(
+        semaphore: Arc<Semaphore>,
+    ) -> impl Filter<Extract = (Response,), Error = Infallible> + Clone {
+        let health = warp::path("health").map(|| "ok".into_response());
+        let routes = warp::path("random").map(|| "image".into_response());
+        health
+            .or(with_concurrency_limit(semaphore)
+                .and(routes)
+                .map(|_permit, reply| reply))
+            .unify()
+            .recover(handle_rejection)
+            .map(Reply::into_response)
+    }
+
+    #[tokio::test]
+    async fn malformed_query_is_a_bad_request() {
+        let parse = |path: &'static str| async move {
+            warp::test::request()
+                .path(path)
+                .filter(&query_params::<CopyTagsFromQuery>())
+                .await
+        };
+        assert!(!parse("/").await.unwrap().merge);
+        assert!(parse("/?merge=true").await.unwrap().merge);
+
+        let rejection = parse("/?merge=maybe").await.unwrap_err();
+        assert!(matches!(
+            rejection.find::<ImageError>(),
+            Some(ImageError::InvalidParameter(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn saturated_server_sheds_requests_but_not_health_checks() {
+        let semaphore = Arc::new(Semaphore::new(2));
+        let api = limited_api(semaphore.clone());
+
+        let response = warp::test::request().path("/random").reply(&api).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // two requests in flight hold every permit
+        let in_flight = semaphore.clone().acquire_many_owned(2).await.unwrap();
+        let response = warp::test::request().path("/random").reply(&api).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().contains_key("retry-after"));
+        let response = warp::test::request().path("/health").reply(&api).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        drop(in_flight);
+        let response = warp::test::request().path("/random").reply(&api).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    fn public_url(
+        hosts: Option<&[&str]>,
+    ) -> impl Filter<Extract = (Option<String>,), Error = Infallible> + Clone {
+        with_public_base_url(
+            hosts.map(|hosts| Arc::new(hosts.iter().map(|h| h.to_string()).collect())),
+        )
+    }
+
+    #[tokio::test]
+    async fn public_url_comes_from_allowed_host_headers() {
+        let filter = public_url(Some(&["img.example.com"]));
+        let url = warp::test::request()
+            .header("host", "IMG.example.com")
+            .header("x-forwarded-proto", "https, http")
+            .filter(&filter)
+            .await
+            .unwrap();
+        assert_eq!(url.as_deref(), Some("https://img.example.com"));
+
+        let url = warp::test::request()
+            .header("host", "img.example.com")
+            .header("x-forwarded-proto", "gopher")
+            .filter(&filter)
+            .await
+            .unwrap();
+        assert_eq!(url.as_deref(), Some("http://img.example.com"));
+    }
+
+    #[tokio::test]
+    async fn public_url_ignores_unlisted_hosts_and_the_disabled_mode() {
+        let url = warp::test::request()
+            .header("host", "evil.example.com")
+            .filter(&public_url(Some(&["img.example.com"])))
+            .await
+            .unwrap();
+        assert_eq!(url, None);
+
+        let url = warp::test::request()
+            .header("host", "img.example.com")
+            .filter(&public_url(None))
+            .await
+            .unwrap();
+        assert_eq!(url, None);
+    }
+
+    /// An idempotent route counting how often it really runs. It fails
+    /// with 400 when the body is `fail` and waits for `release` when it is
+    /// `wait`.
+    fn counted_route(
+        runs: Arc<std::sync::atomic::AtomicUsize>,
+        release: Arc<tokio::sync::Notify>,
+        idempotency: Idempotency,
+    ) -> impl Filter<Extract = (Response,), Error = Infallible> + Clone {
+        let route = warp::post()
+            .and(warp::path("image"))
+            .and(warp::body::bytes())
+            .and_then(move |body: Bytes| {
+                let runs = runs.clone();
+                let release = release.clone();
+                async move {
+                    let run = runs.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+                    match &body[..] {
+                        b"fail" => Err(warp::reject::custom(ImageError::InvalidParameter(
+                            "bad".to_string(),
+                        ))),
+                        b"wait" => {
+                            release.notified().await;
+                            Ok(format!("run {}", run))
+                        }
+                        _ => Ok(format!("run {}", run)),
+                    }
+                }
+            });
+        with_idempotency(idempotency, route)
+            .recover(handle_rejection)
+            .map(Reply::into_response)
+    }
+
+    fn post(body: &'static str, key: &str) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .method("POST")
+            .path("/image")
+            .header("authorization", "Bearer some-key")
+            .header("idempotency-key", key)
+            .body(body)
+    }
+
+    fn idempotent_api() -> (
+        tempfile::TempDir,
+        Arc<std::sync::atomic::AtomicUsize>,
+        Arc<tokio::sync::Notify>,
+        impl Filter<Extract = (Response,), Error = Infallible> + Clone,
+    ) {
+        let (dir, store) = crate::test_support::temp_store();
+        let runs = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let release = Arc::new(tokio::sync::Notify::new());
+        let idempotency = Idempotency::new(store, std::time::Duration::from_secs(60));
+        let api = counted_route(runs.clone(), release.clone(), idempotency);
+        (dir, runs, release, api)
+    }
+
+    #[tokio::test]
+    async fn retry_after_success_replays_the_response() {
+        let (_dir, runs, _, api) = idempotent_api();
+        let first = post("ok", "k1").reply(&api).await;
+        let retry = post("ok", "k1").reply(&api).await;
+        assert_eq!(runs.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(retry.status(), first.status());
+        assert_eq!(retry.body(), "run 1");
+
+        // another key runs again
+        let other = post("ok", "k2").reply(&api).await;
+        assert_eq!(other.body(), "run 2");
+    }
+
+    #[tokio::test]
+    async fn retry_after_failure_replays_the_error() {
+        let (_dir, runs, _, api) = idempotent_api();
+        let first = post("fail", "k1").reply(&api).await;
+        assert_eq!(first.status(), StatusCode::BAD_REQUEST);
+        let retry = post("fail", "k1").reply(&api).await;
+        assert_eq!(retry.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(runs.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_duplicate_gets_409() {
+        let (_dir, runs, release, api) = idempotent_api();
+        let first = tokio::spawn({
+            let api = api.clone();
+            async move { post("wait", "k1").reply(&api).await }
+        });
+        while runs.load(std::sync::atomic::Ordering::SeqCst) == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let duplicate = post("wait", "k1").reply(&api).await;
+        assert_eq!(duplicate.status(), StatusCode::CONFLICT);
+
+        release.notify_one();
+        assert_eq!(first.await.unwrap().body(), "run 1");
+        let retry = post("wait", "k1").reply(&api).await;
+        assert_eq!(retry.body(), "run 1");
+        assert_eq!(runs.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn requests_taking_slow_ms_are_warned_about() {
+        let logs = capture_logs();
+        let api = warp::path::full().then(|path: FullPath| async move {
+            if path.as_str() == "/slow" {
+                tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+            }
+            warp::reply::with_status("done", StatusCode::ACCEPTED)
+        });
+
+        for (slow_ms, path) in [(Some(20), "/fast"), (None, "/slow"), (Some(20), "/slow")] {
+            let response = warp::test::request()
+                .path(path)
+                .filter(&with_slow_request_warning(api, slow_ms))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::ACCEPTED);
+        }
+
+        let contents = logs.contents();
+        assert_eq!(contents.matches("Slow request").count(), 1, "{}", contents);
+        assert!(contents.contains("Slow request: GET /slow took"));
+        assert!(contents.contains("(202 Accepted)"));
+    }
+
+    #[tokio::test]
+    async fn hotlinks_from_unlisted_referers_are_forbidden() {
+        let status = |allowed: &[&str], allow_empty: bool, referer: Option<&'static str>| {
+            let allowed = Arc::new(allowed.iter().map(|d| d.to_string()).collect());
+            let api = reply_to_hotlinks(
+                with_allowed_referer(allowed, allow_empty).map(|| "image".into_response()),
+            );
+            async move {
+                let request = warp::test::request().path("/images/a.png");
+                let request = match referer {
+                    Some(referer) => request.header("referer", referer),
+                    None => request,
+                };
+                request.reply(&api).await.status()
+            }
+        };
+        let listed = &["example.com"];
+
+        for referer in ["https://example.com/gallery", "http://cdn.Example.com/"] {
+            assert_eq!(status(listed, false, Some(referer)).await, StatusCode::OK);
+        }
+        for referer in [
+            "https://evil.com/",
+            "https://notexample.com/",
+            "https://example.com.evil.com/",
+            "not a url",
+        ] {
+            assert_eq!(
+                status(listed, true, Some(referer)).await,
+                StatusCode::FORBIDDEN,
+                "{}",
+                referer
+            );
+        }
+
+        for referer in [None, Some(""), Some("  ")] {
+            assert_eq!(status(listed, false, referer).await, StatusCode::FORBIDDEN);
+            assert_eq!(status(listed, true, referer).await, StatusCode::OK);
+        }
+
+        // no list, no protection
+        assert_eq!(status(&[], false, None).await, StatusCode::OK);
+        assert_eq!(
+            status(&[], false, Some("https://evil.com/")).await,
+            StatusCode::OK
+        );
+    }
+}
@@ -0,0 +1,612 @@
+//! Ordered schema migrations. Each one runs once, in its own transaction,
+//! and is recorded in `schema_version`. New schema changes go at the end of
+//! `MIGRATIONS` with the next version number; never edit one that shipped.
+
+use crate::models::AppliedMigration;
+use anyhow::{anyhow, Result};
+use rusqlite::{params, Connection, Transaction};
+use std::collections::BTreeMap;
+use std::fmt;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::info;
+
+struct Migration {
+    version: u32,
+    description: &'static str,
+    apply: fn(&Transaction) -> Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create base tables",
+        apply: create_base_tables,
+    },
+    Migration {
+        version: 2,
+        description: "add columns from before versioned migrations",
+        apply: add_legacy_columns,
+    },
+    Migration {
+        version: 3,
+        description: "add key scopes and URL ingest quotas",
+        apply: add_key_scopes,
+    },
+    Migration {
+        version: 4,
+        description: "add per-key allowed tags",
+        apply: add_allowed_tags,
+    },
+    Migration {
+        version: 5,
+        description: "add stable public image ids",
+        apply: add_public_ids,
+    },
+    Migration {
+        version: 6,
+        description: "add image uploaders and key webhooks",
+        apply: add_key_webhooks,
+    },
+    Migration {
+        version: 7,
+        description: "flag images whose files are broken",
+        apply: add_broken_flag,
+    },
+    Migration {
+        version: 8,
+        description: "track files derived from images",
+        apply: add_derived_files,
+    },
+    Migration {
+        version: 9,
+        description: "add named image collections",
+        apply: add_collections,
+    },
+    Migration {
+        version: 10,
+        description: "store image formats",
+        apply: add_image_formats,
+    },
+    Migration {
+        version: 11,
+        description: "count serves for least_served draws",
+        apply: add_serve_counts,
+    },
+];
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+fn create_version_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Brings the database up to the latest version and returns it.
+pub fn run(conn: &mut Connection) -> Result<u32> {
+    create_version_table(conn)?;
+
+    let current = current_version(conn)?;
+    if current > latest_version() {
+        return Err(anyhow!(
+            "Database schema version {} is newer than this build supports ({})",
+            current,
+            latest_version()
+        ));
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        info!(
+            "Applying schema migration {}: {}",
+            migration.version, migration.description
+        );
+        let tx = conn.transaction()?;
+        (migration.apply)(&tx)
+            .map_err(|e| anyhow!("Schema migration {} failed: {}", migration.version, e))?;
+        tx.execute(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?, ?, ?)",
+            params![
+                migration.version,
+                migration.description,
+                OffsetDateTime::now_utc().format(&Rfc3339)?
+            ],
+        )?;
+        tx.commit()?;
+    }
+
+    Ok(latest_version())
+}
+
+/// A column as `pragma_table_info` describes it.
+struct ColumnShape {
+    decl_type: String,
+    not_null: bool,
+    primary_key: bool,
+    has_default: bool,
+}
+
+impl fmt::Display for ColumnShape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let nullability = if self.not_null {
+            "NOT NULL"
+        } else {
+            "nullable"
+        };
+        write!(f, "{} {}", self.decl_type, nullability)?;
+        if self.primary_key {
+            write!(f, " PRIMARY KEY")?;
+        }
+        Ok(())
+    }
+}
+
+impl ColumnShape {
+    fn matches(&self, other: &ColumnShape) -> bool {
+        self.decl_type == other.decl_type
+            && self.not_null == other.not_null
+            && self.primary_key == other.primary_key
+    }
+}
+
+/// Compares every table with what the migrations create on an empty
+/// database, so a hand-edited schema stops startup instead of failing
+/// queries one by one. Extra columns are fine unless inserts can't fill them.
+pub fn check_schema(conn: &Connection) -> Result<()> {
+    let expected = Connection::open_in_memory()?;
+    create_version_table(&expected)?;
+    for migration in MIGRATIONS {
+        let tx = expected.unchecked_transaction()?;
+        (migration.apply)(&tx)?;
+        tx.commit()?;
+    }
+
+    let mut problems = Vec::new();
+    for table in table_names(&expected)? {
+        let want = table_columns(&expected, &table)?;
+        let found = table_columns(conn, &table)?;
+        if found.is_empty() {
+            problems.push(format!("table {} is missing", table));
+            continue;
+        }
+        for (name, column) in &want {
+            match found.get(name) {
+                None => problems.push(format!("{}.{} expected {}, missing", table, name, column)),
+                Some(actual) if !actual.matches(column) => problems.push(format!(
+                    "{}.{} expected {}, found {}",
+                    table, name, column, actual
+                )),
+                Some(_) => {}
+            }
+        }
+        for (name, column) in &found {
+            if !want.contains_key(name) && column.not_null && !column.has_default {
+                problems.push(format!(
+                    "{}.{} is unexpected and NOT NULL without a default",
+                    table, name
+                ));
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "Database schema doesn't match this build:\n  {}\nFix the database or start with --skip-schema-check",
+        problems.join("\n  ")
+    ))
+}
+
+fn table_names(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%'
+         ORDER BY name",
+    )?;
+    let names = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+    Ok(names)
+}
+
+fn table_columns(conn: &Connection, table: &str) -> Result<BTreeMap<String, ColumnShape>> {
+    let mut stmt = conn.prepare(
+        "SELECT name, type, \"notnull\", pk, dflt_value IS NOT NULL FROM pragma_table_info(?)",
+    )?;
+    let columns = stmt
+        .query_map([table], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                ColumnShape {
+                    decl_type: row.get::<_, String>(1)?.to_uppercase(),
+                    not_null: row.get(2)?,
+                    primary_key: row.get::<_, i64>(3)? > 0,
+                    has_default: row.get(4)?,
+                },
+            ))
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(columns)
+}
+
+pub fn current_version(conn: &Connection) -> Result<u32> {
+    Ok(conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        [],
+        |row| row.get(0),
+    )?)
+}
+
+pub fn applied(conn: &Connection) -> Result<Vec<AppliedMigration>> {
+    let mut stmt = conn
+        .prepare("SELECT version, description, applied_at FROM schema_version ORDER BY version")?;
+    let applied = stmt
+        .query_map([], |row| {
+            Ok(AppliedMigration {
+                version: row.get(0)?,
+                description: row.get(1)?,
+                applied_at: row.get(2)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(applied)
+}
+
+/// Databases from before versioning already have some of these tables, so
+/// this only fills in what is missing.
+fn create_base_tables(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS images (
+            hash TEXT PRIMARY KEY,
+            filename TEXT NOT NULL UNIQUE,
+            created_at TEXT NOT NULL,
+            modified_at TEXT NOT NULL,
+            width INTEGER,
+            height INTEGER,
+            size_bytes INTEGER,
+            phash INTEGER,
+            original_format TEXT,
+            latitude REAL,
+            longitude REAL
+        );
+
+        CREATE TABLE IF NOT EXISTS tags (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL UNIQUE
+        );
+        CREATE INDEX IF NOT EXISTS idx_tags_name ON tags(name);
+
+        CREATE TABLE IF NOT EXISTS image_tags (
+            image_hash TEXT NOT NULL,
+            tag_id INTEGER NOT NULL,
+            PRIMARY KEY (image_hash, tag_id),
+            FOREIGN KEY (image_hash) REFERENCES images(hash),
+            FOREIGN KEY (tag_id) REFERENCES tags(id)
+        );
+
+        CREATE TABLE IF NOT EXISTS image_metadata (
+            image_hash TEXT NOT NULL,
+            key TEXT NOT NULL,
+            value TEXT NOT NULL,
+            PRIMARY KEY (image_hash, key),
+            FOREIGN KEY (image_hash) REFERENCES images(hash)
+        );
+        CREATE INDEX IF NOT EXISTS idx_image_metadata_key ON image_metadata(key, value);
+
+        CREATE TABLE IF NOT EXISTS api_keys (
+            key TEXT PRIMARY KEY,
+            username TEXT NOT NULL UNIQUE,
+            created_at TEXT NOT NULL,
+            last_used_at TEXT,
+            is_active BOOLEAN NOT NULL DEFAULT 1,
+            requests_per_second INTEGER,
+            max_batch_size INTEGER,
+            default_filters TEXT
+        );
+
+        CREATE TABLE IF NOT EXISTS idempotency_keys (
+            api_key TEXT NOT NULL,
+            idempotency_key TEXT NOT NULL,
+            method TEXT NOT NULL,
+            path TEXT NOT NULL,
+            status INTEGER NOT NULL,
+            content_type TEXT,
+            body BLOB NOT NULL,
+            created_at TEXT NOT NULL,
+            PRIMARY KEY (api_key, idempotency_key)
+        );
+
+        CREATE TABLE IF NOT EXISTS change_log (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            event TEXT NOT NULL,
+            image_hash TEXT NOT NULL,
+            filename TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_change_log_image ON change_log(image_hash, event);
+
+        -- highest cursor dropped by retention, so stale consumers can be told
+        CREATE TABLE IF NOT EXISTS change_log_pruned (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            cursor INTEGER NOT NULL
+        );
+
+        -- scratch row rewritten by the storage recovery probe
+        CREATE TABLE IF NOT EXISTS storage_probe (
+            id INTEGER PRIMARY KEY,
+            checked_at TEXT NOT NULL
+        );",
+    )?;
+    Ok(())
+}
+
+/// Columns that used to be added by checking for them at startup. Old
+/// databases may have any subset of them.
+fn add_legacy_columns(tx: &Transaction) -> Result<()> {
+    add_column_if_missing(tx, "api_keys", "requests_per_second", "INTEGER")?;
+    add_column_if_missing(tx, "api_keys", "max_batch_size", "INTEGER")?;
+    add_column_if_missing(tx, "api_keys", "default_filters", "TEXT")?;
+    add_column_if_missing(tx, "images", "phash", "INTEGER")?;
+    add_column_if_missing(tx, "images", "original_format", "TEXT")?;
+    add_column_if_missing(tx, "images", "latitude", "REAL")?;
+    add_column_if_missing(tx, "images", "longitude", "REAL")?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS idx_images_location ON images(latitude, longitude)",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Keys from before scopes could already ingest URLs, so they keep both.
+fn add_key_scopes(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE api_keys ADD COLUMN scopes TEXT NOT NULL DEFAULT 'upload';
+        UPDATE api_keys SET scopes = 'upload,ingest_url';
+        ALTER TABLE api_keys ADD COLUMN url_ingest_daily_bytes INTEGER;
+
+        CREATE TABLE api_key_usage (
+            api_key TEXT NOT NULL,
+            day TEXT NOT NULL,
+            url_ingest_bytes INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (api_key, day)
+        );",
+    )?;
+    Ok(())
+}
+
+/// Comma-separated normalized tag names, NULL for unrestricted keys.
+fn add_allowed_tags(tx: &Transaction) -> Result<()> {
+    tx.execute("ALTER TABLE api_keys ADD COLUMN allowed_tags TEXT", [])?;
+    Ok(())
+}
+
+/// Random UUIDv4 in SQL, so the trigger covers every insert path.
+const NEW_PUBLIC_ID: &str = "lower(hex(randomblob(4)) || '-' || hex(randomblob(2)) || '-4' || substr(hex(randomblob(2)), 2) || '-' || substr('89ab', 1 + abs(random()) % 4, 1) || substr(hex(randomblob(2)), 2) || '-' || hex(randomblob(6)))";
+
+fn add_public_ids(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(&format!(
+        "ALTER TABLE images ADD COLUMN public_id TEXT;
+        UPDATE images SET public_id = {id};
+        CREATE UNIQUE INDEX idx_images_public_id ON images(public_id);
+
+        CREATE TRIGGER images_public_id AFTER INSERT ON images
+        WHEN NEW.public_id IS NULL
+        BEGIN
+            UPDATE images SET public_id = {id} WHERE rowid = NEW.rowid;
+        END;",
+        id = NEW_PUBLIC_ID
+    ))?;
+    Ok(())
+}
+
+/// Images added before this have no recorded uploader and never notify.
+fn add_key_webhooks(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE images ADD COLUMN uploaded_by TEXT;
+
+        CREATE TABLE key_webhooks (
+            username TEXT PRIMARY KEY,
+            url TEXT NOT NULL,
+            secret TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        );",
+    )?;
+    Ok(())
+}
+
+/// A non-NULL reason marks an image whose file can't be read or decoded.
+fn add_broken_flag(tx: &Transaction) -> Result<()> {
+    tx.execute_batch("ALTER TABLE images ADD COLUMN broken_reason TEXT;")?;
+    Ok(())
+}
+
+/// `path` is relative to the derived files directory.
+fn add_derived_files(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE derived_files (
+            parent_hash TEXT NOT NULL,
+            variant TEXT NOT NULL,
+            path TEXT NOT NULL,
+            bytes INTEGER NOT NULL,
+            last_access TEXT NOT NULL,
+            PRIMARY KEY (parent_hash, variant)
+        );
+        CREATE INDEX idx_derived_files_last_access ON derived_files(last_access);",
+    )?;
+    Ok(())
+}
+
+/// Curated sets of images that `/random?collection=` draws from.
+fn add_collections(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE collections (
+            name TEXT PRIMARY KEY,
+            created_at TEXT NOT NULL
+        );
+        CREATE TABLE collection_images (
+            collection TEXT NOT NULL,
+            image_hash TEXT NOT NULL,
+            added_at TEXT NOT NULL,
+            PRIMARY KEY (collection, image_hash),
+            FOREIGN KEY (collection) REFERENCES collections(name),
+            FOREIGN KEY (image_hash) REFERENCES images(hash)
+        );
+        CREATE INDEX idx_collection_images_hash ON collection_images(image_hash);",
+    )?;
+    Ok(())
+}
+
+/// Named like the usual extension of the file's actual contents, e.g.
+/// `JPG`. Existing rows are filled in from their files at startup.
+fn add_image_formats(tx: &Transaction) -> Result<()> {
+    tx.execute("ALTER TABLE images ADD COLUMN format TEXT", [])?;
+    Ok(())
+}
+
+/// How often an image was handed out by `least_served` random draws.
+fn add_serve_counts(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "ALTER TABLE images ADD COLUMN serve_count INTEGER NOT NULL DEFAULT 0",
+        [],
+    )?;
+    Ok(())
+}
+
+fn add_column_if_missing(tx: &Transaction, table: &str, column: &str, decl: &str) -> Result<()> {
+    let exists: bool = tx.query_row(
+        "SELECT EXISTS(SELECT 1 FROM pragma_table_info(?) WHERE name = ?)",
+        params![table, column],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        info!("Adding {} column to {} table", column, table);
+        tx.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A database as the server left it before migrations were versioned:
+    /// the original tables, some of the columns added at startup since, and
+    /// no `schema_version`.
+    fn legacy_database() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE images (
+                hash TEXT PRIMARY KEY,
+                filename TEXT NOT NULL UNIQUE,
+                created_at TEXT NOT NULL,
+                modified_at TEXT NOT NULL,
+                width INTEGER,
+                height INTEGER,
+                size_bytes INTEGER,
+                phash INTEGER
+            );
+            CREATE TABLE tags (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL UNIQUE
+            );
+            CREATE TABLE image_tags (
+                image_hash TEXT NOT NULL,
+                tag_id INTEGER NOT NULL,
+                PRIMARY KEY (image_hash, tag_id),
+                FOREIGN KEY (image_hash) REFERENCES images(hash),
+                FOREIGN KEY (tag_id) REFERENCES tags(id)
+            );
+            CREATE TABLE api_keys (
+                key TEXT PRIMARY KEY,
+                username TEXT NOT NULL UNIQUE,
+                created_at TEXT NOT NULL,
+                last_used_at TEXT,
+                is_active BOOLEAN NOT NULL DEFAULT 1,
+                requests_per_second INTEGER
+            );
+
+            INSERT INTO images (hash, filename, created_at, modified_at)
+                VALUES ('abc', 'abc.png', '2024-01-01T00:00:00Z', '2024-01-01T00:00:00Z');
+            INSERT INTO tags (name) VALUES ('neko');
+            INSERT INTO image_tags (image_hash, tag_id) VALUES ('abc', 1);
+            INSERT INTO api_keys (key, username, created_at, requests_per_second)
+                VALUES ('old-key', 'alice', '2024-01-01T00:00:00Z', 5);",
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn an_unversioned_database_is_brought_to_the_latest_schema() {
+        let mut conn = legacy_database();
+
+        assert_eq!(run(&mut conn).unwrap(), latest_version());
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        let versions: Vec<u32> = applied(&conn).unwrap().iter().map(|m| m.version).collect();
+        assert_eq!(versions, (1..=latest_version()).collect::<Vec<_>>());
+        check_schema(&conn).unwrap();
+
+        // existing rows survive and are filled in
+        let (public_id, serve_count): (Option<String>, i64) = conn
+            .query_row(
+                "SELECT public_id, serve_count FROM images WHERE hash = 'abc'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(public_id.unwrap().len(), 36);
+        assert_eq!(serve_count, 0);
+        let tag: String = conn
+            .query_row(
+                "SELECT t.name FROM image_tags it JOIN tags t ON t.id = it.tag_id
+                 WHERE it.image_hash = 'abc'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(tag, "neko");
+        let (requests_per_second, scopes): (i64, String) = conn
+            .query_row(
+                "SELECT requests_per_second, scopes FROM api_keys WHERE key = 'old-key'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(requests_per_second, 5);
+        assert_eq!(scopes, "upload,ingest_url");
+
+        // a second run has nothing left to do
+        assert_eq!(run(&mut conn).unwrap(), latest_version());
+        assert_eq!(applied(&conn).unwrap().len(), latest_version() as usize);
+    }
+
+    #[test]
+    fn a_database_from_a_newer_build_is_refused() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        run(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO schema_version (version, description, applied_at)
+             VALUES (?, 'from the future', '2030-01-01T00:00:00Z')",
+            [latest_version() + 1],
+        )
+        .unwrap();
+
+        let error = run(&mut conn).unwrap_err().to_string();
+        assert!(
+            error.contains("newer than this build supports"),
+            "{}",
+            error
+        );
+    }
+}
@@ -1,5 +1,9 @@
-use bytes::Bytes;
+use crate::byte_size;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use time::format_description::well_known::Rfc3339;
 use time::OffsetDateTime;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -10,10 +14,97 @@ pub struct ImageResponse {
     pub width: u32,
     pub height: u32,
     pub size_bytes: u64,
+    /// `size_bytes` for people, e.g. `1.5 MiB`
+    #[serde(default)]
+    pub size_human: String,
     pub hash: String,
+    /// Stable public id, kept when the file is renamed or replaced
+    #[serde(default)]
+    pub id: String,
     pub tags: Vec<String>,
     pub created_at: String,
     pub modified_at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_format: Option<String>,
+    /// Where the photo was taken, from its EXIF GPS data
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<GeoLocation>,
+    /// Base64 of the file, only with `inline=true` and for files up to
+    /// `INLINE_MAX_BYTES`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct GeoLocation {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl ImageResponse {
+    /// Points `url` at `base_url` (`scheme://host`) instead of `BASE_URL`.
+    pub fn rebase(&mut self, base_url: &str) {
+        if let Some((_, path)) = self.url.rsplit_once("/images/") {
+            self.url = format!("{}/images/{}", base_url, path);
+        }
+    }
+}
+
+/// What public image URLs are built from (`URL_STYLE`). Every style keeps
+/// resolving, so switching only changes which one responses hand out.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum UrlStyle {
+    /// `/images/{filename}`, which changes if the file is renamed
+    #[default]
+    Filename,
+    /// `/images/h/{hash}`
+    Hash,
+    /// `/images/id/{id}`
+    Id,
+}
+
+impl UrlStyle {
+    /// Path under `/images/` for an image.
+    pub fn path(self, filename: &str, hash: &str, id: &str) -> String {
+        match self {
+            UrlStyle::Filename => filename.to_string(),
+            UrlStyle::Hash => format!("h/{}", hash),
+            UrlStyle::Id => format!("id/{}", id),
+        }
+    }
+}
+
+/// Column an `/images/...` URL is resolved by.
+#[derive(Debug, Clone, Copy)]
+pub enum ImageKey {
+    Filename,
+    Hash,
+    Id,
+}
+
+impl ImageKey {
+    pub fn column(self) -> &'static str {
+        match self {
+            ImageKey::Filename => "filename",
+            ImageKey::Hash => "hash",
+            ImageKey::Id => "public_id",
+        }
+    }
+}
+
+impl std::str::FromStr for UrlStyle {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "filename" => Ok(UrlStyle::Filename),
+            "hash" => Ok(UrlStyle::Hash),
+            "id" => Ok(UrlStyle::Id),
+            _ => Err(()),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -22,6 +113,13 @@ pub struct AddImageRequest {
     #[serde(rename = "type")]
     pub path_type: PathType,
     pub tags: Vec<String>,
+    /// SHA-256 the fetched file must have, as hex
+    #[serde(default)]
+    pub expected_hash: Option<String>,
+    /// Add the tags to an identical image that is already stored instead of
+    /// failing as a duplicate
+    #[serde(default)]
+    pub merge_tags: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -36,6 +134,33 @@ pub struct GenerateApiKeyRequest {
     pub username: String,
     pub requests_per_second: Option<u32>, // none = unlimited
     pub max_batch_size: Option<u32>,      // none = no batching allowed (default=1)
+    pub default_filters: Option<DefaultFilters>,
+    pub scopes: Option<Vec<ApiKeyScope>>, // none = upload only
+    #[serde(default, deserialize_with = "byte_size::deserialize_opt")]
+    pub url_ingest_daily_bytes: Option<u64>, // none = unlimited
+    #[serde(default)]
+    pub allowed_tags: Vec<String>, // empty = all tags
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PresignUploadRequest {
+    pub content_type: String,
+    pub tags: Vec<String>,
+    pub max_size_bytes: Option<u64>,
+    pub expires_in_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchGenerateApiKeysRequest {
+    pub keys: Vec<GenerateApiKeyRequest>,
+    /// Skip usernames that already have a key instead of failing the batch
+    #[serde(default)]
+    pub skip_existing: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CloneApiKeyRequest {
+    pub new_username: String,
 }
 
 #[derive(Deserialize)]
@@ -46,6 +171,77 @@ pub struct RemoveApiKeyRequest {
 #[derive(Debug, Deserialize)]
 pub struct UpdateApiKeyRequest {
     pub requests_per_second: Option<u32>,
+    /// Left unchanged when absent, `{}` clears them
+    pub default_filters: Option<DefaultFilters>,
+    /// Left unchanged when absent
+    pub scopes: Option<Vec<ApiKeyScope>>,
+    /// Left unchanged when absent, `null` removes the quota
+    #[serde(default, deserialize_with = "byte_size::deserialize_nullable")]
+    pub url_ingest_daily_bytes: Option<Option<u64>>,
+    /// Left unchanged when absent, `[]` lifts the restriction
+    pub allowed_tags: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangedSinceQuery {
+    pub timestamp: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SyncChangesQuery {
+    #[serde(default)]
+    pub since: i64,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChangeEntry {
+    pub cursor: i64,
+    pub event: String,
+    pub hash: String,
+    pub filename: String,
+    pub occurred_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SizeDistributionQuery {
+    /// Comma-separated, ascending bucket boundaries in bytes
+    pub boundaries: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SizeBucket {
+    pub range_start: u64,
+    /// Exclusive upper bound, `None` for the open-ended last bucket
+    pub range_end: Option<u64>,
+    pub count: u64,
+    pub total_bytes: u64,
+}
+
+/// An image flagged broken because its file can't be read or decoded.
+#[derive(Debug, Serialize)]
+pub struct BrokenImageEntry {
+    pub filename: String,
+    pub hash: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagListQuery {
+    #[serde(default)]
+    pub include_empty: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SlowQueriesQuery {
+    pub threshold_ms: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AutocompleteQuery {
+    pub q: String,
+    pub limit: Option<usize>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -53,6 +249,18 @@ pub struct BatchAddImageRequest {
     pub images: Vec<AddImageRequest>,
 }
 
+/// Caps on the size of a single request, checked before any SQL is built.
+/// Advertised by `GET /`.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct RequestLimits {
+    pub max_filter_tags: usize,
+    pub max_filter_metadata: usize,
+    pub exists_batch_max: usize,
+    pub api_key_batch_max: usize,
+    pub max_redirects: u32,
+    pub inline_max_bytes: u64,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct BatchRandomRequest {
     pub count: u32,
@@ -64,9 +272,282 @@ pub struct BatchRandomRequest {
     pub height: Option<u32>,
     pub height_min: Option<u32>,
     pub height_max: Option<u32>,
+    /// Sizes take bytes or strings like `500k` and `1.5MB`
+    #[serde(default, deserialize_with = "byte_size::deserialize_opt")]
     pub size: Option<u64>,
+    #[serde(default, deserialize_with = "byte_size::deserialize_opt")]
     pub size_min: Option<u64>,
+    #[serde(default, deserialize_with = "byte_size::deserialize_opt")]
     pub size_max: Option<u64>,
+    #[serde(default)]
+    pub tag_match: TagMatch,
+    /// Metadata keys the image must have set
+    #[serde(default)]
+    pub has_metadata: Vec<String>,
+    /// Metadata values the image must match exactly
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    /// `minlat,minlon,maxlat,maxlon`
+    pub bbox: Option<BoundingBox>,
+    /// Username of the key that uploaded the image. Keys other than the
+    /// admin key may only name themselves
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// Tags the image must not carry
+    #[serde(default)]
+    pub exclude_tags: Vec<String>,
+    /// Name of a collection the image must belong to
+    #[serde(default)]
+    pub collection: Option<String>,
+}
+
+/// Area an image's GPS location must fall in. `min_lon` greater than
+/// `max_lon` means the box crosses the antimeridian.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct BoundingBox {
+    pub min_lat: f64,
+    pub min_lon: f64,
+    pub max_lat: f64,
+    pub max_lon: f64,
+}
+
+impl std::str::FromStr for BoundingBox {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("bbox must be minlat,minlon,maxlat,maxlon (got '{}')", s);
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+        let [min_lat, min_lon, max_lat, max_lon] = values[..] else {
+            return Err(invalid());
+        };
+
+        let lat_ok = |lat: f64| (-90.0..=90.0).contains(&lat);
+        let lon_ok = |lon: f64| (-180.0..=180.0).contains(&lon);
+        if !(lat_ok(min_lat) && lat_ok(max_lat) && lon_ok(min_lon) && lon_ok(max_lon)) {
+            return Err(format!("bbox is outside -90..90, -180..180 (got '{}')", s));
+        }
+        if min_lat > max_lat {
+            return Err(format!("bbox minlat must not exceed maxlat (got '{}')", s));
+        }
+        Ok(Self {
+            min_lat,
+            min_lon,
+            max_lat,
+            max_lon,
+        })
+    }
+}
+
+impl TryFrom<String> for BoundingBox {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<BoundingBox> for String {
+    fn from(bbox: BoundingBox) -> Self {
+        format!(
+            "{},{},{},{}",
+            bbox.min_lat, bbox.min_lon, bbox.max_lat, bbox.max_lon
+        )
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TagMatch {
+    /// Image has at least the requested tags
+    #[default]
+    All,
+    /// Image has exactly the requested tags, no more and no fewer
+    Exact,
+}
+
+impl std::str::FromStr for TagMatch {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(TagMatch::All),
+            "exact" => Ok(TagMatch::Exact),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Sort keys accepted by `GET /images`. Each maps to a fixed column so user
+/// input never reaches the SQL text.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageSort {
+    Width,
+    Height,
+    Size,
+    #[default]
+    Created,
+}
+
+impl ImageSort {
+    pub fn column(self) -> &'static str {
+        match self {
+            ImageSort::Width => "i.width",
+            ImageSort::Height => "i.height",
+            ImageSort::Size => "i.size_bytes",
+            ImageSort::Created => "i.created_at",
+        }
+    }
+}
+
+impl std::str::FromStr for ImageSort {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "width" => Ok(ImageSort::Width),
+            "height" => Ok(ImageSort::Height),
+            "size" => Ok(ImageSort::Size),
+            "created" => Ok(ImageSort::Created),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ImageSort {
+    /// Name of the sort in `GET /search`, the inverse of `from_search_field`.
+    pub fn search_field(self) -> &'static str {
+        match self {
+            ImageSort::Width => "width",
+            ImageSort::Height => "height",
+            ImageSort::Size => "size_bytes",
+            ImageSort::Created => "created_at",
+        }
+    }
+
+    /// Parses a `GET /search` sort field, which names the column itself.
+    pub fn from_search_field(field: &str) -> Option<Self> {
+        match field {
+            "width" => Some(ImageSort::Width),
+            "height" => Some(ImageSort::Height),
+            "size_bytes" => Some(ImageSort::Size),
+            "created_at" => Some(ImageSort::Created),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+impl SortOrder {
+    pub fn keyword(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+}
+
+impl std::str::FromStr for SortOrder {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "asc" => Ok(SortOrder::Asc),
+            "desc" => Ok(SortOrder::Desc),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How `/random` picks among matching images, chosen per request with the
+/// `X-Strategy` header so strategies can be compared on live traffic.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionStrategy {
+    /// Every match is equally likely.
+    #[default]
+    Uniform,
+    /// Among the matches served least often by this strategy, at random.
+    LeastServed,
+}
+
+impl SelectionStrategy {
+    pub const ALL: [SelectionStrategy; 2] =
+        [SelectionStrategy::Uniform, SelectionStrategy::LeastServed];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SelectionStrategy::Uniform => "uniform",
+            SelectionStrategy::LeastServed => "least_served",
+        }
+    }
+}
+
+impl std::str::FromStr for SelectionStrategy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|strategy| strategy.as_str() == s)
+            .ok_or(())
+    }
+}
+
+/// Behaviour switched per request by feature headers rather than query
+/// parameters. Only values on each header's allowlist are accepted.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FeatureFlags {
+    pub strategy: SelectionStrategy,
+}
+
+/// Position in a `GET /images?after=` listing. Images are keyed on
+/// `(created_at, hash)` so the next page is stable while images are added.
+/// Without a hash the cursor is a bare instant and everything created at
+/// that instant is skipped.
+#[derive(Debug, Clone)]
+pub struct ImageCursor {
+    pub created_at: String,
+    pub hash: Option<String>,
+}
+
+impl ImageCursor {
+    /// The opaque `after=` token `next` links carry. It holds the position
+    /// itself, so it keeps working when the image it came from is deleted.
+    pub fn token(&self) -> String {
+        let position = format!(
+            "{} {}",
+            self.created_at,
+            self.hash.as_deref().unwrap_or_default()
+        );
+        URL_SAFE_NO_PAD.encode(position)
+    }
+
+    /// Reads a `token`, `None` if `token` isn't one.
+    pub fn from_token(token: &str) -> Option<Self> {
+        let position = String::from_utf8(URL_SAFE_NO_PAD.decode(token).ok()?).ok()?;
+        let (created_at, hash) = position.split_once(' ')?;
+        OffsetDateTime::parse(created_at, &Rfc3339).ok()?;
+        if !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(Self {
+            created_at: created_at.to_string(),
+            hash: (!hash.is_empty()).then(|| hash.to_string()),
+        })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -76,9 +557,13 @@ pub struct BatchImageResponse {
     pub successful: usize,
     pub failed: usize,
     pub errors: Vec<String>,
+    /// `no_matches` when nothing matches the filters, instead of one error
+    /// per draw
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ApiKey {
     pub key: String,
     pub username: String,
@@ -89,6 +574,355 @@ pub struct ApiKey {
     pub is_active: bool,
     pub requests_per_second: Option<u32>,
     pub max_batch_size: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_filters: Option<DefaultFilters>,
+    pub scopes: Vec<ApiKeyScope>,
+    pub url_ingest_daily_bytes: Option<u64>,
+    /// When set, the key only sees images carrying one of these tags and
+    /// must put one on every image it adds
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allowed_tags: Vec<String>,
+    /// Only set on the key `ADMIN_KEY` resolves to, whatever the username
+    #[serde(skip)]
+    pub is_admin: bool,
+}
+
+impl ApiKey {
+    pub fn has_scope(&self, scope: ApiKeyScope) -> bool {
+        self.scopes.contains(&scope)
+    }
+}
+
+/// What a key may write. Reads only need a valid key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeyScope {
+    /// Add images from uploaded bytes or server-local paths
+    Upload,
+    /// Have the server download images from URLs
+    IngestUrl,
+}
+
+impl ApiKeyScope {
+    pub const ALL: [ApiKeyScope; 2] = [ApiKeyScope::Upload, ApiKeyScope::IngestUrl];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ApiKeyScope::Upload => "upload",
+            ApiKeyScope::IngestUrl => "ingest_url",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|scope| scope.as_str() == s)
+    }
+}
+
+/// Filters stored on an API key and applied underneath every random and
+/// list request it makes. Same fields as the `POST /random` body.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DefaultFilters {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag_match: Option<TagMatch>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width_min: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width_max: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height_min: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height_max: Option<u32>,
+    #[serde(
+        default,
+        deserialize_with = "byte_size::deserialize_opt",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub size: Option<u64>,
+    #[serde(
+        default,
+        deserialize_with = "byte_size::deserialize_opt",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub size_min: Option<u64>,
+    #[serde(
+        default,
+        deserialize_with = "byte_size::deserialize_opt",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub size_max: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub has_metadata: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bbox: Option<BoundingBox>,
+}
+
+impl DefaultFilters {
+    /// Requests quietly ignore half-open or inverted ranges, but a stored
+    /// default that never applies would go unnoticed, so those are refused.
+    pub fn validate(&self) -> Result<(), String> {
+        fn range<T: PartialOrd>(name: &str, min: Option<T>, max: Option<T>) -> Result<(), String> {
+            match (min, max) {
+                (Some(min), Some(max)) if min > max => {
+                    Err(format!("{}_min must not exceed {}_max", name, name))
+                }
+                (Some(_), None) | (None, Some(_)) => Err(format!(
+                    "{}_min and {}_max must be set together",
+                    name, name
+                )),
+                _ => Ok(()),
+            }
+        }
+        range("width", self.width_min, self.width_max)?;
+        range("height", self.height_min, self.height_max)?;
+        range("size", self.size_min, self.size_max)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+            && self.tag_match.is_none()
+            && self.exclude_tags.is_empty()
+            && self.width.is_none()
+            && self.width_min.is_none()
+            && self.width_max.is_none()
+            && self.height.is_none()
+            && self.height_min.is_none()
+            && self.height_max.is_none()
+            && self.size.is_none()
+            && self.size_min.is_none()
+            && self.size_max.is_none()
+            && self.has_metadata.is_empty()
+            && self.metadata.is_empty()
+            && self.bbox.is_none()
+    }
+
+    pub fn to_filters(&self) -> ImageFilters {
+        ImageFilters {
+            tags: Some(self.tags.clone()),
+            tag_match: self.tag_match.unwrap_or_default(),
+            width: BatchRandomRequest::parse_dimension(self.width, self.width_min, self.width_max),
+            height: BatchRandomRequest::parse_dimension(
+                self.height,
+                self.height_min,
+                self.height_max,
+            ),
+            size: BatchRandomRequest::parse_size(self.size, self.size_min, self.size_max),
+            has_metadata: self.has_metadata.clone(),
+            metadata: self.metadata.clone(),
+            bbox: self.bbox,
+            any_tags: Vec::new(),
+            exclude_tags: self.exclude_tags.clone(),
+            owner: None,
+            collection: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AppliedMigration {
+    pub version: u32,
+    pub description: String,
+    pub applied_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileInfo {
+    pub filename: String,
+    pub filesystem_size_bytes: u64,
+    pub db_size_bytes: Option<u64>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub mtime: Option<OffsetDateTime>,
+    pub modified_at_db: String,
+    pub in_sync: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LookupMatch {
+    #[serde(flatten)]
+    pub image: ImageResponse,
+    pub distance: u32,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct InlineQuery {
+    #[serde(default)]
+    pub inline: bool,
+}
+
+/// `?format=` on the image file routes.
+#[derive(Debug, Default, Deserialize)]
+pub struct ConvertQuery {
+    pub format: Option<String>,
+    #[serde(default)]
+    pub inline: bool,
+}
+
+/// A `GET /random?explain=true` result.
+#[derive(Debug, Serialize)]
+pub struct ExplainedImage {
+    #[serde(flatten)]
+    pub image: ImageResponse,
+    pub matched_tags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CatalogManifest {
+    pub images: Vec<CatalogEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    pub filename: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    pub limit: Option<usize>,
+    /// `next_cursor` from the previous page
+    pub cursor: Option<String>,
+    /// `snapshot_at` from the first page, so later pages see the same images
+    pub snapshot_at: Option<String>,
+}
+
+/// `catalog.json` of an export. A superset of `CatalogManifest`, so an
+/// export can be imported again as is.
+#[derive(Debug, Serialize)]
+pub struct ExportManifest {
+    pub snapshot_at: String,
+    pub images: Vec<CatalogEntry>,
+    pub counts: ExportCounts,
+    /// Images selected for the page whose file was gone by the time it was read
+    pub skipped: Vec<SkippedExport>,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportCounts {
+    pub included: usize,
+    pub skipped: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SkippedExport {
+    pub filename: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportZipResult {
+    pub imported: usize,
+    pub updated: usize,
+    pub failed: usize,
+    pub missing_from_archive: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+/// `POST /admin/tags/remap` body. Also accepted as CSV, one `old,new`
+/// pair per line.
+#[derive(Debug, Deserialize)]
+pub struct TagRemapRequest {
+    pub mappings: Vec<TagMapping>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TagMapping {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagRemapEntry {
+    pub from: String,
+    pub to: String,
+    pub status: TagRemapStatus,
+    /// Images that had `from`
+    pub images: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TagRemapStatus {
+    Renamed,
+    /// `to` already existed and `from` was folded into it
+    Merged,
+    NotFound,
+    Unchanged,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CopyTagsRequest {
+    /// Filename or hash of the image to copy tags from
+    pub from: String,
+    /// Also copy the source's metadata, overwriting keys the target shares
+    #[serde(default)]
+    pub include_metadata: bool,
+}
+
+/// `?merge=` on `POST /images/{filename}/copy-tags-from/{source}`
+#[derive(Debug, Default, Deserialize)]
+pub struct CopyTagsFromQuery {
+    #[serde(default)]
+    pub merge: bool,
+}
+
+/// One entry of the `POST /tags/by-hash` body
+#[derive(Debug, Deserialize)]
+pub struct HashTagChange {
+    pub hash: String,
+    #[serde(default)]
+    pub add: Vec<String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HashTagChangeResult {
+    pub hash: String,
+    pub filename: String,
+    /// Tags the image didn't have before
+    pub added: Vec<String>,
+    /// Tags that were on the image and got removed
+    pub removed: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CopyTagsResult {
+    pub tags: Vec<String>,
+    pub added: Vec<String>,
+    pub already_present: Vec<String>,
+    /// Tags a replacing copy took off the target
+    pub removed: Vec<String>,
+    /// The target's metadata after the copy, when metadata was included
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct BackfillResult {
+    pub scanned: usize,
+    pub updated: usize,
+    pub skipped_missing: usize,
+    pub failed: usize,
+    pub errors: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct SidecarWriteResult {
+    pub written: usize,
+    pub failed: usize,
+    pub errors: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -96,21 +930,115 @@ pub struct UpdateApiKeyStatusRequest {
     pub is_active: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ImageFilters {
     pub tags: Option<Vec<String>>,
+    pub tag_match: TagMatch,
     pub width: Option<DimensionFilter>,
     pub height: Option<DimensionFilter>,
     pub size: Option<SizeFilter>,
+    pub has_metadata: Vec<String>,
+    pub metadata: BTreeMap<String, String>,
+    pub bbox: Option<BoundingBox>,
+    /// Images must carry at least one of these, on top of `tags`. Set from
+    /// a key's `allowed_tags`, never from the request.
+    pub any_tags: Vec<String>,
+    /// Images carrying any of these are left out
+    pub exclude_tags: Vec<String>,
+    /// Username the image was uploaded by
+    pub owner: Option<String>,
+    /// Collection the image must belong to
+    pub collection: Option<String>,
+}
+
+/// A `GET /search` query: the listing filters plus creation time bounds,
+/// sorting and paging.
+#[derive(Debug, Clone)]
+pub struct SearchFilters {
+    pub filters: ImageFilters,
+    /// Exclusive bounds on `created_at`, as UTC RFC 3339
+    pub created_after: Option<String>,
+    pub created_before: Option<String>,
+    pub sort: ImageSort,
+    pub order: SortOrder,
+    pub limit: u32,
+    pub offset: u32,
+}
+
+impl SearchFilters {
+    /// Everything but `filters`, which callers build and restrict first.
+    /// Unlike `GET /images`, an unknown sort field is an error rather than
+    /// a fallback.
+    pub fn from_query(
+        params: &std::collections::HashMap<String, String>,
+        filters: ImageFilters,
+        default_limit: u32,
+        max_limit: u32,
+    ) -> Result<Self, String> {
+        let sort = match params.get("sort") {
+            Some(field) => ImageSort::from_search_field(field).ok_or_else(|| {
+                format!(
+                    "sort must be one of created_at, size_bytes, width, height (got '{}')",
+                    field
+                )
+            })?,
+            None => ImageSort::default(),
+        };
+        let order = match params.get("order") {
+            Some(order) => order
+                .parse()
+                .map_err(|_| format!("order must be asc or desc (got '{}')", order))?,
+            None => SortOrder::default(),
+        };
+        let limit = match params.get("limit") {
+            Some(limit) => limit
+                .parse::<u32>()
+                .map_err(|_| format!("limit must be a number (got '{}')", limit))?
+                .min(max_limit),
+            None => default_limit,
+        };
+        let offset = match params.get("offset") {
+            Some(offset) => offset
+                .parse()
+                .map_err(|_| format!("offset must be a number (got '{}')", offset))?,
+            None => 0,
+        };
+
+        Ok(Self {
+            filters,
+            created_after: Self::parse_time(params, "created_after")?,
+            created_before: Self::parse_time(params, "created_before")?,
+            sort,
+            order,
+            limit,
+            offset,
+        })
+    }
+
+    fn parse_time(
+        params: &std::collections::HashMap<String, String>,
+        name: &str,
+    ) -> Result<Option<String>, String> {
+        let Some(value) = params.get(name) else {
+            return Ok(None);
+        };
+        let parsed = OffsetDateTime::parse(value, &Rfc3339)
+            .map_err(|e| format!("{} must be RFC3339: {}", name, e))?;
+        parsed
+            .to_offset(time::UtcOffset::UTC)
+            .format(&Rfc3339)
+            .map(Some)
+            .map_err(|e| format!("{}: {}", name, e))
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum DimensionFilter {
     Exact(u32),
     Range(u32, u32),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum SizeFilter {
     Exact(u64),
     Range(u64, u64),
@@ -118,12 +1046,13 @@ pub enum SizeFilter {
 
 #[allow(unused)]
 impl ImageFilters {
-    pub fn from_query(params: &std::collections::HashMap<String, String>) -> Self {
+    pub fn from_query(params: &std::collections::HashMap<String, String>) -> Result<Self, String> {
         let tags = params.get("tags").map(|t| {
             t.split(',')
                 .map(|s| s.trim().to_string())
                 .collect::<Vec<String>>()
         });
+        let exclude_tags = Self::parse_exclude_tags(params);
 
         let width = Self::parse_dimension(
             params.get("width"),
@@ -135,18 +1064,205 @@ impl ImageFilters {
             params.get("height_min"),
             params.get("height_max"),
         );
-        let size = Self::parse_size(
-            params.get("size"),
-            params.get("size_min"),
-            params.get("size_max"),
+        let size = BatchRandomRequest::parse_size(
+            Self::size_param(params, "size")?,
+            Self::size_param(params, "size_min")?,
+            Self::size_param(params, "size_max")?,
         );
 
-        Self {
+        let tag_match = params
+            .get("tag_match")
+            .and_then(|m| m.parse().ok())
+            .unwrap_or_default();
+
+        let (has_metadata, metadata) = Self::parse_metadata(params);
+        let bbox = params.get("bbox").map(|b| b.parse()).transpose()?;
+
+        Ok(Self {
             tags,
+            tag_match,
             width,
             height,
             size,
+            has_metadata,
+            metadata,
+            bbox,
+            any_tags: Vec::new(),
+            exclude_tags,
+            owner: None,
+            collection: None,
+        })
+    }
+
+    /// Reads the comma-separated `exclude_tags` query parameter, skipping
+    /// empty entries.
+    pub fn parse_exclude_tags(params: &std::collections::HashMap<String, String>) -> Vec<String> {
+        params
+            .get("exclude_tags")
+            .map(|t| {
+                t.split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Reads a `size`, `size_min` or `size_max` query parameter, which may
+    /// use units such as `500k` or `1.5MB`.
+    pub fn size_param(
+        params: &std::collections::HashMap<String, String>,
+        name: &str,
+    ) -> Result<Option<u64>, String> {
+        params
+            .get(name)
+            .map(|value| byte_size::parse(value).map_err(|e| format!("{}: {}", name, e)))
+            .transpose()
+    }
+
+    /// Query parameters `GET /random` understands, besides `metadata.<key>`.
+    const RANDOM_QUERY_PARAMS: [&'static str; 18] = [
+        "tags",
+        "exclude_tags",
+        "tag_match",
+        "width",
+        "width_min",
+        "width_max",
+        "height",
+        "height_min",
+        "height_max",
+        "size",
+        "size_min",
+        "size_max",
+        "has_metadata",
+        "bbox",
+        "explain",
+        "inline",
+        "owner",
+        "collection",
+    ];
+
+    /// Names in `params` that `GET /random` would ignore, sorted.
+    pub fn unknown_random_params(params: &std::collections::HashMap<String, String>) -> Vec<&str> {
+        let mut unknown: Vec<&str> = params
+            .keys()
+            .map(String::as_str)
+            .filter(|name| {
+                !Self::RANDOM_QUERY_PARAMS.contains(name)
+                    && name.strip_prefix("metadata.").is_none_or(str::is_empty)
+            })
+            .collect();
+        unknown.sort_unstable();
+        unknown
+    }
+
+    /// Reads `has_metadata=key1,key2` and `metadata.<key>=<value>` parameters.
+    pub fn parse_metadata(
+        params: &std::collections::HashMap<String, String>,
+    ) -> (Vec<String>, BTreeMap<String, String>) {
+        let has_metadata = params
+            .get("has_metadata")
+            .map(|keys| {
+                keys.split(',')
+                    .map(|k| k.trim().to_string())
+                    .filter(|k| !k.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let metadata = params
+            .iter()
+            .filter_map(|(name, value)| {
+                name.strip_prefix("metadata.")
+                    .filter(|key| !key.is_empty())
+                    .map(|key| (key.to_string(), value.clone()))
+            })
+            .collect();
+
+        (has_metadata, metadata)
+    }
+
+    /// Fills in whatever the request left unset from a key's defaults.
+    /// Request tags replace the default tags (and their `tag_match`), and
+    /// request metadata values win over default ones for the same key.
+    /// Excluded tags add up.
+    pub fn with_defaults(mut self, defaults: Option<&DefaultFilters>) -> Self {
+        let Some(defaults) = defaults else {
+            return self;
+        };
+        let defaults = defaults.to_filters();
+
+        if self.tags.as_ref().is_none_or(|tags| tags.is_empty()) {
+            self.tags = defaults.tags;
+            self.tag_match = defaults.tag_match;
+        }
+        for tag in defaults.exclude_tags {
+            if !self.exclude_tags.contains(&tag) {
+                self.exclude_tags.push(tag);
+            }
+        }
+        self.width = self.width.or(defaults.width);
+        self.height = self.height.or(defaults.height);
+        self.size = self.size.or(defaults.size);
+        if self.has_metadata.is_empty() {
+            self.has_metadata = defaults.has_metadata;
+        }
+        for (key, value) in defaults.metadata {
+            self.metadata.entry(key).or_insert(value);
         }
+        self.bbox = self.bbox.or(defaults.bbox);
+        self
+    }
+
+    /// Limits results to images carrying at least one of `allowed`, if any.
+    pub fn restrict_to(mut self, allowed: &[String]) -> Self {
+        self.any_tags = allowed.to_vec();
+        self
+    }
+
+    /// The tags of a matching image that the tag filters asked for, in the
+    /// image's order.
+    pub fn matched_tags(&self, image_tags: &[String]) -> Vec<String> {
+        let requested: Vec<String> = self
+            .tags
+            .iter()
+            .flatten()
+            .chain(&self.any_tags)
+            .map(|tag| tag.trim().to_lowercase().replace(' ', "_"))
+            .collect();
+        image_tags
+            .iter()
+            .filter(|tag| requested.contains(tag))
+            .cloned()
+            .collect()
+    }
+
+    pub fn fingerprint(&self) -> String {
+        let mut tags = self.tags.clone().unwrap_or_default();
+        tags.sort();
+        tags.dedup();
+        let mut exclude_tags = self.exclude_tags.clone();
+        exclude_tags.sort();
+        exclude_tags.dedup();
+        let mut has_metadata = self.has_metadata.clone();
+        has_metadata.sort();
+        has_metadata.dedup();
+        format!(
+            "tags={};exclude_tags={};tag_match={:?};width={:?};height={:?};size={:?};has_metadata={};metadata={:?};bbox={:?};any_tags={};owner={:?};collection={:?}",
+            tags.join(","),
+            exclude_tags.join(","),
+            self.tag_match,
+            self.width,
+            self.height,
+            self.size,
+            has_metadata.join(","),
+            self.metadata,
+            self.bbox,
+            self.any_tags.join(","),
+            self.owner,
+            self.collection
+        )
     }
 
     fn parse_dimension(
@@ -165,32 +1281,23 @@ impl ImageFilters {
             None
         }
     }
-
-    fn parse_size(
-        exact: Option<&String>,
-        min: Option<&String>,
-        max: Option<&String>,
-    ) -> Option<SizeFilter> {
-        if let Some(exact) = exact {
-            exact.parse().ok().map(SizeFilter::Exact)
-        } else if let (Some(min), Some(max)) = (min, max) {
-            match (min.parse(), max.parse()) {
-                (Ok(min), Ok(max)) if min <= max => Some(SizeFilter::Range(min, max)),
-                _ => None,
-            }
-        } else {
-            None
-        }
-    }
 }
 
 impl BatchRandomRequest {
     pub fn to_filters(&self) -> ImageFilters {
         ImageFilters {
             tags: Some(self.tags.clone()),
+            tag_match: self.tag_match,
             width: Self::parse_dimension(self.width, self.width_min, self.width_max),
             height: Self::parse_dimension(self.height, self.height_min, self.height_max),
             size: Self::parse_size(self.size, self.size_min, self.size_max),
+            has_metadata: self.has_metadata.clone(),
+            metadata: self.metadata.clone(),
+            bbox: self.bbox,
+            any_tags: Vec::new(),
+            exclude_tags: self.exclude_tags.clone(),
+            owner: self.owner.clone(),
+            collection: self.collection.clone(),
         }
     }
 
@@ -227,10 +1334,58 @@ impl BatchRandomRequest {
     }
 }
 
-#[derive(Debug)]
-pub struct FileUpload {
-    pub filename: String,
-    pub content_type: String,
-    pub data: Bytes,
-    pub tags: Vec<String>,
+/// Outcome of a request sent with an `Idempotency-Key`, replayed verbatim
+/// to retries of the same request.
+#[derive(Debug, Clone)]
+pub struct IdempotentResponse {
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExistsBatchRequest {
+    /// Filenames or hashes
+    pub images: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageExists {
+    pub exists: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified_at: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetWebhookRequest {
+    pub url: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Collection {
+    pub name: String,
+    pub images: u64,
+    pub created_at: String,
+}
+
+/// `POST` and `DELETE /admin/collections/{name}/images` body
+#[derive(Debug, Deserialize)]
+pub struct CollectionImagesRequest {
+    pub hashes: Vec<String>,
+}
+
+/// The webhook a key registered for events about its own uploads.
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyWebhook {
+    pub username: String,
+    pub url: String,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub created_at: String,
 }
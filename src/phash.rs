@@ -0,0 +1,86 @@
+use image::imageops::FilterType;
+use image::DynamicImage;
+use std::f64::consts::PI;
+use std::sync::{Arc, RwLock};
+
+const PHASH_SIZE: usize = 32;
+const PHASH_LOW_FREQ: usize = 8;
+
+/// Computes a 64-bit DCT perceptual hash. Visually similar images (crops,
+/// re-encodes, small edits) end up a small Hamming distance apart.
+pub fn compute_phash(img: &DynamicImage) -> u64 {
+    let gray = img
+        .resize_exact(PHASH_SIZE as u32, PHASH_SIZE as u32, FilterType::Triangle)
+        .to_luma8();
+    let pixels: Vec<f64> = gray.pixels().map(|p| p.0[0] as f64).collect();
+
+    let mut coefficients = [0f64; PHASH_LOW_FREQ * PHASH_LOW_FREQ];
+    for u in 0..PHASH_LOW_FREQ {
+        for v in 0..PHASH_LOW_FREQ {
+            let mut sum = 0.0;
+            for y in 0..PHASH_SIZE {
+                for x in 0..PHASH_SIZE {
+                    sum += pixels[y * PHASH_SIZE + x]
+                        * (((2 * x + 1) as f64 * u as f64 * PI) / (2 * PHASH_SIZE) as f64).cos()
+                        * (((2 * y + 1) as f64 * v as f64 * PI) / (2 * PHASH_SIZE) as f64).cos();
+                }
+            }
+            coefficients[v * PHASH_LOW_FREQ + u] = sum;
+        }
+    }
+
+    // the DC term only carries overall brightness, leave it out of the median
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let median = sorted[sorted.len() / 2];
+
+    coefficients.iter().enumerate().fold(
+        0u64,
+        |hash, (i, &c)| if c > median { hash | (1 << i) } else { hash },
+    )
+}
+
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// In-memory index of perceptual hashes keyed by image hash. A linear scan is
+/// plenty fast for libraries up to around a million images; the search API is
+/// kept narrow so a BK-tree can replace the Vec without touching callers.
+#[derive(Clone, Default)]
+pub struct PhashIndex {
+    entries: Arc<RwLock<Vec<(u64, String)>>>,
+}
+
+impl PhashIndex {
+    pub fn new(entries: Vec<(u64, String)>) -> Self {
+        Self {
+            entries: Arc::new(RwLock::new(entries)),
+        }
+    }
+
+    pub fn insert(&self, phash: u64, image_hash: String) {
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        entries.retain(|(_, hash)| *hash != image_hash);
+        entries.push((phash, image_hash));
+    }
+
+    pub fn remove(&self, image_hash: &str) {
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        entries.retain(|(_, hash)| hash != image_hash);
+    }
+
+    /// Returns `(image_hash, distance)` pairs within `max_distance`, closest first.
+    pub fn search(&self, phash: u64, max_distance: u32) -> Vec<(String, u32)> {
+        let entries = self.entries.read().unwrap_or_else(|e| e.into_inner());
+        let mut matches: Vec<(String, u32)> = entries
+            .iter()
+            .filter_map(|(candidate, hash)| {
+                let distance = hamming_distance(phash, *candidate);
+                (distance <= max_distance).then(|| (hash.clone(), distance))
+            })
+            .collect();
+        matches.sort_by_key(|(_, distance)| *distance);
+        matches
+    }
+}
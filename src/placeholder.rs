@@ -0,0 +1,143 @@
+use bytes::Bytes;
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use moka::sync::Cache;
+use std::io::Cursor;
+use tracing::warn;
+
+/// Longest side of a placeholder; larger images are scaled down to fit.
+const MAX_SIDE: u32 = 512;
+const MIN_SIDE: u32 = 32;
+const CACHE_CAPACITY: u64 = 256;
+/// Hash characters printed on the placeholder.
+const LABEL_LEN: usize = 8;
+
+/// 3x5 glyphs for the hex digits, one row per entry with the low three bits
+/// as pixels, left to right.
+const GLYPHS: [[u8; 5]; 16] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+    [0b010, 0b101, 0b111, 0b101, 0b101],
+    [0b110, 0b101, 0b110, 0b101, 0b110],
+    [0b011, 0b100, 0b100, 0b100, 0b011],
+    [0b110, 0b101, 0b101, 0b101, 0b110],
+    [0b111, 0b100, 0b111, 0b100, 0b111],
+    [0b111, 0b100, 0b111, 0b100, 0b100],
+];
+
+/// Generates the PNG served in place of a broken image when
+/// `FALLBACK_PLACEHOLDER` is on: a solid color derived from the hash with
+/// the start of the hash printed on it, at the image's aspect ratio.
+#[derive(Clone)]
+pub struct Placeholders {
+    cache: Option<Cache<String, Bytes>>,
+}
+
+impl Placeholders {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            cache: enabled.then(|| Cache::new(CACHE_CAPACITY)),
+        }
+    }
+
+    /// The placeholder for `hash`, or `None` when placeholders are off.
+    pub fn get(&self, hash: &str, width: u32, height: u32) -> Option<Bytes> {
+        let cache = self.cache.as_ref()?;
+        if let Some(png) = cache.get(hash) {
+            return Some(png);
+        }
+        match render(hash, width, height) {
+            Ok(png) => {
+                cache.insert(hash.to_string(), png.clone());
+                Some(png)
+            }
+            Err(e) => {
+                warn!("Failed to render placeholder for {}: {}", hash, e);
+                None
+            }
+        }
+    }
+}
+
+fn render(hash: &str, width: u32, height: u32) -> image::ImageResult<Bytes> {
+    let (width, height) = fit(width, height);
+    let channel = |i: usize| {
+        let value = hash
+            .get(i * 2..i * 2 + 2)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .unwrap_or(0x80);
+        // keep to mid tones so the label stays readable
+        64 + value / 2
+    };
+    let background = Rgb([channel(0), channel(1), channel(2)]);
+    let luma =
+        0.299 * background[0] as f32 + 0.587 * background[1] as f32 + 0.114 * background[2] as f32;
+    let foreground = if luma > 128.0 {
+        Rgb([0, 0, 0])
+    } else {
+        Rgb([255, 255, 255])
+    };
+
+    let mut img = RgbImage::from_pixel(width, height, background);
+    draw_label(&mut img, &hash[..hash.len().min(LABEL_LEN)], foreground);
+
+    let mut data = Vec::new();
+    DynamicImage::ImageRgb8(img).write_to(&mut Cursor::new(&mut data), ImageFormat::Png)?;
+    Ok(Bytes::from(data))
+}
+
+/// Scales the image's dimensions down to fit `MAX_SIDE`, keeping the
+/// aspect ratio. Unknown dimensions give a square.
+fn fit(width: u32, height: u32) -> (u32, u32) {
+    if width == 0 || height == 0 {
+        return (MAX_SIDE, MAX_SIDE);
+    }
+    let scale = (MAX_SIDE as f64 / width.max(height) as f64).min(1.0);
+    let side = |value: u32| ((value as f64 * scale).round() as u32).max(MIN_SIDE);
+    (side(width), side(height))
+}
+
+fn draw_label(img: &mut RgbImage, label: &str, color: Rgb<u8>) {
+    let glyphs: Vec<&[u8; 5]> = label
+        .chars()
+        .filter_map(|c| c.to_digit(16))
+        .map(|digit| &GLYPHS[digit as usize])
+        .collect();
+    if glyphs.is_empty() {
+        return;
+    }
+    // each glyph is three pixels wide plus one of spacing
+    let units_wide = glyphs.len() as u32 * 4 - 1;
+    let scale = (img.width() * 3 / 4 / units_wide)
+        .min(img.height() / 2 / 5)
+        .max(1);
+    let left = img.width().saturating_sub(units_wide * scale) / 2;
+    let top = img.height().saturating_sub(5 * scale) / 2;
+
+    for (index, glyph) in glyphs.iter().enumerate() {
+        let glyph_left = left + index as u32 * 4 * scale;
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) == 0 {
+                    continue;
+                }
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        let x = glyph_left + column * scale + dx;
+                        let y = top + row as u32 * scale + dy;
+                        if x < img.width() && y < img.height() {
+                            img.put_pixel(x, y, color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
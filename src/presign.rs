@@ -0,0 +1,217 @@
+use crate::error::ImageError;
+use hmac::{Hmac, Mac};
+use moka::sync::Cache;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+/// What a presigned upload token allows. The token is readable by whoever
+/// holds it, so it names the owning key's user rather than the key itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresignClaims {
+    pub username: String,
+    pub content_type: String,
+    pub max_size_bytes: u64,
+    /// Unix timestamp in seconds
+    pub expires_at: i64,
+    pub tags: Vec<String>,
+    /// Issued with the admin key, which has no stored key to check
+    #[serde(default)]
+    pub is_admin: bool,
+    /// Random per token, so it can be used only once, see
+    /// `Presigner::consume`
+    #[serde(default)]
+    pub nonce: String,
+}
+
+/// Signs and verifies presigned upload tokens with HMAC-SHA256. Tokens are
+/// `<hex payload>.<hex signature>`, so they are URL-safe as-is.
+#[derive(Clone)]
+pub struct Presigner {
+    secret: Arc<Vec<u8>>,
+    /// Nonces of tokens that were used. Entries only need to outlive the
+    /// longest token lifetime, after which the token is expired anyway.
+    used: Cache<String, ()>,
+}
+
+impl Presigner {
+    pub fn new(secret: &str, max_ttl: Duration) -> Self {
+        Self {
+            secret: Arc::new(secret.as_bytes().to_vec()),
+            used: Cache::builder()
+                .time_to_live(max_ttl.max(Duration::from_secs(1)))
+                .build(),
+        }
+    }
+
+    pub fn sign(&self, claims: &PresignClaims) -> String {
+        let payload = serde_json::to_vec(claims).unwrap_or_default();
+        let signature = self.hmac(&payload);
+        format!("{}.{}", to_hex(&payload), to_hex(&signature))
+    }
+
+    pub fn verify(&self, token: &str) -> Result<PresignClaims, ImageError> {
+        let invalid = || ImageError::InvalidPresign("malformed token".to_string());
+        let (payload, signature) = token.split_once('.').ok_or_else(invalid)?;
+        let payload = from_hex(payload).ok_or_else(invalid)?;
+        let signature = from_hex(signature).ok_or_else(invalid)?;
+
+        if self.mac(&payload).verify_slice(&signature).is_err() {
+            return Err(ImageError::InvalidPresign("bad signature".to_string()));
+        }
+
+        let claims: PresignClaims = serde_json::from_slice(&payload).map_err(|_| invalid())?;
+        if claims.expires_at <= OffsetDateTime::now_utc().unix_timestamp() {
+            return Err(ImageError::InvalidPresign("token expired".to_string()));
+        }
+        if claims.nonce.is_empty() || self.used.contains_key(&claims.nonce) {
+            return Err(ImageError::InvalidPresign("token already used".to_string()));
+        }
+        Ok(claims)
+    }
+
+    /// Marks the token `claims` came from as used. Fails if another request
+    /// got there first, so each token uploads at most once.
+    pub fn consume(&self, claims: &PresignClaims) -> Result<(), ImageError> {
+        if self
+            .used
+            .entry(claims.nonce.clone())
+            .or_insert(())
+            .is_fresh()
+        {
+            Ok(())
+        } else {
+            Err(ImageError::InvalidPresign("token already used".to_string()))
+        }
+    }
+
+    fn hmac(&self, message: &[u8]) -> Vec<u8> {
+        self.mac(message).finalize().into_bytes().to_vec()
+    }
+
+    fn mac(&self, message: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("any key length");
+        mac.update(message);
+        mac
+    }
+}
+
+/// HMAC-SHA256, also used to sign webhook deliveries.
+pub fn hmac_sha256(secret: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("any key length");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(expires_in: i64) -> PresignClaims {
+        PresignClaims {
+            username: "alice".to_string(),
+            content_type: "image/png".to_string(),
+            max_size_bytes: 1024,
+            expires_at: OffsetDateTime::now_utc().unix_timestamp() + expires_in,
+            tags: vec!["neko".to_string()],
+            is_admin: false,
+            nonce: "n1".to_string(),
+        }
+    }
+
+    fn presigner() -> Presigner {
+        Presigner::new("secret", Duration::from_secs(900))
+    }
+
+    fn rejection(result: Result<PresignClaims, ImageError>) -> String {
+        match result {
+            Err(ImageError::InvalidPresign(msg)) => msg,
+            other => panic!("expected InvalidPresign, got {:?}", other.map(|c| c.nonce)),
+        }
+    }
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(
+            to_hex(&mac),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn signed_token_verifies() {
+        let presigner = presigner();
+        let claims = presigner.verify(&presigner.sign(&claims(60))).unwrap();
+        assert_eq!(claims.username, "alice");
+        assert_eq!(claims.tags, vec!["neko"]);
+    }
+
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let presigner = presigner();
+        let token = presigner.sign(&claims(60));
+        let (payload, signature) = token.split_once('.').unwrap();
+
+        // a payload raising the size limit, under the old signature
+        let mut forged = claims(60);
+        forged.max_size_bytes = u64::MAX;
+        let forged_payload = to_hex(&serde_json::to_vec(&forged).unwrap());
+        let forged_token = format!("{}.{}", forged_payload, signature);
+        assert_eq!(rejection(presigner.verify(&forged_token)), "bad signature");
+
+        let mut flipped = from_hex(signature).unwrap();
+        flipped[0] ^= 1;
+        let flipped_token = format!("{}.{}", payload, to_hex(&flipped));
+        assert_eq!(rejection(presigner.verify(&flipped_token)), "bad signature");
+
+        let other = Presigner::new("other secret", Duration::from_secs(900));
+        assert_eq!(rejection(other.verify(&token)), "bad signature");
+
+        assert_eq!(rejection(presigner.verify(payload)), "malformed token");
+        assert_eq!(rejection(presigner.verify("zz.zz")), "malformed token");
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let presigner = presigner();
+        let token = presigner.sign(&claims(-1));
+        assert_eq!(rejection(presigner.verify(&token)), "token expired");
+    }
+
+    #[test]
+    fn tokens_are_single_use() {
+        let presigner = presigner();
+        let token = presigner.sign(&claims(60));
+        let first = presigner.verify(&token).unwrap();
+        let racing = presigner.verify(&token).unwrap();
+
+        presigner.consume(&first).unwrap();
+        assert!(matches!(
+            presigner.consume(&racing),
+            Err(ImageError::InvalidPresign(_))
+        ));
+        assert_eq!(rejection(presigner.verify(&token)), "token already used");
+
+        // other tokens are unaffected
+        let mut next = claims(60);
+        next.nonce = "n2".to_string();
+        assert!(presigner.verify(&presigner.sign(&next)).is_ok());
+    }
+}
@@ -0,0 +1,147 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use time::OffsetDateTime;
+use tracing::warn;
+
+const MAX_SLOW_QUERIES: usize = 100;
+const SQL_PREFIX_LEN: usize = 120;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SlowQuery {
+    pub sql_prefix: String,
+    pub duration_ms: u64,
+    #[serde(with = "time::serde::rfc3339")]
+    pub occurred_at: OffsetDateTime,
+}
+
+/// Keeps the slowest queries seen since startup, bounded to `MAX_SLOW_QUERIES`,
+/// and warns about any taking at least `SLOW_MS`.
+#[derive(Clone, Default)]
+pub struct QueryLog {
+    entries: Arc<Mutex<VecDeque<SlowQuery>>>,
+    warn_ms: Option<u64>,
+}
+
+impl QueryLog {
+    pub fn new(warn_ms: Option<u64>) -> Self {
+        Self {
+            entries: Arc::default(),
+            warn_ms,
+        }
+    }
+
+    pub fn record(&self, sql: &str, duration_ms: u64) {
+        let entry = SlowQuery {
+            sql_prefix: sql
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .chars()
+                .take(SQL_PREFIX_LEN)
+                .collect(),
+            duration_ms,
+            occurred_at: OffsetDateTime::now_utc(),
+        };
+        if self.warn_ms.is_some_and(|warn_ms| duration_ms >= warn_ms) {
+            warn!("Slow query took {}ms: {}", duration_ms, entry.sql_prefix);
+        }
+
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() < MAX_SLOW_QUERIES {
+            entries.push_back(entry);
+            return;
+        }
+
+        // full: evict the fastest entry if the new one is slower
+        if let Some((idx, fastest)) = entries
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, q)| q.duration_ms)
+        {
+            if fastest.duration_ms < duration_ms {
+                entries.remove(idx);
+                entries.push_back(entry);
+            }
+        }
+    }
+
+    /// Returns queries at or above `threshold_ms`, slowest first.
+    pub fn slowest(&self, threshold_ms: u64, limit: usize) -> Vec<SlowQuery> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let mut queries: Vec<SlowQuery> = entries
+            .iter()
+            .filter(|q| q.duration_ms >= threshold_ms)
+            .cloned()
+            .collect();
+        queries.sort_by_key(|q| std::cmp::Reverse(q.duration_ms));
+        queries.truncate(limit);
+        queries
+    }
+}
+
+/// Times a single query and records it in the `QueryLog` when dropped.
+pub struct QueryTimer<'a> {
+    log: &'a QueryLog,
+    sql: &'a str,
+    started: Instant,
+}
+
+impl<'a> QueryTimer<'a> {
+    pub fn start(log: &'a QueryLog, sql: &'a str) -> Self {
+        Self {
+            log,
+            sql,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Drop for QueryTimer<'_> {
+    fn drop(&mut self) {
+        self.log
+            .record(self.sql, self.started.elapsed().as_millis() as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::capture_logs;
+    use std::time::Duration;
+
+    #[test]
+    fn queries_at_the_threshold_are_warned_about() {
+        let logs = capture_logs();
+        let log = QueryLog::new(Some(20));
+        {
+            let _timer = QueryTimer::start(&log, "SELECT  fast\n FROM images");
+        }
+        {
+            let _timer = QueryTimer::start(&log, "SELECT slow FROM images");
+            std::thread::sleep(Duration::from_millis(30));
+        }
+
+        let contents = logs.contents();
+        assert_eq!(contents.matches("Slow query").count(), 1, "{}", contents);
+        assert!(contents.contains("SELECT slow FROM images"));
+        // every query is kept, slowest first and whitespace collapsed
+        let kept: Vec<String> = log
+            .slowest(0, 10)
+            .into_iter()
+            .map(|query| query.sql_prefix)
+            .collect();
+        assert_eq!(kept, ["SELECT slow FROM images", "SELECT fast FROM images"]);
+        assert_eq!(log.slowest(20, 10).len(), 1);
+    }
+
+    #[test]
+    fn nothing_is_warned_about_without_a_threshold() {
+        let logs = capture_logs();
+        let log = QueryLog::new(None);
+        log.record("SELECT 1", 60_000);
+        assert!(logs.contents().is_empty());
+        assert_eq!(log.slowest(0, 10).len(), 1);
+    }
+}
@@ -0,0 +1,38 @@
+//! Daily URL download quotas. Days are UTC, so every quota resets at
+//! midnight UTC.
+
+use std::fmt;
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, OffsetDateTime, Time};
+
+#[derive(Debug, Clone)]
+pub struct QuotaExceeded {
+    /// Bytes the key may still download today
+    pub remaining: u64,
+    pub resets_at: OffsetDateTime,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Daily URL download quota exceeded, {} bytes left until {}",
+            self.remaining,
+            self.resets_at.format(&Rfc3339).map_err(|_| fmt::Error)?
+        )
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// The key usage is counted under, e.g. `2025-01-22`.
+pub fn today() -> String {
+    OffsetDateTime::now_utc().date().to_string()
+}
+
+pub fn next_reset() -> OffsetDateTime {
+    let now = OffsetDateTime::now_utc();
+    (now.date() + Duration::days(1))
+        .with_time(Time::MIDNIGHT)
+        .assume_utc()
+}
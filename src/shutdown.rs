@@ -0,0 +1,120 @@
+use crate::temp_files;
+use std::future::Future;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+/// Resolves on the first SIGINT or SIGTERM. Docker stops containers with
+/// SIGTERM, Ctrl-C sends SIGINT.
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for SIGINT: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received SIGINT"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
+}
+
+/// Stops the server taking new connections, waits up to `timeout` for the
+/// requests `in_flight` counts, then sweeps the temp files that downloads
+/// cut off by the shutdown leave behind. Returns how many requests were
+/// abandoned.
+pub async fn drain(
+    stop: oneshot::Sender<()>,
+    server: impl Future,
+    timeout: Duration,
+    in_flight: impl Fn() -> usize,
+    images_dir: &Path,
+) -> usize {
+    let draining_at_signal = in_flight();
+    info!(
+        "Shutting down, draining {} in-flight requests for up to {}s",
+        draining_at_signal,
+        timeout.as_secs()
+    );
+    let _ = stop.send(());
+    let left = match tokio::time::timeout(timeout, server).await {
+        Ok(_) => 0,
+        Err(_) => in_flight(),
+    };
+    info!(
+        "Drained {} requests, abandoned {}",
+        draining_at_signal.saturating_sub(left),
+        left
+    );
+
+    match temp_files::sweep(images_dir, Duration::ZERO) {
+        Ok(removed) => info!("Removed {} temp files", removed),
+        Err(e) => warn!("Failed to sweep temp files: {}", e),
+    }
+    left
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_files::TEMP_PREFIX;
+
+    #[tokio::test]
+    async fn drained_servers_abandon_nothing_and_temp_files_are_swept() {
+        let dir = tempfile::tempdir().unwrap();
+        let temp = dir.path().join(format!("{}download", TEMP_PREFIX));
+        let image = dir.path().join("kept.png");
+        std::fs::write(&temp, b"partial").unwrap();
+        std::fs::write(&image, b"image").unwrap();
+
+        let (stop, stopped) = oneshot::channel();
+        let server = async {
+            stopped.await.unwrap();
+        };
+        let left = drain(stop, server, Duration::from_secs(5), || 1, dir.path()).await;
+        assert_eq!(left, 0);
+        assert!(!temp.exists());
+        assert!(image.exists());
+    }
+
+    #[tokio::test]
+    async fn requests_still_running_at_the_timeout_are_abandoned() {
+        let dir = tempfile::tempdir().unwrap();
+        let temp = dir.path().join(format!("{}download", TEMP_PREFIX));
+        std::fs::write(&temp, b"partial").unwrap();
+
+        let (stop, stopped) = oneshot::channel::<()>();
+        let started = std::time::Instant::now();
+        let left = drain(
+            stop,
+            std::future::pending::<()>(),
+            Duration::from_millis(50),
+            || 2,
+            dir.path(),
+        )
+        .await;
+        assert_eq!(left, 2);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        // the server was still told to stop, and the sweep still ran
+        assert!(stopped.await.is_ok());
+        assert!(!temp.exists());
+    }
+}
@@ -0,0 +1,59 @@
+use crate::temp_files::TEMP_PREFIX;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+pub const SIDECAR_EXTENSION: &str = "json";
+
+/// Everything needed to restore an image's database row, written next to the
+/// image as `<filename>.json` so tags survive a lost `images.db`. Fields such
+/// as rating or source live in `metadata`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Sidecar {
+    pub filename: String,
+    pub hash: String,
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    pub created_at: String,
+    pub modified_at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_format: Option<String>,
+}
+
+pub fn sidecar_path(images_dir: &Path, filename: &str) -> PathBuf {
+    images_dir.join(format!("{}.{}", filename, SIDECAR_EXTENSION))
+}
+
+pub fn is_sidecar(filename: &str) -> bool {
+    Path::new(filename)
+        .extension()
+        .is_some_and(|ext| ext == SIDECAR_EXTENSION)
+}
+
+/// Writes to a temporary file first and renames it into place, so a crash
+/// never leaves a half-written sidecar behind.
+pub fn write(images_dir: &Path, sidecar: &Sidecar) -> Result<()> {
+    let path = sidecar_path(images_dir, &sidecar.filename);
+    let temp_path = images_dir.join(format!("{}{}.sidecar", TEMP_PREFIX, Uuid::new_v4()));
+    std::fs::write(&temp_path, serde_json::to_vec_pretty(sidecar)?)?;
+    if let Err(e) = std::fs::rename(&temp_path, &path) {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+pub fn read(path: &Path) -> Result<Sidecar> {
+    Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+}
+
+pub fn remove(images_dir: &Path, filename: &str) -> Result<()> {
+    let path = sidecar_path(images_dir, filename);
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
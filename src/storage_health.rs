@@ -0,0 +1,197 @@
+use crate::store::ImageStore;
+use serde_json::json;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::{debug, error, info, warn};
+
+// sqlite/io error text for the failures that won't go away on retry
+const STORAGE_ERROR_PATTERNS: [&str; 5] = [
+    "readonly database",
+    "read-only file system",
+    "database or disk is full",
+    "no space left on device",
+    "disk i/o error",
+];
+
+/// Watches for writes failing because the database can no longer be written
+/// (read-only file, full disk) and switches the server to read-only mode
+/// until a probe write succeeds again.
+#[derive(Clone)]
+pub struct StorageHealth {
+    degraded: Arc<AtomicBool>,
+    failures: Arc<Mutex<Vec<Instant>>>,
+    failure_threshold: usize,
+    failure_window: Duration,
+    alert_webhook: Option<Arc<String>>,
+    client: reqwest::Client,
+}
+
+impl StorageHealth {
+    pub fn new(
+        failure_threshold: usize,
+        failure_window: Duration,
+        alert_webhook: Option<String>,
+    ) -> Self {
+        Self {
+            degraded: Arc::new(AtomicBool::new(false)),
+            failures: Arc::new(Mutex::new(Vec::new())),
+            failure_threshold: failure_threshold.max(1),
+            failure_window,
+            alert_webhook: alert_webhook.map(Arc::new),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::SeqCst)
+    }
+
+    pub fn is_storage_error(message: &str) -> bool {
+        let message = message.to_lowercase();
+        STORAGE_ERROR_PATTERNS.iter().any(|p| message.contains(p))
+    }
+
+    /// Counts a failed write. Only read-only/disk-full errors count, and
+    /// enough of them in a row within the window trips read-only mode.
+    pub fn record_failure(&self, message: &str) {
+        if !Self::is_storage_error(message) {
+            return;
+        }
+
+        let now = Instant::now();
+        let consecutive = {
+            let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+            failures.retain(|&at| now.duration_since(at) <= self.failure_window);
+            failures.push(now);
+            failures.len()
+        };
+        warn!(
+            "Storage write failure {}/{}: {}",
+            consecutive, self.failure_threshold, message
+        );
+
+        if consecutive >= self.failure_threshold && !self.degraded.swap(true, Ordering::SeqCst) {
+            error!(
+                "Storage is unwritable, switching to read-only mode: {}",
+                message
+            );
+            self.alert("storage_degraded", message);
+        }
+    }
+
+    pub fn record_success(&self) {
+        self.failures
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+
+    fn recover(&self) {
+        self.record_success();
+        if self.degraded.swap(false, Ordering::SeqCst) {
+            info!("Storage is writable again, leaving read-only mode");
+            self.alert("storage_recovered", "probe write succeeded");
+        }
+    }
+
+    fn alert(&self, event: &'static str, detail: &str) {
+        let Some(url) = self.alert_webhook.clone() else {
+            return;
+        };
+
+        let payload = json!({
+            "event": event,
+            "detail": detail,
+            "timestamp": OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default()
+        });
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            let request = client
+                .post(url.as_str())
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(payload.to_string());
+            if let Err(e) = request.send().await {
+                warn!("Failed to send storage alert to webhook: {}", e);
+            }
+        });
+    }
+
+    /// While degraded, periodically attempts a small write and leaves
+    /// read-only mode once it succeeds.
+    pub fn spawn_recovery_probe(&self, store: ImageStore, interval: Duration) {
+        let health = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if !health.is_degraded() {
+                    continue;
+                }
+
+                let store = store.clone();
+                match tokio::task::spawn_blocking(move || store.probe_write()).await {
+                    Ok(Ok(())) => health.recover(),
+                    Ok(Err(e)) => debug!("Storage probe write failed: {}", e),
+                    Err(e) => warn!("Storage probe task failed: {}", e),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::with_writable_storage;
+    use crate::test_support::temp_store;
+    use warp::Filter;
+
+    #[tokio::test]
+    async fn read_only_database_trips_read_only_mode_until_a_probe_succeeds() {
+        let (dir, store) = temp_store();
+        let read_only = store.with_read_only_database(&dir.path().join("images.db"));
+        let health = StorageHealth::new(3, Duration::from_secs(60), None);
+
+        for _ in 0..2 {
+            let e = read_only.probe_write().unwrap_err();
+            health.record_failure(&e.to_string());
+        }
+        assert!(!health.is_degraded());
+        // errors of other kinds don't count towards the threshold
+        health.record_failure("UNIQUE constraint failed: images.hash");
+        assert!(!health.is_degraded());
+        let e = read_only.probe_write().unwrap_err();
+        health.record_failure(&e.to_string());
+        assert!(health.is_degraded());
+
+        let writes = with_writable_storage(health.clone()).map(|| "written");
+        let rejection = warp::test::request().filter(&writes).await.unwrap_err();
+        assert!(matches!(
+            rejection.find::<crate::error::ImageError>(),
+            Some(crate::error::ImageError::StorageDegraded)
+        ));
+
+        // the file is writable again
+        health.spawn_recovery_probe(store, Duration::from_millis(10));
+        for _ in 0..100 {
+            if !health.is_degraded() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!health.is_degraded());
+        assert!(warp::test::request().filter(&writes).await.is_ok());
+    }
+
+    #[test]
+    fn failures_outside_the_window_are_forgotten() {
+        let health = StorageHealth::new(2, Duration::ZERO, None);
+        health.record_failure("attempt to write a readonly database");
+        std::thread::sleep(Duration::from_millis(5));
+        health.record_failure("attempt to write a readonly database");
+        assert!(!health.is_degraded());
+    }
+}
@@ -1,16 +1,45 @@
+use crate::byte_size;
+use crate::cache::TagCache;
+use crate::canonical::{self, CanonicalFormat};
+use crate::change_log::ChangeEvent;
 use crate::config::Config;
-use crate::models::{ApiKey, DimensionFilter, ImageFilters, ImageResponse, PathType, SizeFilter};
+use crate::derived_cache::{DerivedCacheStats, DerivedFiles};
+use crate::exif;
+use crate::heic::{self, HeicConversion};
+use crate::host_throttle::HostThrottle;
+use crate::migrations;
+use crate::models::{
+    ApiKey, ApiKeyScope, AppliedMigration, BackfillResult, BrokenImageEntry, CatalogEntry,
+    CatalogManifest, ChangeEntry, Collection, CopyTagsResult, DefaultFilters, DimensionFilter,
+    ExportCounts, ExportManifest, FileInfo, GenerateApiKeyRequest, GeoLocation, HashTagChange,
+    HashTagChangeResult, IdempotentResponse, ImageCursor, ImageExists, ImageFilters, ImageKey,
+    ImageResponse, ImageSort, ImportZipResult, KeyWebhook, PathType, SearchFilters,
+    SelectionStrategy, SidecarWriteResult, SizeBucket, SizeFilter, SkippedExport, SortOrder,
+    TagMapping, TagMatch, TagRemapEntry, TagRemapStatus, UpdateApiKeyRequest, UrlStyle,
+};
+use crate::phash::{compute_phash, PhashIndex};
+use crate::query_log::{QueryLog, QueryTimer, SlowQuery};
+use crate::quota::{self, QuotaExceeded};
+use crate::sidecar::{self, Sidecar};
+use crate::temp_files::{self, TEMP_PREFIX};
+use crate::url_guard;
 use anyhow::{anyhow, Result};
 use bytes::Bytes;
+use dashmap::DashMap;
 use futures_util::StreamExt;
-use image::{GenericImageView, ImageFormat};
+use image::{DynamicImage, GenericImageView, ImageFormat, ImageOutputFormat};
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{params, Error as SqliteError, OptionalExtension};
+use rusqlite::types::Value;
+use rusqlite::{params, Error as SqliteError, OptionalExtension, TransactionBehavior};
 use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::io::Read;
 use std::path::PathBuf;
-use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use time::format_description::well_known::Rfc3339;
 use time::OffsetDateTime;
 use tokio::io::AsyncWriteExt;
@@ -19,8 +48,21 @@ use url::Url;
 use uuid::Uuid;
 
 const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024; // 10 MiB
-const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
-const MAX_REDIRECTS: u32 = 5;
+pub const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+// used when a 429 has no usable Retry-After
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(30);
+// each id is bound twice (filename and hash), well under sqlite's limit
+const EXISTS_CHUNK_SIZE: usize = 500;
+const BACKFILL_BATCH_SIZE: usize = 100;
+const RANDOM_DRAW_ATTEMPTS: u32 = 3;
+const CONVERTED_JPEG_QUALITY: u8 = 85;
+const CATALOG_MANIFEST: &str = "catalog.json";
+// a catalog is held in memory while it's imported, so a ZIP that inflates
+// past these is refused rather than allowed to exhaust it
+const MAX_ZIP_ENTRIES: usize = 10_000;
+const MAX_ZIP_UNCOMPRESSED_BYTES: u64 = 512 * 1024 * 1024; // 512 MiB
+
+type ZipFiles = Vec<(String, Vec<u8>)>;
 
 // Allowed content types for images
 const ALLOWED_CONTENT_TYPES: [&str; 7] = [
@@ -33,23 +75,6 @@ const ALLOWED_CONTENT_TYPES: [&str; 7] = [
     "binary/octet-stream", // Some servers don't set proper content type
 ];
 
-const BLOCKED_URL_PATTERNS: [&str; 12] = [
-    "localhost",
-    "127.",
-    "0.0.0.0",
-    // Private network ranges (RFC 1918)
-    "10.",
-    "172.16.",
-    "172.17.",
-    "172.18.",
-    "172.19.",
-    "172.20.",
-    "172.21.",
-    "192.168.",
-    // Link-local addresses (RFC 3927)
-    "169.254.",
-];
-
 const BLOCKED_HOSTNAMES: [&str; 4] = [
     "metadata.google.internal",     // Google Cloud
     "169.254.169.254",              // AWS
@@ -57,10 +82,142 @@ const BLOCKED_HOSTNAMES: [&str; 4] = [
     "metadata.platformequinix.com", // Equinix Metal
 ];
 
+const EMPTY_TAG_CONDITION: &str =
+    "NOT EXISTS (SELECT 1 FROM image_tags it WHERE it.tag_id = tags.id)";
+
+/// What `sync_database` needs to insert a file found on disk.
+struct ScannedFile {
+    hash: String,
+    width: u32,
+    height: u32,
+    size_bytes: u64,
+    phash: Option<u64>,
+    format: Option<String>,
+    location: Option<(f64, f64)>,
+    modified_at: String,
+}
+
+impl ScannedFile {
+    fn read(path: &std::path::Path, strict: bool) -> Result<Self> {
+        let (hash, width, height, size_bytes, phash) =
+            ImageStore::read_file_metadata(path, strict)?;
+        let modified_at = std::fs::metadata(path)?
+            .modified()
+            .map(OffsetDateTime::from)
+            .unwrap_or_else(|_| OffsetDateTime::now_utc())
+            .format(&Rfc3339)?;
+        Ok(Self {
+            hash,
+            width,
+            height,
+            size_bytes,
+            phash,
+            format: ImageStore::read_file_format(path),
+            location: ImageStore::read_file_location(path),
+            modified_at,
+        })
+    }
+}
+
+/// Returned when an image's file can't be read or decoded. The image has
+/// been flagged broken and is left out of random draws and listings until
+/// its file is replaced or refreshed.
+#[derive(Debug)]
+pub struct BrokenImage {
+    pub hash: String,
+    pub width: u32,
+    pub height: u32,
+    pub reason: String,
+}
+
+impl fmt::Display for BrokenImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Image {} is broken: {}", self.hash, self.reason)
+    }
+}
+
+impl std::error::Error for BrokenImage {}
+
+/// Returned when a fetched file's SHA-256 isn't the `expected_hash` the
+/// caller asked for.
+#[derive(Debug, Clone)]
+pub struct HashMismatch {
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for HashMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "File hash {} does not match the expected {}",
+            self.actual, self.expected
+        )
+    }
+}
+
+impl std::error::Error for HashMismatch {}
+
+/// Returned when an added file has the same contents as a stored image.
+#[derive(Debug, Clone)]
+pub struct DuplicateImage {
+    pub hash: String,
+    pub uploaded_by: Option<String>,
+}
+
+impl fmt::Display for DuplicateImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Image already exists: {}", self.hash)
+    }
+}
+
+impl std::error::Error for DuplicateImage {}
+
+/// Returned when adding a tag that doesn't exist yet while the server
+/// already holds `MAX_DISTINCT_TAGS` tags.
+#[derive(Debug, Clone)]
+pub struct TagLimitReached {
+    pub limit: u64,
+}
+
+impl fmt::Display for TagLimitReached {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Distinct tag limit of {} reached", self.limit)
+    }
+}
+
+impl std::error::Error for TagLimitReached {}
+
 pub struct ImageStore {
     pool: Pool<SqliteConnectionManager>,
     images_dir: PathBuf,
     base_url: String,
+    url_style: UrlStyle,
+    phash_index: PhashIndex,
+    heic: HeicConversion,
+    canonical: Option<CanonicalFormat>,
+    keep_empty_tags: bool,
+    max_distinct_tags: Option<u64>,
+    blocked_tags: Arc<Vec<String>>,
+    write_sidecars: bool,
+    strict_decode: bool,
+    url_allowlist: Arc<Vec<String>>,
+    max_redirects: u32,
+    host_throttle: HostThrottle,
+    tag_cache: TagCache,
+    query_log: QueryLog,
+    derived: Arc<DerivedFiles>,
+    /// Filenames with a replacement in progress.
+    replacing: Arc<DashMap<String, Arc<Mutex<()>>>>,
+}
+
+/// One row of a listing query, before it becomes an `ImageResponse`.
+struct ImageRow {
+    filename: String,
+    hash: String,
+    created_at: String,
+    modified_at: String,
+    original_format: Option<String>,
 }
 
 impl ImageStore {
@@ -72,111 +229,540 @@ impl ImageStore {
         std::fs::create_dir_all(&images_dir)?;
         info!("Ensuring images directory exists at {:?}", images_dir);
 
-        let conn = pool.get()?;
-
-        // Create tables if they don't exist
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS images (
-                hash TEXT PRIMARY KEY,
-                filename TEXT NOT NULL UNIQUE,
-                created_at TEXT NOT NULL,
-                modified_at TEXT NOT NULL,
-                width INTEGER,
-                height INTEGER,
-                size_bytes INTEGER
-            )",
-            [],
-        )?;
-
-        // Create tags table
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS tags (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                name TEXT NOT NULL UNIQUE
-            )",
-            [],
-        )?;
-
-        // Create image_tags junction table
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS image_tags (
-                image_hash TEXT NOT NULL,
-                tag_id INTEGER NOT NULL,
-                PRIMARY KEY (image_hash, tag_id),
-                FOREIGN KEY (image_hash) REFERENCES images(hash),
-                FOREIGN KEY (tag_id) REFERENCES tags(id)
-            )",
-            [],
-        )?;
-
-        // First create the api_keys table if it doesn't exist
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS api_keys (
-                key TEXT PRIMARY KEY,
-                username TEXT NOT NULL UNIQUE,
-                created_at TEXT NOT NULL,
-                last_used_at TEXT,
-                is_active BOOLEAN NOT NULL DEFAULT 1,
-                requests_per_second INTEGER,
-                max_batch_size INTEGER
-            )",
-            [],
-        )?;
-
-        // Then add the requests_per_second column if it doesn't exist
-        let columns = conn.query_row(
-            "SELECT COUNT(*) FROM pragma_table_info('api_keys') WHERE name='requests_per_second'",
-            [],
-            |row| row.get::<_, i32>(0),
-        )?;
-
-        if columns == 0 {
-            info!("Adding requests_per_second column to api_keys table");
-            conn.execute(
-                "ALTER TABLE api_keys ADD COLUMN requests_per_second INTEGER",
-                [],
-            )?;
+        let mut conn = pool.get()?;
+        let version = migrations::run(&mut conn)?;
+        info!("Database schema is at version {}", version);
+        if config.skip_schema_check {
+            warn!("Skipping the database schema check");
+        } else {
+            migrations::check_schema(&conn)?;
         }
 
-        conn.execute(
-            "UPDATE images SET width = NULL, height = NULL WHERE width IS NULL",
-            [],
-        )?;
-
         let base_url = format!("{}/images", config.get_base_url());
 
+        let phashes = conn
+            .prepare("SELECT phash, hash FROM images WHERE phash IS NOT NULL AND hash IS NOT NULL")?
+            .query_map([], |row| Ok((row.get::<_, i64>(0)? as u64, row.get(1)?)))?
+            .collect::<Result<Vec<(u64, String)>, _>>()?;
+        info!("Loaded {} perceptual hashes", phashes.len());
+
+        let derived_dir = images_dir.with_file_name("derived");
         let store = Self {
             pool,
             images_dir,
             base_url,
+            url_style: config.url_style(),
+            phash_index: PhashIndex::new(phashes),
+            heic: HeicConversion::from_config(&config.heic_convert_format, config.heic_quality)?,
+            canonical: config
+                .canonical_format
+                .as_deref()
+                .map(|format| CanonicalFormat::from_config(format, config.canonical_quality))
+                .transpose()?,
+            keep_empty_tags: config.keep_empty_tags,
+            max_distinct_tags: config.max_distinct_tags,
+            blocked_tags: Arc::new(config.blocked_tags.clone()),
+            write_sidecars: config.write_sidecars,
+            strict_decode: config.strict_decode,
+            url_allowlist: Arc::new(config.url_allowlist.clone()),
+            max_redirects: config.max_redirects,
+            host_throttle: HostThrottle::new(
+                config.download_host_concurrency,
+                config.download_host_interval(),
+            ),
+            tag_cache: TagCache::new(config.tag_cache_size, config.cache_ttl()),
+            query_log: QueryLog::new(config.slow_ms),
+            derived: Arc::new(DerivedFiles::new(
+                derived_dir,
+                config.derived_cache_max_bytes,
+            )),
+            replacing: Arc::new(DashMap::new()),
         };
 
         info!("Syncing database with existing images...");
-        store.sync_database()?;
+        store.sync_database(config.sync_concurrency())?;
 
         Ok(store)
     }
 
-    fn sync_database(&self) -> Result<()> {
-        let conn = self.pool.get()?;
-        let entries = std::fs::read_dir(&self.images_dir)?;
-        let mut count = 0;
+    /// Adds rows for image files that aren't in the database yet, e.g. ones
+    /// copied into the directory while the server was down. Files are hashed
+    /// and decoded on `workers` threads, then inserted in batches.
+    fn sync_database(&self, workers: usize) -> Result<()> {
+        if self.write_sidecars {
+            self.restore_from_sidecars()?;
+        }
+
+        self.backfill_formats()?;
+
+        let started = Instant::now();
+        let mut conn = self.pool.get()?;
+        let known = conn
+            .prepare("SELECT filename FROM images")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<HashSet<String>, _>>()?;
 
-        for entry in entries {
+        let mut pending = Vec::new();
+        for entry in std::fs::read_dir(&self.images_dir)? {
             let entry = entry?;
-            let filename = entry.file_name();
-            let filename_str = filename.to_string_lossy();
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let filename = entry.file_name().to_string_lossy().into_owned();
+            if sidecar::is_sidecar(&filename)
+                || temp_files::is_temp(&filename)
+                || known.contains(&filename)
+            {
+                continue;
+            }
+            pending.push(filename);
+        }
+        if pending.is_empty() {
+            info!("Database is in sync with the images directory");
+            return Ok(());
+        }
 
-            match conn.execute(
-                "INSERT OR IGNORE INTO images (filename) VALUES (?)",
-                [filename_str.as_ref()],
-            ) {
-                Ok(_) => count += 1,
-                Err(e) => warn!("Failed to sync file {}: {}", filename_str, e),
+        let workers = workers.clamp(1, pending.len());
+        info!(
+            "Scanning {} new images with {} workers",
+            pending.len(),
+            workers
+        );
+        let scanned = self.scan_files(&pending, workers);
+
+        let mut added = 0;
+        let mut failed = 0;
+        for batch in scanned.chunks(BACKFILL_BATCH_SIZE) {
+            let tx = conn.transaction()?;
+            let mut phashes = Vec::new();
+
+            for (filename, scan) in batch {
+                let scan = match scan {
+                    Ok(scan) => scan,
+                    Err(e) => {
+                        warn!("Failed to sync file {}: {}", filename, e);
+                        failed += 1;
+                        continue;
+                    }
+                };
+                let inserted = tx.execute(
+                    "INSERT OR IGNORE INTO images (filename, hash, created_at, modified_at, width, height, size_bytes, phash, format) 
+                     VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                    params![
+                        filename,
+                        scan.hash,
+                        scan.modified_at,
+                        scan.modified_at,
+                        scan.width,
+                        scan.height,
+                        scan.size_bytes as i64,
+                        scan.phash.map(|phash| phash as i64),
+                        scan.format,
+                    ],
+                )?;
+                if inserted == 0 {
+                    warn!(
+                        "Failed to sync file {}: same content as an existing image",
+                        filename
+                    );
+                    failed += 1;
+                    continue;
+                }
+                Self::set_image_location(&tx, &scan.hash, scan.location)?;
+                Self::log_change(&tx, ChangeEvent::ImageAdded, &scan.hash)?;
+                phashes.push((scan.phash, scan.hash.clone()));
+            }
+
+            tx.commit()?;
+            added += phashes.len();
+            for (phash, hash) in phashes {
+                if let Some(phash) = phash {
+                    self.phash_index.insert(phash, hash);
+                }
+            }
+        }
+
+        info!(
+            "Synced {} new images with database ({} failed) in {:?}",
+            added,
+            failed,
+            started.elapsed()
+        );
+        Ok(())
+    }
+
+    /// Fills in the format, and dimensions if missing, of rows stored before
+    /// formats were recorded. Only file headers are read, and once every row
+    /// has a format this finds nothing to do.
+    fn backfill_formats(&self) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        let rows: Vec<(i64, String)> = conn
+            .prepare(
+                "SELECT rowid, filename FROM images
+                 WHERE (format IS NULL OR width IS NULL OR height IS NULL)
+                   AND broken_reason IS NULL",
+            )?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        info!("Filling in formats for {} images", rows.len());
+        let mut filled = 0;
+        for batch in rows.chunks(BACKFILL_BATCH_SIZE) {
+            let tx = conn.transaction()?;
+            for (rowid, filename) in batch {
+                let header = image::io::Reader::open(self.images_dir.join(filename))
+                    .and_then(|reader| reader.with_guessed_format())
+                    .map_err(anyhow::Error::from)
+                    .and_then(|reader| {
+                        let format = reader.format();
+                        let dimensions = reader.into_dimensions()?;
+                        check_dimensions(dimensions)?;
+                        Ok((format, dimensions))
+                    });
+                let (format, (width, height)) = match header {
+                    Ok(header) => header,
+                    Err(e) => {
+                        warn!("Failed to read the format of {}: {}", filename, e);
+                        continue;
+                    }
+                };
+                tx.execute(
+                    "UPDATE images SET format = ?, width = COALESCE(width, ?),
+                     height = COALESCE(height, ?) WHERE rowid = ?",
+                    params![format.map(Self::format_name), width, height, rowid],
+                )?;
+                filled += 1;
+            }
+            tx.commit()?;
+        }
+        info!("Filled in formats for {} of {} images", filled, rows.len());
+        Ok(())
+    }
+
+    /// Reads each file's metadata on up to `workers` threads, returning the
+    /// results in the order of `filenames`.
+    fn scan_files(
+        &self,
+        filenames: &[String],
+        workers: usize,
+    ) -> Vec<(String, Result<ScannedFile>)> {
+        scan_in_parallel(filenames, workers, |filename| {
+            ScannedFile::read(&self.images_dir.join(filename), self.strict_decode)
+        })
+    }
+
+    /// Recreates the rows, tags and metadata of images that have a sidecar
+    /// but are missing from the database, e.g. after `images.db` was lost.
+    fn restore_from_sidecars(&self) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        let mut restored = 0;
+
+        for entry in std::fs::read_dir(&self.images_dir)? {
+            let path = entry?.path();
+            if !path
+                .file_name()
+                .is_some_and(|name| sidecar::is_sidecar(&name.to_string_lossy()))
+            {
+                continue;
+            }
+
+            let sidecar = match sidecar::read(&path) {
+                Ok(sidecar) => sidecar,
+                Err(e) => {
+                    warn!("Skipping unreadable sidecar {:?}: {}", path, e);
+                    continue;
+                }
+            };
+            let exists: bool = conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM images WHERE filename = ? AND hash IS NOT NULL)",
+                [&sidecar.filename],
+                |row| row.get(0),
+            )?;
+            if exists {
+                continue;
+            }
+
+            let file_path = self.images_dir.join(&sidecar.filename);
+            if !file_path.exists() {
+                warn!("Sidecar for missing image {}, skipping", sidecar.filename);
+                continue;
+            }
+            let (hash, width, height, size_bytes, phash) =
+                match Self::read_file_metadata(&file_path, self.strict_decode) {
+                    Ok(metadata) => metadata,
+                    Err(e) => {
+                        warn!("Failed to read image {}: {}", sidecar.filename, e);
+                        continue;
+                    }
+                };
+            if hash != sidecar.hash {
+                warn!(
+                    "Sidecar hash for {} does not match the file, using the file's hash",
+                    sidecar.filename
+                );
+            }
+
+            let tx = conn.transaction()?;
+            tx.execute(
+                "DELETE FROM images WHERE filename = ? AND hash IS NULL",
+                [&sidecar.filename],
+            )?;
+            tx.execute(
+                "INSERT INTO images (filename, hash, created_at, modified_at, width, height, size_bytes, phash, original_format, format) 
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                params![
+                    sidecar.filename,
+                    hash,
+                    sidecar.created_at,
+                    sidecar.modified_at,
+                    width,
+                    height,
+                    size_bytes as i64,
+                    phash.map(|phash| phash as i64),
+                    sidecar.original_format,
+                    Self::read_file_format(&file_path),
+                ],
+            )?;
+            for tag in &sidecar.tags {
+                tx.execute("INSERT OR IGNORE INTO tags (name) VALUES (?)", [tag])?;
+                tx.execute(
+                    "INSERT OR IGNORE INTO image_tags (image_hash, tag_id) 
+                     SELECT ?, id FROM tags WHERE name = ?",
+                    params![hash, tag],
+                )?;
+            }
+            for (key, value) in &sidecar.metadata {
+                tx.execute(
+                    "INSERT OR REPLACE INTO image_metadata (image_hash, key, value) VALUES (?, ?, ?)",
+                    params![hash, key, value],
+                )?;
+            }
+            Self::set_image_location(&tx, &hash, Self::read_file_location(&file_path))?;
+            Self::log_change(&tx, ChangeEvent::ImageAdded, &hash)?;
+            tx.commit()?;
+
+            if let Some(phash) = phash {
+                self.phash_index.insert(phash, hash);
+            }
+            restored += 1;
+        }
+
+        if restored > 0 {
+            info!("Restored {} images from sidecars", restored);
+        }
+        Ok(())
+    }
+
+    fn load_sidecar(&self, image_hash: &str) -> Result<Option<Sidecar>> {
+        let conn = self.pool.get()?;
+        let row: Option<(String, String, String, Option<String>)> = conn
+            .query_row(
+                "SELECT filename, created_at, modified_at, original_format FROM images WHERE hash = ?",
+                [image_hash],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .optional()?;
+        let Some((filename, created_at, modified_at, original_format)) = row else {
+            return Ok(None);
+        };
+
+        let metadata = conn
+            .prepare("SELECT key, value FROM image_metadata WHERE image_hash = ?")?
+            .query_map([image_hash], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<BTreeMap<String, String>, _>>()?;
+
+        Ok(Some(Sidecar {
+            filename,
+            hash: image_hash.to_string(),
+            tags: self.get_image_tags(image_hash)?,
+            metadata,
+            created_at,
+            modified_at,
+            original_format,
+        }))
+    }
+
+    fn write_sidecar(&self, image_hash: &str) -> Result<()> {
+        if let Some(sidecar) = self.load_sidecar(image_hash)? {
+            sidecar::write(&self.images_dir, &sidecar)?;
+        }
+        Ok(())
+    }
+
+    /// Refreshes an image's sidecar after a write when `WRITE_SIDECARS` is
+    /// on. The database is the source of truth, so failures are only logged.
+    fn sync_sidecar(&self, image_hash: &str) {
+        if !self.write_sidecars {
+            return;
+        }
+        if let Err(e) = self.write_sidecar(image_hash) {
+            warn!("Failed to write sidecar for image {}: {}", image_hash, e);
+        }
+    }
+
+    /// Writes a sidecar for every image, e.g. when turning on `WRITE_SIDECARS`
+    /// for an existing library.
+    pub fn write_all_sidecars(&self) -> Result<SidecarWriteResult> {
+        let hashes = {
+            let conn = self.pool.get()?;
+            let mut stmt = conn.prepare("SELECT hash FROM images WHERE hash IS NOT NULL")?;
+            let hashes = stmt
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            hashes
+        };
+
+        let mut result = SidecarWriteResult::default();
+        for hash in hashes {
+            match self.write_sidecar(&hash) {
+                Ok(()) => result.written += 1,
+                Err(e) => {
+                    error!("Failed to write sidecar for image {}: {}", hash, e);
+                    result.failed += 1;
+                    result.errors.push(format!("{}: {}", hash, e));
+                }
+            }
+        }
+
+        info!(
+            "Wrote {} sidecars ({} failed)",
+            result.written, result.failed
+        );
+        Ok(result)
+    }
+
+    pub fn backfill_metadata(&self) -> Result<BackfillResult> {
+        let mut conn = self.pool.get()?;
+        let rows: Vec<(i64, String)> = {
+            let mut stmt = conn.prepare(
+                "SELECT rowid, filename FROM images 
+                 WHERE hash IS NULL OR width IS NULL OR height IS NULL OR size_bytes IS NULL 
+                    OR phash IS NULL OR format IS NULL",
+            )?;
+            let rows = stmt
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<Vec<_>, _>>()?;
+            rows
+        };
+
+        let mut result = BackfillResult {
+            scanned: rows.len(),
+            ..Default::default()
+        };
+        info!("Backfilling metadata for {} images", rows.len());
+
+        for (batch_idx, batch) in rows.chunks(BACKFILL_BATCH_SIZE).enumerate() {
+            let tx = conn.transaction()?;
+
+            for (rowid, filename) in batch {
+                let file_path = self.images_dir.join(filename);
+                if !file_path.exists() {
+                    warn!("Skipping backfill for missing file: {}", filename);
+                    result.skipped_missing += 1;
+                    continue;
+                }
+
+                let metadata = match Self::read_file_metadata(&file_path, self.strict_decode) {
+                    Ok(metadata) => metadata,
+                    Err(e) => {
+                        error!("Failed to read metadata for {}: {}", filename, e);
+                        result.failed += 1;
+                        result.errors.push(format!("{}: {}", filename, e));
+                        continue;
+                    }
+                };
+                let (hash, width, height, size_bytes, phash) = metadata;
+
+                // a row that can't be updated, e.g. a copy of a file another
+                // row already holds, is reported without failing the batch
+                match tx.query_row(
+                    "UPDATE images 
+                     SET hash = COALESCE(hash, ?), width = ?, height = ?, size_bytes = ?, phash = ?,
+                         format = ?
+                     WHERE rowid = ?
+                     RETURNING hash",
+                    params![
+                        hash,
+                        width,
+                        height,
+                        size_bytes as i64,
+                        phash.map(|phash| phash as i64),
+                        Self::read_file_format(&file_path),
+                        rowid
+                    ],
+                    |row| row.get::<_, String>(0),
+                ) {
+                    Ok(hash) => {
+                        if let Some(phash) = phash {
+                            self.phash_index.insert(phash, hash);
+                        }
+                        result.updated += 1;
+                    }
+                    Err(e) => {
+                        error!("Failed to backfill {}: {}", filename, e);
+                        result.failed += 1;
+                        result.errors.push(format!("{}: {}", filename, e));
+                    }
+                }
             }
+
+            tx.commit()?;
+            info!(
+                "Backfill progress: batch {} done, {}/{} rows processed",
+                batch_idx + 1,
+                (batch_idx * BACKFILL_BATCH_SIZE + batch.len()).min(rows.len()),
+                rows.len()
+            );
         }
 
-        info!("Synced {} images with database", count);
+        info!(
+            "Backfill complete: {} updated, {} missing, {} failed",
+            result.updated, result.skipped_missing, result.failed
+        );
+        Ok(result)
+    }
+
+    fn read_file_metadata(
+        path: &std::path::Path,
+        strict: bool,
+    ) -> Result<(String, u32, u32, u64, Option<u64>)> {
+        let size_bytes = std::fs::metadata(path)?.len();
+        let ((width, height), phash) = decode_file(path, strict)?;
+        let hash = Self::calculate_file_hash(path)?;
+        Ok((hash, width, height, size_bytes, phash))
+    }
+
+    fn read_file_location(path: &std::path::Path) -> Option<(f64, f64)> {
+        exif::gps_location(&std::fs::read(path).ok()?)
+    }
+
+    /// The format of a file's contents, whatever its extension says. Only
+    /// the header is read.
+    fn read_file_format(path: &std::path::Path) -> Option<String> {
+        image::io::Reader::open(path)
+            .ok()?
+            .with_guessed_format()
+            .ok()?
+            .format()
+            .map(Self::format_name)
+    }
+
+    /// Rejects a fetched file whose SHA-256 isn't the one the caller expected.
+    fn check_expected_hash(path: &std::path::Path, expected: Option<&str>) -> Result<()> {
+        let Some(expected) = expected else {
+            return Ok(());
+        };
+        let actual = Self::calculate_file_hash(path)?;
+        if actual != expected {
+            return Err(HashMismatch {
+                expected: expected.to_string(),
+                actual,
+            }
+            .into());
+        }
         Ok(())
     }
 
@@ -196,43 +782,23 @@ impl ImageStore {
         Ok(format!("{:x}", hasher.finalize()))
     }
 
-    async fn validate_url(&self, url: &str) -> Result<Url> {
+    /// A URL images may be downloaded from: a public one whose host is on
+    /// `URL_ALLOWLIST`.
+    pub async fn validate_url(&self, url: &str) -> Result<Url> {
         let parsed_url = Url::parse(url).map_err(|e| anyhow!("Invalid URL: {}", e))?;
-
-        if !["http", "https"].contains(&parsed_url.scheme()) {
-            return Err(anyhow!("Only HTTP(S) URLs are supported"));
-        }
-
         let host_str = parsed_url.host_str().unwrap_or_default();
-
-        for pattern in BLOCKED_URL_PATTERNS {
-            if host_str.contains(pattern) {
-                return Err(anyhow!("URL contains blocked pattern: {}", pattern));
-            }
-        }
-
-        for hostname in BLOCKED_HOSTNAMES {
-            if host_str.eq_ignore_ascii_case(hostname) {
-                return Err(anyhow!("URL hostname is blocked: {}", hostname));
-            }
-        }
-
-        if let Some(port) = parsed_url.port() {
-            match port {
-                22 | 23 | 25 | 445 | 3306 | 5432 | 27017 => {
-                    return Err(anyhow!("Port {} is not allowed", port));
-                }
-                _ => {}
-            }
+        if !host_allowed(&self.url_allowlist, host_str) {
+            return Err(anyhow!("URL host is not allowed: {}", host_str));
         }
 
-        Ok(parsed_url)
+        validate_public_url(url).await
     }
 
     async fn check_content_type(&self, client: &reqwest::Client, url: &Url) -> Result<()> {
         info!("Checking content type for URL: {}", url);
 
         let response = client.head(url.as_str()).send().await?;
+        self.pause_if_rate_limited(url, &response);
 
         if !response.status().is_success() {
             return Err(anyhow!("URL returned status code: {}", response.status()));
@@ -255,7 +821,12 @@ impl ImageStore {
                 .map_err(|_| anyhow!("Invalid content type header"))?
                 .to_lowercase();
 
-            if !ALLOWED_CONTENT_TYPES
+            if heic::HEIC_CONTENT_TYPES
+                .iter()
+                .any(|&t| content_type.contains(t))
+            {
+                heic::ensure_supported()?;
+            } else if !ALLOWED_CONTENT_TYPES
                 .iter()
                 .any(|&t| content_type.contains(t))
             {
@@ -269,56 +840,296 @@ impl ImageStore {
         Ok(())
     }
 
-    async fn download_image(&self, url: &str) -> Result<PathBuf> {
+    /// Pauses the host's download queue when it answered 429, for as long as
+    /// its `Retry-After` asks. Returns whether it did.
+    fn pause_if_rate_limited(&self, url: &Url, response: &reqwest::Response) -> bool {
+        if response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return false;
+        }
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok())
+            .map_or(DEFAULT_RETRY_AFTER, Duration::from_secs);
+        warn!(
+            "{} is rate limiting downloads, pausing it for {:?}",
+            url.host_str().unwrap_or_default(),
+            retry_after
+        );
+        self.host_throttle
+            .pause(url.host_str().unwrap_or_default(), retry_after);
+        true
+    }
+
+    /// Downloads `url` to a temp file. With `ingest_by` set, the bytes count
+    /// against that key's daily URL download quota.
+    async fn download_image(&self, url: &str, ingest_by: Option<&ApiKey>) -> Result<PathBuf> {
         let url = self.validate_url(url).await?;
 
+        let budget = match ingest_by {
+            Some(key) => match key.url_ingest_daily_bytes {
+                Some(limit) => {
+                    let remaining = limit.saturating_sub(self.url_ingest_used_today(&key.key)?);
+                    if remaining == 0 {
+                        return Err(QuotaExceeded {
+                            remaining,
+                            resets_at: quota::next_reset(),
+                        }
+                        .into());
+                    }
+                    Some((key.key.as_str(), remaining))
+                }
+                None => None,
+            },
+            None => None,
+        };
+
+        // redirects must stay on allowlisted hosts and public addresses too.
+        // Names are checked by the resolver when connecting, IPs only here.
         let client = reqwest::Client::builder()
             .timeout(DOWNLOAD_TIMEOUT)
-            .redirect(reqwest::redirect::Policy::limited(MAX_REDIRECTS as usize))
+            .redirect(redirect_policy(
+                self.url_allowlist.clone(),
+                self.max_redirects as usize,
+            ))
+            .dns_resolver(Arc::new(url_guard::PublicOnlyResolver))
             .build()?;
 
+        let _host_permit = self
+            .host_throttle
+            .acquire(url.host_str().unwrap_or_default())
+            .await;
         self.check_content_type(&client, &url).await?;
 
-        let temp_path = self.images_dir.join(format!("temp_{}", Uuid::new_v4()));
+        let temp_path = self
+            .images_dir
+            .join(format!("{}{}", TEMP_PREFIX, Uuid::new_v4()));
         info!("Downloading to temporary file: {:?}", temp_path);
 
         let response = client.get(url.as_str()).send().await?;
+        if self.pause_if_rate_limited(&url, &response) {
+            return Err(anyhow!("URL returned status code: {}", response.status()));
+        }
+
+        if let (Some((_, remaining)), Some(length)) = (budget, response.content_length()) {
+            if length > remaining {
+                return Err(QuotaExceeded {
+                    remaining,
+                    resets_at: quota::next_reset(),
+                }
+                .into());
+            }
+        }
 
         let mut file = tokio::fs::File::create(&temp_path).await?;
         let mut downloaded_size: u64 = 0;
         let mut stream = response.bytes_stream();
 
-        while let Some(chunk) = stream.next().await {
-            let chunk = chunk?;
-            downloaded_size += chunk.len() as u64;
+        let result = async {
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk?;
+                downloaded_size += chunk.len() as u64;
 
-            if downloaded_size > MAX_FILE_SIZE {
-                file.shutdown().await?;
-                tokio::fs::remove_file(&temp_path).await?;
-                return Err(anyhow!(
-                    "File too large: {} bytes (max {} bytes)",
-                    downloaded_size,
-                    MAX_FILE_SIZE
-                ));
+                if downloaded_size > MAX_FILE_SIZE {
+                    return Err(anyhow!(
+                        "File too large: {} bytes (max {} bytes)",
+                        downloaded_size,
+                        MAX_FILE_SIZE
+                    ));
+                }
+                if let Some((_, remaining)) = budget {
+                    if downloaded_size > remaining {
+                        return Err(QuotaExceeded {
+                            remaining: 0,
+                            resets_at: quota::next_reset(),
+                        }
+                        .into());
+                    }
+                }
+
+                file.write_all(&chunk).await?;
             }
+            Ok(())
+        }
+        .await;
+        file.shutdown().await?;
 
-            file.write_all(&chunk).await?;
+        // aborted downloads used the bandwidth too
+        if let Some((api_key, _)) = budget {
+            if let Err(e) = self.record_url_ingest(api_key, downloaded_size) {
+                warn!("Failed to record URL ingest usage: {}", e);
+            }
         }
 
-        file.shutdown().await?;
+        if let Err(e) = result {
+            tokio::fs::remove_file(&temp_path).await?;
+            return Err(e);
+        }
         info!("Download completed: {} bytes", downloaded_size);
 
         Ok(temp_path)
     }
 
-    pub async fn add_image(&self, path: &str, path_type: PathType) -> Result<String> {
-        match path_type {
-            PathType::Local => {
-                let src_path = std::path::Path::new(path);
-                info!("Validating local file: {}", path);
-
-                if !src_path.exists() {
-                    error!("File not found: {}", path);
+    /// Bytes `api_key` has downloaded through URL ingestion today.
+    pub fn url_ingest_used_today(&self, api_key: &str) -> Result<u64> {
+        let conn = self.pool.get()?;
+        let used: Option<i64> = conn
+            .query_row(
+                "SELECT url_ingest_bytes FROM api_key_usage WHERE api_key = ? AND day = ?",
+                params![api_key, quota::today()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(used.unwrap_or(0) as u64)
+    }
+
+    fn record_url_ingest(&self, api_key: &str, bytes: u64) -> Result<()> {
+        let conn = self.pool.get()?;
+        let today = quota::today();
+        conn.execute(
+            "INSERT INTO api_key_usage (api_key, day, url_ingest_bytes) VALUES (?, ?, ?)
+             ON CONFLICT (api_key, day) DO UPDATE SET url_ingest_bytes = url_ingest_bytes + excluded.url_ingest_bytes",
+            params![api_key, today, bytes as i64],
+        )?;
+        // only today counts, older days are dead weight
+        conn.execute(
+            "DELETE FROM api_key_usage WHERE api_key = ? AND day < ?",
+            params![api_key, today],
+        )?;
+        Ok(())
+    }
+
+    /// Re-encodes a downloaded HEIC/HEIF file in place. Returns whether a
+    /// conversion happened.
+    async fn convert_heic_file(&self, path: &std::path::Path) -> Result<bool> {
+        if !Self::is_heif_file(path)? {
+            return Ok(false);
+        }
+
+        let conversion = self.heic_conversion();
+        info!("Converting HEIC image to {:?}", conversion.format);
+        let data = tokio::fs::read(path).await?;
+        let converted = heic::convert(&data, conversion)?;
+        tokio::fs::write(path, converted).await?;
+        Ok(true)
+    }
+
+    /// A HEIC/HEIF file re-encoded for storing, and the format it now has.
+    /// `None` for any other file, which is left alone.
+    fn read_heic_file(&self, path: &std::path::Path) -> Result<Option<(Vec<u8>, ImageFormat)>> {
+        if !Self::is_heif_file(path)? {
+            return Ok(None);
+        }
+
+        let conversion = self.heic_conversion();
+        info!("Converting HEIC image to {:?}", conversion.format);
+        let converted = heic::convert(&std::fs::read(path)?, conversion)?;
+        Ok(Some((converted, conversion.format)))
+    }
+
+    fn is_heif_file(path: &std::path::Path) -> Result<bool> {
+        let mut header = [0u8; 12];
+        let mut file = std::fs::File::open(path)?;
+        Ok(file.read_exact(&mut header).is_ok() && heic::is_heif(&header))
+    }
+
+    /// The format of a file to be added, by its contents. Only formats the
+    /// server stores are accepted.
+    fn detect_format(path: &std::path::Path) -> Result<ImageFormat> {
+        info!("Checking image format...");
+        let img_file = std::fs::File::open(path)?;
+        let format = image::io::Reader::new(std::io::BufReader::new(img_file))
+            .with_guessed_format()?
+            .format();
+
+        match format {
+            Some(fmt) => match fmt {
+                ImageFormat::Png
+                | ImageFormat::Jpeg
+                | ImageFormat::Gif
+                | ImageFormat::WebP
+                | ImageFormat::Bmp => {
+                    info!("Detected image format: {:?}", fmt);
+                    Ok(fmt)
+                }
+                unsupported => {
+                    error!("Unsupported image format: {:?}", unsupported);
+                    Err(anyhow!("Unsupported image format: {:?}", unsupported))
+                }
+            },
+            None => {
+                error!("Could not determine image format");
+                Err(anyhow!("Could not determine image format"))
+            }
+        }
+    }
+
+    /// HEIC goes straight to the canonical format when one is configured so
+    /// it isn't re-encoded twice.
+    fn heic_conversion(&self) -> HeicConversion {
+        match self.canonical {
+            Some(canonical) => HeicConversion {
+                format: canonical.format,
+                quality: canonical.quality,
+            },
+            None => self.heic,
+        }
+    }
+
+    /// Re-encodes an image in the canonical format. Returns `None` when no
+    /// canonical format is configured, the image already uses it, or it is
+    /// animated.
+    fn canonicalize(
+        &self,
+        data: &[u8],
+        format: ImageFormat,
+    ) -> Result<Option<(Vec<u8>, ImageFormat)>> {
+        let Some(target) = self.canonical.filter(|c| c.format != format) else {
+            return Ok(None);
+        };
+        if canonical::is_animated(data, format) {
+            info!("Keeping animated {:?} image in its original format", format);
+            return Ok(None);
+        }
+
+        info!("Converting {:?} image to {:?}", format, target.format);
+        let img = image::load_from_memory_with_format(data, format)
+            .map_err(|e| anyhow!("Invalid image: {}", e))?;
+        let encoded = canonical::encode(&img, target.format, target.quality)?;
+        Ok(Some((encoded, target.format)))
+    }
+
+    fn canonicalize_file(
+        &self,
+        path: &std::path::Path,
+        format: ImageFormat,
+    ) -> Result<Option<(Vec<u8>, ImageFormat)>> {
+        if self.canonical.is_none_or(|c| c.format == format) {
+            return Ok(None);
+        }
+        self.canonicalize(&std::fs::read(path)?, format)
+    }
+
+    fn format_name(format: ImageFormat) -> String {
+        format.extensions_str()[0].to_uppercase()
+    }
+
+    /// URL downloads count against `ingest_by`'s quota when one is given.
+    pub async fn add_image(
+        &self,
+        path: &str,
+        path_type: PathType,
+        expected_hash: Option<&str>,
+        ingest_by: Option<&ApiKey>,
+    ) -> Result<String> {
+        match path_type {
+            PathType::Local => {
+                let src_path = std::path::Path::new(path);
+                info!("Validating local file: {}", path);
+
+                if !src_path.exists() {
+                    error!("File not found: {}", path);
                     return Err(anyhow!("Local file not found: {}", path));
                 }
 
@@ -339,175 +1150,259 @@ impl ImageStore {
                     ));
                 }
 
-                info!("Checking image format...");
-                let img_file = std::fs::File::open(src_path)?;
-                let format = image::io::Reader::new(std::io::BufReader::new(img_file))
-                    .with_guessed_format()?
-                    .format();
-
-                let format = match format {
-                    Some(fmt) => match fmt {
-                        ImageFormat::Png
-                        | ImageFormat::Jpeg
-                        | ImageFormat::Gif
-                        | ImageFormat::WebP
-                        | ImageFormat::Bmp => {
-                            info!("Detected image format: {:?}", fmt);
-                            fmt
+                Self::check_expected_hash(src_path, expected_hash)?;
+
+                // conversion drops EXIF, so read it from the original
+                let location = Self::read_file_location(src_path);
+                let (stored_format, converted, original_format) =
+                    match self.read_heic_file(src_path)? {
+                        Some((data, format)) => (format, Some(data), Some("HEIC".to_string())),
+                        None => {
+                            let format = Self::detect_format(src_path)?;
+                            match self.canonicalize_file(src_path, format)? {
+                                Some((data, target)) => {
+                                    (target, Some(data), Some(Self::format_name(format)))
+                                }
+                                None => (format, None, None),
+                            }
                         }
-                        unsupported => {
-                            error!("Unsupported image format: {:?}", unsupported);
-                            return Err(anyhow!("Unsupported image format: {:?}", unsupported));
-                        }
-                    },
-                    None => {
-                        error!("Could not determine image format");
-                        return Err(anyhow!("Could not determine image format"));
-                    }
-                };
+                    };
 
-                let ext = format.extensions_str()[0];
+                let ext = stored_format.extensions_str()[0];
                 let filename = format!("{}.{}", Uuid::new_v4(), ext);
                 let dest_path = self.images_dir.join(&filename);
 
-                info!("Copying file to: {:?}", dest_path);
-                std::fs::copy(path, &dest_path)?;
+                match converted {
+                    Some(data) => {
+                        info!("Writing converted file to: {:?}", dest_path);
+                        std::fs::write(&dest_path, data)?;
+                    }
+                    None => {
+                        info!("Copying file to: {:?}", dest_path);
+                        std::fs::copy(path, &dest_path)?;
+                    }
+                }
 
                 info!("Verifying image integrity...");
-                let img = image::open(&dest_path)?;
-                let dimensions = img.dimensions();
+                let (dimensions, phash) = match decode_file(&dest_path, self.strict_decode) {
+                    Ok(decoded) => decoded,
+                    Err(e) => {
+                        let _ = std::fs::remove_file(&dest_path);
+                        return Err(anyhow!("Invalid image: {}", e));
+                    }
+                };
                 info!(
                     "Successfully validated image: {} ({}x{} pixels, format: {:?})",
-                    filename, dimensions.0, dimensions.1, format
+                    filename, dimensions.0, dimensions.1, stored_format
                 );
+                let size_bytes = std::fs::metadata(&dest_path)?.len();
                 let now = OffsetDateTime::now_utc();
                 let now_str = now.format(&Rfc3339)?;
                 let hash = Self::calculate_file_hash(&dest_path)?;
 
                 info!("File hash: {}", hash);
+                if let Some(duplicate) = self.find_duplicate(&hash)? {
+                    std::fs::remove_file(&dest_path)?;
+                    return Err(duplicate.into());
+                }
 
                 let conn = self.pool.get()?;
                 conn.execute(
-                    "INSERT INTO images (filename, hash, created_at, modified_at, width, height, size_bytes) 
-                     VALUES (?, ?, ?, ?, ?, ?, ?)",
-                    [
-                        &filename,
-                        &hash,
-                        &now_str,
-                        &now_str,
-                        &dimensions.0.to_string(),
-                        &dimensions.1.to_string(),
-                        &metadata.len().to_string(),
+                    "INSERT INTO images (filename, hash, created_at, modified_at, width, height, size_bytes, phash, original_format, format) 
+                     VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                    params![
+                        filename,
+                        hash,
+                        now_str,
+                        now_str,
+                        dimensions.0,
+                        dimensions.1,
+                        size_bytes as i64,
+                        phash.map(|phash| phash as i64),
+                        original_format,
+                        Self::format_name(stored_format),
                     ],
-                )?;
+                )
+                .map_err(|e| self.discard_failed_add(&dest_path, &hash, e))?;
+                Self::set_image_location(&conn, &hash, location)?;
+                Self::log_change(&conn, ChangeEvent::ImageAdded, &hash)?;
+                if let Some(phash) = phash {
+                    self.phash_index.insert(phash, hash.clone());
+                }
+                self.sync_sidecar(&hash);
 
                 Ok(hash)
             }
             PathType::Url => {
                 info!("Processing URL: {}", path);
-                let temp_path = self.download_image(path).await?;
+                let temp_path = self.download_image(path, ingest_by).await?;
+                self.add_downloaded_file(&temp_path, expected_hash).await
+            }
+        }
+    }
+
+    /// The rest of a URL add once the download is in `temp_path`: checks,
+    /// converts and stores it like a local file. `temp_path` is gone
+    /// afterwards either way.
+    async fn add_downloaded_file(
+        &self,
+        temp_path: &std::path::Path,
+        expected_hash: Option<&str>,
+    ) -> Result<String> {
+        if let Err(e) = Self::check_expected_hash(temp_path, expected_hash) {
+            tokio::fs::remove_file(temp_path).await?;
+            return Err(e);
+        }
+
+        let mut original_format = match self.convert_heic_file(temp_path).await {
+            Ok(converted) => converted.then(|| "HEIC".to_string()),
+            Err(e) => {
+                tokio::fs::remove_file(temp_path).await?;
+                return Err(e);
+            }
+        };
 
-                info!("Checking image format...");
-                let format = image::io::Reader::new(std::io::BufReader::new(std::fs::File::open(
-                    &temp_path,
-                )?))
+        info!("Checking image format...");
+        let format =
+            image::io::Reader::new(std::io::BufReader::new(std::fs::File::open(temp_path)?))
                 .with_guessed_format()?
                 .format();
 
-                let format = match format {
-                    Some(fmt) => match fmt {
-                        ImageFormat::Png
-                        | ImageFormat::Jpeg
-                        | ImageFormat::Gif
-                        | ImageFormat::WebP
-                        | ImageFormat::Bmp => {
-                            info!("Detected image format: {:?}", fmt);
-                            fmt
-                        }
-                        unsupported => {
-                            tokio::fs::remove_file(&temp_path).await?;
-                            error!("Unsupported image format: {:?}", unsupported);
-                            return Err(anyhow!("Unsupported image format: {:?}", unsupported));
-                        }
-                    },
-                    None => {
-                        tokio::fs::remove_file(&temp_path).await?;
-                        error!("Could not determine image format");
-                        return Err(anyhow!("Could not determine image format"));
-                    }
-                };
+        let format = match format {
+            Some(fmt) => match fmt {
+                ImageFormat::Png
+                | ImageFormat::Jpeg
+                | ImageFormat::Gif
+                | ImageFormat::WebP
+                | ImageFormat::Bmp => {
+                    info!("Detected image format: {:?}", fmt);
+                    fmt
+                }
+                unsupported => {
+                    tokio::fs::remove_file(temp_path).await?;
+                    error!("Unsupported image format: {:?}", unsupported);
+                    return Err(anyhow!("Unsupported image format: {:?}", unsupported));
+                }
+            },
+            None => {
+                tokio::fs::remove_file(temp_path).await?;
+                error!("Could not determine image format");
+                return Err(anyhow!("Could not determine image format"));
+            }
+        };
 
-                let ext = format.extensions_str()[0];
-                let filename = format!("{}.{}", Uuid::new_v4(), ext);
-                let dest_path = self.images_dir.join(&filename);
+        let location = Self::read_file_location(temp_path);
+        let converted = match self.canonicalize_file(temp_path, format) {
+            Ok(converted) => converted,
+            Err(e) => {
+                tokio::fs::remove_file(temp_path).await?;
+                return Err(e);
+            }
+        };
+        let stored_format = converted.as_ref().map_or(format, |(_, f)| *f);
 
-                tokio::fs::rename(&temp_path, &dest_path).await?;
+        let ext = stored_format.extensions_str()[0];
+        let filename = format!("{}.{}", Uuid::new_v4(), ext);
+        let dest_path = self.images_dir.join(&filename);
 
-                info!("Verifying image integrity...");
-                let img = image::open(&dest_path)?;
-                let dimensions = img.dimensions();
-                info!(
-                    "Successfully validated image: {} ({}x{} pixels, format: {:?})",
-                    filename, dimensions.0, dimensions.1, format
-                );
+        match converted {
+            Some((data, _)) => {
+                tokio::fs::write(&dest_path, data).await?;
+                tokio::fs::remove_file(temp_path).await?;
+                original_format.get_or_insert_with(|| Self::format_name(format));
+            }
+            None => tokio::fs::rename(temp_path, &dest_path).await?,
+        }
 
-                let metadata = std::fs::metadata(&dest_path)?;
-                let now = OffsetDateTime::now_utc();
-                let now_str = now.format(&Rfc3339)?;
-                let hash = Self::calculate_file_hash(&dest_path)?;
+        info!("Verifying image integrity...");
+        let (dimensions, phash) = match decode_file(&dest_path, self.strict_decode) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                let _ = std::fs::remove_file(&dest_path);
+                return Err(anyhow!("Invalid image: {}", e));
+            }
+        };
+        info!(
+            "Successfully validated image: {} ({}x{} pixels, format: {:?})",
+            filename, dimensions.0, dimensions.1, stored_format
+        );
 
-                info!("File hash: {}", hash);
+        let metadata = std::fs::metadata(&dest_path)?;
+        let now = OffsetDateTime::now_utc();
+        let now_str = now.format(&Rfc3339)?;
+        let hash = Self::calculate_file_hash(&dest_path)?;
 
-                let conn = self.pool.get()?;
-                conn.execute(
-                    "INSERT INTO images (filename, hash, created_at, modified_at, width, height, size_bytes) 
-                     VALUES (?, ?, ?, ?, ?, ?, ?)",
-                    [
-                        &filename,
-                        &hash,
-                        &now_str,
-                        &now_str,
-                        &dimensions.0.to_string(),
-                        &dimensions.1.to_string(),
-                        &metadata.len().to_string(),
-                    ],
-                )?;
+        info!("File hash: {}", hash);
+        if let Some(duplicate) = self.find_duplicate(&hash)? {
+            std::fs::remove_file(&dest_path)?;
+            return Err(duplicate.into());
+        }
 
-                Ok(hash)
-            }
+        let conn = self.pool.get()?;
+        conn.execute(
+            "INSERT INTO images (filename, hash, created_at, modified_at, width, height, size_bytes, phash, original_format, format) 
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                filename,
+                hash,
+                now_str,
+                now_str,
+                dimensions.0,
+                dimensions.1,
+                metadata.len() as i64,
+                phash.map(|phash| phash as i64),
+                original_format,
+                Self::format_name(stored_format),
+            ],
+        )
+        .map_err(|e| self.discard_failed_add(&dest_path, &hash, e))?;
+        Self::set_image_location(&conn, &hash, location)?;
+        Self::log_change(&conn, ChangeEvent::ImageAdded, &hash)?;
+        if let Some(phash) = phash {
+            self.phash_index.insert(phash, hash.clone());
         }
+        self.sync_sidecar(&hash);
+
+        Ok(hash)
     }
 
     pub fn get_image_by_filename(&self, filename: &str) -> Result<ImageResponse> {
         let conn = self.pool.get()?;
-        let (hash, created_at, modified_at): (String, String, String) = conn.query_row(
-            "SELECT hash, created_at, modified_at FROM images WHERE filename = ?",
+        let (hash, id, created_at, modified_at, original_format): (
+            String,
+            String,
+            String,
+            String,
+            Option<String>,
+        ) = conn.query_row(
+            "SELECT hash, public_id, created_at, modified_at, original_format FROM images WHERE filename = ?",
             [filename],
-            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            },
         )?;
 
         let tags = self.get_image_tags(&hash)?;
         let file_path = self.images_dir.join(filename);
-
-        let metadata = std::fs::metadata(&file_path)?;
-
-        let img = image::open(&file_path)?;
-        let dimensions = img.dimensions();
-
-        let format = file_path
-            .extension()
-            .and_then(|ext| ext.to_str())
-            .map(|ext| ext.to_uppercase())
-            .unwrap_or_else(|| "UNKNOWN".to_string());
+        let (size_bytes, dimensions, format) = self.stored_image_info(&hash, &file_path)?;
+        let location = self.get_image_location(&hash)?;
 
         Ok(ImageResponse {
-            url: format!("{}/{}", self.base_url, filename),
+            url: self.image_url(filename, &hash, &id),
             filename: filename.to_string(),
             format,
             width: dimensions.0,
             height: dimensions.1,
-            size_bytes: metadata.len(),
+            size_bytes,
+            size_human: byte_size::format(size_bytes),
             hash,
+            id,
             tags,
             created_at: OffsetDateTime::parse(&created_at, &Rfc3339)?
                 .format(&Rfc3339)
@@ -515,330 +1410,2555 @@ impl ImageStore {
             modified_at: OffsetDateTime::parse(&modified_at, &Rfc3339)?
                 .format(&Rfc3339)
                 .unwrap_or_else(|_| "".to_string()),
+            original_format,
+            location,
+            data: None,
+            content_type: None,
         })
     }
 
-    pub fn generate_api_key(
-        &self,
-        username: &str,
-        requests_per_second: Option<u32>,
-        max_batch_size: Option<u32>,
-    ) -> Result<String> {
-        let conn = self.pool.get()?;
+    /// Reads an image's raw bytes along with its content type.
+    pub fn read_image_file(&self, filename: &str) -> Result<(Vec<u8>, &'static str)> {
+        let hash = self.check_image_file(filename)?;
+        let file_path = self.images_dir.join(filename);
+        let data = std::fs::read(&file_path).map_err(|e| match &hash {
+            Some(hash) => self.mark_broken(hash, e.to_string()),
+            None => e.into(),
+        })?;
+        let content_type = ImageFormat::from_path(&file_path)
+            .map(Self::format_content_type)
+            .unwrap_or("application/octet-stream");
+        Ok((data, content_type))
+    }
 
-        let api_key = Uuid::new_v4().to_string();
-        let now = OffsetDateTime::now_utc().format(&Rfc3339)?;
+    /// Confirms a stored file can still be served and returns its image's
+    /// hash, or `None` for files the database doesn't know. A file that
+    /// can't be read, or whose size changed and no longer decodes, flags the
+    /// image broken; only a size change is worth the cost of decoding here.
+    pub fn check_image_file(&self, filename: &str) -> Result<Option<String>> {
+        let row: Option<(String, Option<i64>, bool)> = {
+            let conn = self.pool.get()?;
+            conn.query_row(
+                "SELECT hash, size_bytes, broken_reason IS NOT NULL FROM images WHERE filename = ?",
+                [filename],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?
+        };
+        let Some((hash, size_bytes, broken)) = row else {
+            return Ok(None);
+        };
+        if broken {
+            if let Some(broken) = self.broken_image(&hash)? {
+                return Err(broken.into());
+            }
+        }
 
-        conn.execute(
-            "INSERT INTO api_keys (key, username, created_at, requests_per_second, max_batch_size) 
-             VALUES (?, ?, ?, ?, ?)",
-            params![
-                &api_key,
-                username,
-                &now,
-                requests_per_second,
-                max_batch_size
-            ],
+        let file_path = self.images_dir.join(filename);
+        let checked = std::fs::metadata(&file_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|metadata| {
+                if size_bytes.is_some_and(|size| size as u64 != metadata.len()) {
+                    decode_file(&file_path, self.strict_decode)?;
+                }
+                Ok(())
+            });
+        checked.map_err(|e| self.mark_broken(&hash, e.to_string()))?;
+        Ok(Some(hash))
+    }
+
+    /// Size, dimensions and format for a response, straight from the row so
+    /// serving an image doesn't touch its file. Rows missing any of them,
+    /// e.g. ones the startup backfill couldn't read, are repaired from the
+    /// file.
+    fn stored_image_info(
+        &self,
+        hash: &str,
+        file_path: &std::path::Path,
+    ) -> Result<(u64, (u32, u32), String)> {
+        let (width, height, size_bytes, format, broken_reason) = {
+            let conn = self.pool.get()?;
+            conn.query_row(
+                "SELECT width, height, size_bytes, format, broken_reason FROM images WHERE hash = ?",
+                [hash],
+                |row| {
+                    Ok((
+                        row.get::<_, Option<u32>>(0)?,
+                        row.get::<_, Option<u32>>(1)?,
+                        row.get::<_, Option<i64>>(2)?,
+                        row.get::<_, Option<String>>(3)?,
+                        row.get::<_, Option<String>>(4)?,
+                    ))
+                },
+            )?
+        };
+        if let Some(reason) = broken_reason {
+            return Err(BrokenImage {
+                hash: hash.to_string(),
+                width: width.unwrap_or(0),
+                height: height.unwrap_or(0),
+                reason,
+            }
+            .into());
+        }
+
+        if let (Some(width), Some(height), Some(size_bytes), Some(format)) =
+            (width, height, size_bytes, format)
+        {
+            return Ok((size_bytes as u64, (width, height), format));
+        }
+        let (size_bytes, dimensions) = self.read_dimensions(hash, file_path)?;
+        let format = Self::read_file_format(file_path);
+        self.pool.get()?.execute(
+            "UPDATE images SET width = ?, height = ?, size_bytes = ?, format = COALESCE(?, format)
+             WHERE hash = ?",
+            params![dimensions.0, dimensions.1, size_bytes as i64, format, hash],
         )?;
+        info!("Repaired stored dimensions of image {}", hash);
+        Ok((
+            size_bytes,
+            dimensions,
+            format.unwrap_or_else(|| "UNKNOWN".to_string()),
+        ))
+    }
 
-        Ok(api_key)
+    /// Size and dimensions of an image's file, flagging the image broken
+    /// when the file can't be read or decoded.
+    fn read_dimensions(
+        &self,
+        hash: &str,
+        file_path: &std::path::Path,
+    ) -> Result<(u64, (u32, u32))> {
+        if let Some(broken) = self.broken_image(hash)? {
+            return Err(broken.into());
+        }
+        std::fs::metadata(file_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|metadata| {
+                let (dimensions, _) = decode_file(file_path, self.strict_decode)?;
+                Ok((metadata.len(), dimensions))
+            })
+            .map_err(|e| self.mark_broken(hash, e.to_string()))
     }
 
-    pub fn remove_api_key(&self, username: &str) -> Result<bool> {
+    fn broken_image(&self, hash: &str) -> Result<Option<BrokenImage>> {
         let conn = self.pool.get()?;
-        let rows_affected = conn.execute("DELETE FROM api_keys WHERE username = ?", [username])?;
-        Ok(rows_affected > 0)
+        Ok(conn
+            .query_row(
+                "SELECT width, height, broken_reason FROM images
+                 WHERE hash = ? AND broken_reason IS NOT NULL",
+                [hash],
+                |row| {
+                    Ok(BrokenImage {
+                        hash: hash.to_string(),
+                        width: row.get::<_, Option<u32>>(0)?.unwrap_or(0),
+                        height: row.get::<_, Option<u32>>(1)?.unwrap_or(0),
+                        reason: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?)
     }
 
-    pub fn list_api_keys(&self) -> Result<Vec<ApiKey>> {
+    /// Flags `hash` broken after its file failed to read or decode, and
+    /// returns the error to hand back in place of the image.
+    fn mark_broken(&self, hash: &str, reason: String) -> anyhow::Error {
+        let flagged = self
+            .pool
+            .get()
+            .map_err(anyhow::Error::from)
+            .and_then(|conn| {
+                conn.execute(
+                    "UPDATE images SET broken_reason = ? WHERE hash = ?",
+                    params![reason, hash],
+                )
+                .map_err(anyhow::Error::from)
+            });
+        match flagged {
+            Ok(_) => warn!("Flagged image {} as broken: {}", hash, reason),
+            Err(e) => error!("Failed to flag image {} as broken: {}", hash, e),
+        }
+        match self.broken_image(hash) {
+            Ok(Some(broken)) => broken.into(),
+            _ => anyhow!("Image {} is broken: {}", hash, reason),
+        }
+    }
+
+    pub fn list_broken_images(&self) -> Result<Vec<BrokenImageEntry>> {
         let conn = self.pool.get()?;
         let mut stmt = conn.prepare(
-            "SELECT key, username, created_at, last_used_at, is_active, requests_per_second, max_batch_size 
-             FROM api_keys 
-             ORDER BY created_at DESC",
+            "SELECT filename, hash, broken_reason FROM images
+             WHERE broken_reason IS NOT NULL ORDER BY filename",
         )?;
-
-        let keys = stmt
+        let entries = stmt
             .query_map([], |row| {
-                let created_at_str: String = row.get(2)?;
-                let last_used_at_str: Option<String> = row.get(3)?;
-
-                let created_at = OffsetDateTime::parse(&created_at_str, &Rfc3339).map_err(|e| {
-                    SqliteError::FromSqlConversionFailure(
-                        0,
-                        rusqlite::types::Type::Text,
-                        Box::new(e),
-                    )
-                })?;
-
-                let last_used_at = if let Some(dt_str) = last_used_at_str {
-                    Some(OffsetDateTime::parse(&dt_str, &Rfc3339).map_err(|e| {
-                        SqliteError::FromSqlConversionFailure(
-                            0,
-                            rusqlite::types::Type::Text,
-                            Box::new(e),
-                        )
-                    })?)
-                } else {
-                    None
-                };
-
-                Ok(ApiKey {
-                    key: row.get(0)?,
-                    username: row.get(1)?,
-                    created_at,
-                    last_used_at,
-                    is_active: row.get(4)?,
-                    requests_per_second: row.get(5)?,
-                    max_batch_size: row.get(6)?,
+                Ok(BrokenImageEntry {
+                    filename: row.get(0)?,
+                    hash: row.get(1)?,
+                    reason: row.get(2)?,
                 })
             })?
             .collect::<Result<Vec<_>, _>>()?;
+        Ok(entries)
+    }
 
-        Ok(keys)
+    pub fn count_broken_images(&self) -> Result<u64> {
+        let conn = self.pool.get()?;
+        Ok(conn.query_row(
+            "SELECT COUNT(*) FROM images WHERE broken_reason IS NOT NULL",
+            [],
+            |row| row.get(0),
+        )?)
     }
 
-    pub fn validate_api_key(&self, key: &str) -> Result<bool> {
+    pub fn get_file_info(&self, filename: &str) -> Result<FileInfo> {
         let conn = self.pool.get()?;
-        let result: Option<bool> = conn
+        let (db_size_bytes, db_hash, modified_at_db): (Option<i64>, Option<String>, String) = conn
             .query_row(
-                "SELECT is_active FROM api_keys WHERE key = ?",
-                [key],
-                |row| row.get(0),
-            )
-            .optional()?;
+                "SELECT size_bytes, hash, modified_at FROM images WHERE filename = ?",
+                [filename],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )?;
 
-        match result {
-            Some(true) => Ok(true),
-            Some(false) => Err(anyhow!("inactive_key")),
-            None => Ok(false),
+        let path = self.images_dir.join(filename);
+        let metadata = std::fs::metadata(&path)?;
+        let mtime = metadata.modified().ok().map(OffsetDateTime::from);
+        let db_size_bytes = db_size_bytes.map(|s| s as u64);
+
+        // modified_at also moves on tag and metadata edits, so the file is
+        // checked against its hash rather than its mtime
+        let in_sync = db_size_bytes == Some(metadata.len())
+            && db_hash.is_some_and(|hash| Self::calculate_file_hash(&path).ok() == Some(hash));
+
+        Ok(FileInfo {
+            filename: filename.to_string(),
+            filesystem_size_bytes: metadata.len(),
+            db_size_bytes,
+            mtime,
+            in_sync,
+            modified_at_db,
+        })
+    }
+
+    /// SQL condition that the image `hash_column` refers to carries one of
+    /// `tags`, with a parameter per tag.
+    fn tagged_with_any(hash_column: &str, tags: &[String]) -> String {
+        format!(
+            "EXISTS (SELECT 1 FROM image_tags x JOIN tags xt ON x.tag_id = xt.id WHERE x.image_hash = {} AND xt.name IN ({}))",
+            hash_column,
+            tags.iter().map(|_| "?").collect::<Vec<_>>().join(",")
+        )
+    }
+
+    /// `tagged_with_any` as an extra `AND` clause for a key's `allowed_tags`,
+    /// or nothing when the key sees every image.
+    fn visible_to(hash_column: &str, allowed_tags: &[String]) -> String {
+        if allowed_tags.is_empty() {
+            String::new()
+        } else {
+            format!(" AND {}", Self::tagged_with_any(hash_column, allowed_tags))
         }
     }
 
-    pub fn get_api_key(&self, key: &str) -> Result<ApiKey> {
+    /// Resolves each of `ids` (a filename or hash) straight from the images
+    /// table, without touching the files. Images without one of
+    /// `allowed_tags`, when given, are reported as missing.
+    pub fn images_exist(
+        &self,
+        ids: &[String],
+        allowed_tags: &[String],
+    ) -> Result<HashMap<String, ImageExists>> {
         let conn = self.pool.get()?;
-        let result = conn.query_row(
-            "SELECT key, username, created_at, last_used_at, is_active, requests_per_second, max_batch_size FROM api_keys WHERE key = ?",
-            [key],
-            |row| {
-                let created_at_str: String = row.get(2)?;
-                let last_used_at_str: Option<String> = row.get(3)?;
+        let mut found = HashMap::new();
+
+        for chunk in ids.chunks(EXISTS_CHUNK_SIZE) {
+            let placeholders = chunk.iter().map(|_| "?").collect::<Vec<_>>().join(",");
+            let mut stmt = conn.prepare(&format!(
+                "SELECT filename, hash, size_bytes, modified_at 
+                 FROM images 
+                 WHERE (filename IN ({0}) OR hash IN ({0})){1}",
+                placeholders,
+                Self::visible_to("images.hash", allowed_tags)
+            ))?;
+            let rows = stmt.query_map(
+                rusqlite::params_from_iter(chunk.iter().chain(chunk.iter()).chain(allowed_tags)),
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        ImageExists {
+                            exists: true,
+                            hash: row.get(1)?,
+                            size_bytes: row.get::<_, Option<i64>>(2)?.map(|s| s as u64),
+                            modified_at: row.get(3)?,
+                        },
+                    ))
+                },
+            )?;
+            for row in rows {
+                let (filename, exists) = row?;
+                if let Some(hash) = exists.hash.clone() {
+                    found.insert(hash, exists.clone());
+                }
+                found.insert(filename, exists);
+            }
+        }
 
-                let created_at = OffsetDateTime::parse(&created_at_str, &Rfc3339)
-                    .map_err(|e| SqliteError::FromSqlConversionFailure(
-                        0,
-                        rusqlite::types::Type::Text,
-                        Box::new(e),
-                    ))?;
+        Ok(ids
+            .iter()
+            .map(|id| {
+                let exists = found.get(id).cloned().unwrap_or(ImageExists {
+                    exists: false,
+                    hash: None,
+                    size_bytes: None,
+                    modified_at: None,
+                });
+                (id.clone(), exists)
+            })
+            .collect())
+    }
 
-                let last_used_at = if let Some(dt_str) = last_used_at_str {
-                    Some(OffsetDateTime::parse(&dt_str, &Rfc3339)
-                        .map_err(|e| SqliteError::FromSqlConversionFailure(
-                            0,
-                            rusqlite::types::Type::Text,
-                            Box::new(e),
-                        ))?)
-                } else {
-                    None
-                };
-
-                Ok(ApiKey {
-                    key: row.get(0)?,
-                    username: row.get(1)?,
-                    created_at,
-                    last_used_at,
-                    is_active: row.get(4)?,
-                    requests_per_second: row.get(5)?,
-                    max_batch_size: row.get(6)?,
-                })
-            },
-        )?;
-        Ok(result)
-    }
-
-    pub fn update_key_last_used(&self, key: &str) -> Result<()> {
-        let conn = self.pool.get()?;
-        let now = OffsetDateTime::now_utc().format(&Rfc3339)?;
-
-        conn.execute(
-            "UPDATE api_keys SET last_used_at = ? WHERE key = ?",
-            params![now, key],
-        )?;
-
-        Ok(())
+    /// Swaps the file behind `filename` for `data` and refreshes its row, so
+    /// the filename, tags and metadata stay. `if_match` is the hash the
+    /// caller expects the image to still have; it's checked again in the
+    /// transaction that rewrites the row.
+    pub fn replace_image(
+        &self,
+        filename: &str,
+        data: &[u8],
+        content_type: &str,
+        if_match: Option<&str>,
+    ) -> Result<ImageResponse> {
+        // two replacements of one file would swap each other's backups
+        let lock = self
+            .replacing
+            .entry(filename.to_string())
+            .or_default()
+            .clone();
+        let result = {
+            let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+            self.replace_image_locked(filename, data, content_type, if_match)
+        };
+        drop(lock);
+        self.replacing
+            .remove_if(filename, |_, lock| Arc::strong_count(lock) == 1);
+        result
     }
 
-    pub fn update_api_key_rate_limit(
+    fn replace_image_locked(
         &self,
-        username: &str,
-        requests_per_second: Option<u32>,
-    ) -> Result<()> {
-        let conn = self.pool.get()?;
-
-        let rows_affected = conn.execute(
-            "UPDATE api_keys SET requests_per_second = ? WHERE username = ? AND is_active = 1",
-            params![requests_per_second, username],
-        )?;
+        filename: &str,
+        data: &[u8],
+        content_type: &str,
+        if_match: Option<&str>,
+    ) -> Result<ImageResponse> {
+        let current_hash = self
+            .hash_for_filename(filename)?
+            .ok_or_else(|| anyhow!("Image not found: {}", filename))?;
+        if let Some(expected) = if_match {
+            if expected != "*" && expected != current_hash {
+                return Err(anyhow!(
+                    "Precondition failed: image hash is {}",
+                    current_hash
+                ));
+            }
+        }
 
-        if rows_affected == 0 {
+        if !ALLOWED_CONTENT_TYPES.contains(&content_type) {
+            return Err(anyhow!("Unsupported content type: {}", content_type));
+        }
+        let file_path = self.images_dir.join(filename);
+        let format = image::guess_format(data).map_err(|e| anyhow!("Invalid image: {}", e))?;
+        if ImageFormat::from_mime_type(content_type) != Some(format) {
             return Err(anyhow!(
-                "No active API key found for username: {}",
-                username
+                "Invalid image: data is not {} as its Content-Type says",
+                content_type
+            ));
+        }
+        // the extension decides how the file is served, so it has to fit
+        if ImageFormat::from_path(&file_path).ok() != Some(format) {
+            return Err(anyhow!(
+                "Invalid image: {} can only be replaced with the same format",
+                filename
             ));
         }
+        decode_bytes(data, self.strict_decode).map_err(|e| anyhow!("Invalid image: {}", e))?;
 
-        Ok(())
-    }
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        let hash = format!("{:x}", hasher.finalize());
+        if hash != current_hash && self.get_image_by_hash(&hash)?.is_some() {
+            return Err(anyhow!("Image with hash {} already exists", hash));
+        }
 
-    pub fn add_tags(&self, image_hash: &str, tags: &[String]) -> Result<()> {
-        let mut conn = self.pool.get()?;
-        let tx = conn.transaction()?;
+        // keep the old file until the row is updated, to put it back if not
+        let staged = self
+            .images_dir
+            .join(format!("{}{}", TEMP_PREFIX, Uuid::new_v4()));
+        let backup = self
+            .images_dir
+            .join(format!("{}{}", TEMP_PREFIX, Uuid::new_v4()));
+        std::fs::write(&staged, data)?;
+        // a broken image may have lost its file entirely
+        let backed_up = match std::fs::rename(&file_path, &backup) {
+            Ok(()) => true,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            Err(e) => {
+                let _ = std::fs::remove_file(&staged);
+                return Err(e.into());
+            }
+        };
+        if let Err(e) = std::fs::rename(&staged, &file_path) {
+            if backed_up {
+                std::fs::rename(&backup, &file_path)?;
+            }
+            let _ = std::fs::remove_file(&staged);
+            return Err(e.into());
+        }
 
-        for tag in tags {
-            let tag = tag.to_lowercase().replace(' ', "_");
+        match self.refresh_image_matching(filename, if_match) {
+            Ok(response) => {
+                if backed_up {
+                    if let Err(e) = std::fs::remove_file(&backup) {
+                        warn!("Failed to remove replaced file for {}: {}", filename, e);
+                    }
+                }
+                Ok(response)
+            }
+            Err(e) => {
+                if backed_up {
+                    std::fs::rename(&backup, &file_path)?;
+                } else {
+                    let _ = std::fs::remove_file(&file_path);
+                }
+                Err(e)
+            }
+        }
+    }
 
-            tx.execute("INSERT OR IGNORE INTO tags (name) VALUES (?)", [&tag])?;
+    /// Re-reads the file behind `filename` and rewrites its dimensions, size,
+    /// hash and perceptual hash, e.g. after the file was replaced on disk.
+    /// Tags and metadata follow the image to its new hash.
+    pub fn refresh_image(&self, filename: &str) -> Result<ImageResponse> {
+        self.refresh_image_matching(filename, None)
+    }
 
-            let tag_id: i64 =
-                tx.query_row("SELECT id FROM tags WHERE name = ?", [&tag], |row| {
-                    row.get(0)
-                })?;
+    /// `refresh_image`, failing with a precondition error unless the row
+    /// still has hash `if_match` (or `if_match` is `*`).
+    fn refresh_image_matching(
+        &self,
+        filename: &str,
+        if_match: Option<&str>,
+    ) -> Result<ImageResponse> {
+        let file_path = self.images_dir.join(filename);
+        if !file_path.exists() {
+            return Err(anyhow!("Image file not found: {}", filename));
+        }
+        let (hash, width, height, size_bytes, phash) =
+            Self::read_file_metadata(&file_path, self.strict_decode)
+                .map_err(|e| anyhow!("Invalid image {}: {}", filename, e))?;
 
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let old_hash: String = tx
+            .query_row(
+                "SELECT hash FROM images WHERE filename = ?",
+                [filename],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| anyhow!("Image not found: {}", filename))?;
+        if let Some(expected) = if_match {
+            if expected != "*" && expected != old_hash {
+                return Err(anyhow!("Precondition failed: image hash is {}", old_hash));
+            }
+        }
+        if hash != old_hash {
+            let taken: bool = tx.query_row(
+                "SELECT EXISTS(SELECT 1 FROM images WHERE hash = ?)",
+                [&hash],
+                |row| row.get(0),
+            )?;
+            if taken {
+                return Err(anyhow!("Image with hash {} already exists", hash));
+            }
+            // children and parent are re-keyed one at a time, so check the
+            // references at commit instead of per statement
+            tx.execute_batch("PRAGMA defer_foreign_keys = ON")?;
             tx.execute(
-                "INSERT OR IGNORE INTO image_tags (image_hash, tag_id) VALUES (?, ?)",
-                params![image_hash, tag_id],
+                "UPDATE image_tags SET image_hash = ? WHERE image_hash = ?",
+                params![hash, old_hash],
+            )?;
+            tx.execute(
+                "UPDATE image_metadata SET image_hash = ? WHERE image_hash = ?",
+                params![hash, old_hash],
+            )?;
+            tx.execute(
+                "UPDATE collection_images SET image_hash = ? WHERE image_hash = ?",
+                params![hash, old_hash],
             )?;
+            Self::log_change(&tx, ChangeEvent::ImageRemoved, &old_hash)?;
+        }
+        // variants of the old content are stale
+        let derived = if hash != old_hash {
+            Self::take_derived_files(&tx, &old_hash)?
+        } else {
+            Vec::new()
+        };
+        tx.execute(
+            "UPDATE images SET hash = ?, width = ?, height = ?, size_bytes = ?, phash = ?,
+             format = ?, broken_reason = NULL WHERE hash = ?",
+            params![
+                hash,
+                width,
+                height,
+                size_bytes as i64,
+                phash.map(|phash| phash as i64),
+                Self::read_file_format(&file_path),
+                old_hash
+            ],
+        )?;
+        Self::set_image_location(&tx, &hash, Self::read_file_location(&file_path))?;
+        Self::touch_image(&tx, &hash)?;
+        if hash != old_hash {
+            Self::log_change(&tx, ChangeEvent::ImageAdded, &hash)?;
         }
-
         tx.commit()?;
-        Ok(())
-    }
 
-    pub fn remove_tags(&self, image_hash: &str, tags: &[String]) -> Result<()> {
-        let mut conn = self.pool.get()?;
-        let tx = conn.transaction()?;
+        self.phash_index.remove(&old_hash);
+        if let Some(phash) = phash {
+            self.phash_index.insert(phash, hash.clone());
+        }
+        self.tag_cache.invalidate(&old_hash);
+        self.sync_sidecar(&hash);
+        self.remove_derived_paths(&derived);
 
-        for tag in tags {
-            let tag = tag.to_lowercase().replace(' ', "_");
+        self.get_image_by_filename(filename)
+    }
 
-            if let Ok(tag_id) = tx.query_row("SELECT id FROM tags WHERE name = ?", [&tag], |row| {
-                row.get::<_, i64>(0)
-            }) {
-                tx.execute(
-                    "DELETE FROM image_tags WHERE image_hash = ? AND tag_id = ?",
-                    params![image_hash, tag_id],
-                )?;
-            }
+    /// Removes the file of an add whose row couldn't be inserted. A
+    /// concurrent add of the same contents takes the hash between the
+    /// duplicate check and the insert, so that still reports a duplicate.
+    fn discard_failed_add(
+        &self,
+        dest_path: &std::path::Path,
+        hash: &str,
+        e: rusqlite::Error,
+    ) -> anyhow::Error {
+        if let Err(remove_err) = std::fs::remove_file(dest_path) {
+            warn!("Failed to remove {:?}: {}", dest_path, remove_err);
+        }
+        match self.find_duplicate(hash) {
+            Ok(Some(duplicate)) => duplicate.into(),
+            _ => e.into(),
         }
-
-        tx.commit()?;
-        Ok(())
     }
 
-    pub fn get_image_tags(&self, image_hash: &str) -> Result<Vec<String>> {
+    fn find_duplicate(&self, hash: &str) -> Result<Option<DuplicateImage>> {
         let conn = self.pool.get()?;
-        let mut stmt = conn.prepare(
-            "SELECT t.name 
-             FROM tags t 
-             JOIN image_tags it ON t.id = it.tag_id 
-             WHERE it.image_hash = ?
-             ORDER BY t.name",
-        )?;
-
-        let tags = stmt
-            .query_map([image_hash], |row| row.get::<_, String>(0))?
-            .collect::<Result<Vec<_>, _>>()?;
-
-        Ok(tags)
+        let uploaded_by = conn
+            .query_row(
+                "SELECT uploaded_by FROM images WHERE hash = ?",
+                [hash],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(uploaded_by.map(|uploaded_by| DuplicateImage {
+            hash: hash.to_string(),
+            uploaded_by,
+        }))
     }
 
-    pub fn get_all_tags(&self) -> Result<Vec<(String, i64)>> {
+    pub fn get_image_by_hash(&self, hash: &str) -> Result<Option<ImageResponse>> {
         let conn = self.pool.get()?;
-        let mut stmt = conn.prepare(
-            "SELECT t.name, COUNT(it.image_hash) as count 
-             FROM tags t 
-             LEFT JOIN image_tags it ON t.id = it.tag_id 
-             GROUP BY t.name 
-             ORDER BY t.name",
-        )?;
+        let filename: Option<String> = conn
+            .query_row(
+                "SELECT filename FROM images WHERE hash = ?",
+                [hash],
+                |row| row.get(0),
+            )
+            .optional()?;
 
-        let tags = stmt
-            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
-            .collect::<Result<Vec<(String, i64)>, _>>()?;
+        filename
+            .map(|filename| self.get_image_by_filename(&filename))
+            .transpose()
+    }
 
-        Ok(tags)
+    pub fn lookup_image(&self, data: &[u8]) -> Result<Option<ImageResponse>> {
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        self.get_image_by_hash(&format!("{:x}", hasher.finalize()))
     }
 
-    pub fn remove_image(&self, filename: &str) -> Result<()> {
-        let mut conn = self.pool.get()?;
-        let file_path = self.images_dir.join(filename);
+    pub fn lookup_similar_images(
+        &self,
+        data: &[u8],
+        max_distance: u32,
+    ) -> Result<Vec<(ImageResponse, u32)>> {
+        let img = image::load_from_memory(data).map_err(|e| anyhow!("Invalid image: {}", e))?;
+        let phash = compute_phash(&img);
+
+        let mut results = Vec::new();
+        for (hash, distance) in self.phash_index.search(phash, max_distance) {
+            match self.get_image_by_hash(&hash) {
+                Ok(Some(image)) => results.push((image, distance)),
+                Ok(None) => warn!("Perceptual hash index references unknown image {}", hash),
+                Err(e) => warn!("Failed to load lookup candidate {}: {}", hash, e),
+            }
+        }
 
-        let tx = conn.transaction()?;
+        Ok(results)
+    }
 
-        let hash: String = tx.query_row(
-            "SELECT hash FROM images WHERE filename = ?",
-            [filename],
-            |row| row.get(0),
-        )?;
+    pub async fn import_zip_catalog(
+        &self,
+        zip_data: &[u8],
+        apply_tags: bool,
+    ) -> Result<ImportZipResult> {
+        let (manifest, files) =
+            Self::read_zip_catalog(zip_data, MAX_ZIP_ENTRIES, MAX_ZIP_UNCOMPRESSED_BYTES)?;
+        let mut result = ImportZipResult::default();
+
+        let mut tags_by_file: std::collections::HashMap<String, Vec<String>> = manifest
+            .images
+            .into_iter()
+            .map(|entry| (entry.filename, entry.tags))
+            .collect();
+
+        info!(
+            "Importing {} files from ZIP catalog ({} manifest entries)",
+            files.len(),
+            tags_by_file.len()
+        );
 
-        tx.execute("DELETE FROM image_tags WHERE image_hash = ?", [&hash])?;
+        for (name, data) in files {
+            let tags = tags_by_file.remove(&name).unwrap_or_default();
+            if apply_tags {
+                if let Some(tag) = self.find_blocked_tag(&tags) {
+                    result.failed += 1;
+                    result
+                        .errors
+                        .push(format!("{}: Tag is blocked: {}", name, tag));
+                    continue;
+                }
+            }
 
-        tx.execute("DELETE FROM images WHERE hash = ?", [&hash])?;
+            let mut hasher = Sha256::new();
+            hasher.update(&data);
+            let hash = format!("{:x}", hasher.finalize());
 
-        tx.execute(
-            "DELETE FROM tags WHERE id NOT IN (SELECT DISTINCT tag_id FROM image_tags)",
-            [],
-        )?;
+            let existing = match self.get_image_by_hash(&hash) {
+                Ok(existing) => existing.is_some(),
+                Err(e) => {
+                    warn!("Failed to check for existing image {}: {}", name, e);
+                    false
+                }
+            };
+
+            if !existing {
+                let content_type = match image::guess_format(&data) {
+                    _ if heic::is_heif(&data) => heic::HEIC_CONTENT_TYPES[0],
+                    Ok(format) => Self::format_content_type(format),
+                    Err(e) => {
+                        result.failed += 1;
+                        result
+                            .errors
+                            .push(format!("{}: Invalid image: {}", name, e));
+                        continue;
+                    }
+                };
 
-        tx.commit()?;
+                if let Err(e) = self
+                    .add_image_data(&Bytes::from(data), &name, content_type)
+                    .await
+                {
+                    error!("Failed to import {} from ZIP catalog: {}", name, e);
+                    result.failed += 1;
+                    result.errors.push(format!("{}: {}", name, e));
+                    continue;
+                }
+            }
 
-        if file_path.exists() {
-            std::fs::remove_file(file_path)?;
+            if apply_tags && !tags.is_empty() {
+                if let Err(e) = self.add_tags(&hash, &tags) {
+                    error!("Failed to tag {} from ZIP catalog: {}", name, e);
+                    result.failed += 1;
+                    result
+                        .errors
+                        .push(format!("{}: Failed to add tags: {}", name, e));
+                    continue;
+                }
+            }
+
+            if existing {
+                result.updated += 1;
+            } else {
+                result.imported += 1;
+            }
         }
 
-        Ok(())
+        result.missing_from_archive = tags_by_file.into_keys().collect();
+        result.missing_from_archive.sort();
+
+        info!(
+            "ZIP catalog import complete: {} imported, {} updated, {} failed",
+            result.imported, result.updated, result.failed
+        );
+        Ok(result)
     }
 
-    pub fn update_api_key_status(&self, username: &str, is_active: bool) -> Result<()> {
-        let conn = self.pool.get()?;
+    /// Builds one page of a ZIP export in the `import-zip-catalog` format.
+    /// Only images created up to `snapshot_at` are selected, in
+    /// `(created_at, hash)` order after `cursor`, so images added while an
+    /// export is paged through don't shift it. The page is picked in one
+    /// read transaction, and files deleted before they are read are listed
+    /// in `skipped` rather than failing the export.
+    pub async fn export_zip(
+        &self,
+        snapshot_at: &str,
+        cursor: Option<(&str, &str)>,
+        limit: usize,
+    ) -> Result<(Vec<u8>, ExportManifest)> {
+        let (mut selected, mut tags) = {
+            let mut conn = self.pool.get()?;
+            let tx = conn.transaction()?;
+            let (after_created, after_hash) = cursor.unwrap_or(("", ""));
+            let selected = {
+                let mut stmt = tx.prepare(
+                    "SELECT filename, hash, created_at FROM images
+                     WHERE created_at <= ? AND (created_at, hash) > (?, ?)
+                     ORDER BY created_at, hash
+                     LIMIT ?",
+                )?;
+                let rows = stmt
+                    .query_map(
+                        params![snapshot_at, after_created, after_hash, limit as i64 + 1],
+                        |row| {
+                            Ok((
+                                row.get::<_, String>(0)?,
+                                row.get::<_, String>(1)?,
+                                row.get::<_, String>(2)?,
+                            ))
+                        },
+                    )?
+                    .collect::<Result<Vec<_>, _>>()?;
+                rows
+            };
+            let mut tags = HashMap::with_capacity(selected.len());
+            {
+                let mut stmt = tx.prepare(
+                    "SELECT t.name FROM tags t
+                     JOIN image_tags it ON t.id = it.tag_id
+                     WHERE it.image_hash = ?
+                     ORDER BY t.name",
+                )?;
+                for (_, hash, _) in selected.iter().take(limit) {
+                    let names = stmt
+                        .query_map([hash], |row| row.get::<_, String>(0))?
+                        .collect::<Result<Vec<_>, _>>()?;
+                    tags.insert(hash.clone(), names);
+                }
+            }
+            tx.commit()?;
+            (selected, tags)
+        };
 
-        let rows_affected = conn.execute(
-            "UPDATE api_keys SET is_active = ? WHERE username = ?",
-            params![is_active, username],
-        )?;
+        let next_cursor = if selected.len() > limit {
+            selected.truncate(limit);
+            selected
+                .last()
+                .map(|(_, hash, created_at)| format!("{},{}", created_at, hash))
+        } else {
+            None
+        };
 
-        if rows_affected == 0 {
-            return Err(anyhow!("No API key found for username: {}", username));
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        let mut images = Vec::with_capacity(selected.len());
+        let mut skipped = Vec::new();
+        for (filename, hash, _) in selected {
+            let data = match tokio::fs::read(self.images_dir.join(&filename)).await {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("Skipping {} in export: {}", filename, e);
+                    skipped.push(SkippedExport {
+                        filename,
+                        reason: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+            writer.start_file(filename.as_str(), options)?;
+            std::io::Write::write_all(&mut writer, &data)?;
+            images.push(CatalogEntry {
+                tags: tags.remove(&hash).unwrap_or_default(),
+                filename,
+            });
         }
 
-        Ok(())
+        let manifest = ExportManifest {
+            snapshot_at: snapshot_at.to_string(),
+            counts: ExportCounts {
+                included: images.len(),
+                skipped: skipped.len(),
+            },
+            images,
+            skipped,
+            next_cursor,
+        };
+        writer.start_file(
+            CATALOG_MANIFEST,
+            options.compression_method(zip::CompressionMethod::Deflated),
+        )?;
+        serde_json::to_writer_pretty(&mut writer, &manifest)?;
+        let data = writer.finish()?.into_inner();
+
+        Ok((data, manifest))
     }
 
-    pub fn get_random_image_with_filters(&self, filters: &ImageFilters) -> Result<ImageResponse> {
-        let conn = self.pool.get()?;
-        let mut conditions = Vec::new();
-        let mut param_values = Vec::new();
+    /// The manifest and files of a catalog ZIP. At most `max_entries`
+    /// entries and `max_bytes` of inflated data are read.
+    fn read_zip_catalog(
+        zip_data: &[u8],
+        max_entries: usize,
+        max_bytes: u64,
+    ) -> Result<(CatalogManifest, ZipFiles)> {
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(zip_data))
+            .map_err(|e| anyhow!("Invalid ZIP archive: {}", e))?;
+
+        if archive.len() > max_entries {
+            return Err(anyhow!(
+                "ZIP archive has {} entries (max {})",
+                archive.len(),
+                max_entries
+            ));
+        }
 
-        let mut query = String::from(
-            "SELECT i.filename, i.hash, i.created_at, i.modified_at 
-             FROM images i",
-        );
+        let mut manifest = None;
+        let mut files = Vec::new();
+        let mut total_bytes = 0u64;
 
-        if let Some(tags) = &filters.tags {
-            if !tags.is_empty() {
-                query.push_str(
-                    "
-                    JOIN image_tags it ON i.hash = it.image_hash
-                    JOIN tags t ON it.tag_id = t.id
-                ",
-                );
-                conditions.push(format!(
-                    "t.name IN ({})",
-                    tags.iter().map(|_| "?").collect::<Vec<_>>().join(",")
+        for i in 0..archive.len() {
+            let entry = archive.by_index(i)?;
+            if entry.is_dir() {
+                continue;
+            }
+
+            let name = entry.name().to_string();
+            if entry.size() > MAX_FILE_SIZE {
+                return Err(anyhow!(
+                    "File too large: {} is {} bytes (max {} bytes)",
+                    name,
+                    entry.size(),
+                    MAX_FILE_SIZE
                 ));
-                param_values.extend(tags.iter().cloned());
             }
-        }
+
+            // the sizes in the archive's headers are the sender's word, so
+            // the limits are checked against what actually inflates
+            let remaining = max_bytes - total_bytes;
+            let mut data = Vec::with_capacity(entry.size().min(remaining) as usize);
+            entry
+                .take(MAX_FILE_SIZE.min(remaining) + 1)
+                .read_to_end(&mut data)?;
+            if data.len() as u64 > MAX_FILE_SIZE {
+                return Err(anyhow!(
+                    "File too large: {} (max {} bytes)",
+                    name,
+                    MAX_FILE_SIZE
+                ));
+            }
+            total_bytes += data.len() as u64;
+            if total_bytes > max_bytes {
+                return Err(anyhow!(
+                    "ZIP archive inflates to more than {} bytes",
+                    max_bytes
+                ));
+            }
+
+            if name == CATALOG_MANIFEST {
+                manifest = Some(
+                    serde_json::from_slice::<CatalogManifest>(&data)
+                        .map_err(|e| anyhow!("Invalid {}: {}", CATALOG_MANIFEST, e))?,
+                );
+            } else {
+                // manifests refer to images by their base name
+                let base_name = name.rsplit('/').next().unwrap_or(&name).to_string();
+                files.push((base_name, data));
+            }
+        }
+
+        let manifest =
+            manifest.ok_or_else(|| anyhow!("ZIP archive is missing {}", CATALOG_MANIFEST))?;
+        Ok((manifest, files))
+    }
+
+    fn format_content_type(format: ImageFormat) -> &'static str {
+        match format {
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Png => "image/png",
+            ImageFormat::Gif => "image/gif",
+            ImageFormat::WebP => "image/webp",
+            ImageFormat::Bmp => "image/bmp",
+            _ => "application/octet-stream",
+        }
+    }
+
+    pub fn generate_api_key(
+        &self,
+        request: &GenerateApiKeyRequest,
+        scopes: &[ApiKeyScope],
+    ) -> Result<String> {
+        let conn = self.pool.get()?;
+        Self::insert_api_key(&conn, request, scopes)
+    }
+
+    /// Creates a key for each request in one transaction, so either all of
+    /// them are created or none are. Returns the keys in request order.
+    pub fn generate_api_keys(
+        &self,
+        requests: &[(&GenerateApiKeyRequest, Vec<ApiKeyScope>)],
+    ) -> Result<Vec<String>> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        let api_keys = requests
+            .iter()
+            .map(|(request, scopes)| Self::insert_api_key(&tx, request, scopes))
+            .collect::<Result<Vec<_>>>()?;
+        tx.commit()?;
+        Ok(api_keys)
+    }
+
+    /// Which of `usernames` already have a key.
+    pub fn existing_usernames(&self, usernames: &[&str]) -> Result<Vec<String>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare("SELECT 1 FROM api_keys WHERE username = ?")?;
+        let mut existing = Vec::new();
+        for username in usernames {
+            if stmt.exists([username])? {
+                existing.push(username.to_string());
+            }
+        }
+        Ok(existing)
+    }
+
+    fn insert_api_key(
+        conn: &rusqlite::Connection,
+        request: &GenerateApiKeyRequest,
+        scopes: &[ApiKeyScope],
+    ) -> Result<String> {
+        let api_key = Uuid::new_v4().to_string();
+        let now = OffsetDateTime::now_utc().format(&Rfc3339)?;
+
+        conn.execute(
+            "INSERT INTO api_keys (key, username, created_at, requests_per_second, max_batch_size, default_filters, scopes, url_ingest_daily_bytes, allowed_tags) 
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                &api_key,
+                &request.username,
+                &now,
+                request.requests_per_second,
+                request.max_batch_size,
+                Self::encode_default_filters(request.default_filters.as_ref())?,
+                Self::encode_scopes(scopes),
+                request.url_ingest_daily_bytes.map(|b| b as i64),
+                Self::encode_allowed_tags(&request.allowed_tags)
+            ],
+        )?;
+
+        Ok(api_key)
+    }
+
+    /// Creates a new key for `new_username` with the same settings as the
+    /// source user's key.
+    pub fn clone_api_key(&self, source_username: &str, new_username: &str) -> Result<String> {
+        let conn = self.pool.get()?;
+
+        let api_key = Uuid::new_v4().to_string();
+        let now = OffsetDateTime::now_utc().format(&Rfc3339)?;
+
+        let rows_affected = conn.execute(
+            "INSERT INTO api_keys (key, username, created_at, is_active, requests_per_second, max_batch_size, default_filters, scopes, url_ingest_daily_bytes, allowed_tags)
+             SELECT ?, ?, ?, is_active, requests_per_second, max_batch_size, default_filters, scopes, url_ingest_daily_bytes, allowed_tags
+             FROM api_keys WHERE username = ?",
+            params![&api_key, new_username, &now, source_username],
+        )?;
+
+        if rows_affected == 0 {
+            return Err(anyhow!(
+                "No API key found for username: {}",
+                source_username
+            ));
+        }
+
+        Ok(api_key)
+    }
+
+    pub fn remove_api_key(&self, username: &str, dry_run: bool) -> Result<bool> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM api_key_usage WHERE api_key IN (SELECT key FROM api_keys WHERE username = ?)",
+            [username],
+        )?;
+        // a later key reusing the name must not hear about these uploads
+        tx.execute(
+            "UPDATE images SET uploaded_by = NULL WHERE uploaded_by = ?",
+            [username],
+        )?;
+        tx.execute("DELETE FROM key_webhooks WHERE username = ?", [username])?;
+        let rows_affected = tx.execute("DELETE FROM api_keys WHERE username = ?", [username])?;
+        if !dry_run {
+            tx.commit()?;
+        }
+        Ok(rows_affected > 0)
+    }
+
+    pub fn count_api_keys(&self) -> Result<u64> {
+        let conn = self.pool.get()?;
+        Ok(conn.query_row("SELECT COUNT(*) FROM api_keys", [], |row| row.get(0))?)
+    }
+
+    pub fn list_api_keys(&self) -> Result<Vec<ApiKey>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "SELECT key, username, created_at, last_used_at, is_active, requests_per_second, max_batch_size, default_filters, scopes, url_ingest_daily_bytes, allowed_tags
+             FROM api_keys 
+             ORDER BY created_at DESC",
+        )?;
+
+        let keys = stmt
+            .query_map([], |row| {
+                let created_at_str: String = row.get(2)?;
+                let last_used_at_str: Option<String> = row.get(3)?;
+
+                let created_at = OffsetDateTime::parse(&created_at_str, &Rfc3339).map_err(|e| {
+                    SqliteError::FromSqlConversionFailure(
+                        0,
+                        rusqlite::types::Type::Text,
+                        Box::new(e),
+                    )
+                })?;
+
+                let last_used_at = if let Some(dt_str) = last_used_at_str {
+                    Some(OffsetDateTime::parse(&dt_str, &Rfc3339).map_err(|e| {
+                        SqliteError::FromSqlConversionFailure(
+                            0,
+                            rusqlite::types::Type::Text,
+                            Box::new(e),
+                        )
+                    })?)
+                } else {
+                    None
+                };
+
+                Ok(ApiKey {
+                    key: row.get(0)?,
+                    username: row.get(1)?,
+                    created_at,
+                    last_used_at,
+                    is_active: row.get(4)?,
+                    requests_per_second: row.get(5)?,
+                    max_batch_size: row.get(6)?,
+                    default_filters: Self::decode_default_filters(row.get(7)?)?,
+                    scopes: Self::decode_scopes(&row.get::<_, String>(8)?),
+                    url_ingest_daily_bytes: row.get::<_, Option<i64>>(9)?.map(|b| b as u64),
+                    allowed_tags: Self::decode_allowed_tags(row.get(10)?),
+                    is_admin: false,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(keys)
+    }
+
+    fn encode_default_filters(filters: Option<&DefaultFilters>) -> Result<Option<String>> {
+        match filters {
+            Some(filters) if !filters.is_empty() => Ok(Some(serde_json::to_string(filters)?)),
+            _ => Ok(None),
+        }
+    }
+
+    fn encode_scopes(scopes: &[ApiKeyScope]) -> String {
+        scopes
+            .iter()
+            .map(|scope| scope.as_str())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    fn decode_scopes(scopes: &str) -> Vec<ApiKeyScope> {
+        scopes.split(',').filter_map(ApiKeyScope::parse).collect()
+    }
+
+    /// Normalizes tags the way they are stored, dropping blanks and repeats.
+    pub fn normalize_tag_list(tags: &[String]) -> Vec<String> {
+        let mut normalized: Vec<String> = Vec::with_capacity(tags.len());
+        for tag in tags {
+            let tag = tag.trim().to_lowercase().replace(' ', "_");
+            if !tag.is_empty() && !normalized.contains(&tag) {
+                normalized.push(tag);
+            }
+        }
+        normalized
+    }
+
+    fn encode_allowed_tags(tags: &[String]) -> Option<String> {
+        let tags = Self::normalize_tag_list(tags);
+        (!tags.is_empty()).then(|| tags.join(","))
+    }
+
+    fn decode_allowed_tags(tags: Option<String>) -> Vec<String> {
+        tags.map(|tags| tags.split(',').map(str::to_string).collect())
+            .unwrap_or_default()
+    }
+
+    fn decode_default_filters(json: Option<String>) -> rusqlite::Result<Option<DefaultFilters>> {
+        json.map(|json| {
+            serde_json::from_str(&json).map_err(|e| {
+                SqliteError::FromSqlConversionFailure(7, rusqlite::types::Type::Text, Box::new(e))
+            })
+        })
+        .transpose()
+    }
+
+    pub fn validate_api_key(&self, key: &str) -> Result<bool> {
+        let conn = self.pool.get()?;
+        let result: Option<bool> = conn
+            .query_row(
+                "SELECT is_active FROM api_keys WHERE key = ?",
+                [key],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        match result {
+            Some(true) => Ok(true),
+            Some(false) => Err(anyhow!("inactive_key")),
+            None => Ok(false),
+        }
+    }
+
+    pub fn get_api_key(&self, key: &str) -> Result<ApiKey> {
+        let conn = self.pool.get()?;
+        let result = conn.query_row(
+            "SELECT key, username, created_at, last_used_at, is_active, requests_per_second, max_batch_size, default_filters, scopes, url_ingest_daily_bytes, allowed_tags FROM api_keys WHERE key = ?",
+            [key],
+            |row| {
+                let created_at_str: String = row.get(2)?;
+                let last_used_at_str: Option<String> = row.get(3)?;
+
+                let created_at = OffsetDateTime::parse(&created_at_str, &Rfc3339)
+                    .map_err(|e| SqliteError::FromSqlConversionFailure(
+                        0,
+                        rusqlite::types::Type::Text,
+                        Box::new(e),
+                    ))?;
+
+                let last_used_at = if let Some(dt_str) = last_used_at_str {
+                    Some(OffsetDateTime::parse(&dt_str, &Rfc3339)
+                        .map_err(|e| SqliteError::FromSqlConversionFailure(
+                            0,
+                            rusqlite::types::Type::Text,
+                            Box::new(e),
+                        ))?)
+                } else {
+                    None
+                };
+
+                Ok(ApiKey {
+                    key: row.get(0)?,
+                    username: row.get(1)?,
+                    created_at,
+                    last_used_at,
+                    is_active: row.get(4)?,
+                    requests_per_second: row.get(5)?,
+                    max_batch_size: row.get(6)?,
+                    default_filters: Self::decode_default_filters(row.get(7)?)?,
+                    scopes: Self::decode_scopes(&row.get::<_, String>(8)?),
+                    url_ingest_daily_bytes: row.get::<_, Option<i64>>(9)?.map(|b| b as u64),
+                    allowed_tags: Self::decode_allowed_tags(row.get(10)?),
+                    is_admin: false,
+                })
+            },
+        )?;
+        Ok(result)
+    }
+
+    pub fn get_api_key_for_username(&self, username: &str) -> Result<Option<String>> {
+        let conn = self.pool.get()?;
+        let key = conn
+            .query_row(
+                "SELECT key FROM api_keys WHERE username = ?",
+                [username],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(key)
+    }
+
+    /// Records which key's user added an image, for events about their own
+    /// uploads. The first uploader wins.
+    pub fn set_image_uploader(&self, hash: &str, username: &str) -> Result<()> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "UPDATE images SET uploaded_by = ? WHERE hash = ? AND uploaded_by IS NULL",
+            params![username, hash],
+        )?;
+        Ok(())
+    }
+
+    /// Registers or replaces `username`'s webhook.
+    pub fn set_key_webhook(&self, username: &str, url: &str, secret: &str) -> Result<KeyWebhook> {
+        let conn = self.pool.get()?;
+        let webhook = KeyWebhook {
+            username: username.to_string(),
+            url: url.to_string(),
+            secret: secret.to_string(),
+            created_at: OffsetDateTime::now_utc().format(&Rfc3339)?,
+        };
+        conn.execute(
+            "INSERT OR REPLACE INTO key_webhooks (username, url, secret, created_at) VALUES (?, ?, ?, ?)",
+            params![webhook.username, webhook.url, webhook.secret, webhook.created_at],
+        )?;
+        Ok(webhook)
+    }
+
+    pub fn get_key_webhook(&self, username: &str) -> Result<Option<KeyWebhook>> {
+        let conn = self.pool.get()?;
+        let webhook = conn
+            .query_row(
+                "SELECT username, url, secret, created_at FROM key_webhooks WHERE username = ?",
+                [username],
+                Self::key_webhook_from_row,
+            )
+            .optional()?;
+        Ok(webhook)
+    }
+
+    pub fn list_key_webhooks(&self) -> Result<Vec<KeyWebhook>> {
+        let conn = self.pool.get()?;
+        let webhooks = conn
+            .prepare(
+                "SELECT username, url, secret, created_at FROM key_webhooks ORDER BY username",
+            )?
+            .query_map([], Self::key_webhook_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(webhooks)
+    }
+
+    pub fn remove_key_webhook(&self, username: &str) -> Result<bool> {
+        let conn = self.pool.get()?;
+        let removed = conn.execute("DELETE FROM key_webhooks WHERE username = ?", [username])?;
+        Ok(removed > 0)
+    }
+
+    pub fn list_collections(&self) -> Result<Vec<Collection>> {
+        let conn = self.pool.get()?;
+        let collections = conn
+            .prepare(
+                "SELECT c.name, COUNT(ci.image_hash), c.created_at
+                 FROM collections c LEFT JOIN collection_images ci ON ci.collection = c.name
+                 GROUP BY c.name ORDER BY c.name",
+            )?
+            .query_map([], |row| {
+                Ok(Collection {
+                    name: row.get(0)?,
+                    images: row.get::<_, i64>(1)? as u64,
+                    created_at: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(collections)
+    }
+
+    /// The hashes in a collection, oldest addition first, or `None` if it
+    /// doesn't exist.
+    pub fn collection_hashes(&self, name: &str) -> Result<Option<Vec<String>>> {
+        let conn = self.pool.get()?;
+        if !Self::collection_exists(&conn, name)? {
+            return Ok(None);
+        }
+        let hashes = conn
+            .prepare(
+                "SELECT image_hash FROM collection_images WHERE collection = ?
+                 ORDER BY added_at, image_hash",
+            )?
+            .query_map([name], |row| row.get(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(hashes))
+    }
+
+    /// Returns false if the collection already existed.
+    pub fn create_collection(&self, name: &str) -> Result<bool> {
+        let conn = self.pool.get()?;
+        let now = OffsetDateTime::now_utc().format(&Rfc3339)?;
+        let created = conn.execute(
+            "INSERT OR IGNORE INTO collections (name, created_at) VALUES (?, ?)",
+            params![name, now],
+        )?;
+        Ok(created > 0)
+    }
+
+    /// Deletes the collection, leaving its images alone. Returns false if it
+    /// didn't exist.
+    pub fn delete_collection(&self, name: &str) -> Result<bool> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM collection_images WHERE collection = ?", [name])?;
+        let removed = tx.execute("DELETE FROM collections WHERE name = ?", [name])?;
+        tx.commit()?;
+        Ok(removed > 0)
+    }
+
+    /// Adds images by hash. Returns the hashes that were added and those no
+    /// image has; hashes already in the collection are in neither.
+    pub fn add_to_collection(
+        &self,
+        name: &str,
+        hashes: &[String],
+    ) -> Result<(Vec<String>, Vec<String>)> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        if !Self::collection_exists(&tx, name)? {
+            return Err(anyhow!("Collection not found: {}", name));
+        }
+        let now = OffsetDateTime::now_utc().format(&Rfc3339)?;
+        let mut added = Vec::new();
+        let mut not_found = Vec::new();
+        for hash in hashes {
+            let rows = tx.execute(
+                "INSERT OR IGNORE INTO collection_images (collection, image_hash, added_at)
+                 SELECT ?, hash, ? FROM images WHERE hash = ?",
+                params![name, now, hash],
+            )?;
+            if rows > 0 {
+                added.push(hash.clone());
+            } else if tx
+                .query_row("SELECT 1 FROM images WHERE hash = ?", [hash], |_| Ok(()))
+                .optional()?
+                .is_none()
+            {
+                not_found.push(hash.clone());
+            }
+        }
+        tx.commit()?;
+        Ok((added, not_found))
+    }
+
+    /// Returns the hashes that were in the collection and got removed.
+    pub fn remove_from_collection(&self, name: &str, hashes: &[String]) -> Result<Vec<String>> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        if !Self::collection_exists(&tx, name)? {
+            return Err(anyhow!("Collection not found: {}", name));
+        }
+        let mut removed = Vec::new();
+        for hash in hashes {
+            let rows = tx.execute(
+                "DELETE FROM collection_images WHERE collection = ? AND image_hash = ?",
+                params![name, hash],
+            )?;
+            if rows > 0 {
+                removed.push(hash.clone());
+            }
+        }
+        tx.commit()?;
+        Ok(removed)
+    }
+
+    fn collection_exists(conn: &rusqlite::Connection, name: &str) -> Result<bool> {
+        Ok(conn
+            .query_row("SELECT 1 FROM collections WHERE name = ?", [name], |_| {
+                Ok(())
+            })
+            .optional()?
+            .is_some())
+    }
+
+    /// The webhook of whoever uploaded `filename`, if they registered one.
+    pub fn uploader_webhook(&self, filename: &str) -> Result<Option<KeyWebhook>> {
+        let conn = self.pool.get()?;
+        let webhook = conn
+            .query_row(
+                "SELECT w.username, w.url, w.secret, w.created_at
+                 FROM images i JOIN key_webhooks w ON w.username = i.uploaded_by
+                 WHERE i.filename = ?",
+                [filename],
+                Self::key_webhook_from_row,
+            )
+            .optional()?;
+        Ok(webhook)
+    }
+
+    fn key_webhook_from_row(row: &rusqlite::Row) -> rusqlite::Result<KeyWebhook> {
+        Ok(KeyWebhook {
+            username: row.get(0)?,
+            url: row.get(1)?,
+            secret: row.get(2)?,
+            created_at: row.get(3)?,
+        })
+    }
+
+    pub fn update_key_last_used(&self, key: &str) -> Result<()> {
+        let conn = self.pool.get()?;
+        let now = OffsetDateTime::now_utc().format(&Rfc3339)?;
+
+        conn.execute(
+            "UPDATE api_keys SET last_used_at = ? WHERE key = ?",
+            params![now, key],
+        )?;
+
+        Ok(())
+    }
+
+    /// Sets the rate limit, and the default filters too when given. Empty
+    /// filters clear them.
+    /// `requests_per_second` is always overwritten, the other fields only
+    /// when present in `changes`.
+    pub fn update_api_key(&self, username: &str, changes: &UpdateApiKeyRequest) -> Result<()> {
+        let conn = self.pool.get()?;
+
+        let mut sets = vec!["requests_per_second = ?"];
+        let mut values: Vec<Value> = vec![changes.requests_per_second.map(i64::from).into()];
+        if let Some(filters) = &changes.default_filters {
+            sets.push("default_filters = ?");
+            values.push(Self::encode_default_filters(Some(filters))?.into());
+        }
+        if let Some(scopes) = &changes.scopes {
+            sets.push("scopes = ?");
+            values.push(Self::encode_scopes(scopes).into());
+        }
+        if let Some(quota) = changes.url_ingest_daily_bytes {
+            sets.push("url_ingest_daily_bytes = ?");
+            values.push(quota.map(|b| b as i64).into());
+        }
+        if let Some(tags) = &changes.allowed_tags {
+            sets.push("allowed_tags = ?");
+            values.push(Self::encode_allowed_tags(tags).into());
+        }
+        values.push(username.to_string().into());
+
+        let rows_affected = conn.execute(
+            &format!(
+                "UPDATE api_keys SET {} WHERE username = ? AND is_active = 1",
+                sets.join(", ")
+            ),
+            rusqlite::params_from_iter(values),
+        )?;
+
+        if rows_affected == 0 {
+            return Err(anyhow!(
+                "No active API key found for username: {}",
+                username
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the first of `tags` that `BLOCKED_TAGS` rejects, normalized.
+    pub fn find_blocked_tag(&self, tags: &[String]) -> Option<String> {
+        tags.iter()
+            .map(|tag| tag.to_lowercase().replace(' ', "_"))
+            .find(|tag| self.blocked_tags.contains(tag))
+    }
+
+    pub fn add_tags(&self, image_hash: &str, tags: &[String]) -> Result<()> {
+        if let Some(tag) = self.find_blocked_tag(tags) {
+            return Err(anyhow!("Tag is blocked: {}", tag));
+        }
+
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        Self::touch_image(&tx, image_hash)?;
+        self.attach_tags(&tx, image_hash, tags)?;
+        Self::log_change(&tx, ChangeEvent::TagsChanged, image_hash)?;
+
+        tx.commit()?;
+        self.tag_cache.invalidate(image_hash);
+        self.sync_sidecar(image_hash);
+        Ok(())
+    }
+
+    /// Returns the tags that were on the image and got removed. A dry run
+    /// rolls everything back.
+    pub fn remove_tags(
+        &self,
+        image_hash: &str,
+        tags: &[String],
+        dry_run: bool,
+    ) -> Result<Vec<String>> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        Self::touch_image(&tx, image_hash)?;
+        let removed = Self::detach_tags(&tx, image_hash, tags)?;
+        self.drop_empty_tags(&tx)?;
+        Self::log_change(&tx, ChangeEvent::TagsChanged, image_hash)?;
+
+        if dry_run {
+            return Ok(removed);
+        }
+        tx.commit()?;
+        self.tag_cache.invalidate(image_hash);
+        self.sync_sidecar(image_hash);
+        Ok(removed)
+    }
+
+    /// Applies each change to the image stored under its hash, in one
+    /// transaction. Removals run after additions, so a tag in both lists ends
+    /// up removed. Nothing changes if a hash is unknown or a tag is blocked.
+    pub fn change_tags_by_hash(
+        &self,
+        changes: &[HashTagChange],
+        dry_run: bool,
+    ) -> Result<Vec<HashTagChangeResult>> {
+        let added: Vec<String> = changes.iter().flat_map(|c| c.add.clone()).collect();
+        if let Some(tag) = self.find_blocked_tag(&added) {
+            return Err(anyhow!("Tag is blocked: {}", tag));
+        }
+
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        let mut results = Vec::with_capacity(changes.len());
+        for change in changes {
+            let filename: String = tx
+                .query_row(
+                    "SELECT filename FROM images WHERE hash = ?",
+                    [&change.hash],
+                    |row| row.get(0),
+                )
+                .optional()?
+                .ok_or_else(|| anyhow!("Image not found: {}", change.hash))?;
+            Self::touch_image(&tx, &change.hash)?;
+            let added = self.attach_tags(&tx, &change.hash, &change.add)?;
+            let removed = Self::detach_tags(&tx, &change.hash, &change.remove)?;
+            Self::log_change(&tx, ChangeEvent::TagsChanged, &change.hash)?;
+            results.push(HashTagChangeResult {
+                hash: change.hash.clone(),
+                filename,
+                added,
+                removed,
+            });
+        }
+        self.drop_empty_tags(&tx)?;
+
+        if dry_run {
+            return Ok(results);
+        }
+        tx.commit()?;
+        for result in &results {
+            self.tag_cache.invalidate(&result.hash);
+            self.sync_sidecar(&result.hash);
+        }
+        Ok(results)
+    }
+
+    /// Fails with `TagLimitReached` if `tags` would create more tags than
+    /// `MAX_DISTINCT_TAGS` allows. Lets callers refuse before storing an
+    /// image; `add_tags` enforces the limit regardless.
+    pub fn check_tag_limit(&self, tags: &[String]) -> Result<()> {
+        let Some(limit) = self.max_distinct_tags else {
+            return Ok(());
+        };
+        let conn = self.pool.get()?;
+        let mut new_tags = Vec::new();
+        for tag in tags {
+            let tag = tag.to_lowercase().replace(' ', "_");
+            if !new_tags.contains(&tag) && !Self::tag_exists(&conn, &tag)? {
+                new_tags.push(tag);
+            }
+        }
+        if !new_tags.is_empty() && Self::count_tags(&conn)? + new_tags.len() as u64 > limit {
+            return Err(TagLimitReached { limit }.into());
+        }
+        Ok(())
+    }
+
+    fn tag_exists(conn: &rusqlite::Connection, tag: &str) -> Result<bool> {
+        Ok(conn
+            .query_row("SELECT 1 FROM tags WHERE name = ?", [tag], |_| Ok(()))
+            .optional()?
+            .is_some())
+    }
+
+    fn count_tags(conn: &rusqlite::Connection) -> Result<u64> {
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM tags", [], |row| row.get(0))?;
+        Ok(count as u64)
+    }
+
+    /// Returns the tags the image didn't have yet, normalized. Creating a
+    /// tag beyond `MAX_DISTINCT_TAGS` fails with `TagLimitReached`; existing
+    /// tags can always be applied.
+    fn attach_tags(
+        &self,
+        conn: &rusqlite::Connection,
+        image_hash: &str,
+        tags: &[String],
+    ) -> Result<Vec<String>> {
+        let mut tag_count = None;
+        let mut added = Vec::new();
+        for tag in tags {
+            let tag = tag.to_lowercase().replace(' ', "_");
+
+            if let Some(limit) = self.max_distinct_tags {
+                if !Self::tag_exists(conn, &tag)? {
+                    let count = match tag_count {
+                        Some(count) => count,
+                        None => Self::count_tags(conn)?,
+                    };
+                    if count >= limit {
+                        return Err(TagLimitReached { limit }.into());
+                    }
+                    tag_count = Some(count + 1);
+                }
+            }
+            conn.execute("INSERT OR IGNORE INTO tags (name) VALUES (?)", [&tag])?;
+
+            let tag_id: i64 =
+                conn.query_row("SELECT id FROM tags WHERE name = ?", [&tag], |row| {
+                    row.get(0)
+                })?;
+
+            let rows = conn.execute(
+                "INSERT OR IGNORE INTO image_tags (image_hash, tag_id) VALUES (?, ?)",
+                params![image_hash, tag_id],
+            )?;
+            if rows > 0 {
+                added.push(tag);
+            }
+        }
+        Ok(added)
+    }
+
+    /// Returns the tags that were on the image and got detached, normalized.
+    /// Leaves empty tags to the caller's `drop_empty_tags`.
+    fn detach_tags(
+        conn: &rusqlite::Connection,
+        image_hash: &str,
+        tags: &[String],
+    ) -> Result<Vec<String>> {
+        let mut removed = Vec::new();
+        for tag in tags {
+            let tag = tag.to_lowercase().replace(' ', "_");
+
+            if let Ok(tag_id) =
+                conn.query_row("SELECT id FROM tags WHERE name = ?", [&tag], |row| {
+                    row.get::<_, i64>(0)
+                })
+            {
+                let rows = conn.execute(
+                    "DELETE FROM image_tags WHERE image_hash = ? AND tag_id = ?",
+                    params![image_hash, tag_id],
+                )?;
+                if rows > 0 {
+                    removed.push(tag);
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Merges every tag of `source` (a filename or hash) into the image stored
+    /// as `target_filename` in a single transaction. With `replace` the
+    /// target's tags that `source` lacks are removed, so both end up with the
+    /// same tags.
+    /// Copies `source`'s tags onto the target, replacing the target's own
+    /// when `replace` is set. With `include_metadata` the source's metadata
+    /// is copied too, in the same transaction.
+    pub fn copy_tags(
+        &self,
+        target_filename: &str,
+        source: &str,
+        replace: bool,
+        include_metadata: bool,
+    ) -> Result<CopyTagsResult> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+
+        let target_hash: String = tx
+            .query_row(
+                "SELECT hash FROM images WHERE filename = ?",
+                [target_filename],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| anyhow!("Image not found: {}", target_filename))?;
+        let source_hash: String = tx
+            .query_row(
+                "SELECT hash FROM images WHERE filename = ?1 OR hash = ?1",
+                [source],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| anyhow!("Source image not found: {}", source))?;
+
+        if source_hash == target_hash {
+            return Err(anyhow!("Cannot copy tags from an image to itself"));
+        }
+
+        let source_tags = {
+            let mut stmt = tx.prepare(
+                "SELECT t.id, t.name 
+                 FROM tags t 
+                 JOIN image_tags it ON t.id = it.tag_id 
+                 WHERE it.image_hash = ?
+                 ORDER BY t.name",
+            )?;
+            let tags = stmt
+                .query_map([&source_hash], |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            tags
+        };
+
+        let removed = if replace {
+            let stale = {
+                let mut stmt = tx.prepare(
+                    "SELECT t.name 
+                     FROM tags t 
+                     JOIN image_tags it ON t.id = it.tag_id 
+                     WHERE it.image_hash = ?",
+                )?;
+                let tags = stmt
+                    .query_map([&target_hash], |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()?;
+                tags
+            };
+            let stale: Vec<String> = stale
+                .into_iter()
+                .filter(|tag| !source_tags.iter().any(|(_, name)| name == tag))
+                .collect();
+            let removed = Self::detach_tags(&tx, &target_hash, &stale)?;
+            self.drop_empty_tags(&tx)?;
+            removed
+        } else {
+            Vec::new()
+        };
+
+        let mut added = Vec::new();
+        let mut already_present = Vec::new();
+        for (tag_id, name) in source_tags {
+            let inserted = tx.execute(
+                "INSERT OR IGNORE INTO image_tags (image_hash, tag_id) VALUES (?, ?)",
+                params![&target_hash, tag_id],
+            )?;
+            if inserted > 0 {
+                added.push(name);
+            } else {
+                already_present.push(name);
+            }
+        }
+
+        if !added.is_empty() || !removed.is_empty() {
+            Self::touch_image(&tx, &target_hash)?;
+            Self::log_change(&tx, ChangeEvent::TagsChanged, &target_hash)?;
+        }
+
+        let metadata = if include_metadata {
+            let copied = tx.execute(
+                "INSERT INTO image_metadata (image_hash, key, value)
+                 SELECT ?1, key, value FROM image_metadata WHERE image_hash = ?2
+                 ON CONFLICT (image_hash, key) DO UPDATE SET value = excluded.value",
+                params![&target_hash, &source_hash],
+            )?;
+            if copied > 0 {
+                Self::touch_image(&tx, &target_hash)?;
+                Self::log_change(&tx, ChangeEvent::MetadataChanged, &target_hash)?;
+            }
+            let mut stmt =
+                tx.prepare("SELECT key, value FROM image_metadata WHERE image_hash = ?")?;
+            let metadata = stmt
+                .query_map([&target_hash], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<BTreeMap<String, String>, _>>()?;
+            Some(metadata)
+        } else {
+            None
+        };
+
+        let tags = {
+            let mut stmt = tx.prepare(
+                "SELECT t.name 
+                 FROM tags t 
+                 JOIN image_tags it ON t.id = it.tag_id 
+                 WHERE it.image_hash = ?
+                 ORDER BY t.name",
+            )?;
+            let tags = stmt
+                .query_map([&target_hash], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            tags
+        };
+
+        tx.commit()?;
+        self.tag_cache.invalidate(&target_hash);
+        self.sync_sidecar(&target_hash);
+        Ok(CopyTagsResult {
+            tags,
+            added,
+            already_present,
+            removed,
+            metadata,
+        })
+    }
+
+    /// Sets (or overwrites) custom metadata keys on an image and returns all
+    /// of its metadata.
+    pub fn set_image_metadata(
+        &self,
+        filename: &str,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<BTreeMap<String, String>> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+
+        let hash: String = tx
+            .query_row(
+                "SELECT hash FROM images WHERE filename = ?",
+                [filename],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| anyhow!("Image not found: {}", filename))?;
+
+        for (key, value) in metadata {
+            tx.execute(
+                "INSERT INTO image_metadata (image_hash, key, value) VALUES (?, ?, ?)
+                 ON CONFLICT (image_hash, key) DO UPDATE SET value = excluded.value",
+                params![&hash, key, value],
+            )?;
+        }
+        Self::touch_image(&tx, &hash)?;
+        Self::log_change(&tx, ChangeEvent::MetadataChanged, &hash)?;
+
+        let all = {
+            let mut stmt =
+                tx.prepare("SELECT key, value FROM image_metadata WHERE image_hash = ?")?;
+            let all = stmt
+                .query_map([&hash], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<BTreeMap<String, String>, _>>()?;
+            all
+        };
+
+        tx.commit()?;
+        self.sync_sidecar(&hash);
+        Ok(all)
+    }
+
+    /// Bumps `modified_at` so sync tooling and caches notice the change.
+    fn touch_image(conn: &rusqlite::Connection, image_hash: &str) -> Result<()> {
+        let now = OffsetDateTime::now_utc().format(&Rfc3339)?;
+        conn.execute(
+            "UPDATE images SET modified_at = ? WHERE hash = ?",
+            params![now, image_hash],
+        )?;
+        Ok(())
+    }
+
+    /// Appends to the `/sync/changes` feed. Must run while the image row
+    /// still exists, since the filename is read from it. Earlier entries the
+    /// new one supersedes are dropped.
+    fn log_change(conn: &rusqlite::Connection, event: ChangeEvent, image_hash: &str) -> Result<()> {
+        if event.collapses() {
+            conn.execute(
+                "DELETE FROM change_log WHERE image_hash = ? AND event = ?",
+                params![image_hash, event.as_str()],
+            )?;
+        } else if event == ChangeEvent::ImageRemoved {
+            conn.execute(
+                "DELETE FROM change_log WHERE image_hash = ? AND event IN (?, ?)",
+                params![
+                    image_hash,
+                    ChangeEvent::TagsChanged.as_str(),
+                    ChangeEvent::MetadataChanged.as_str()
+                ],
+            )?;
+        }
+
+        let now = OffsetDateTime::now_utc().format(&Rfc3339)?;
+        conn.execute(
+            "INSERT INTO change_log (event, image_hash, filename, created_at) 
+             SELECT ?, hash, filename, ? FROM images WHERE hash = ?",
+            params![event.as_str(), now, image_hash],
+        )?;
+        Ok(())
+    }
+
+    /// Returns up to `limit` change log entries after `since`, oldest first,
+    /// along with the highest cursor that retention has dropped. With
+    /// `allowed_tags`, only entries for images carrying one of them are
+    /// returned, plus those for images that are gone, so removals still
+    /// reach the caller.
+    pub fn get_changes(
+        &self,
+        since: i64,
+        limit: usize,
+        allowed_tags: &[String],
+    ) -> Result<(Vec<ChangeEntry>, i64)> {
+        let conn = self.pool.get()?;
+        let pruned_through: i64 = conn
+            .query_row(
+                "SELECT cursor FROM change_log_pruned WHERE id = 1",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or(0);
+
+        let visible = if allowed_tags.is_empty() {
+            String::new()
+        } else {
+            format!(
+                " AND (NOT EXISTS (SELECT 1 FROM images WHERE hash = change_log.image_hash) OR {})",
+                Self::tagged_with_any("change_log.image_hash", allowed_tags)
+            )
+        };
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, event, image_hash, filename, created_at 
+             FROM change_log 
+             WHERE id > ?{} 
+             ORDER BY id 
+             LIMIT ?",
+            visible
+        ))?;
+        let mut values: Vec<Value> = vec![since.into()];
+        values.extend(allowed_tags.iter().map(|tag| Value::from(tag.clone())));
+        values.push((limit as i64).into());
+        let changes = stmt
+            .query_map(rusqlite::params_from_iter(values), |row| {
+                Ok(ChangeEntry {
+                    cursor: row.get(0)?,
+                    event: row.get(1)?,
+                    hash: row.get(2)?,
+                    filename: row.get(3)?,
+                    occurred_at: row.get(4)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok((changes, pruned_through))
+    }
+
+    pub fn prune_change_log(&self, retention: Duration) -> Result<usize> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        let pruned = {
+            let mut stmt = tx.prepare(
+                "DELETE FROM change_log WHERE julianday(created_at) <= julianday('now', ?) RETURNING id",
+            )?;
+            let pruned = stmt
+                .query_map([format!("-{} seconds", retention.as_secs())], |row| {
+                    row.get::<_, i64>(0)
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            pruned
+        };
+        if let Some(max) = pruned.iter().max() {
+            tx.execute(
+                "INSERT INTO change_log_pruned (id, cursor) VALUES (1, ?) 
+                 ON CONFLICT (id) DO UPDATE SET cursor = MAX(cursor, excluded.cursor)",
+                [max],
+            )?;
+        }
+        tx.commit()?;
+        Ok(pruned.len())
+    }
+
+    /// Filenames of images tagged `tag` that were added more than `max_age`
+    /// ago.
+    pub fn images_past_tag_ttl(&self, tag: &str, max_age: Duration) -> Result<Vec<String>> {
+        let conn = self.pool.get()?;
+        let cutoff = (OffsetDateTime::now_utc() - max_age).format(&Rfc3339)?;
+        let mut stmt = conn.prepare(
+            "SELECT i.filename 
+             FROM images i 
+             JOIN image_tags it ON it.image_hash = i.hash 
+             JOIN tags t ON t.id = it.tag_id 
+             WHERE t.name = ? AND julianday(i.created_at) <= julianday(?)",
+        )?;
+        let filenames = stmt
+            .query_map(params![tag, cutoff], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(filenames)
+    }
+
+    /// Images modified after `since`, limited to those carrying one of
+    /// `allowed_tags` when given.
+    pub fn get_changed_since(
+        &self,
+        since: OffsetDateTime,
+        allowed_tags: &[String],
+    ) -> Result<Vec<(String, String)>> {
+        let conn = self.pool.get()?;
+        let since = since.to_offset(time::UtcOffset::UTC).format(&Rfc3339)?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT filename, modified_at 
+             FROM images 
+             WHERE julianday(modified_at) > julianday(?){} 
+             ORDER BY julianday(modified_at)",
+            Self::visible_to("images.hash", allowed_tags)
+        ))?;
+
+        let images = stmt
+            .query_map(
+                rusqlite::params_from_iter(std::iter::once(&since).chain(allowed_tags)),
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?
+            .collect::<Result<Vec<(String, String)>, _>>()?;
+
+        Ok(images)
+    }
+
+    pub fn get_image_tags(&self, image_hash: &str) -> Result<Vec<String>> {
+        if let Some(tags) = self.tag_cache.get(image_hash) {
+            return Ok(tags);
+        }
+
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "SELECT t.name 
+             FROM tags t 
+             JOIN image_tags it ON t.id = it.tag_id 
+             WHERE it.image_hash = ?
+             ORDER BY t.name",
+        )?;
+
+        let tags = stmt
+            .query_map([image_hash], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.tag_cache.insert(image_hash, &tags);
+        Ok(tags)
+    }
+
+    /// Tags without any images are left out unless `include_empty` is set.
+    pub fn get_all_tags(&self, include_empty: bool) -> Result<Vec<(String, i64)>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT t.name, COUNT(it.image_hash) as count 
+             FROM tags t 
+             LEFT JOIN image_tags it ON t.id = it.tag_id 
+             GROUP BY t.name 
+             {}
+             ORDER BY t.name",
+            if include_empty {
+                ""
+            } else {
+                "HAVING count > 0"
+            }
+        ))?;
+
+        let tags = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(String, i64)>, _>>()?;
+
+        Ok(tags)
+    }
+
+    /// Groups tags in use by namespace, the part before the first `:` as in
+    /// `artist:name`, and counts the tags in each. Tags without one are left
+    /// out.
+    pub fn list_namespaces(&self) -> Result<Vec<(String, i64)>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "SELECT substr(t.name, 1, instr(t.name, ':') - 1) AS namespace, COUNT(*) 
+             FROM tags t 
+             WHERE instr(t.name, ':') > 1 
+               AND EXISTS (SELECT 1 FROM image_tags it WHERE it.tag_id = t.id) 
+             GROUP BY namespace 
+             ORDER BY namespace",
+        )?;
+
+        let namespaces = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(String, i64)>, _>>()?;
+
+        Ok(namespaces)
+    }
+
+    /// Deletes tags that no longer have any images, returning their names.
+    /// Runs regardless of `KEEP_EMPTY_TAGS`.
+    pub fn gc_tags(&self, dry_run: bool) -> Result<Vec<String>> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        let removed = {
+            let mut stmt = tx.prepare(&format!(
+                "DELETE FROM tags WHERE {} RETURNING name",
+                EMPTY_TAG_CONDITION
+            ))?;
+            let mut removed = stmt
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            removed.sort();
+            removed
+        };
+        if !dry_run {
+            tx.commit()?;
+        }
+        Ok(removed)
+    }
+
+    /// Applies `mappings` in order in one transaction, so a later mapping
+    /// sees the result of earlier ones. Nothing changes if any target is
+    /// blocked.
+    pub fn remap_tags(&self, mappings: &[TagMapping], dry_run: bool) -> Result<Vec<TagRemapEntry>> {
+        let targets: Vec<String> = mappings.iter().map(|m| m.to.clone()).collect();
+        if let Some(tag) = self.find_blocked_tag(&targets) {
+            return Err(anyhow!("Tag is blocked: {}", tag));
+        }
+
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        let mut results = Vec::with_capacity(mappings.len());
+        let mut touched = Vec::new();
+        for mapping in mappings {
+            let from = mapping.from.trim().to_lowercase().replace(' ', "_");
+            let to = mapping.to.trim().to_lowercase().replace(' ', "_");
+            if from.is_empty() || to.is_empty() {
+                return Err(anyhow!("Invalid mapping: tag names must not be empty"));
+            }
+            let (status, hashes) = Self::rename_tag(&tx, &from, &to)?;
+            results.push(TagRemapEntry {
+                from,
+                to,
+                status,
+                images: hashes.len(),
+            });
+            touched.extend(hashes);
+        }
+        if dry_run {
+            return Ok(results);
+        }
+        tx.commit()?;
+
+        touched.sort();
+        touched.dedup();
+        for hash in &touched {
+            self.tag_cache.invalidate(hash);
+            self.sync_sidecar(hash);
+        }
+        Ok(results)
+    }
+
+    /// Renames `from` to `to`, merging into `to` when it already exists.
+    /// Returns the images that carried `from`.
+    fn rename_tag(
+        tx: &rusqlite::Transaction,
+        from: &str,
+        to: &str,
+    ) -> Result<(TagRemapStatus, Vec<String>)> {
+        let Some(from_id) = tx
+            .query_row("SELECT id FROM tags WHERE name = ?", [from], |row| {
+                row.get::<_, i64>(0)
+            })
+            .optional()?
+        else {
+            return Ok((TagRemapStatus::NotFound, Vec::new()));
+        };
+        if from == to {
+            return Ok((TagRemapStatus::Unchanged, Vec::new()));
+        }
+
+        let hashes = {
+            let mut stmt = tx.prepare("SELECT image_hash FROM image_tags WHERE tag_id = ?")?;
+            let hashes = stmt
+                .query_map([from_id], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            hashes
+        };
+
+        let to_id = tx
+            .query_row("SELECT id FROM tags WHERE name = ?", [to], |row| {
+                row.get::<_, i64>(0)
+            })
+            .optional()?;
+        let status = match to_id {
+            Some(to_id) => {
+                tx.execute(
+                    "INSERT OR IGNORE INTO image_tags (image_hash, tag_id)
+                     SELECT image_hash, ? FROM image_tags WHERE tag_id = ?",
+                    params![to_id, from_id],
+                )?;
+                tx.execute("DELETE FROM image_tags WHERE tag_id = ?", [from_id])?;
+                tx.execute("DELETE FROM tags WHERE id = ?", [from_id])?;
+                TagRemapStatus::Merged
+            }
+            None => {
+                tx.execute(
+                    "UPDATE tags SET name = ? WHERE id = ?",
+                    params![to, from_id],
+                )?;
+                TagRemapStatus::Renamed
+            }
+        };
+
+        for hash in &hashes {
+            Self::touch_image(tx, hash)?;
+            Self::log_change(tx, ChangeEvent::TagsChanged, hash)?;
+        }
+        Ok((status, hashes))
+    }
+
+    /// Applies the `KEEP_EMPTY_TAGS` policy after associations were removed.
+    /// Every path that detaches tags from images goes through here.
+    fn drop_empty_tags(&self, conn: &rusqlite::Connection) -> Result<()> {
+        if !self.keep_empty_tags {
+            conn.execute(
+                &format!("DELETE FROM tags WHERE {}", EMPTY_TAG_CONDITION),
+                [],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn autocomplete_tags(&self, prefix: &str, limit: usize) -> Result<Vec<String>> {
+        let conn = self.pool.get()?;
+        let prefix = prefix
+            .to_lowercase()
+            .replace(' ', "_")
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+
+        let mut stmt = conn.prepare(
+            "SELECT name 
+             FROM tags 
+             WHERE name LIKE ? || '%' ESCAPE '\\'
+             ORDER BY name 
+             LIMIT ?",
+        )?;
+
+        let tags = stmt
+            .query_map(params![prefix, limit as i64], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(tags)
+    }
+
+    /// Returns the removed image's hash. A dry run rolls back and leaves the
+    /// file and its sidecar alone.
+    pub fn remove_image(&self, filename: &str, dry_run: bool) -> Result<String> {
+        let mut conn = self.pool.get()?;
+        let file_path = self.images_dir.join(filename);
+
+        let tx = conn.transaction()?;
+
+        let hash: String = tx.query_row(
+            "SELECT hash FROM images WHERE filename = ?",
+            [filename],
+            |row| row.get(0),
+        )?;
+
+        Self::log_change(&tx, ChangeEvent::ImageRemoved, &hash)?;
+        tx.execute("DELETE FROM image_tags WHERE image_hash = ?", [&hash])?;
+        tx.execute("DELETE FROM image_metadata WHERE image_hash = ?", [&hash])?;
+        tx.execute(
+            "DELETE FROM collection_images WHERE image_hash = ?",
+            [&hash],
+        )?;
+
+        tx.execute("DELETE FROM images WHERE hash = ?", [&hash])?;
+
+        self.drop_empty_tags(&tx)?;
+        let derived = Self::take_derived_files(&tx, &hash)?;
+
+        if dry_run {
+            return Ok(hash);
+        }
+        tx.commit()?;
+        self.phash_index.remove(&hash);
+        self.tag_cache.invalidate(&hash);
+        self.remove_derived_paths(&derived);
+
+        if file_path.exists() {
+            std::fs::remove_file(file_path)?;
+        }
+        if let Err(e) = sidecar::remove(&self.images_dir, filename) {
+            warn!("Failed to remove sidecar for image {}: {}", filename, e);
+        }
+
+        Ok(hash)
+    }
+
+    /// Writes a file generated from the image with `parent_hash`, replacing
+    /// any earlier one for the same variant.
+    pub fn put_derived_file(
+        &self,
+        parent_hash: &str,
+        variant: &str,
+        data: &[u8],
+    ) -> Result<PathBuf> {
+        if variant.is_empty()
+            || !variant
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        {
+            return Err(anyhow!("Invalid variant key: {}", variant));
+        }
+        std::fs::create_dir_all(&self.derived.dir)?;
+        let name = format!("{}_{}", parent_hash, variant);
+        let path = self.derived.dir.join(&name);
+        let staged = self
+            .derived
+            .dir
+            .join(format!("{}{}", TEMP_PREFIX, Uuid::new_v4()));
+        std::fs::write(&staged, data)?;
+        std::fs::rename(&staged, &path)?;
+
+        let conn = self.pool.get()?;
+        let now = OffsetDateTime::now_utc().format(&Rfc3339)?;
+        conn.execute(
+            "INSERT OR REPLACE INTO derived_files (parent_hash, variant, path, bytes, last_access)
+             VALUES (?, ?, ?, ?, ?)",
+            params![parent_hash, variant, name, data.len() as i64, now],
+        )?;
+        Ok(path)
+    }
+
+    /// The stored variant of an image, if it is still cached. Counts as an
+    /// access for eviction.
+    pub fn derived_file(&self, parent_hash: &str, variant: &str) -> Result<Option<PathBuf>> {
+        let conn = self.pool.get()?;
+        let name: Option<String> = conn
+            .query_row(
+                "SELECT path FROM derived_files WHERE parent_hash = ? AND variant = ?",
+                params![parent_hash, variant],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(path) = name.map(|name| self.derived.dir.join(name)) else {
+            return Ok(None);
+        };
+        if !path.exists() {
+            conn.execute(
+                "DELETE FROM derived_files WHERE parent_hash = ? AND variant = ?",
+                params![parent_hash, variant],
+            )?;
+            return Ok(None);
+        }
+        self.derived.touch(parent_hash, variant);
+        Ok(Some(path))
+    }
+
+    /// The image stored as `filename` re-encoded as `target`, from the
+    /// derived cache when it's there. `None` when the stored file already is
+    /// `target`, so the original bytes can be served as they are.
+    pub fn converted_image(
+        &self,
+        filename: &str,
+        hash: &str,
+        target: ImageFormat,
+    ) -> Result<Option<Vec<u8>>> {
+        let stored_format: Option<String> = {
+            let conn = self.pool.get()?;
+            conn.query_row("SELECT format FROM images WHERE hash = ?", [hash], |row| {
+                row.get(0)
+            })?
+        };
+        if stored_format.as_deref() == Some(Self::format_name(target).as_str()) {
+            return Ok(None);
+        }
+
+        let variant = format!("format.{}", target.extensions_str()[0]);
+        if let Some(path) = self.derived_file(hash, &variant)? {
+            return Ok(Some(std::fs::read(path)?));
+        }
+        let (data, _) = self.read_image_file(filename)?;
+        let img = image::load_from_memory(&data)?;
+        let mut converted = std::io::Cursor::new(Vec::new());
+        // JPEG has no alpha and the WebP encoder only takes 8-bit RGB(A)
+        match target {
+            ImageFormat::Jpeg => DynamicImage::ImageRgb8(img.to_rgb8()).write_to(
+                &mut converted,
+                ImageOutputFormat::Jpeg(CONVERTED_JPEG_QUALITY),
+            )?,
+            ImageFormat::WebP => {
+                DynamicImage::ImageRgba8(img.to_rgba8()).write_to(&mut converted, target)?
+            }
+            _ => img.write_to(&mut converted, target)?,
+        }
+        let converted = converted.into_inner();
+        if let Err(e) = self.put_derived_file(hash, &variant, &converted) {
+            warn!("Failed to cache {} as {}: {}", filename, variant, e);
+        }
+        Ok(Some(converted))
+    }
+
+    pub fn derived_cache_stats(&self) -> Result<DerivedCacheStats> {
+        let conn = self.pool.get()?;
+        let (files, bytes): (i64, i64) = conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(bytes), 0) FROM derived_files",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        Ok(DerivedCacheStats {
+            files: files as u64,
+            bytes: bytes as u64,
+        })
+    }
+
+    pub fn derived_cache_budget(&self) -> u64 {
+        self.derived.max_bytes
+    }
+
+    pub fn derived_cache_evictions(&self) -> u64 {
+        self.derived.evictions()
+    }
+
+    /// Writes pending access times, then drops the least recently served
+    /// variants until the cache fits `DERIVED_CACHE_MAX_BYTES`. Returns how
+    /// many were evicted.
+    pub fn evict_derived_files(&self) -> Result<usize> {
+        let mut conn = self.pool.get()?;
+        let touches = self.derived.take_touches();
+        let tx = conn.transaction()?;
+        for ((parent_hash, variant), last_access) in &touches {
+            tx.execute(
+                "UPDATE derived_files SET last_access = ? WHERE parent_hash = ? AND variant = ?",
+                params![last_access, parent_hash, variant],
+            )?;
+        }
+
+        let mut total: i64 = tx.query_row(
+            "SELECT COALESCE(SUM(bytes), 0) FROM derived_files",
+            [],
+            |row| row.get(0),
+        )?;
+        let mut evicted = Vec::new();
+        if total as u64 > self.derived.max_bytes {
+            let mut stmt = tx.prepare(
+                "SELECT parent_hash, variant, path, bytes FROM derived_files
+                 ORDER BY last_access ASC",
+            )?;
+            let mut rows = stmt.query([])?;
+            while total as u64 > self.derived.max_bytes {
+                let Some(row) = rows.next()? else {
+                    break;
+                };
+                let bytes: i64 = row.get(3)?;
+                evicted.push((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ));
+                total -= bytes;
+            }
+        }
+        for (parent_hash, variant, _) in &evicted {
+            tx.execute(
+                "DELETE FROM derived_files WHERE parent_hash = ? AND variant = ?",
+                params![parent_hash, variant],
+            )?;
+        }
+        tx.commit()?;
+
+        let paths: Vec<String> = evicted.into_iter().map(|(_, _, path)| path).collect();
+        self.remove_derived_paths(&paths);
+        self.derived.record_evictions(paths.len() as u64);
+        Ok(paths.len())
+    }
+
+    /// Removes every derived file, returning how many there were and their
+    /// size.
+    pub fn purge_derived_files(&self) -> Result<DerivedCacheStats> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        let removed: Vec<(String, i64)> = tx
+            .prepare("SELECT path, bytes FROM derived_files")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        tx.execute("DELETE FROM derived_files", [])?;
+        tx.commit()?;
+        self.derived.take_touches();
+
+        let stats = DerivedCacheStats {
+            files: removed.len() as u64,
+            bytes: removed.iter().map(|(_, bytes)| *bytes as u64).sum(),
+        };
+        let paths: Vec<String> = removed.into_iter().map(|(path, _)| path).collect();
+        self.remove_derived_paths(&paths);
+        Ok(stats)
+    }
+
+    /// Drops the rows of an image's variants, returning their paths to
+    /// delete once the transaction commits.
+    fn take_derived_files(conn: &rusqlite::Connection, parent_hash: &str) -> Result<Vec<String>> {
+        let paths = conn
+            .prepare("SELECT path FROM derived_files WHERE parent_hash = ?")?
+            .query_map([parent_hash], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        conn.execute(
+            "DELETE FROM derived_files WHERE parent_hash = ?",
+            [parent_hash],
+        )?;
+        Ok(paths)
+    }
+
+    fn remove_derived_paths(&self, paths: &[String]) {
+        for path in paths {
+            match std::fs::remove_file(self.derived.dir.join(path)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => warn!("Failed to remove derived file {}: {}", path, e),
+            }
+        }
+    }
+
+    pub fn update_api_key_status(&self, username: &str, is_active: bool) -> Result<()> {
+        let conn = self.pool.get()?;
+
+        let rows_affected = conn.execute(
+            "UPDATE api_keys SET is_active = ? WHERE username = ?",
+            params![is_active, username],
+        )?;
+
+        if rows_affected == 0 {
+            return Err(anyhow!("No API key found for username: {}", username));
+        }
+
+        Ok(())
+    }
+
+    /// Builds the shared `SELECT ... WHERE ... GROUP BY` for filtered image
+    /// queries. Callers append their own ordering and limits.
+    fn build_filter_query(filters: &ImageFilters) -> (String, Vec<String>) {
+        let mut conditions = Vec::new();
+        let mut param_values = Vec::new();
+
+        let mut query = String::from(
+            "SELECT i.filename, i.hash, i.created_at, i.modified_at, i.original_format 
+             FROM images i",
+        );
+
+        // HAVING counts distinct names, so a repeated tag would make it
+        // demand one more match than the IN list can ever produce
+        let tags = filters.tags.as_ref().map(|tags| {
+            let mut normalized: Vec<String> = Vec::with_capacity(tags.len());
+            for tag in tags {
+                let tag = tag.to_lowercase().replace(' ', "_");
+                if !normalized.contains(&tag) {
+                    normalized.push(tag);
+                }
+            }
+            normalized
+        });
+
+        if let Some(tags) = &tags {
+            if !tags.is_empty() {
+                query.push_str(
+                    "
+                    JOIN image_tags it ON i.hash = it.image_hash
+                    JOIN tags t ON it.tag_id = t.id
+                ",
+                );
+                conditions.push(format!(
+                    "t.name IN ({})",
+                    tags.iter().map(|_| "?").collect::<Vec<_>>().join(",")
+                ));
+                param_values.extend(tags.iter().cloned());
+            }
+        }
 
         if let Some(width_filter) = &filters.width {
             match width_filter {
@@ -854,169 +3974,2544 @@ impl ImageStore {
             }
         }
 
-        if let Some(height_filter) = &filters.height {
-            match height_filter {
-                DimensionFilter::Exact(h) => {
-                    conditions.push("height = ?".to_string());
-                    param_values.push(h.to_string());
-                }
-                DimensionFilter::Range(min, max) => {
-                    conditions.push("height BETWEEN ? AND ?".to_string());
-                    param_values.push(min.to_string());
-                    param_values.push(max.to_string());
-                }
+        if let Some(height_filter) = &filters.height {
+            match height_filter {
+                DimensionFilter::Exact(h) => {
+                    conditions.push("height = ?".to_string());
+                    param_values.push(h.to_string());
+                }
+                DimensionFilter::Range(min, max) => {
+                    conditions.push("height BETWEEN ? AND ?".to_string());
+                    param_values.push(min.to_string());
+                    param_values.push(max.to_string());
+                }
+            }
+        }
+
+        if let Some(size_filter) = &filters.size {
+            match size_filter {
+                SizeFilter::Exact(s) => {
+                    conditions.push("size_bytes = ?".to_string());
+                    param_values.push(s.to_string());
+                }
+                SizeFilter::Range(min, max) => {
+                    conditions.push("size_bytes BETWEEN ? AND ?".to_string());
+                    param_values.push(min.to_string());
+                    param_values.push(max.to_string());
+                }
+            }
+        }
+
+        for key in &filters.has_metadata {
+            conditions.push(
+                "EXISTS (SELECT 1 FROM image_metadata m WHERE m.image_hash = i.hash AND m.key = ?)"
+                    .to_string(),
+            );
+            param_values.push(key.clone());
+        }
+
+        for (key, value) in &filters.metadata {
+            conditions.push(
+                "EXISTS (SELECT 1 FROM image_metadata m WHERE m.image_hash = i.hash AND m.key = ? AND m.value = ?)"
+                    .to_string(),
+            );
+            param_values.push(key.clone());
+            param_values.push(value.clone());
+        }
+
+        // images without GPS data have NULL coordinates and never match
+        if let Some(bbox) = &filters.bbox {
+            conditions.push("i.latitude BETWEEN ? AND ?".to_string());
+            param_values.push(bbox.min_lat.to_string());
+            param_values.push(bbox.max_lat.to_string());
+            if bbox.min_lon <= bbox.max_lon {
+                conditions.push("i.longitude BETWEEN ? AND ?".to_string());
+            } else {
+                conditions.push("(i.longitude >= ? OR i.longitude <= ?)".to_string());
+            }
+            param_values.push(bbox.min_lon.to_string());
+            param_values.push(bbox.max_lon.to_string());
+        }
+
+        if let Some(owner) = &filters.owner {
+            conditions.push("i.uploaded_by = ?".to_string());
+            param_values.push(owner.clone());
+        }
+
+        if let Some(collection) = &filters.collection {
+            conditions.push(
+                "EXISTS (SELECT 1 FROM collection_images c WHERE c.image_hash = i.hash AND c.collection = ?)"
+                    .to_string(),
+            );
+            param_values.push(collection.clone());
+        }
+
+        // a broken file can't be served, so don't hand it out
+        conditions.push("i.broken_reason IS NULL".to_string());
+
+        // kept out of the tag join so it doesn't count towards HAVING
+        if !filters.any_tags.is_empty() {
+            conditions.push(Self::tagged_with_any("i.hash", &filters.any_tags));
+            param_values.extend(filters.any_tags.iter().cloned());
+        }
+
+        // a NOT EXISTS rather than part of the join, so untagged images stay
+        // eligible and the HAVING count only sees the wanted tags
+        let exclude_tags: Vec<String> = filters
+            .exclude_tags
+            .iter()
+            .map(|tag| tag.to_lowercase().replace(' ', "_"))
+            .collect();
+        if !exclude_tags.is_empty() {
+            conditions.push(format!(
+                "NOT EXISTS (SELECT 1 FROM image_tags x JOIN tags xt ON x.tag_id = xt.id WHERE x.image_hash = i.hash AND xt.name IN ({}))",
+                exclude_tags.iter().map(|_| "?").collect::<Vec<_>>().join(",")
+            ));
+            param_values.extend(exclude_tags);
+        }
+
+        if !conditions.is_empty() {
+            query.push_str(" WHERE ");
+            query.push_str(&conditions.join(" AND "));
+        }
+
+        if let Some(tags) = &tags {
+            if !tags.is_empty() {
+                // bound like the tags themselves, never formatted into the SQL
+                query.push_str(
+                    " GROUP BY i.hash HAVING COUNT(DISTINCT t.name) = CAST(? AS INTEGER)",
+                );
+                param_values.push(tags.len().to_string());
+                if filters.tag_match == TagMatch::Exact {
+                    query.push_str(
+                        " AND (SELECT COUNT(*) FROM image_tags x WHERE x.image_hash = i.hash) = CAST(? AS INTEGER)",
+                    );
+                    param_values.push(tags.len().to_string());
+                }
+            }
+        }
+
+        (query, param_values)
+    }
+
+    /// Counts images per `[start, end)` size range. An end of `u64::MAX`
+    /// leaves the bucket open-ended.
+    pub fn size_distribution(&self, buckets: &[(u64, u64)]) -> Result<Vec<SizeBucket>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "SELECT COUNT(*), COALESCE(SUM(size_bytes), 0) 
+             FROM images 
+             WHERE size_bytes BETWEEN ? AND ?",
+        )?;
+
+        buckets
+            .iter()
+            .map(|&(start, end)| {
+                let last = end.saturating_sub(1).min(i64::MAX as u64);
+                let (count, total_bytes): (i64, i64) = stmt.query_row(
+                    params![start.min(i64::MAX as u64) as i64, last as i64],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )?;
+                Ok(SizeBucket {
+                    range_start: start,
+                    range_end: (end != u64::MAX).then_some(end),
+                    count: count as u64,
+                    total_bytes: total_bytes as u64,
+                })
+            })
+            .collect()
+    }
+
+    /// Performs a tiny write to check whether the database accepts writes.
+    /// Looks up the stored response for `idempotency_key`, ignoring entries
+    /// older than `ttl` that the sweep hasn't purged yet.
+    pub fn get_idempotent_response(
+        &self,
+        api_key: &str,
+        idempotency_key: &str,
+        ttl: Duration,
+    ) -> Result<Option<IdempotentResponse>> {
+        let conn = self.pool.get()?;
+        let response = conn
+            .query_row(
+                "SELECT method, path, status, content_type, body 
+                 FROM idempotency_keys 
+                 WHERE api_key = ? AND idempotency_key = ? 
+                   AND julianday(created_at) > julianday('now', ?)",
+                params![
+                    api_key,
+                    idempotency_key,
+                    format!("-{} seconds", ttl.as_secs())
+                ],
+                |row| {
+                    Ok(IdempotentResponse {
+                        method: row.get(0)?,
+                        path: row.get(1)?,
+                        status: row.get(2)?,
+                        content_type: row.get(3)?,
+                        body: row.get(4)?,
+                    })
+                },
+            )
+            .optional()?;
+
+        Ok(response)
+    }
+
+    pub fn save_idempotent_response(
+        &self,
+        api_key: &str,
+        idempotency_key: &str,
+        response: &IdempotentResponse,
+    ) -> Result<()> {
+        let conn = self.pool.get()?;
+        let now = OffsetDateTime::now_utc().format(&Rfc3339)?;
+        conn.execute(
+            "INSERT OR REPLACE INTO idempotency_keys 
+             (api_key, idempotency_key, method, path, status, content_type, body, created_at) 
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                api_key,
+                idempotency_key,
+                response.method,
+                response.path,
+                response.status,
+                response.content_type,
+                response.body,
+                now
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn purge_idempotency_keys(&self, ttl: Duration) -> Result<usize> {
+        let conn = self.pool.get()?;
+        let purged = conn.execute(
+            "DELETE FROM idempotency_keys WHERE julianday(created_at) <= julianday('now', ?)",
+            [format!("-{} seconds", ttl.as_secs())],
+        )?;
+        Ok(purged)
+    }
+
+    pub fn schema_migrations(&self) -> Result<(u32, Vec<AppliedMigration>)> {
+        let conn = self.pool.get()?;
+        Ok((
+            migrations::current_version(&conn)?,
+            migrations::applied(&conn)?,
+        ))
+    }
+
+    pub fn probe_write(&self) -> Result<()> {
+        let conn = self.pool.get()?;
+        let now = OffsetDateTime::now_utc().format(&Rfc3339)?;
+        conn.execute(
+            "INSERT OR REPLACE INTO storage_probe (id, checked_at) VALUES (1, ?)",
+            [now],
+        )?;
+        Ok(())
+    }
+
+    pub fn slow_queries(&self, threshold_ms: u64, limit: usize) -> Vec<SlowQuery> {
+        self.query_log.slowest(threshold_ms, limit)
+    }
+
+    /// Draws one matching image the way `strategy` says. `least_served`
+    /// counts what it hands out; other strategies don't touch the row.
+    pub fn get_random_image_with_strategy(
+        &self,
+        filters: &ImageFilters,
+        strategy: SelectionStrategy,
+    ) -> Result<ImageResponse> {
+        let conn = self.pool.get()?;
+        let (mut query, param_values) = Self::build_filter_query(filters);
+        match strategy {
+            SelectionStrategy::Uniform => query.push_str(" ORDER BY RANDOM() LIMIT 1"),
+            SelectionStrategy::LeastServed => {
+                query.push_str(" ORDER BY i.serve_count, RANDOM() LIMIT 1")
+            }
+        }
+
+        let params: Vec<&str> = param_values.iter().map(|s| s.as_str()).collect();
+
+        // redraw a few times rather than failing on one unreadable file
+        let mut attempt = 1;
+        loop {
+            let timer = QueryTimer::start(&self.query_log, &query);
+            let row = conn.query_row(&query, rusqlite::params_from_iter(&params), |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                ))
+            })?;
+            drop(timer);
+
+            let (filename, hash, created_at, modified_at, original_format) = row;
+            match self.build_image_response(
+                &filename,
+                &hash,
+                &created_at,
+                &modified_at,
+                original_format,
+            ) {
+                Err(e) if attempt < RANDOM_DRAW_ATTEMPTS => {
+                    warn!("Skipping unreadable image {}: {}", filename, e);
+                    attempt += 1;
+                }
+                Ok(image) if strategy == SelectionStrategy::LeastServed => {
+                    // a failed count only skews the next draws, so still serve
+                    if let Err(e) = conn.execute(
+                        "UPDATE images SET serve_count = serve_count + 1 WHERE hash = ?",
+                        [&hash],
+                    ) {
+                        warn!("Failed to count serve of {}: {}", filename, e);
+                    }
+                    return Ok(image);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// How many images `list_images_with_filters` can page through.
+    pub fn count_images_with_filters(&self, filters: &ImageFilters) -> Result<u64> {
+        let conn = self.pool.get()?;
+        let (query, param_values) = Self::build_filter_query(filters);
+        let query = format!("SELECT COUNT(*) FROM ({})", query);
+        let params: Vec<&str> = param_values.iter().map(|s| s.as_str()).collect();
+
+        let _timer = QueryTimer::start(&self.query_log, &query);
+        Ok(conn.query_row(&query, rusqlite::params_from_iter(params), |row| row.get(0))?)
+    }
+
+    pub fn list_images_with_filters(
+        &self,
+        filters: &ImageFilters,
+        sort: ImageSort,
+        order: SortOrder,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Vec<ImageResponse>> {
+        let conn = self.pool.get()?;
+        let (mut query, mut param_values) = Self::build_filter_query(filters);
+        query.push_str(&format!(
+            " ORDER BY {} {}, i.hash LIMIT ? OFFSET ?",
+            sort.column(),
+            order.keyword()
+        ));
+        param_values.push(limit.to_string());
+        param_values.push(offset.to_string());
+        self.query_image_page(&conn, &query, &param_values)
+    }
+
+    /// A page of images matching `search`, and how many match in all.
+    pub fn search_images(&self, search: &SearchFilters) -> Result<(Vec<ImageResponse>, u64)> {
+        let conn = self.pool.get()?;
+        let (filter_query, mut param_values) = Self::build_filter_query(&search.filters);
+        // joined back to images so the time bounds and sort columns apply
+        // after any GROUP BY in the filter query
+        let mut query = format!(
+            "SELECT s.* FROM ({}) s JOIN images i ON i.hash = s.hash WHERE 1 = 1",
+            filter_query
+        );
+        if let Some(after) = &search.created_after {
+            query.push_str(" AND julianday(i.created_at) > julianday(?)");
+            param_values.push(after.clone());
+        }
+        if let Some(before) = &search.created_before {
+            query.push_str(" AND julianday(i.created_at) < julianday(?)");
+            param_values.push(before.clone());
+        }
+
+        let count_query = format!("SELECT COUNT(*) FROM ({})", query);
+        let total: u64 = {
+            let params: Vec<&str> = param_values.iter().map(|s| s.as_str()).collect();
+            let _timer = QueryTimer::start(&self.query_log, &count_query);
+            conn.query_row(&count_query, rusqlite::params_from_iter(params), |row| {
+                row.get(0)
+            })?
+        };
+
+        query.push_str(&format!(
+            " ORDER BY {} {}, i.hash LIMIT ? OFFSET ?",
+            search.sort.column(),
+            search.order.keyword()
+        ));
+        param_values.push(search.limit.to_string());
+        param_values.push(search.offset.to_string());
+        let images = self.query_image_page(&conn, &query, &param_values)?;
+        Ok((images, total))
+    }
+
+    /// The page of images following `cursor` in `(created_at, hash)` order,
+    /// with the same filters as `list_images_with_filters`. Unlike an offset,
+    /// the cursor doesn't shift when images are added ahead of it. Also
+    /// returns the cursor after the page's last row, or `None` when the page
+    /// came back short and so is the last; unreadable images left out of
+    /// the page still move the cursor on.
+    pub fn list_images_after(
+        &self,
+        filters: &ImageFilters,
+        cursor: &ImageCursor,
+        order: SortOrder,
+        limit: u32,
+    ) -> Result<(Vec<ImageResponse>, Option<ImageCursor>)> {
+        let conn = self.pool.get()?;
+        let (filter_query, mut param_values) = Self::build_filter_query(filters);
+        // wrapped so the cursor applies after any GROUP BY in the filter query
+        let mut query = format!("SELECT * FROM ({}) WHERE ", filter_query);
+        let comparison = match order {
+            SortOrder::Asc => ">",
+            SortOrder::Desc => "<",
+        };
+        param_values.push(cursor.created_at.clone());
+        match &cursor.hash {
+            Some(hash) => {
+                query.push_str(&format!("(created_at, hash) {} (?, ?)", comparison));
+                param_values.push(hash.clone());
+            }
+            None => query.push_str(&format!("created_at {} ?", comparison)),
+        }
+        query.push_str(&format!(
+            " ORDER BY created_at {0}, hash {0} LIMIT ?",
+            order.keyword()
+        ));
+        param_values.push(limit.to_string());
+        let rows = self.query_image_rows(&conn, &query, &param_values)?;
+        let next = (limit > 0 && rows.len() == limit as usize)
+            .then(|| rows.last())
+            .flatten()
+            .map(|row| ImageCursor {
+                created_at: row.created_at.clone(),
+                hash: Some(row.hash.clone()),
+            });
+        Ok((self.build_image_page(rows), next))
+    }
+
+    /// Runs a listing query selecting filename, hash, created_at,
+    /// modified_at and original_format, in that order.
+    fn query_image_page(
+        &self,
+        conn: &rusqlite::Connection,
+        query: &str,
+        param_values: &[String],
+    ) -> Result<Vec<ImageResponse>> {
+        let rows = self.query_image_rows(conn, query, param_values)?;
+        Ok(self.build_image_page(rows))
+    }
+
+    fn query_image_rows(
+        &self,
+        conn: &rusqlite::Connection,
+        query: &str,
+        param_values: &[String],
+    ) -> Result<Vec<ImageRow>> {
+        let params: Vec<&str> = param_values.iter().map(|s| s.as_str()).collect();
+
+        let timer = QueryTimer::start(&self.query_log, query);
+        let mut stmt = conn.prepare(query)?;
+        let rows = stmt
+            .query_map(rusqlite::params_from_iter(params), |row| {
+                Ok(ImageRow {
+                    filename: row.get(0)?,
+                    hash: row.get(1)?,
+                    created_at: row.get(2)?,
+                    modified_at: row.get(3)?,
+                    original_format: row.get(4)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        drop(timer);
+        Ok(rows)
+    }
+
+    fn build_image_page(&self, rows: Vec<ImageRow>) -> Vec<ImageResponse> {
+        // one unreadable file shouldn't take the whole page down with it
+        rows.into_iter()
+            .filter_map(|row| {
+                self.build_image_response(
+                    &row.filename,
+                    &row.hash,
+                    &row.created_at,
+                    &row.modified_at,
+                    row.original_format,
+                )
+                .map_err(|e| warn!("Skipping unreadable image {}: {}", row.filename, e))
+                .ok()
+            })
+            .collect()
+    }
+
+    /// `{BASE_URL}/images`, which public image URLs and listings sit under.
+    pub fn images_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// `{BASE_URL}` itself.
+    pub fn server_url(&self) -> &str {
+        self.base_url
+            .strip_suffix("/images")
+            .unwrap_or(&self.base_url)
+    }
+
+    /// Public URL of an image in the configured `URL_STYLE`.
+    fn image_url(&self, filename: &str, hash: &str, id: &str) -> String {
+        format!(
+            "{}/{}",
+            self.base_url,
+            self.url_style.path(filename, hash, id)
+        )
+    }
+
+    fn get_image_id(&self, hash: &str) -> Result<String> {
+        let conn = self.pool.get()?;
+        Ok(conn.query_row(
+            "SELECT public_id FROM images WHERE hash = ?",
+            [hash],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// URL of a freshly added image, for upload responses.
+    pub fn url_for_hash(&self, hash: &str) -> Result<String> {
+        let conn = self.pool.get()?;
+        let (filename, id): (String, String) = conn.query_row(
+            "SELECT filename, public_id FROM images WHERE hash = ?",
+            [hash],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        Ok(self.image_url(&filename, hash, &id))
+    }
+
+    /// Filename, hash and `modified_at` of the image a file route names.
+    pub fn resolve_image_file(
+        &self,
+        column: ImageKey,
+        value: &str,
+    ) -> Result<Option<(String, String, String)>> {
+        let conn = self.pool.get()?;
+        let found = conn
+            .query_row(
+                &format!(
+                    "SELECT filename, hash, modified_at FROM images WHERE {} = ?",
+                    column.column()
+                ),
+                [value],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        Ok(found)
+    }
+
+    pub fn image_modified_at(&self, hash: &str) -> Result<Option<String>> {
+        let conn = self.pool.get()?;
+        let modified_at = conn
+            .query_row(
+                "SELECT modified_at FROM images WHERE hash = ?",
+                [hash],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(modified_at)
+    }
+
+    /// Listing cursor pointing at the image stored as `filename`.
+    pub fn image_cursor(&self, filename: &str) -> Result<Option<ImageCursor>> {
+        let conn = self.pool.get()?;
+        let cursor = conn
+            .query_row(
+                "SELECT created_at, hash FROM images WHERE filename = ?",
+                [filename],
+                |row| {
+                    Ok(ImageCursor {
+                        created_at: row.get(0)?,
+                        hash: Some(row.get(1)?),
+                    })
+                },
+            )
+            .optional()?;
+        Ok(cursor)
+    }
+
+    /// Hash of the image stored as `filename`, for ETags on file responses.
+    pub fn hash_for_filename(&self, filename: &str) -> Result<Option<String>> {
+        let conn = self.pool.get()?;
+        let hash = conn
+            .query_row(
+                "SELECT hash FROM images WHERE filename = ?",
+                [filename],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(hash)
+    }
+
+    fn get_image_location(&self, hash: &str) -> Result<Option<GeoLocation>> {
+        let conn = self.pool.get()?;
+        let location = conn
+            .query_row(
+                "SELECT latitude, longitude FROM images
+                 WHERE hash = ? AND latitude IS NOT NULL AND longitude IS NOT NULL",
+                [hash],
+                |row| {
+                    Ok(GeoLocation {
+                        latitude: row.get(0)?,
+                        longitude: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(location)
+    }
+
+    /// Records where an image was taken, when its EXIF data says.
+    fn set_image_location(
+        conn: &rusqlite::Connection,
+        hash: &str,
+        location: Option<(f64, f64)>,
+    ) -> Result<()> {
+        if let Some((latitude, longitude)) = location {
+            conn.execute(
+                "UPDATE images SET latitude = ?, longitude = ? WHERE hash = ?",
+                params![latitude, longitude, hash],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn build_image_response(
+        &self,
+        filename: &str,
+        hash: &str,
+        created_at: &str,
+        modified_at: &str,
+        original_format: Option<String>,
+    ) -> Result<ImageResponse> {
+        let tags = self.get_image_tags(hash)?;
+        let file_path = self.images_dir.join(filename);
+        let (size_bytes, dimensions, format) = self.stored_image_info(hash, &file_path)?;
+        let location = self.get_image_location(hash)?;
+        let id = self.get_image_id(hash)?;
+
+        Ok(ImageResponse {
+            url: self.image_url(filename, hash, &id),
+            filename: filename.to_string(),
+            format,
+            width: dimensions.0,
+            height: dimensions.1,
+            size_bytes,
+            size_human: byte_size::format(size_bytes),
+            hash: hash.to_string(),
+            id,
+            tags,
+            created_at: OffsetDateTime::parse(created_at, &Rfc3339)?
+                .format(&Rfc3339)
+                .unwrap_or_else(|_| "".to_string()),
+            modified_at: OffsetDateTime::parse(modified_at, &Rfc3339)?
+                .format(&Rfc3339)
+                .unwrap_or_else(|_| "".to_string()),
+            original_format,
+            location,
+            data: None,
+            content_type: None,
+        })
+    }
+
+    pub async fn add_image_data(
+        &self,
+        data: &Bytes,
+        _filename: &str,
+        content_type: &str,
+    ) -> Result<String> {
+        let location = exif::gps_location(data);
+        let mut original_format = None;
+        let converted;
+        let (data, content_type) =
+            if heic::is_heic_content_type(content_type) || heic::is_heif(data) {
+                let conversion = self.heic_conversion();
+                info!("Converting HEIC upload to {:?}", conversion.format);
+                converted = Bytes::from(heic::convert(data, conversion)?);
+                original_format = Some("HEIC".to_string());
+                (&converted, conversion.content_type())
+            } else {
+                (data, content_type)
+            };
+
+        if !ALLOWED_CONTENT_TYPES.contains(&content_type) {
+            return Err(anyhow!("Unsupported content type: {}", content_type));
+        }
+
+        let mut canonicalized = None;
+        if let Some(format) =
+            ImageFormat::from_mime_type(content_type).or_else(|| image::guess_format(data).ok())
+        {
+            if let Some((encoded, target)) = self.canonicalize(data, format)? {
+                original_format.get_or_insert_with(|| Self::format_name(format));
+                canonicalized = Some((Bytes::from(encoded), Self::format_content_type(target)));
+            }
+        }
+        let (data, content_type) = match &canonicalized {
+            Some((encoded, content_type)) => (encoded, *content_type),
+            None => (data, content_type),
+        };
+
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        let hash = format!("{:x}", hasher.finalize());
+
+        let ext = match content_type {
+            "image/jpeg" => "jpg",
+            "image/png" => "png",
+            "image/gif" => "gif",
+            "image/webp" => "webp",
+            "image/bmp" | "image/x-ms-bmp" => "bmp",
+            _ => return Err(anyhow!("Unsupported image format")),
+        };
+
+        if let Some(duplicate) = self.find_duplicate(&hash)? {
+            return Err(duplicate.into());
+        }
+
+        // named by the full hash, so a file already there holds these exact
+        // bytes, e.g. from an upload still being written
+        let new_filename = format!("{}.{}", hash, ext);
+        let file_path = self.images_dir.join(&new_filename);
+
+        if file_path.exists() {
+            return Err(anyhow!("Image already exists: {}", new_filename));
+        }
+
+        // Verify it's a valid image
+        let (dimensions, phash) =
+            decode_bytes(data, self.strict_decode).map_err(|e| anyhow!("Invalid image: {}", e))?;
+
+        // Save the file
+        let mut file = tokio::fs::File::create(&file_path).await?;
+        file.write_all(data).await?;
+
+        let now = OffsetDateTime::now_utc().format(&Rfc3339)?;
+
+        let conn = self.pool.get()?;
+        conn.execute(
+            "INSERT INTO images (hash, filename, created_at, modified_at, width, height, size_bytes, phash, original_format, format) 
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                hash,
+                new_filename,
+                now,
+                now,
+                dimensions.0 as i64,
+                dimensions.1 as i64,
+                data.len() as i64,
+                phash.map(|phash| phash as i64),
+                original_format,
+                ImageFormat::from_mime_type(content_type).map(Self::format_name)
+            ],
+        )?;
+        Self::set_image_location(&conn, &hash, location)?;
+        Self::log_change(&conn, ChangeEvent::ImageAdded, &hash)?;
+        if let Some(phash) = phash {
+            self.phash_index.insert(phash, hash.clone());
+        }
+        self.sync_sidecar(&hash);
+
+        Ok(hash)
+    }
+}
+
+/// Dimensions and perceptual hash of an image file. With `strict` off a
+/// file whose pixels don't fully decode, e.g. a truncated one, is accepted
+/// on its header alone and gets no perceptual hash.
+fn decode_file(path: &std::path::Path, strict: bool) -> Result<((u32, u32), Option<u64>)> {
+    // by contents, not extension, so a misnamed file still decodes
+    let decoded = image::io::Reader::open(path)?
+        .with_guessed_format()?
+        .decode();
+    let (dimensions, phash) = match decoded {
+        Ok(img) => (img.dimensions(), Some(compute_phash(&img))),
+        Err(e) if strict => return Err(e.into()),
+        Err(e) => {
+            let dimensions = image::io::Reader::open(path)?
+                .with_guessed_format()?
+                .into_dimensions()?;
+            warn!("Accepting {:?} from its header alone: {}", path, e);
+            (dimensions, None)
+        }
+    };
+    check_dimensions(dimensions)?;
+    Ok((dimensions, phash))
+}
+
+/// [`decode_file`] for an image still in memory.
+fn decode_bytes(data: &[u8], strict: bool) -> Result<((u32, u32), Option<u64>)> {
+    let (dimensions, phash) = match image::load_from_memory(data) {
+        Ok(img) => (img.dimensions(), Some(compute_phash(&img))),
+        Err(e) if strict => return Err(e.into()),
+        Err(e) => {
+            let dimensions = image::io::Reader::new(std::io::Cursor::new(data))
+                .with_guessed_format()?
+                .into_dimensions()?;
+            warn!("Accepting image from its header alone: {}", e);
+            (dimensions, None)
+        }
+    };
+    check_dimensions(dimensions)?;
+    Ok((dimensions, phash))
+}
+
+/// Some malformed headers decode to zero pixels. Such a row would never
+/// match a dimension filter, so the image is refused instead.
+fn check_dimensions((width, height): (u32, u32)) -> Result<()> {
+    if width == 0 || height == 0 {
+        return Err(anyhow!("image is {}x{} pixels", width, height));
+    }
+    Ok(())
+}
+
+/// Runs `scan` over `items` on up to `workers` threads, returning the
+/// results in the order of `items`.
+fn scan_in_parallel<T: Send>(
+    items: &[String],
+    workers: usize,
+    scan: impl Fn(&str) -> T + Sync,
+) -> Vec<(String, T)> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(items.len()));
+
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                let mut scanned = Vec::new();
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(i) else {
+                        break;
+                    };
+                    scanned.push((i, scan(item)));
+                }
+                results
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .extend(scanned);
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap_or_else(|e| e.into_inner());
+    results.sort_by_key(|(i, _)| *i);
+    results
+        .into_iter()
+        .map(|(i, result)| (items[i].clone(), result))
+        .collect()
+}
+
+/// Checks that `url` is HTTP(S) and points at a public host, for anything
+/// the server sends requests to. The download allowlist is applied on top
+/// of this by `ImageStore::validate_url`.
+pub async fn validate_public_url(url: &str) -> Result<Url> {
+    let parsed_url = Url::parse(url).map_err(|e| anyhow!("Invalid URL: {}", e))?;
+
+    if !["http", "https"].contains(&parsed_url.scheme()) {
+        return Err(anyhow!("Only HTTP(S) URLs are supported"));
+    }
+
+    let host_str = parsed_url.host_str().unwrap_or_default();
+    for hostname in BLOCKED_HOSTNAMES {
+        if host_str.eq_ignore_ascii_case(hostname) {
+            return Err(anyhow!("URL hostname is not allowed: {}", hostname));
+        }
+    }
+
+    if let Some(port) = parsed_url.port() {
+        match port {
+            22 | 23 | 25 | 445 | 3306 | 5432 | 27017 => {
+                return Err(anyhow!("Port {} is not allowed", port));
+            }
+            _ => {}
+        }
+    }
+
+    url_guard::check_url_host(&parsed_url).await?;
+
+    Ok(parsed_url)
+}
+
+/// Follows redirects only while `redirect_refusal` has no objection.
+fn redirect_policy(allowlist: Arc<Vec<String>>, max_redirects: usize) -> reqwest::redirect::Policy {
+    reqwest::redirect::Policy::custom(move |attempt| {
+        match redirect_refusal(
+            &allowlist,
+            max_redirects,
+            attempt.url(),
+            attempt.previous().len(),
+        ) {
+            Some(reason) => attempt.error(reason),
+            None => attempt.follow(),
+        }
+    })
+}
+
+/// Why a download may not follow a redirect to `url` after `previous`
+/// hops, or `None` if it may.
+fn redirect_refusal(
+    allowlist: &[String],
+    max_redirects: usize,
+    url: &Url,
+    previous: usize,
+) -> Option<String> {
+    let host = url.host_str().unwrap_or_default();
+    let blocked_ip = match url.host() {
+        Some(url::Host::Ipv4(ip)) => url_guard::is_blocked_ip(ip.into()),
+        Some(url::Host::Ipv6(ip)) => url_guard::is_blocked_ip(ip.into()),
+        _ => false,
+    };
+    if previous > max_redirects {
+        Some("too many redirects".to_string())
+    } else if !host_allowed(allowlist, host) {
+        Some(format!("Redirect host is not allowed: {}", host))
+    } else if blocked_ip {
+        Some(format!("Redirect address is not allowed: {}", host))
+    } else {
+        None
+    }
+}
+
+/// An empty allowlist allows every host. Entries match the host itself and
+/// its subdomains.
+pub(crate) fn host_allowed(allowlist: &[String], host: &str) -> bool {
+    if allowlist.is_empty() {
+        return true;
+    }
+    let host = host.trim_end_matches('.').to_lowercase();
+    allowlist.iter().any(|domain| {
+        host == *domain
+            || host
+                .strip_suffix(domain.as_str())
+                .is_some_and(|prefix| prefix.ends_with('.'))
+    })
+}
+
+/// Stands in for a database file that has flipped to read-only.
+#[cfg(test)]
+impl ImageStore {
+    pub fn with_read_only_database(&self, db_path: &std::path::Path) -> Self {
+        let manager = SqliteConnectionManager::file(db_path)
+            .with_flags(rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY);
+        Self {
+            pool: Pool::new(manager).expect("read-only pool"),
+            ..self.clone()
+        }
+    }
+}
+
+impl Clone for ImageStore {
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            images_dir: self.images_dir.clone(),
+            base_url: self.base_url.clone(),
+            url_style: self.url_style,
+            phash_index: self.phash_index.clone(),
+            heic: self.heic,
+            canonical: self.canonical,
+            keep_empty_tags: self.keep_empty_tags,
+            max_distinct_tags: self.max_distinct_tags,
+            blocked_tags: self.blocked_tags.clone(),
+            write_sidecars: self.write_sidecars,
+            strict_decode: self.strict_decode,
+            url_allowlist: self.url_allowlist.clone(),
+            max_redirects: self.max_redirects,
+            host_throttle: self.host_throttle.clone(),
+            tag_cache: self.tag_cache.clone(),
+            query_log: self.query_log.clone(),
+            derived: self.derived.clone(),
+            replacing: self.replacing.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{add_png, config, png, temp_store};
+    use std::io::Write;
+
+    /// A catalog ZIP holding `catalog.json` and `files`, deflated.
+    fn catalog_zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        writer.start_file(CATALOG_MANIFEST, options).unwrap();
+        writer.write_all(br#"{"images": []}"#).unwrap();
+        for (name, data) in files {
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn startup_names_a_corrupted_column_type() {
+        let (dir, store) = temp_store();
+        store
+            .pool
+            .get()
+            .unwrap()
+            .execute_batch(
+                "ALTER TABLE images RENAME COLUMN width TO old_width;
+                 ALTER TABLE images ADD COLUMN width TEXT;",
+            )
+            .unwrap();
+        drop(store);
+
+        let db_path = dir.path().join("images.db");
+        let db_path = db_path.to_str().unwrap();
+        let images_dir = dir.path().join("images");
+        let err = ImageStore::new(db_path, images_dir.clone(), &config(&[]))
+            .err()
+            .expect("schema check should fail");
+        assert!(
+            err.to_string()
+                .contains("images.width expected INTEGER nullable, found TEXT nullable"),
+            "{}",
+            err
+        );
+
+        ImageStore::new(db_path, images_dir, &config(&["--skip-schema-check"]))
+            .expect("skipping the check starts anyway");
+    }
+
+    #[tokio::test]
+    async fn backfill_fills_null_metadata() {
+        let (_dir, store) = temp_store();
+        let hash = add_png(&store, 1).await;
+        store
+            .pool
+            .get()
+            .unwrap()
+            .execute(
+                "UPDATE images SET width = NULL, height = NULL, size_bytes = NULL, format = NULL
+                 WHERE hash = ?",
+                [&hash],
+            )
+            .unwrap();
+
+        let result = store.backfill_metadata().unwrap();
+        assert_eq!(result.scanned, 1);
+        assert_eq!(result.updated, 1);
+
+        let image = store
+            .get_image_by_filename(&format!("{}.png", hash))
+            .unwrap();
+        assert_eq!((image.width, image.height), (4, 4));
+        assert_eq!(image.size_bytes, png(4, 4, 1).len() as u64);
+        assert_eq!(image.format, "PNG");
+    }
+
+    #[tokio::test]
+    async fn backfill_reports_failing_rows_and_carries_on() {
+        let (_dir, store) = temp_store();
+        let hash = add_png(&store, 1).await;
+        let conn = store.pool.get().unwrap();
+        conn.execute("UPDATE images SET width = NULL WHERE hash = ?", [&hash])
+            .unwrap();
+        // a hashless copy of the same file can't take the hash it backfills to
+        std::fs::write(store.images_dir.join("copy.png"), png(4, 4, 1)).unwrap();
+        std::fs::write(store.images_dir.join("garbage.png"), b"not an image").unwrap();
+        for filename in ["copy.png", "garbage.png", "missing.png"] {
+            conn.execute(
+                "INSERT INTO images (filename, created_at, modified_at)
+                 VALUES (?, '2024-01-01T00:00:00Z', '2024-01-01T00:00:00Z')",
+                [filename],
+            )
+            .unwrap();
+        }
+
+        let result = store.backfill_metadata().unwrap();
+        assert_eq!(result.scanned, 4);
+        assert_eq!(result.updated, 1);
+        assert_eq!(result.skipped_missing, 1);
+        assert_eq!(result.failed, 2);
+        assert_eq!(result.errors.len(), 2);
+
+        let width: Option<i64> = conn
+            .query_row("SELECT width FROM images WHERE hash = ?", [&hash], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(width, Some(4));
+    }
+
+    #[test]
+    fn zip_catalog_within_limits_is_read() {
+        let zip = catalog_zip(&[("a.png", b"aaaa"), ("dir/b.png", b"bbbb")]);
+        let (_, files) = ImageStore::read_zip_catalog(&zip, 3, 8 + 14).unwrap();
+        let names: Vec<&str> = files.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["a.png", "b.png"]);
+    }
+
+    #[test]
+    fn zip_catalog_with_too_many_entries_is_refused() {
+        let zip = catalog_zip(&[("a.png", b"a"), ("b.png", b"b"), ("c.png", b"c")]);
+        let err = ImageStore::read_zip_catalog(&zip, 3, u64::MAX).unwrap_err();
+        assert!(err.to_string().contains("4 entries (max 3)"), "{}", err);
+    }
+
+    #[test]
+    fn zip_bomb_is_refused_once_it_inflates_past_the_limit() {
+        // a few KiB of deflate stream inflating to 4 MiB per entry
+        let zeros = vec![0u8; 4 * 1024 * 1024];
+        let zip = catalog_zip(&[("a.png", &zeros), ("b.png", &zeros), ("c.png", &zeros)]);
+        assert!(zip.len() < 64 * 1024);
+
+        let err = ImageStore::read_zip_catalog(&zip, 100, 10 * 1024 * 1024).unwrap_err();
+        assert!(err.to_string().contains("inflates to more than"), "{}", err);
+    }
+
+    #[cfg(not(feature = "heic"))]
+    #[tokio::test]
+    async fn local_heic_add_says_support_is_not_compiled_in() {
+        let (dir, store) = temp_store();
+        let path = dir.path().join("photo.heic");
+        let mut data = b"\0\0\0\x18ftypheic\0\0\0\0mif1heic".to_vec();
+        data.resize(64, 0);
+        std::fs::write(&path, data).unwrap();
+
+        let err = store
+            .add_image(path.to_str().unwrap(), PathType::Local, None, None)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("HEIC support is not compiled"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn local_add_stores_a_copy() {
+        let (dir, store) = temp_store();
+        let path = dir.path().join("source.png");
+        std::fs::write(&path, png(4, 4, 9)).unwrap();
+
+        let hash = store
+            .add_image(path.to_str().unwrap(), PathType::Local, None, None)
+            .await
+            .unwrap();
+        let image = store.get_image_by_hash(&hash).unwrap().unwrap();
+        assert_eq!(image.format, "PNG");
+        assert_eq!(image.original_format, None);
+        assert!(path.exists());
+    }
+
+    #[tokio::test]
+    async fn file_info_stays_in_sync_after_a_retag() {
+        let (_dir, store) = temp_store();
+        let hash = add_png(&store, 1).await;
+        let filename = format!("{}.png", hash);
+        store.add_tags(&hash, &["retagged".to_string()]).unwrap();
+        // as if the retag came long after the file was written
+        store
+            .pool
+            .get()
+            .unwrap()
+            .execute(
+                "UPDATE images SET modified_at = '2030-01-01T00:00:00Z' WHERE hash = ?",
+                [&hash],
+            )
+            .unwrap();
+        assert!(store.get_file_info(&filename).unwrap().in_sync);
+
+        std::fs::write(store.images_dir.join(&filename), png(4, 4, 2)).unwrap();
+        assert!(!store.get_file_info(&filename).unwrap().in_sync);
+    }
+
+    #[tokio::test]
+    async fn copy_tags_can_bring_the_metadata_along() {
+        let (_dir, store) = temp_store();
+        let source = format!("{}.png", add_png(&store, 1).await);
+        let target = format!("{}.png", add_png(&store, 2).await);
+        store
+            .add_tags(source.trim_end_matches(".png"), &["cat".to_string()])
+            .unwrap();
+        let metadata = |pairs: &[(&str, &str)]| -> BTreeMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        store
+            .set_image_metadata(&source, &metadata(&[("artist", "a"), ("rating", "safe")]))
+            .unwrap();
+        store
+            .set_image_metadata(&target, &metadata(&[("artist", "b"), ("source", "x")]))
+            .unwrap();
+
+        let result = store.copy_tags(&target, &source, false, false).unwrap();
+        assert_eq!(result.tags, ["cat"]);
+        assert!(result.metadata.is_none());
+
+        let result = store.copy_tags(&target, &source, false, true).unwrap();
+        assert_eq!(
+            result.metadata.unwrap(),
+            metadata(&[("artist", "a"), ("rating", "safe"), ("source", "x")])
+        );
+    }
+
+    fn query_filters(query: &[(&str, &str)]) -> ImageFilters {
+        let params = query
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        ImageFilters::from_query(&params).unwrap()
+    }
+
+    #[tokio::test]
+    async fn repeated_filter_tags_still_match() {
+        let (_dir, store) = temp_store();
+        let neko = add_png(&store, 1).await;
+        store
+            .add_tags(&neko, &["neko".to_string(), "maid".to_string()])
+            .unwrap();
+        let other = add_png(&store, 2).await;
+        store.add_tags(&other, &["maid".to_string()]).unwrap();
+
+        for tags in ["neko,neko", "neko,Neko", "neko, neko ,maid"] {
+            let filters = query_filters(&[("tags", tags)]);
+            assert_eq!(
+                store.count_images_with_filters(&filters).unwrap(),
+                1,
+                "{}",
+                tags
+            );
+        }
+        let exact = query_filters(&[("tags", "maid,neko,neko"), ("tag_match", "exact")]);
+        assert_eq!(store.count_images_with_filters(&exact).unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn key_filters_merged_with_request_tags_still_match() {
+        let (_dir, store) = temp_store();
+        let neko = add_png(&store, 1).await;
+        store
+            .add_tags(&neko, &["neko".to_string(), "maid".to_string()])
+            .unwrap();
+        let other = add_png(&store, 2).await;
+        store.add_tags(&other, &["neko".to_string()]).unwrap();
+
+        let defaults = DefaultFilters {
+            tags: vec!["neko".to_string(), "neko".to_string()],
+            ..Default::default()
+        };
+        // the key's default tags, restricted to its allowed tags
+        let filters = query_filters(&[])
+            .with_defaults(Some(&defaults))
+            .restrict_to(&["neko".to_string(), "neko".to_string()]);
+        assert_eq!(store.count_images_with_filters(&filters).unwrap(), 2);
+
+        // request tags replace the defaults but keep the restriction
+        let filters = query_filters(&[("tags", "maid,neko,maid")])
+            .with_defaults(Some(&defaults))
+            .restrict_to(&["neko".to_string()]);
+        assert_eq!(store.count_images_with_filters(&filters).unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn export_skips_files_deleted_mid_export() {
+        let (_dir, store) = temp_store();
+        let mut hashes = Vec::new();
+        for seed in 1..=3 {
+            let hash = add_png(&store, seed).await;
+            store.add_tags(&hash, &["neko".to_string()]).unwrap();
+            hashes.push(hash);
+        }
+        let gone: String = store
+            .pool
+            .get()
+            .unwrap()
+            .query_row(
+                "SELECT filename FROM images WHERE hash = ?",
+                [&hashes[1]],
+                |row| row.get(0),
+            )
+            .unwrap();
+        std::fs::remove_file(store.images_dir.join(&gone)).unwrap();
+
+        let snapshot = "9999-12-31T00:00:00Z";
+        let (data, first) = store.export_zip(snapshot, None, 2).await.unwrap();
+        let cursor = first.next_cursor.clone().expect("a second page");
+        let (created_at, hash) = cursor.split_once(',').unwrap();
+        let (_, second) = store
+            .export_zip(snapshot, Some((created_at, hash)), 2)
+            .await
+            .unwrap();
+        assert!(second.next_cursor.is_none());
+
+        let pages = [&first, &second];
+        let skipped: Vec<_> = pages.iter().flat_map(|page| &page.skipped).collect();
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].filename, gone);
+        let included: usize = pages.iter().map(|page| page.counts.included).sum();
+        let skipped_count: usize = pages.iter().map(|page| page.counts.skipped).sum();
+        assert_eq!((included, skipped_count), (2, 1));
+        assert!(pages
+            .iter()
+            .flat_map(|page| &page.images)
+            .all(|entry| entry.filename != gone && entry.tags == ["neko"]));
+
+        // the page still reads back as an importable catalog
+        let (manifest, files) =
+            ImageStore::read_zip_catalog(&data, MAX_ZIP_ENTRIES, MAX_ZIP_UNCOMPRESSED_BYTES)
+                .unwrap();
+        assert_eq!(manifest.images.len(), first.counts.included);
+        assert_eq!(files.len(), first.counts.included);
+    }
+
+    #[tokio::test]
+    async fn allowlist_only_restricts_downloads() {
+        let (_dir, store) =
+            crate::test_support::temp_store_with(&["--url-allowlist", "example.com"]);
+        let err = store.validate_url("http://8.8.8.8/hook").await.unwrap_err();
+        assert!(err.to_string().contains("not allowed"), "{}", err);
+        assert!(validate_public_url("http://8.8.8.8/hook").await.is_ok());
+
+        // the SSRF checks still apply to both
+        for url in [
+            "http://127.0.0.1/hook",
+            "ftp://8.8.8.8/",
+            "http://8.8.8.8:22/",
+        ] {
+            assert!(validate_public_url(url).await.is_err(), "{}", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn encoded_internal_addresses_are_refused() {
+        for url in [
+            // 127.0.0.1 written as one decimal, hex and octal
+            "http://2130706433/",
+            "http://0x7f.0.0.1/",
+            "http://0177.0.0.1/",
+            // 10.0.0.1 and 169.254.169.254 as decimals
+            "http://167772161/",
+            "http://2852039166/latest/meta-data",
+            "http://[::1]/",
+            "http://[0:0:0:0:0:0:0:1]:8080/",
+            "http://[::ffff:127.0.0.1]/",
+            "http://[::ffff:7f00:1]/",
+            "http://[fc00::1]/",
+            "http://[fd12:3456::1]/",
+            "http://[fe80::1]/",
+        ] {
+            let err = validate_public_url(url).await.unwrap_err();
+            assert!(err.to_string().contains("not allowed"), "{}: {}", url, err);
+        }
+
+        // public addresses in the same notations still pass
+        for url in [
+            "http://134744072/",
+            "http://0x8.8.8.8/",
+            "http://[2001:4860::8888]/",
+        ] {
+            assert!(validate_public_url(url).await.is_ok(), "{}", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn hashes_sharing_a_short_prefix_are_different_images() {
+        let (dir, store) = temp_store();
+        let data = png(4, 4, 1);
+        let hash = format!("{:x}", Sha256::digest(&data));
+
+        // an image whose hash only shares the first 8 characters, stored
+        // under the short filename old uploads got
+        let mut other = hash[..8].to_string();
+        other.extend(hash[8..].chars().map(|c| if c == '0' { '1' } else { '0' }));
+        let short_name = format!("{}.png", &hash[..8]);
+        std::fs::write(dir.path().join("images").join(&short_name), png(4, 4, 2)).unwrap();
+        store
+            .pool
+            .get()
+            .unwrap()
+            .execute(
+                "INSERT INTO images (hash, filename, created_at, modified_at, width, height, size_bytes, format)
+                 VALUES (?, ?, '2024-01-01T00:00:00Z', '2024-01-01T00:00:00Z', 4, 4, 1, 'PNG')",
+                [&other, &short_name],
+            )
+            .unwrap();
+
+        let added = store
+            .add_image_data(&data, "test.png", "image/png")
+            .await
+            .unwrap();
+        assert_eq!(added, hash);
+        assert_ne!(added, other);
+        let stored = store.get_image_by_hash(&hash).unwrap().unwrap();
+        assert_eq!(stored.filename, format!("{}.png", hash));
+        assert_eq!(
+            store.get_image_by_hash(&other).unwrap().unwrap().filename,
+            short_name
+        );
+
+        // the same bytes again are a real duplicate
+        let err = store
+            .add_image_data(&data, "test.png", "image/png")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("already exists"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn deletion_webhook_belongs_to_the_uploader() {
+        let (_dir, store) = temp_store();
+        let mine = add_png(&store, 1).await;
+        let theirs = add_png(&store, 2).await;
+        store.set_image_uploader(&mine, "alice").unwrap();
+        store.set_image_uploader(&theirs, "bob").unwrap();
+        store
+            .set_key_webhook("alice", "https://hooks.example.com/a", "s")
+            .unwrap();
+
+        let filename = |hash: &str| store.get_image_by_hash(hash).unwrap().unwrap().filename;
+        let webhook = store.uploader_webhook(&filename(&mine)).unwrap().unwrap();
+        assert_eq!(webhook.username, "alice");
+        assert!(store
+            .uploader_webhook(&filename(&theirs))
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn derived_files_are_evicted_least_recently_served_first() {
+        let (_dir, store) =
+            crate::test_support::temp_store_with(&["--derived-cache-max-bytes", "10"]);
+        let hash = add_png(&store, 1).await;
+        let a = store.put_derived_file(&hash, "a.webp", b"aaaa").unwrap();
+        let b = store.put_derived_file(&hash, "b.webp", b"bbbb").unwrap();
+        let c = store.put_derived_file(&hash, "c.webp", b"cccc").unwrap();
+
+        // serving `a` makes `b` the least recently used
+        assert_eq!(
+            store.derived_file(&hash, "a.webp").unwrap(),
+            Some(a.clone())
+        );
+        assert_eq!(store.evict_derived_files().unwrap(), 1);
+        assert!(a.exists() && !b.exists() && c.exists());
+        assert_eq!(store.derived_file(&hash, "b.webp").unwrap(), None);
+        assert_eq!(store.derived_cache_stats().unwrap().bytes, 8);
+        assert_eq!(store.derived_cache_evictions(), 1);
+
+        // under budget nothing goes
+        assert_eq!(store.evict_derived_files().unwrap(), 0);
+
+        // and removing the image takes its variants with it
+        store.remove_image(&format!("{}.png", hash), false).unwrap();
+        assert!(!a.exists() && !c.exists());
+        assert_eq!(store.derived_cache_stats().unwrap().files, 0);
+    }
+
+    #[tokio::test]
+    async fn replacing_an_image_keeps_its_tags_and_takes_the_new_size() {
+        let (_dir, store) = temp_store();
+        let hash = add_png(&store, 1).await;
+        let filename = format!("{}.png", hash);
+        store.add_tags(&hash, &["neko".to_string()]).unwrap();
+
+        let replaced = store
+            .replace_image(&filename, &png(8, 6, 2), "image/png", Some(&hash))
+            .unwrap();
+        assert_eq!((replaced.width, replaced.height), (8, 6));
+        assert_ne!(replaced.hash, hash);
+        assert_eq!(replaced.filename, filename);
+        assert_eq!(replaced.tags, vec!["neko".to_string()]);
+
+        // the old hash is stale now, and a weak tag never matches
+        for stale in [hash.as_str(), &format!("W/\"{}", replaced.hash)] {
+            let err = store
+                .replace_image(&filename, &png(8, 6, 3), "image/png", Some(stale))
+                .unwrap_err();
+            assert!(err.to_string().starts_with("Precondition failed"));
+        }
+        let image = store.get_image_by_filename(&filename).unwrap();
+        assert_eq!(image.hash, replaced.hash);
+        assert!(store.replacing.is_empty());
+    }
+
+    #[tokio::test]
+    async fn concurrent_replacements_with_one_etag_let_one_through() {
+        let (_dir, store) = temp_store();
+        let hash = add_png(&store, 1).await;
+        let filename = format!("{}.png", hash);
+
+        let racers: Vec<_> = (2..6u8)
+            .map(|seed| {
+                let (store, filename, hash) = (store.clone(), filename.clone(), hash.clone());
+                std::thread::spawn(move || {
+                    store.replace_image(&filename, &png(4, 4, seed), "image/png", Some(&hash))
+                })
+            })
+            .collect();
+        let results: Vec<_> = racers.into_iter().map(|t| t.join().unwrap()).collect();
+        let winners: Vec<_> = results.iter().filter_map(|r| r.as_ref().ok()).collect();
+        assert_eq!(winners.len(), 1);
+
+        // the file on disk is the one the winning row describes
+        let image = store.get_image_by_filename(&filename).unwrap();
+        assert_eq!(image.hash, winners[0].hash);
+        let on_disk = std::fs::read(store.images_dir.join(&filename)).unwrap();
+        assert_eq!(format!("{:x}", Sha256::digest(&on_disk)), image.hash);
+    }
+
+    #[tokio::test]
+    async fn exact_tag_match_leaves_out_supersets_and_subsets() {
+        let (_dir, store) = temp_store();
+        let tag = |hash: &str, tags: &[&str]| {
+            let tags: Vec<String> = tags.iter().map(|t| t.to_string()).collect();
+            store.add_tags(hash, &tags).unwrap();
+        };
+        let exact = add_png(&store, 1).await;
+        tag(&exact, &["neko", "maid"]);
+        let superset = add_png(&store, 2).await;
+        tag(&superset, &["neko", "maid", "cat"]);
+        let subset = add_png(&store, 3).await;
+        tag(&subset, &["neko"]);
+
+        let filters = query_filters(&[("tags", "maid,neko"), ("tag_match", "exact")]);
+        let listed = store
+            .list_images_with_filters(&filters, ImageSort::default(), SortOrder::default(), 10, 0)
+            .unwrap();
+        let listed: Vec<&str> = listed.iter().map(|image| image.hash.as_str()).collect();
+        assert_eq!(listed, vec![exact.as_str()]);
+        for _ in 0..10 {
+            let drawn = store
+                .get_random_image_with_strategy(&filters, SelectionStrategy::default())
+                .unwrap();
+            assert_eq!(drawn.hash, exact);
+        }
+
+        // the default still takes the superset
+        let filters = query_filters(&[("tags", "maid,neko")]);
+        assert_eq!(store.count_images_with_filters(&filters).unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn canonical_webp_reencodes_stills_and_keeps_animations() {
+        let (_dir, store) = crate::test_support::temp_store_with(&["--canonical-format", "webp"]);
+        let hash = store
+            .add_image_data(&png(6, 4, 1), "still.png", "image/png")
+            .await
+            .unwrap();
+        let image = store.get_image_by_hash(&hash).unwrap().unwrap();
+        assert_eq!(image.filename, format!("{}.webp", hash));
+        assert_eq!(image.format, "WEBP");
+        assert_eq!(image.original_format.as_deref(), Some("PNG"));
+        assert_eq!((image.width, image.height), (6, 4));
+        let stored = std::fs::read(store.images_dir.join(&image.filename)).unwrap();
+        assert_eq!(image::guess_format(&stored).unwrap(), ImageFormat::WebP);
+        assert_eq!(format!("{:x}", Sha256::digest(&stored)), hash);
+
+        let mut gif = Vec::new();
+        {
+            let mut encoder = image::codecs::gif::GifEncoder::new(&mut gif);
+            for seed in [0u8, 255] {
+                let frame = image::RgbaImage::from_pixel(3, 3, image::Rgba([seed, 0, 0, 255]));
+                encoder.encode_frame(image::Frame::new(frame)).unwrap();
             }
         }
+        let hash = store
+            .add_image_data(&Bytes::from(gif.clone()), "moving.gif", "image/gif")
+            .await
+            .unwrap();
+        let image = store.get_image_by_hash(&hash).unwrap().unwrap();
+        assert_eq!(image.filename, format!("{}.gif", hash));
+        assert_eq!(image.original_format, None);
+        assert_eq!(
+            std::fs::read(store.images_dir.join(&image.filename)).unwrap(),
+            gif
+        );
+    }
 
-        if let Some(size_filter) = &filters.size {
-            match size_filter {
-                SizeFilter::Exact(s) => {
-                    conditions.push("size_bytes = ?".to_string());
-                    param_values.push(s.to_string());
-                }
-                SizeFilter::Range(min, max) => {
-                    conditions.push("size_bytes BETWEEN ? AND ?".to_string());
-                    param_values.push(min.to_string());
-                    param_values.push(max.to_string());
-                }
+    #[tokio::test]
+    async fn metadata_filters_match_keys_and_values() {
+        let (_dir, store) = temp_store();
+        let annotate = |hash: &str, pairs: &[(&str, &str)]| {
+            let metadata = pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            store
+                .set_image_metadata(&format!("{}.png", hash), &metadata)
+                .unwrap();
+        };
+        let signed = add_png(&store, 1).await;
+        annotate(&signed, &[("artist", "alice"), ("source", "pixiv")]);
+        let other_artist = add_png(&store, 2).await;
+        annotate(&other_artist, &[("artist", "bob")]);
+        let bare = add_png(&store, 3).await;
+
+        let matching = |query: &[(&str, &str)]| {
+            let filters = query_filters(query);
+            let mut hashes: Vec<String> = store
+                .list_images_with_filters(
+                    &filters,
+                    ImageSort::default(),
+                    SortOrder::default(),
+                    10,
+                    0,
+                )
+                .unwrap()
+                .into_iter()
+                .map(|image| image.hash)
+                .collect();
+            hashes.sort();
+            hashes
+        };
+        let sorted = |mut hashes: Vec<&String>| {
+            hashes.sort();
+            hashes.into_iter().cloned().collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            matching(&[("has_metadata", "artist")]),
+            sorted(vec![&signed, &other_artist])
+        );
+        // every listed key has to be present
+        assert_eq!(
+            matching(&[("has_metadata", "artist, source")]),
+            vec![signed.clone()]
+        );
+        assert_eq!(
+            matching(&[("metadata.artist", "bob")]),
+            vec![other_artist.clone()]
+        );
+        assert_eq!(
+            matching(&[("metadata.artist", "alice"), ("metadata.source", "pixiv")]),
+            vec![signed.clone()]
+        );
+        assert!(matching(&[
+            ("metadata.artist", "alice"),
+            ("metadata.source", "danbooru")
+        ])
+        .is_empty());
+        assert!(matching(&[("has_metadata", "missing")]).is_empty());
+        assert_eq!(matching(&[]).len(), 3);
+        assert!(matching(&[]).contains(&bare));
+    }
+
+    #[tokio::test]
+    async fn every_untagging_path_handles_empty_tags_alike() {
+        for keep in [false, true] {
+            let args: &[&str] = if keep { &["--keep-empty-tags"] } else { &[] };
+            let (_dir, store) = crate::test_support::temp_store_with(args);
+            let tag = |hash: &str, tags: &[&str]| {
+                let tags: Vec<String> = tags.iter().map(|t| t.to_string()).collect();
+                store.add_tags(hash, &tags).unwrap();
+            };
+            let a = add_png(&store, 1).await;
+            tag(&a, &["solo_a", "shared"]);
+            let b = add_png(&store, 2).await;
+            tag(&b, &["solo_b", "shared"]);
+            let c = add_png(&store, 3).await;
+            tag(&c, &["solo_c"]);
+            let d = add_png(&store, 4).await;
+            tag(&d, &["solo_d"]);
+
+            store
+                .remove_tags(&a, &["solo_a".to_string()], false)
+                .unwrap();
+            // `b` takes `a`'s tags in place of its own
+            store
+                .copy_tags(&format!("{}.png", b), &a, true, false)
+                .unwrap();
+            let change = HashTagChange {
+                hash: c.clone(),
+                add: Vec::new(),
+                remove: vec!["solo_c".to_string()],
+            };
+            store.change_tags_by_hash(&[change], false).unwrap();
+            store.remove_image(&format!("{}.png", d), false).unwrap();
+
+            let emptied = ["solo_a", "solo_b", "solo_c", "solo_d"];
+            let mut expected = vec![("shared".to_string(), 2)];
+            if keep {
+                expected.extend(emptied.iter().map(|t| (t.to_string(), 0)));
+                expected.sort();
             }
+            assert_eq!(store.get_all_tags(true).unwrap(), expected, "keep={}", keep);
+
+            let collected = store.gc_tags(false).unwrap();
+            if keep {
+                assert_eq!(collected, emptied);
+            } else {
+                assert!(collected.is_empty());
+            }
+            assert_eq!(
+                store.get_all_tags(true).unwrap(),
+                vec![("shared".to_string(), 2)]
+            );
         }
+    }
 
-        if !conditions.is_empty() {
-            query.push_str(" WHERE ");
-            query.push_str(&conditions.join(" AND "));
+    #[tokio::test]
+    async fn refresh_picks_up_a_file_rewritten_on_disk() {
+        let (_dir, store) = temp_store();
+        let hash = add_png(&store, 1).await;
+        let filename = format!("{}.png", hash);
+        store.add_tags(&hash, &["neko".to_string()]).unwrap();
+        let mut metadata = BTreeMap::new();
+        metadata.insert("artist".to_string(), "alice".to_string());
+        store.set_image_metadata(&filename, &metadata).unwrap();
+
+        let rewritten = png(10, 5, 2);
+        std::fs::write(store.images_dir.join(&filename), &rewritten).unwrap();
+        let refreshed = store.refresh_image(&filename).unwrap();
+
+        let new_hash = format!("{:x}", Sha256::digest(&rewritten));
+        assert_eq!(refreshed.hash, new_hash);
+        assert_eq!(refreshed.size_bytes, rewritten.len() as u64);
+        assert_eq!((refreshed.width, refreshed.height), (10, 5));
+        assert_eq!(refreshed.filename, filename);
+        assert_eq!(store.get_image_tags(&new_hash).unwrap(), vec!["neko"]);
+        // setting nothing returns what's there
+        assert_eq!(
+            store
+                .set_image_metadata(&filename, &BTreeMap::new())
+                .unwrap(),
+            metadata
+        );
+        assert!(store.get_image_by_hash(&hash).unwrap().is_none());
+
+        // refreshing an unchanged file changes nothing
+        assert_eq!(store.refresh_image(&filename).unwrap().hash, new_hash);
+    }
+
+    #[tokio::test]
+    async fn allowlist_refuses_off_list_hosts_and_redirects() {
+        let (_dir, store) =
+            crate::test_support::temp_store_with(&["--url-allowlist", "example.com"]);
+        for url in ["http://evil.test/a.png", "http://notexample.com/a.png"] {
+            let err = store.validate_url(url).await.unwrap_err();
+            assert!(err.to_string().contains("host is not allowed"), "{}", err);
         }
 
-        if let Some(tags) = &filters.tags {
-            if !tags.is_empty() {
-                query.push_str(&format!(
-                    " GROUP BY i.hash HAVING COUNT(DISTINCT t.name) = {}",
-                    tags.len()
-                ));
-            }
+        let allowlist = vec!["example.com".to_string()];
+        let refusal = |url: &str| redirect_refusal(&allowlist, 5, &Url::parse(url).unwrap(), 1);
+        assert_eq!(
+            refusal("http://evil.test/a.png").as_deref(),
+            Some("Redirect host is not allowed: evil.test")
+        );
+        assert_eq!(refusal("http://cdn.example.com/a.png"), None);
+        assert_eq!(refusal("http://example.com/a.png"), None);
+        // without an allowlist, a redirect to an internal address is still refused
+        assert_eq!(
+            redirect_refusal(&[], 5, &Url::parse("http://10.0.0.1/").unwrap(), 1).as_deref(),
+            Some("Redirect address is not allowed: 10.0.0.1")
+        );
+    }
+
+    #[tokio::test]
+    async fn sidecars_bring_back_rows_lost_with_the_database() {
+        let (dir, store) = crate::test_support::temp_store_with(&["--write-sidecars"]);
+        let hash = add_png(&store, 1).await;
+        let filename = format!("{}.png", hash);
+        store.add_tags(&hash, &["neko".to_string()]).unwrap();
+        let mut metadata = BTreeMap::new();
+        metadata.insert("artist".to_string(), "alice".to_string());
+        store.set_image_metadata(&filename, &metadata).unwrap();
+        let before = store.get_image_by_hash(&hash).unwrap().unwrap();
+        let written = sidecar::read(&sidecar::sidecar_path(&store.images_dir, &filename)).unwrap();
+        assert_eq!(written.tags, vec!["neko"]);
+        assert_eq!(written.metadata, metadata);
+
+        // a fresh database over the same images directory
+        let restored = ImageStore::new(
+            dir.path().join("replacement.db").to_str().unwrap(),
+            dir.path().join("images"),
+            &config(&["--write-sidecars"]),
+        )
+        .unwrap();
+        let after = restored.get_image_by_hash(&hash).unwrap().unwrap();
+        assert_eq!(after.filename, filename);
+        assert_eq!(after.tags, vec!["neko"]);
+        assert_eq!(after.created_at, before.created_at);
+        assert_eq!((after.width, after.height), (4, 4));
+        assert_eq!(
+            restored
+                .set_image_metadata(&filename, &BTreeMap::new())
+                .unwrap(),
+            metadata
+        );
+    }
+
+    #[tokio::test]
+    async fn change_feed_pages_in_order_and_reports_pruning() {
+        let (_dir, store) = temp_store();
+        let a = add_png(&store, 1).await;
+        let b = add_png(&store, 2).await;
+        store.add_tags(&a, &["neko".to_string()]).unwrap();
+        store.add_tags(&a, &["maid".to_string()]).unwrap();
+        store.remove_image(&format!("{}.png", b), false).unwrap();
+
+        let (changes, pruned_through) = store.get_changes(0, 100, &[]).unwrap();
+        assert_eq!(pruned_through, 0);
+        let events: Vec<(&str, &str)> = changes
+            .iter()
+            .map(|c| (c.event.as_str(), c.hash.as_str()))
+            .collect();
+        // the second retag replaces the first
+        assert_eq!(
+            events,
+            vec![
+                ("image_added", a.as_str()),
+                ("image_added", b.as_str()),
+                ("tags_changed", a.as_str()),
+                ("image_removed", b.as_str()),
+            ]
+        );
+        assert!(changes.windows(2).all(|w| w[0].cursor < w[1].cursor));
+
+        // paging from each page's last cursor walks the same entries
+        let mut paged = Vec::new();
+        let mut since = 0;
+        loop {
+            let (page, _) = store.get_changes(since, 1, &[]).unwrap();
+            let Some(last) = page.last() else { break };
+            since = last.cursor;
+            paged.push(last.cursor);
         }
+        let cursors: Vec<i64> = changes.iter().map(|c| c.cursor).collect();
+        assert_eq!(paged, cursors);
+
+        // only entries past the retention go
+        store
+            .pool
+            .get()
+            .unwrap()
+            .execute(
+                "UPDATE change_log SET created_at = '2000-01-01T00:00:00Z' WHERE id <= ?",
+                [cursors[1]],
+            )
+            .unwrap();
+        assert_eq!(
+            store.prune_change_log(Duration::from_secs(3600)).unwrap(),
+            2
+        );
+        let (remaining, pruned_through) = store.get_changes(0, 100, &[]).unwrap();
+        assert_eq!(pruned_through, cursors[1]);
+        assert_eq!(remaining.len(), 2);
+        assert_eq!(
+            store.prune_change_log(Duration::from_secs(3600)).unwrap(),
+            0
+        );
+        assert_eq!(store.get_changes(0, 100, &[]).unwrap().1, cursors[1]);
+    }
 
-        query.push_str(" ORDER BY RANDOM() LIMIT 1");
+    /// Overwrites an image's file with junk and forgets what was recorded
+    /// about it, so the next read has to decode the file and fails.
+    fn corrupt(store: &ImageStore, hash: &str) {
+        let conn = store.pool.get().unwrap();
+        let filename: String = conn
+            .query_row(
+                "SELECT filename FROM images WHERE hash = ?",
+                [hash],
+                |row| row.get(0),
+            )
+            .unwrap();
+        std::fs::write(store.images_dir.join(filename), b"not an image").unwrap();
+        conn.execute(
+            "UPDATE images SET width = NULL, height = NULL, size_bytes = NULL, format = NULL
+             WHERE hash = ?",
+            [hash],
+        )
+        .unwrap();
+    }
 
-        let params: Vec<&str> = param_values.iter().map(|s| s.as_str()).collect();
+    #[tokio::test]
+    async fn unreadable_files_are_skipped_in_pages_and_redrawn_in_random() {
+        let (_dir, store) = temp_store();
+        let good = add_png(&store, 1).await;
+        let bad = add_png(&store, 2).await;
+        corrupt(&store, &bad);
+
+        let page = store
+            .list_images_with_filters(
+                &query_filters(&[]),
+                ImageSort::Created,
+                SortOrder::Desc,
+                10,
+                0,
+            )
+            .unwrap();
+        let hashes: Vec<_> = page.iter().map(|image| image.hash.as_str()).collect();
+        assert_eq!(hashes, [good.as_str()]);
+
+        let other = add_png(&store, 3).await;
+        corrupt(&store, &other);
+        // least served draws the unread file first, so the good one only
+        // comes back through a redraw
+        store
+            .pool
+            .get()
+            .unwrap()
+            .execute("UPDATE images SET serve_count = 5 WHERE hash = ?", [&good])
+            .unwrap();
+        let image = store
+            .get_random_image_with_strategy(&query_filters(&[]), SelectionStrategy::LeastServed)
+            .unwrap();
+        assert_eq!(image.hash, good);
+        assert_eq!(store.count_broken_images().unwrap(), 2);
+    }
 
-        let row = conn.query_row(&query, rusqlite::params_from_iter(params), |row| {
-            Ok((
-                row.get::<_, String>(0)?,
-                row.get::<_, String>(1)?,
-                row.get::<_, String>(2)?,
-                row.get::<_, String>(3)?,
-            ))
-        })?;
+    #[tokio::test]
+    async fn request_filters_win_over_key_defaults_and_exclusions_add_up() {
+        let (_dir, store) = temp_store();
+        let small = add_png(&store, 1).await;
+        store.add_tags(&small, &["neko".to_string()]).unwrap();
+        let large = store
+            .add_image_data(&png(8, 8, 2), "large.png", "image/png")
+            .await
+            .unwrap();
+        store
+            .add_tags(&large, &["neko".to_string(), "meme".to_string()])
+            .unwrap();
+        let maid = add_png(&store, 3).await;
+        store.add_tags(&maid, &["maid".to_string()]).unwrap();
+
+        let defaults = DefaultFilters {
+            tags: vec!["maid".to_string()],
+            exclude_tags: vec!["meme".to_string()],
+            width: Some(4),
+            ..Default::default()
+        };
 
-        let (filename, hash, created_at, modified_at) = row;
-        self.build_image_response(&filename, &hash, &created_at, &modified_at)
+        // defaults fill in what the request leaves unset
+        let filters = query_filters(&[]).with_defaults(Some(&defaults));
+        assert_eq!(filters.tags, Some(vec!["maid".to_string()]));
+        assert_eq!(store.count_images_with_filters(&filters).unwrap(), 1);
+
+        // request tags and width replace the defaults, the default exclusion
+        // still drops the large image
+        let filters =
+            query_filters(&[("tags", "neko"), ("width", "8")]).with_defaults(Some(&defaults));
+        assert_eq!(filters.tags, Some(vec!["neko".to_string()]));
+        assert!(matches!(filters.width, Some(DimensionFilter::Exact(8))));
+        assert_eq!(store.count_images_with_filters(&filters).unwrap(), 0);
+        let filters = query_filters(&[("tags", "neko"), ("width", "8")]);
+        assert_eq!(store.count_images_with_filters(&filters).unwrap(), 1);
+
+        let filters = query_filters(&[("tags", "neko"), ("exclude_tags", "maid,meme")])
+            .with_defaults(Some(&defaults));
+        assert_eq!(filters.exclude_tags, ["maid", "meme"]);
+
+        // a key without defaults leaves the request exactly as it was
+        let request = query_filters(&[("tags", "neko"), ("exclude_tags", "meme")]);
+        let fingerprint = request.fingerprint();
+        let filters = request.with_defaults(None);
+        assert_eq!(filters.fingerprint(), fingerprint);
+        assert_eq!(store.count_images_with_filters(&filters).unwrap(), 1);
     }
 
-    fn build_image_response(
-        &self,
-        filename: &str,
-        hash: &str,
-        created_at: &str,
-        modified_at: &str,
-    ) -> Result<ImageResponse> {
-        let tags = self.get_image_tags(hash)?;
-        let file_path = self.images_dir.join(filename);
+    #[tokio::test]
+    async fn bbox_matches_exif_locations_including_across_the_antimeridian() {
+        let (_dir, store) = temp_store();
+        let mut hashes = Vec::new();
+        for (seed, lat, lon) in [(1, 35.66, 139.7), (2, -33.5, -70.25), (3, -17.7, 178.0)] {
+            let data = crate::test_support::gps_jpeg(seed, lat, lon);
+            hashes.push(
+                store
+                    .add_image_data(&data, "photo.jpg", "image/jpeg")
+                    .await
+                    .unwrap(),
+            );
+        }
+        let (tokyo, santiago, fiji) = (&hashes[0], &hashes[1], &hashes[2]);
+        add_png(&store, 4).await;
+
+        let matching = |bbox: &str| {
+            let filters = query_filters(&[("bbox", bbox)]);
+            let mut hashes: Vec<String> = store
+                .list_images_with_filters(&filters, ImageSort::Created, SortOrder::Asc, 10, 0)
+                .unwrap()
+                .into_iter()
+                .map(|image| image.hash)
+                .collect();
+            hashes.sort();
+            hashes
+        };
+        let sorted = |hashes: &[&String]| {
+            let mut hashes: Vec<String> = hashes.iter().map(|hash| hash.to_string()).collect();
+            hashes.sort();
+            hashes
+        };
 
-        let metadata = std::fs::metadata(&file_path)?;
-        let img = image::open(&file_path)?;
-        let dimensions = img.dimensions();
+        assert_eq!(matching("30,130,40,150"), sorted(&[tokyo]));
+        assert_eq!(matching("-40,-80,-30,-60"), sorted(&[santiago]));
+        // minlon past maxlon wraps around the antimeridian
+        assert_eq!(matching("-20,170,-10,-170"), sorted(&[fiji]));
+        assert!(matching("0,0,10,10").is_empty());
+        // images without GPS data never match
+        assert_eq!(
+            matching("-90,-180,90,180"),
+            sorted(&[tokyo, santiago, fiji])
+        );
 
-        let format = file_path
-            .extension()
-            .and_then(|ext| ext.to_str())
-            .map(|ext| ext.to_uppercase())
-            .unwrap_or_else(|| "UNKNOWN".to_string());
+        let image = store
+            .list_images_with_filters(
+                &query_filters(&[("bbox", "30,130,40,150")]),
+                ImageSort::Created,
+                SortOrder::Asc,
+                1,
+                0,
+            )
+            .unwrap()
+            .remove(0);
+        let location = image.location.unwrap();
+        assert!((location.latitude - 35.66).abs() < 1e-6);
+        assert!((location.longitude - 139.7).abs() < 1e-6);
+    }
 
-        Ok(ImageResponse {
-            url: format!("{}/{}", self.base_url, filename),
-            filename: filename.to_string(),
-            format,
-            width: dimensions.0,
-            height: dimensions.1,
-            size_bytes: metadata.len(),
-            hash: hash.to_string(),
-            tags,
-            created_at: OffsetDateTime::parse(&created_at, &Rfc3339)?
-                .format(&Rfc3339)
-                .unwrap_or_else(|_| "".to_string()),
-            modified_at: OffsetDateTime::parse(&modified_at, &Rfc3339)?
-                .format(&Rfc3339)
-                .unwrap_or_else(|_| "".to_string()),
-        })
+    #[test]
+    fn scans_run_on_every_worker_at_once() {
+        let items: Vec<String> = (0..8).map(|i| format!("file{}", i)).collect();
+        let workers = 4;
+        let arrived = Mutex::new(0);
+        let all_arrived = std::sync::Condvar::new();
+
+        // each of the first scans waits for the others, which only works out
+        // if they run side by side
+        let results = scan_in_parallel(&items, workers, |item| {
+            let mut count = arrived.lock().unwrap();
+            *count += 1;
+            all_arrived.notify_all();
+            let (count, timeout) = all_arrived
+                .wait_timeout_while(count, Duration::from_secs(5), |count| *count < workers)
+                .unwrap();
+            drop(count);
+            (item.to_uppercase(), !timeout.timed_out())
+        });
+
+        let names: Vec<&str> = results.iter().map(|(item, _)| item.as_str()).collect();
+        assert_eq!(names, items.iter().map(String::as_str).collect::<Vec<_>>());
+        for (item, (upper, met)) in &results {
+            assert_eq!(*upper, item.to_uppercase());
+            assert!(met, "{} never saw {} scans running together", item, workers);
+        }
     }
 
-    pub async fn add_image_data(
-        &self,
-        data: &Bytes,
-        _filename: &str,
-        content_type: &str,
-    ) -> Result<String> {
-        if !ALLOWED_CONTENT_TYPES.contains(&content_type) {
-            return Err(anyhow!("Unsupported content type: {}", content_type));
+    #[tokio::test]
+    async fn startup_sync_adds_every_new_file_once() {
+        let (dir, store) = temp_store();
+        let existing = add_png(&store, 200).await;
+        drop(store);
+
+        let images_dir = dir.path().join("images");
+        let mut expected = BTreeMap::new();
+        for seed in 0..12u8 {
+            let data = png(3 + seed as u32 % 3, 4, seed);
+            let filename = format!("new{}.png", seed);
+            std::fs::write(images_dir.join(&filename), &data).unwrap();
+            expected.insert(
+                filename,
+                (format!("{:x}", Sha256::digest(&data)), 3 + seed as u32 % 3),
+            );
         }
+        // a copy of one of them, which only one row can hold, and a
+        // half-written download
+        std::fs::copy(images_dir.join("new0.png"), images_dir.join("copy.png")).unwrap();
+        std::fs::write(images_dir.join("temp_partial"), b"partial").unwrap();
 
-        let mut hasher = Sha256::new();
-        hasher.update(data);
-        let hash = format!("{:x}", hasher.finalize());
+        let store = ImageStore::new(
+            dir.path().join("images.db").to_str().unwrap(),
+            images_dir,
+            &config(&["--sync-concurrency", "4"]),
+        )
+        .unwrap();
+
+        let conn = store.pool.get().unwrap();
+        let rows: BTreeMap<String, (String, u32, u32, String)> = conn
+            .prepare("SELECT filename, hash, width, height, format FROM images WHERE hash != ?")
+            .unwrap()
+            .query_map([&existing], |row| {
+                Ok((
+                    row.get(0)?,
+                    (row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?),
+                ))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let copied = rows.contains_key("copy.png");
+        let original = rows.contains_key("new0.png");
+        assert!(
+            copied != original,
+            "the copy and its original are both one row"
+        );
+        assert_eq!(rows.len(), 12);
+        for (filename, (hash, width)) in &expected {
+            let filename = if filename == "new0.png" && copied {
+                "copy.png"
+            } else {
+                filename
+            };
+            let row = &rows[filename];
+            assert_eq!(
+                row,
+                &(hash.clone(), *width, 4, "PNG".to_string()),
+                "{}",
+                filename
+            );
+        }
+        let added: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM change_log WHERE event = 'image_added'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(added, 13);
+    }
 
-        let ext = match content_type {
-            "image/jpeg" => "jpg",
-            "image/png" => "png",
-            "image/gif" => "gif",
-            "image/webp" => "webp",
-            "image/bmp" | "image/x-ms-bmp" => "bmp",
-            _ => return Err(anyhow!("Unsupported image format")),
+    #[tokio::test]
+    async fn an_origin_429_pauses_downloads_from_that_host() {
+        let (_dir, store) = temp_store();
+        let response = |status: u16, retry_after: Option<&str>| {
+            let mut response = warp::http::Response::builder().status(status);
+            if let Some(retry_after) = retry_after {
+                response = response.header("Retry-After", retry_after);
+            }
+            reqwest::Response::from(response.body("").unwrap())
+        };
+        let busy = Url::parse("https://busy.example/a.png").unwrap();
+        let calm = Url::parse("https://calm.example/a.png").unwrap();
+        let acquire = |url: &Url| {
+            let throttle = store.host_throttle.clone();
+            let host = url.host_str().unwrap().to_string();
+            async move {
+                tokio::time::timeout(Duration::from_millis(300), throttle.acquire(&host))
+                    .await
+                    .is_ok()
+            }
         };
 
-        let new_filename = format!("{}.{}", hash, ext);
-        let file_path = self.images_dir.join(&new_filename);
+        assert!(!store.pause_if_rate_limited(&calm, &response(200, Some("60"))));
+        assert!(store.pause_if_rate_limited(&busy, &response(429, Some("2"))));
+        assert!(!acquire(&busy).await);
+        assert!(acquire(&calm).await);
+    }
 
-        if file_path.exists() {
-            return Err(anyhow!("Image already exists: {}", new_filename));
+    #[tokio::test]
+    async fn tag_lookups_are_cached_until_the_tags_change() {
+        for cache_size in ["10", "0"] {
+            let (_dir, store) =
+                crate::test_support::temp_store_with(&["--tag-cache-size", cache_size]);
+            let cached = cache_size != "0";
+            let hash = add_png(&store, 1).await;
+            store.add_tags(&hash, &["neko".to_string()]).unwrap();
+            assert_eq!(store.get_image_tags(&hash).unwrap(), ["neko"]);
+
+            // a tag written behind the store's back only shows once the
+            // cached list is dropped
+            let conn = store.pool.get().unwrap();
+            conn.execute("INSERT INTO tags (name) VALUES ('sneaky')", [])
+                .unwrap();
+            conn.execute(
+                "INSERT INTO image_tags (image_hash, tag_id)
+                 SELECT ?, id FROM tags WHERE name = 'sneaky'",
+                [&hash],
+            )
+            .unwrap();
+            drop(conn);
+            let expected: &[&str] = if cached {
+                &["neko"]
+            } else {
+                &["neko", "sneaky"]
+            };
+            assert_eq!(store.get_image_tags(&hash).unwrap(), expected);
+
+            store.add_tags(&hash, &["maid".to_string()]).unwrap();
+            assert_eq!(
+                store.get_image_tags(&hash).unwrap(),
+                ["maid", "neko", "sneaky"]
+            );
+
+            store
+                .remove_tags(&hash, &["neko".to_string()], false)
+                .unwrap();
+            assert_eq!(store.get_image_tags(&hash).unwrap(), ["maid", "sneaky"]);
         }
+    }
 
-        // Verify it's a valid image
-        let img = image::load_from_memory(data).map_err(|e| anyhow!("Invalid image: {}", e))?;
+    #[test]
+    fn a_taken_username_rolls_back_the_whole_key_batch() {
+        let (_dir, store) = temp_store();
+        let request = |username: &str| -> GenerateApiKeyRequest {
+            serde_json::from_value(serde_json::json!({ "username": username })).unwrap()
+        };
+        let alice = request("alice");
+        store
+            .generate_api_keys(&[(&alice, vec![ApiKeyScope::Upload])])
+            .unwrap();
+
+        // alice is taken after carol and dave were already inserted
+        let (carol, dave) = (request("carol"), request("dave"));
+        let batch = [
+            (&carol, vec![ApiKeyScope::Upload]),
+            (&dave, vec![ApiKeyScope::Upload]),
+            (&alice, vec![ApiKeyScope::Upload]),
+        ];
+        let error = store.generate_api_keys(&batch).unwrap_err();
+        assert!(error.to_string().contains("UNIQUE constraint failed"));
+        assert_eq!(store.count_api_keys().unwrap(), 1);
+        assert_eq!(
+            store
+                .existing_usernames(&["alice", "carol", "dave"])
+                .unwrap(),
+            ["alice"]
+        );
 
-        let dimensions = img.dimensions();
+        let keys = store.generate_api_keys(&batch[..2]).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(store.count_api_keys().unwrap(), 3);
+    }
 
-        // Save the file
-        let mut file = tokio::fs::File::create(&file_path).await?;
-        file.write_all(data).await?;
+    #[tokio::test]
+    async fn namespaces_count_the_tags_in_use_under_each() {
+        let (_dir, store) = crate::test_support::temp_store_with(&["--keep-empty-tags"]);
+        let tags = |tags: &[&str]| tags.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        let first = add_png(&store, 1).await;
+        let second = add_png(&store, 2).await;
+        store
+            .add_tags(
+                &first,
+                &tags(&["artist:ann", "character:maid", "cat", ":odd"]),
+            )
+            .unwrap();
+        store
+            .add_tags(&second, &tags(&["artist:ann", "artist:bo", "artist:cy"]))
+            .unwrap();
+        // kept as a tag, but with no images it no longer counts
+        store
+            .remove_tags(&second, &tags(&["artist:cy"]), false)
+            .unwrap();
+
+        assert_eq!(
+            store.list_namespaces().unwrap(),
+            [("artist".to_string(), 2), ("character".to_string(), 1)]
+        );
+    }
 
-        let now = OffsetDateTime::now_utc().format(&Rfc3339)?;
+    #[tokio::test]
+    async fn truncated_jpegs_are_refused_only_when_decoding_strictly() {
+        let img = image::RgbImage::from_fn(64, 48, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 5) as u8, ((x + y) * 2) as u8])
+        });
+        let mut jpeg = std::io::Cursor::new(Vec::new());
+        image::DynamicImage::ImageRgb8(img)
+            .write_to(&mut jpeg, image::ImageFormat::Jpeg)
+            .unwrap();
+        let jpeg = jpeg.into_inner();
+        let truncated = Bytes::copy_from_slice(&jpeg[..jpeg.len() / 2]);
+
+        let (_dir, strict) = temp_store();
+        let error = strict
+            .add_image_data(&truncated, "cut.jpg", "image/jpeg")
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("Invalid image"), "{}", error);
+
+        let (_dir, lenient) = crate::test_support::temp_store_with(&["--strict-decode", "false"]);
+        let hash = lenient
+            .add_image_data(&truncated, "cut.jpg", "image/jpeg")
+            .await
+            .unwrap();
+        let image = lenient.get_image_by_hash(&hash).unwrap().unwrap();
+        assert_eq!((image.width, image.height), (64, 48));
+    }
 
-        let conn = self.pool.get()?;
-        conn.execute(
-            "INSERT INTO images (hash, filename, created_at, modified_at, width, height, size_bytes) 
-             VALUES (?, ?, ?, ?, ?, ?, ?)",
-            params![
-                hash,
-                new_filename,
-                now,
-                now,
-                dimensions.0 as i64,
-                dimensions.1 as i64,
-                data.len() as i64
-            ],
-        )?;
+    #[tokio::test]
+    async fn downloads_stop_following_redirects_past_the_limit() {
+        use warp::Filter;
+
+        // `/hops/n` redirects n more times before answering
+        let hops = warp::path!("hops" / u32).map(|n: u32| -> Box<dyn warp::Reply> {
+            if n == 0 {
+                Box::new("done")
+            } else {
+                let next = format!("/hops/{}", n - 1)
+                    .parse::<warp::http::Uri>()
+                    .unwrap();
+                Box::new(warp::redirect::found(next))
+            }
+        });
+        let (addr, server) = warp::serve(hops).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        // by name, as the policy only refuses internal addresses given as IPs
+        let url = |n: u32| format!("http://localhost:{}/hops/{}", addr.port(), n);
+        let client = reqwest::Client::builder()
+            .redirect(redirect_policy(Arc::default(), 2))
+            .build()
+            .unwrap();
+
+        let response = client.get(url(2)).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "done");
+
+        let error = client.get(url(3)).send().await.unwrap_err();
+        assert!(error.is_redirect());
+        let mut reasons = Vec::new();
+        let mut source: Option<&dyn std::error::Error> = Some(&error);
+        while let Some(error) = source {
+            reasons.push(error.to_string());
+            source = error.source();
+        }
+        assert!(
+            reasons.iter().any(|reason| reason == "too many redirects"),
+            "{:?}",
+            reasons
+        );
+    }
 
-        Ok(hash)
+    #[tokio::test]
+    async fn only_new_tags_count_toward_the_distinct_tag_limit() {
+        let (_dir, store) = crate::test_support::temp_store_with(&["--max-distinct-tags", "3"]);
+        let tags = |tags: &[&str]| tags.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        let limit_reached = |result: Result<()>| {
+            result
+                .unwrap_err()
+                .downcast_ref::<TagLimitReached>()
+                .map(|reached| reached.limit)
+        };
+        let hash = add_png(&store, 1).await;
+        store.add_tags(&hash, &tags(&["cat", "maid"])).unwrap();
+
+        store.check_tag_limit(&tags(&["cat", "maid"])).unwrap();
+        // spelled differently, still the tags that exist
+        store.check_tag_limit(&tags(&["CAT", "Cat Girl"])).unwrap();
+        store.check_tag_limit(&tags(&["neko", "neko"])).unwrap();
+        assert_eq!(
+            limit_reached(store.check_tag_limit(&tags(&["neko", "dog"]))),
+            Some(3)
+        );
+
+        // add_tags enforces it too, leaving the image as it was
+        assert_eq!(
+            limit_reached(store.add_tags(&hash, &tags(&["neko", "dog"]))),
+            Some(3)
+        );
+        assert_eq!(store.get_image_tags(&hash).unwrap(), ["cat", "maid"]);
+        store.add_tags(&hash, &tags(&["neko", "cat"])).unwrap();
+        assert_eq!(
+            limit_reached(store.check_tag_limit(&tags(&["dog"]))),
+            Some(3)
+        );
+        store.check_tag_limit(&tags(&["neko"])).unwrap();
     }
-}
 
-impl Clone for ImageStore {
-    fn clone(&self) -> Self {
-        Self {
-            pool: self.pool.clone(),
-            images_dir: self.images_dir.clone(),
-            base_url: self.base_url.clone(),
+    #[tokio::test]
+    async fn excluded_tags_drop_images_but_never_untagged_ones() {
+        let (_dir, store) = temp_store();
+        let tagged = |seed, tags: &[&str]| {
+            let store = store.clone();
+            let tags: Vec<String> = tags.iter().map(|t| t.to_string()).collect();
+            async move {
+                let hash = add_png(&store, seed).await;
+                if !tags.is_empty() {
+                    store.add_tags(&hash, &tags).unwrap();
+                }
+                hash
+            }
+        };
+        let neko = tagged(1, &["neko"]).await;
+        let meme = tagged(2, &["neko", "meme"]).await;
+        let maid = tagged(3, &["maid"]).await;
+        let untagged = tagged(4, &[]).await;
+
+        let matching = |query: &[(&str, &str)]| {
+            let filters = query_filters(query);
+            let mut hashes: Vec<String> = (0..40)
+                .map(|_| {
+                    store
+                        .get_random_image_with_strategy(&filters, SelectionStrategy::default())
+                        .unwrap()
+                        .hash
+                })
+                .collect();
+            hashes.sort();
+            hashes.dedup();
+            assert_eq!(
+                store.count_images_with_filters(&filters).unwrap(),
+                hashes.len() as u64
+            );
+            hashes
+        };
+        let sorted = |mut hashes: Vec<&String>| {
+            hashes.sort();
+            hashes.into_iter().cloned().collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            matching(&[("exclude_tags", "meme")]),
+            sorted(vec![&neko, &maid, &untagged])
+        );
+        assert_eq!(
+            matching(&[("tags", "neko"), ("exclude_tags", "meme")]),
+            sorted(vec![&neko])
+        );
+        // spelled as tags are, and any one of them is enough to drop an image
+        assert_eq!(
+            matching(&[("exclude_tags", " MEME, Maid ,")]),
+            sorted(vec![&neko, &untagged])
+        );
+        assert_eq!(
+            matching(&[("exclude_tags", "dog")]),
+            sorted(vec![&neko, &meme, &maid, &untagged])
+        );
+        let everything_excluded = query_filters(&[("tags", "meme"), ("exclude_tags", "neko")]);
+        assert_eq!(
+            store
+                .count_images_with_filters(&everything_excluded)
+                .unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn formats_come_from_contents_and_old_rows_are_backfilled() {
+        let (dir, store) = temp_store();
+        let old = add_png(&store, 1).await;
+        let image = store.get_image_by_hash(&old).unwrap().unwrap();
+        assert_eq!((image.format.as_str(), image.width), ("PNG", 4));
+        // a row from before formats were stored
+        store
+            .pool
+            .get()
+            .unwrap()
+            .execute(
+                "UPDATE images SET format = NULL, width = NULL WHERE hash = ?",
+                [&old],
+            )
+            .unwrap();
+        drop(store);
+
+        let images_dir = dir.path().join("images");
+        let jpeg = crate::test_support::gps_jpeg(2, 10.0, 20.0);
+        std::fs::write(images_dir.join("misnamed.png"), &jpeg).unwrap();
+        let store = ImageStore::new(
+            dir.path().join("images.db").to_str().unwrap(),
+            images_dir,
+            &config(&[]),
+        )
+        .unwrap();
+
+        let stored = |hash: &str| -> (Option<String>, Option<u32>) {
+            store
+                .pool
+                .get()
+                .unwrap()
+                .query_row(
+                    "SELECT format, width FROM images WHERE hash = ?",
+                    [hash],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .unwrap()
+        };
+        assert_eq!(stored(&old), (Some("PNG".to_string()), Some(4)));
+        let misnamed = format!("{:x}", Sha256::digest(&jpeg));
+        assert_eq!(stored(&misnamed).0.as_deref(), Some("JPG"));
+        let image = store.get_image_by_hash(&misnamed).unwrap().unwrap();
+        assert_eq!(image.format, "JPG");
+
+        // responses take the row's values over the file's
+        store
+            .pool
+            .get()
+            .unwrap()
+            .execute("UPDATE images SET width = 99 WHERE hash = ?", [&old])
+            .unwrap();
+        assert_eq!(store.get_image_by_hash(&old).unwrap().unwrap().width, 99);
+    }
+
+    #[tokio::test]
+    async fn images_decoding_to_zero_pixels_are_refused() {
+        // a GIF whose screen and only frame are 0x0
+        let mut gif = b"GIF89a".to_vec();
+        gif.extend_from_slice(&[0, 0, 0, 0, 0x80, 0, 0]);
+        gif.extend_from_slice(&[0, 0, 0, 255, 255, 255]);
+        gif.extend_from_slice(&[0x2C, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        gif.extend_from_slice(&[2, 2, 0x44, 0x01, 0, 0x3B]);
+        let gif = Bytes::from(gif);
+
+        for strict in ["true", "false"] {
+            let (dir, store) = crate::test_support::temp_store_with(&["--strict-decode", strict]);
+            let error = store
+                .add_image_data(&gif, "empty.gif", "image/gif")
+                .await
+                .unwrap_err()
+                .to_string();
+            assert!(error.starts_with("Invalid image"), "{}", error);
+            if strict == "false" {
+                // the header reads fine, so only the size check stops it
+                assert!(error.contains("image is 0x0 pixels"), "{}", error);
+            }
+            let files = std::fs::read_dir(dir.path().join("images"))
+                .unwrap()
+                .count();
+            assert_eq!(files, 0);
         }
+        assert!(check_dimensions((0, 5)).is_err());
+        assert!(check_dimensions((5, 0)).is_err());
+        assert!(check_dimensions((1, 1)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn a_duplicate_racing_an_add_is_reported_and_its_file_removed() {
+        let (dir, store) = temp_store();
+        let images = dir.path().join("images");
+        // stands in for a concurrent add that takes the hash between the
+        // duplicate check and the insert; FAIL keeps the racer's row
+        store
+            .pool
+            .get()
+            .unwrap()
+            .execute_batch(
+                "CREATE TRIGGER racing_add BEFORE INSERT ON images
+                 WHEN NEW.filename != 'racer.png'
+                 BEGIN
+                     INSERT INTO images (filename, hash, created_at, modified_at, uploaded_by)
+                     VALUES ('racer.png', NEW.hash, NEW.created_at, NEW.modified_at, 'racer');
+                     SELECT RAISE(FAIL, 'UNIQUE constraint failed: images.hash');
+                 END;",
+            )
+            .unwrap();
+        let clear_racer = || {
+            store
+                .pool
+                .get()
+                .unwrap()
+                .execute("DELETE FROM images WHERE filename = 'racer.png'", [])
+                .unwrap();
+        };
+
+        let source = dir.path().join("source.png");
+        std::fs::write(&source, png(4, 4, 31)).unwrap();
+        let err = store
+            .add_image(source.to_str().unwrap(), PathType::Local, None, None)
+            .await
+            .unwrap_err();
+        let duplicate = err.downcast_ref::<DuplicateImage>().expect("a duplicate");
+        assert_eq!(duplicate.uploaded_by.as_deref(), Some("racer"));
+        assert_eq!(std::fs::read_dir(&images).unwrap().count(), 0);
+        clear_racer();
+
+        let download = images.join(format!("{}download", TEMP_PREFIX));
+        std::fs::write(&download, png(4, 4, 31)).unwrap();
+        let err = store
+            .add_downloaded_file(&download, None)
+            .await
+            .unwrap_err();
+        let duplicate = err.downcast_ref::<DuplicateImage>().expect("a duplicate");
+        assert_eq!(duplicate.uploaded_by.as_deref(), Some("racer"));
+        assert_eq!(std::fs::read_dir(&images).unwrap().count(), 0);
     }
 }
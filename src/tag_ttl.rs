@@ -0,0 +1,138 @@
+use crate::cache::ImageCache;
+use crate::models::KeyWebhook;
+use crate::store::ImageStore;
+use crate::webhooks::KeyWebhooks;
+use serde_json::json;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Periodically removes images carrying a `TAG_TTLS` tag once they are older
+/// than its age. Does nothing when no TTLs are configured.
+pub fn spawn_reaper(
+    store: ImageStore,
+    cache: ImageCache,
+    webhooks: KeyWebhooks,
+    ttls: Vec<(String, Duration)>,
+    interval: Duration,
+) {
+    if ttls.is_empty() {
+        return;
+    }
+    for (tag, ttl) in &ttls {
+        info!("Images tagged '{}' expire after {}s", tag, ttl.as_secs());
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match sweep(&store, &cache, &ttls).await {
+                Ok(removed) => {
+                    if !removed.is_empty() {
+                        info!("Removed {} images past their tag TTL", removed.len());
+                    }
+                    for (filename, hash, subscriber) in removed {
+                        if let Some(webhook) = subscriber {
+                            webhooks.deliver(
+                                webhook,
+                                "image.deleted",
+                                json!({ "hash": hash, "filename": filename }),
+                            );
+                        }
+                    }
+                }
+                Err(e) => warn!("Tag TTL task failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Runs one `reap` off the async runtime and drops the removed images from
+/// `cache`, so they stop being served straight away.
+async fn sweep(
+    store: &ImageStore,
+    cache: &ImageCache,
+    ttls: &[(String, Duration)],
+) -> Result<Vec<(String, String, Option<KeyWebhook>)>, tokio::task::JoinError> {
+    let reaper_store = store.clone();
+    let reaper_ttls = ttls.to_vec();
+    let removed = tokio::task::spawn_blocking(move || reap(&reaper_store, &reaper_ttls)).await?;
+    for (filename, _, _) in &removed {
+        cache.invalidate(filename).await;
+    }
+    Ok(removed)
+}
+
+/// Removes expired images, returning each one's filename, hash and the
+/// uploader's webhook, if any, to notify.
+fn reap(
+    store: &ImageStore,
+    ttls: &[(String, Duration)],
+) -> Vec<(String, String, Option<KeyWebhook>)> {
+    let mut removed = Vec::new();
+    for (tag, ttl) in ttls {
+        let expired = match store.images_past_tag_ttl(tag, *ttl) {
+            Ok(expired) => expired,
+            Err(e) => {
+                warn!("Failed to find images past the '{}' TTL: {}", tag, e);
+                continue;
+            }
+        };
+        for filename in expired {
+            // looked up first, the uploader is gone with the row
+            let subscriber = store.uploader_webhook(&filename).unwrap_or_else(|e| {
+                warn!("Failed to look up uploader webhook for {}: {}", filename, e);
+                None
+            });
+            match store.remove_image(&filename, false) {
+                Ok(hash) => {
+                    info!("Removed {}, tagged '{}' past its TTL", filename, tag);
+                    removed.push((filename, hash, subscriber));
+                }
+                Err(e) => warn!("Failed to remove expired image {}: {}", filename, e),
+            }
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{add_png, temp_store};
+
+    #[tokio::test]
+    async fn images_past_their_tag_ttl_are_purged_and_uncached() {
+        let (dir, store) = temp_store();
+        let cache = ImageCache::new(10, Duration::from_secs(60), Duration::from_secs(60));
+        let ttls = vec![("ephemeral".to_string(), Duration::from_millis(200))];
+
+        let expiring = add_png(&store, 1).await;
+        let kept = add_png(&store, 2).await;
+        store
+            .add_tags(&expiring, &["ephemeral".to_string()])
+            .unwrap();
+        store.add_tags(&kept, &["keep".to_string()]).unwrap();
+        let image = store.get_image_by_hash(&expiring).unwrap().unwrap();
+        let filename = image.filename.clone();
+        cache.insert(filename.clone(), image).await;
+
+        // still inside its TTL
+        assert!(sweep(&store, &cache, &ttls).await.unwrap().is_empty());
+        assert!(cache.get(&filename).await.is_some());
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let removed = sweep(&store, &cache, &ttls).await.unwrap();
+        let removed: Vec<(&str, &str)> = removed
+            .iter()
+            .map(|(filename, hash, _)| (filename.as_str(), hash.as_str()))
+            .collect();
+        assert_eq!(removed, vec![(filename.as_str(), expiring.as_str())]);
+        assert!(store.get_image_by_hash(&expiring).unwrap().is_none());
+        assert!(!dir.path().join("images").join(&filename).exists());
+        assert!(cache.get(&filename).await.is_none());
+
+        // images without a TTL tag are left alone however old
+        assert!(store.get_image_by_hash(&kept).unwrap().is_some());
+        assert!(sweep(&store, &cache, &ttls).await.unwrap().is_empty());
+    }
+}
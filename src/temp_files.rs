@@ -0,0 +1,73 @@
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+/// Downloads and sidecar writes land in `temp_<uuid>[...]` files in the
+/// images directory before being renamed into place.
+pub const TEMP_PREFIX: &str = "temp_";
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct TempFileStats {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+pub fn is_temp(filename: &str) -> bool {
+    filename.starts_with(TEMP_PREFIX)
+}
+
+pub fn stats(images_dir: &Path) -> std::io::Result<TempFileStats> {
+    let mut stats = TempFileStats::default();
+    for entry in std::fs::read_dir(images_dir)? {
+        let entry = entry?;
+        if is_temp(&entry.file_name().to_string_lossy()) {
+            stats.count += 1;
+            stats.bytes += entry.metadata().map(|m| m.len()).unwrap_or(0);
+        }
+    }
+    Ok(stats)
+}
+
+/// Deletes temp files last modified more than `max_age` ago. Anything still
+/// being written is younger than that, so only crash leftovers go.
+pub fn sweep(images_dir: &Path, max_age: Duration) -> std::io::Result<usize> {
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for entry in std::fs::read_dir(images_dir)? {
+        let entry = entry?;
+        if !is_temp(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        let age = entry
+            .metadata()
+            .and_then(|m| m.modified())
+            .map(|modified| now.duration_since(modified).unwrap_or_default())
+            .unwrap_or_default();
+        if age < max_age {
+            continue;
+        }
+        match std::fs::remove_file(entry.path()) {
+            Ok(()) => removed += 1,
+            Err(e) => warn!("Failed to remove temp file {:?}: {}", entry.path(), e),
+        }
+    }
+    Ok(removed)
+}
+
+/// Periodically sweeps temp files older than `max_age`.
+pub fn spawn_janitor(images_dir: PathBuf, interval: Duration, max_age: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let dir = images_dir.clone();
+            match tokio::task::spawn_blocking(move || sweep(&dir, max_age)).await {
+                Ok(Ok(0)) => {}
+                Ok(Ok(removed)) => info!("Removed {} stale temp files", removed),
+                Ok(Err(e)) => warn!("Failed to sweep temp files: {}", e),
+                Err(e) => warn!("Temp file sweep task failed: {}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,208 @@
+//! Fixtures shared by the unit tests.
+
+use crate::config::Config;
+use crate::models::{ApiKey, ApiKeyScope};
+use crate::store::ImageStore;
+use bytes::Bytes;
+use clap::Parser;
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+use time::OffsetDateTime;
+use tracing::subscriber::DefaultGuard;
+
+/// The config the server would run with given `args` as command line flags.
+pub fn config(args: &[&str]) -> Config {
+    let mut argv = vec!["waifu", "--admin-key", "test-admin"];
+    argv.extend_from_slice(args);
+    Config::try_parse_from(argv).expect("test config")
+}
+
+/// An `ImageStore` over a fresh database and images directory, which live
+/// as long as the returned `TempDir`.
+pub fn temp_store() -> (TempDir, ImageStore) {
+    temp_store_with(&[])
+}
+
+/// `temp_store` with extra config flags.
+pub fn temp_store_with(args: &[&str]) -> (TempDir, ImageStore) {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let db_path = dir.path().join("images.db");
+    let store = ImageStore::new(
+        db_path.to_str().unwrap(),
+        dir.path().join("images"),
+        &config(args),
+    )
+    .expect("test store");
+    (dir, store)
+}
+
+/// A PNG filled with one colour. Different `seed`s give different bytes
+/// and so different hashes.
+pub fn png(width: u32, height: u32, seed: u8) -> Bytes {
+    let img = RgbImage::from_pixel(width, height, Rgb([seed, 255 - seed, seed / 2]));
+    let mut out = Cursor::new(Vec::new());
+    DynamicImage::ImageRgb8(img)
+        .write_to(&mut out, ImageFormat::Png)
+        .expect("encode png");
+    Bytes::from(out.into_inner())
+}
+
+/// Stores a `png` and returns its hash.
+pub async fn add_png(store: &ImageStore, seed: u8) -> String {
+    store
+        .add_image_data(&png(4, 4, seed), "test.png", "image/png")
+        .await
+        .expect("add png")
+}
+
+/// A TIFF block holding only a GPS IFD, with each coordinate given as its
+/// reference letter and degrees, minutes and seconds as rationals.
+pub fn gps_tiff(
+    big_endian: bool,
+    lat: (u8, [(u32, u32); 3]),
+    lon: (u8, [(u32, u32); 3]),
+) -> Vec<u8> {
+    let u16_bytes = |v: u16| {
+        if big_endian {
+            v.to_be_bytes()
+        } else {
+            v.to_le_bytes()
+        }
+    };
+    let u32_bytes = |v: u32| {
+        if big_endian {
+            v.to_be_bytes()
+        } else {
+            v.to_le_bytes()
+        }
+    };
+    // header, then IFD0 with its one entry, then the GPS IFD with four,
+    // then the latitude and longitude rationals
+    let gps_ifd = 8 + 2 + 12 + 4;
+    let rationals = gps_ifd + 2 + 4 * 12 + 4;
+
+    let mut out = Vec::new();
+    out.extend_from_slice(if big_endian { b"MM" } else { b"II" });
+    out.extend_from_slice(&u16_bytes(42));
+    out.extend_from_slice(&u32_bytes(8));
+    out.extend_from_slice(&u16_bytes(1));
+    for v in [0x8825, 4] {
+        out.extend_from_slice(&u16_bytes(v));
+    }
+    out.extend_from_slice(&u32_bytes(1));
+    out.extend_from_slice(&u32_bytes(gps_ifd));
+    out.extend_from_slice(&u32_bytes(0));
+
+    out.extend_from_slice(&u16_bytes(4));
+    for (i, (reference, _)) in [lat, lon].iter().enumerate() {
+        let tag = 1 + 2 * i as u16;
+        for v in [tag, 2] {
+            out.extend_from_slice(&u16_bytes(v));
+        }
+        out.extend_from_slice(&u32_bytes(2));
+        out.extend_from_slice(&[*reference, 0, 0, 0]);
+        for v in [tag + 1, 5] {
+            out.extend_from_slice(&u16_bytes(v));
+        }
+        out.extend_from_slice(&u32_bytes(3));
+        out.extend_from_slice(&u32_bytes(rationals + 24 * i as u32));
+    }
+    out.extend_from_slice(&u32_bytes(0));
+    for (_, parts) in [lat, lon] {
+        for (numerator, denominator) in parts {
+            out.extend_from_slice(&u32_bytes(numerator));
+            out.extend_from_slice(&u32_bytes(denominator));
+        }
+    }
+    out
+}
+
+/// A JPEG whose EXIF says it was taken at `lat`, `lon` (decimal degrees).
+pub fn gps_jpeg(seed: u8, lat: f64, lon: f64) -> Bytes {
+    let coordinate = |value: f64, positive: u8, negative: u8| {
+        let reference = if value < 0.0 { negative } else { positive };
+        let micro_degrees = (value.abs() * 1_000_000.0).round() as u32;
+        (reference, [(micro_degrees, 1_000_000), (0, 1), (0, 1)])
+    };
+    let mut exif = b"Exif\0\0".to_vec();
+    exif.extend(gps_tiff(
+        false,
+        coordinate(lat, b'N', b'S'),
+        coordinate(lon, b'E', b'W'),
+    ));
+
+    let img = RgbImage::from_pixel(4, 4, Rgb([seed, 255 - seed, seed / 2]));
+    let mut out = Cursor::new(Vec::new());
+    DynamicImage::ImageRgb8(img)
+        .write_to(&mut out, ImageFormat::Jpeg)
+        .expect("encode jpeg");
+    let jpeg = out.into_inner();
+    // the APP1 segment goes right after the start of image marker
+    let mut data = jpeg[..2].to_vec();
+    data.extend_from_slice(&[0xFF, 0xE1]);
+    data.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+    data.extend(exif);
+    data.extend_from_slice(&jpeg[2..]);
+    Bytes::from(data)
+}
+
+/// An active key for `username` with every scope and no limits.
+pub fn api_key(username: &str) -> ApiKey {
+    ApiKey {
+        key: format!("{}-key", username),
+        username: username.to_string(),
+        created_at: OffsetDateTime::now_utc(),
+        last_used_at: None,
+        is_active: true,
+        requests_per_second: None,
+        max_batch_size: None,
+        default_filters: None,
+        scopes: ApiKeyScope::ALL.to_vec(),
+        url_ingest_daily_bytes: None,
+        allowed_tags: Vec::new(),
+        is_admin: false,
+    }
+}
+
+/// Warnings and errors logged on this thread while it is alive.
+pub struct CapturedLogs {
+    buffer: Arc<Mutex<Vec<u8>>>,
+    _guard: DefaultGuard,
+}
+
+impl CapturedLogs {
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.buffer.lock().unwrap()).into_owned()
+    }
+}
+
+struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Captures what gets logged at `WARN` and above. Only covers the current
+/// thread, which is all a `#[tokio::test]` runs on.
+pub fn capture_logs() -> CapturedLogs {
+    let buffer = Arc::new(Mutex::new(Vec::new()));
+    let writer = buffer.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::WARN)
+        .with_ansi(false)
+        .with_writer(move || LogBuffer(writer.clone()))
+        .finish();
+    CapturedLogs {
+        buffer,
+        _guard: tracing::subscriber::set_default(subscriber),
+    }
+}
@@ -0,0 +1,107 @@
+use anyhow::{anyhow, Result};
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use url::{Host, Url};
+
+/// Whether `ip` is somewhere a server-side fetch must not reach: loopback,
+/// private, link-local, unique local or otherwise not publicly routable.
+pub fn is_blocked_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_blocked_ipv4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => is_blocked_ipv4(mapped),
+            None => is_blocked_ipv6(ip),
+        },
+    }
+}
+
+fn is_blocked_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // 0.0.0.0/8 reaches the local host on Linux
+        || a == 0
+        // carrier-grade NAT (RFC 6598)
+        || (a == 100 && (64..128).contains(&b))
+        // reserved for future use (RFC 1112)
+        || a >= 240
+}
+
+fn is_blocked_ipv6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // unique local fc00::/7
+        || (first & 0xfe00) == 0xfc00
+        // link-local fe80::/10
+        || (first & 0xffc0) == 0xfe80
+        // site-local fec0::/10, deprecated but still routed by some stacks
+        || (first & 0xffc0) == 0xfec0
+}
+
+/// Fails unless every address `url`'s host stands for is public. IP hosts
+/// are checked as they are, in whatever notation they were written, since
+/// `Url` has already normalized `0x7f.1`, `2130706433` and friends; names
+/// are resolved first.
+pub async fn check_url_host(url: &Url) -> Result<()> {
+    match url.host() {
+        Some(Host::Ipv4(ip)) => check_ip(IpAddr::V4(ip)),
+        Some(Host::Ipv6(ip)) => check_ip(IpAddr::V6(ip)),
+        Some(Host::Domain(domain)) => {
+            let port = url.port_or_known_default().unwrap_or(80);
+            resolve_public(domain, port).await.map(|_| ())
+        }
+        None => Err(anyhow!("URL has no host")),
+    }
+}
+
+fn check_ip(ip: IpAddr) -> Result<()> {
+    if is_blocked_ip(ip) {
+        Err(anyhow!("URL address is not allowed: {}", ip))
+    } else {
+        Ok(())
+    }
+}
+
+async fn resolve_public(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| anyhow!("URL host not found: {} ({})", host, e))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(anyhow!("URL host not found: {}", host));
+    }
+    // one private answer is enough to refuse, the client may pick any
+    if let Some(addr) = addrs.iter().find(|addr| is_blocked_ip(addr.ip())) {
+        return Err(anyhow!(
+            "URL host {} resolves to an address that is not allowed: {}",
+            host,
+            addr.ip()
+        ));
+    }
+    Ok(addrs)
+}
+
+/// DNS resolver for clients that fetch user-supplied URLs. Checking a URL
+/// before the request isn't enough on its own: a redirect can name another
+/// host, and a name can resolve differently by the time the client
+/// connects. Resolving through this refuses private addresses on every
+/// connection. IP hosts never reach a resolver, so redirects to them still
+/// need `is_blocked_ip`.
+pub struct PublicOnlyResolver;
+
+impl Resolve for PublicOnlyResolver {
+    fn resolve(&self, name: hyper::client::connect::dns::Name) -> Resolving {
+        Box::pin(async move {
+            // the connector fills in the port, 0 is replaced
+            let addrs = resolve_public(name.as_str(), 0).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
@@ -0,0 +1,325 @@
+use crate::error::ImageError;
+use serde_json::{json, Value};
+use tracing::warn;
+use warp::http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use warp::http::{HeaderValue, Method};
+use warp::hyper::body::to_bytes;
+use warp::hyper::Body;
+use warp::path::FullPath;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+pub const SUPPORTED_VERSIONS: [u32; 2] = [1, 2];
+pub const DEFAULT_VERSION: u32 = 1;
+
+/// Outcome of each entry of a batch add, in request order. Attached to the
+/// v1 response so the v2 adapter can report per-item results.
+#[derive(Debug, Clone)]
+pub struct BatchItemResults(pub Vec<Value>);
+
+/// Responses whose v2 shape differs from v1.
+enum V2Shape {
+    /// An image object, or an add response, with `tags` at the top level
+    Image,
+    /// An `images` array of image objects
+    ImageList,
+    BatchAdd,
+}
+
+fn v2_shape(method: &Method, path: &str) -> Option<V2Shape> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        (&Method::GET, ["random"])
+        | (&Method::GET, ["images", _])
+        | (&Method::POST, ["image"])
+        | (&Method::POST, ["upload"]) => Some(V2Shape::Image),
+        (&Method::POST, ["random"]) | (&Method::GET, ["images"]) => Some(V2Shape::ImageList),
+        (&Method::POST, ["images"]) => Some(V2Shape::BatchAdd),
+        _ => None,
+    }
+}
+
+fn parse_version(header: Option<&str>) -> Result<u32, ImageError> {
+    let Some(raw) = header.map(str::trim) else {
+        return Ok(DEFAULT_VERSION);
+    };
+    raw.trim_start_matches(['v', 'V'])
+        .parse::<u32>()
+        .ok()
+        .filter(|version| SUPPORTED_VERSIONS.contains(version))
+        .ok_or_else(|| ImageError::UnsupportedVersion(raw.to_string()))
+}
+
+/// States the version a response was served in. Requests for unsupported
+/// versions were answered in the default version's error shape.
+pub fn add_version_header<T: Reply>(reply: T, accept_version: Option<String>) -> Response {
+    let version = parse_version(accept_version.as_deref()).unwrap_or(DEFAULT_VERSION);
+    let mut response = reply.into_response();
+    response
+        .headers_mut()
+        .insert("X-API-Version", HeaderValue::from(version));
+    response
+}
+
+/// Serves `routes` in the version asked for with `Accept-Version`. Handlers
+/// always produce v1 shapes; newer shapes are mapped from them here.
+pub fn with_api_version<F, T>(
+    routes: F,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (T,), Error = Rejection> + Clone + Send + Sync + 'static,
+    T: Reply,
+{
+    warp::header::optional::<String>("accept-version")
+        .and_then(|header: Option<String>| async move {
+            parse_version(header.as_deref()).map_err(warp::reject::custom)
+        })
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(routes)
+        .and_then(
+            |version: u32, method: Method, path: FullPath, reply: T| async move {
+                Ok::<Response, Rejection>(
+                    adapt(reply.into_response(), version, &method, path.as_str()).await,
+                )
+            },
+        )
+}
+
+async fn adapt(response: Response, version: u32, method: &Method, path: &str) -> Response {
+    if version < 2 || !response.status().is_success() {
+        return response;
+    }
+    let Some(shape) = v2_shape(method, path) else {
+        return response;
+    };
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"application/json"));
+    if !is_json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let items = parts.extensions.remove::<BatchItemResults>();
+    let body = match to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Failed to buffer response for version mapping: {}", e);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    let adapted = serde_json::from_slice::<Value>(&body)
+        .and_then(|value| serde_json::to_vec(&to_v2(shape, value, items)));
+    match adapted {
+        Ok(adapted) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            Response::from_parts(parts, Body::from(adapted))
+        }
+        Err(e) => {
+            warn!("Failed to map response to v2: {}", e);
+            Response::from_parts(parts, Body::from(body))
+        }
+    }
+}
+
+fn to_v2(shape: V2Shape, mut value: Value, items: Option<BatchItemResults>) -> Value {
+    match shape {
+        V2Shape::Image => tags_as_objects(&mut value),
+        V2Shape::ImageList => {
+            if let Some(images) = value.get_mut("images").and_then(Value::as_array_mut) {
+                images.iter_mut().for_each(tags_as_objects);
+            }
+        }
+        V2Shape::BatchAdd => {
+            let mut items = match items {
+                Some(BatchItemResults(items)) => items,
+                // replayed idempotent responses only kept the body, so
+                // rebuild the items from v1's grouping
+                None => batch_items_from_v1(&value),
+            };
+            items.iter_mut().for_each(tags_as_objects);
+            if let Some(object) = value.as_object_mut() {
+                object.remove("errors");
+                object.insert("results".to_string(), Value::Array(items));
+            }
+        }
+    }
+    value
+}
+
+/// v2 tags are objects, leaving room for more than the name.
+fn tags_as_objects(image: &mut Value) {
+    if let Some(tags) = image.get_mut("tags").and_then(Value::as_array_mut) {
+        for tag in tags.iter_mut() {
+            if let Some(name) = tag.as_str().map(str::to_string) {
+                *tag = json!({ "name": name });
+            }
+        }
+    }
+}
+
+fn batch_items_from_v1(value: &Value) -> Vec<Value> {
+    let added = value["results"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|result| {
+            let mut item = result.clone();
+            item["status"] = json!("ok");
+            item
+        });
+    let failed = value["errors"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|error| json!({ "status": "error", "error": error }));
+    added.chain(failed).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image() -> Value {
+        json!({"filename": "a.png", "hash": "aa", "tags": ["cat", "dog"]})
+    }
+
+    async fn body_of(
+        filter: &(impl Filter<Extract = (Response,), Error = Rejection> + 'static),
+        method: &str,
+        path: &str,
+        version: Option<&str>,
+    ) -> (Option<HeaderValue>, Value) {
+        let mut request = warp::test::request().method(method).path(path);
+        if let Some(version) = version {
+            request = request.header("accept-version", version);
+        }
+        let response = request.filter(filter).await.unwrap();
+        let version = add_version_header(response, version.map(str::to_string));
+        let header = version.headers().get("X-API-Version").cloned();
+        let body = to_bytes(version.into_body()).await.unwrap();
+        (header, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn v1_is_served_unchanged_and_v2_gets_tag_objects() {
+        let random = warp::path("random")
+            .and(warp::get())
+            .map(|| warp::reply::json(&image()))
+            .or(warp::path("images")
+                .and(warp::get())
+                .map(|| warp::reply::json(&json!({"images": [image()], "total": 1}))))
+            .unify();
+        let filter = with_api_version(random);
+
+        for version in [None, Some("1"), Some("v1")] {
+            let (header, body) = body_of(&filter, "GET", "/random", version).await;
+            assert_eq!(header.unwrap(), "1");
+            assert_eq!(body, image());
+        }
+
+        let (header, body) = body_of(&filter, "GET", "/random", Some("2")).await;
+        assert_eq!(header.unwrap(), "2");
+        assert_eq!(
+            body,
+            json!({
+                "filename": "a.png",
+                "hash": "aa",
+                "tags": [{"name": "cat"}, {"name": "dog"}]
+            })
+        );
+
+        let (_, body) = body_of(&filter, "GET", "/images", Some("V2")).await;
+        assert_eq!(
+            body,
+            json!({
+                "images": [{
+                    "filename": "a.png",
+                    "hash": "aa",
+                    "tags": [{"name": "cat"}, {"name": "dog"}]
+                }],
+                "total": 1
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn v2_batch_adds_report_every_item_in_order() {
+        let v1 = json!({
+            "message": "1 added",
+            "results": [{"hash": "aa", "tags": ["cat"]}],
+            "errors": ["bad file"]
+        });
+        let items = vec![
+            json!({"status": "error", "error": "bad file"}),
+            json!({"hash": "aa", "tags": ["cat"], "status": "ok"}),
+        ];
+        let expected = json!({
+            "message": "1 added",
+            "results": [
+                {"status": "error", "error": "bad file"},
+                {"hash": "aa", "tags": [{"name": "cat"}], "status": "ok"}
+            ]
+        });
+
+        let batch = {
+            let v1 = v1.clone();
+            let items = items.clone();
+            warp::path("images").and(warp::post()).map(move || {
+                let mut reply = warp::reply::json(&v1).into_response();
+                reply
+                    .extensions_mut()
+                    .insert(BatchItemResults(items.clone()));
+                reply
+            })
+        };
+        let filter = with_api_version(batch);
+        assert_eq!(body_of(&filter, "POST", "/images", None).await.1, v1);
+        assert_eq!(
+            body_of(&filter, "POST", "/images", Some("2")).await.1,
+            expected
+        );
+
+        // a replayed response has lost its items, so they come from v1's
+        // grouping, added before failed
+        let replayed = {
+            let v1 = v1.clone();
+            warp::path("images")
+                .and(warp::post())
+                .map(move || warp::reply::json(&v1))
+        };
+        let (_, body) = body_of(&with_api_version(replayed), "POST", "/images", Some("2")).await;
+        assert_eq!(
+            body["results"],
+            json!([
+                {"hash": "aa", "tags": [{"name": "cat"}], "status": "ok"},
+                {"status": "error", "error": "bad file"}
+            ])
+        );
+        assert!(body.get("errors").is_none());
+    }
+
+    #[tokio::test]
+    async fn unsupported_versions_are_rejected() {
+        let filter = with_api_version(warp::path("random").map(|| warp::reply::json(&image())));
+        for version in ["3", "0", "latest"] {
+            let rejection = warp::test::request()
+                .path("/random")
+                .header("accept-version", version)
+                .filter(&filter)
+                .await
+                .err()
+                .unwrap();
+            assert!(matches!(
+                rejection.find::<ImageError>(),
+                Some(ImageError::UnsupportedVersion(v)) if v == version
+            ));
+        }
+
+        // the error itself goes out in the default version
+        let response = add_version_header(warp::reply(), Some("3".to_string()));
+        assert_eq!(response.headers()["X-API-Version"], "1");
+    }
+}
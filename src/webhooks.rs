@@ -0,0 +1,192 @@
+use crate::models::KeyWebhook;
+use crate::presign::{hmac_sha256, to_hex};
+use crate::url_guard::PublicOnlyResolver;
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::{info, warn};
+use url::Url;
+use uuid::Uuid;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+const EVENT_HEADER: &str = "X-Webhook-Event";
+
+/// Events a key's webhook can receive. Each one is only ever about an image
+/// that key uploaded.
+pub const KEY_WEBHOOK_EVENTS: [&str; 1] = ["image.deleted"];
+
+/// Sends signed events to the webhooks keys register for their own uploads.
+/// Bodies are signed with the webhook's secret as
+/// `X-Webhook-Signature: sha256=<hex HMAC-SHA256>`.
+#[derive(Clone)]
+pub struct KeyWebhooks {
+    client: reqwest::Client,
+}
+
+impl KeyWebhooks {
+    /// Fails rather than fall back to a client without the public-only
+    /// resolver.
+    pub fn new() -> Result<Self> {
+        // a validated URL must not be able to bounce us somewhere internal
+        let client = reqwest::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none())
+            .dns_resolver(Arc::new(PublicOnlyResolver))
+            .build()
+            .map_err(|e| anyhow!("Failed to build webhook client: {}", e))?;
+        Ok(Self { client })
+    }
+
+    pub fn new_secret() -> String {
+        Uuid::new_v4().simple().to_string()
+    }
+
+    /// Proves the caller controls `url`: it must answer a signed
+    /// `webhook.challenge` event by echoing the challenge back as the body.
+    pub async fn verify(&self, url: &Url, secret: &str) -> Result<()> {
+        let challenge = Uuid::new_v4().simple().to_string();
+        let body = json!({
+            "event": "webhook.challenge",
+            "challenge": challenge,
+            "timestamp": now()
+        });
+        let response = self
+            .signed_post(url.as_str(), secret, "webhook.challenge", body)
+            .send()
+            .await
+            .map_err(|e| anyhow!("challenge request failed: {}", e))?;
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "challenge request returned status code: {}",
+                response.status()
+            ));
+        }
+        let answer = response.text().await?;
+        if answer.trim() != challenge {
+            return Err(anyhow!("response body did not echo the challenge"));
+        }
+        Ok(())
+    }
+
+    /// Sends `event` in the background; failed deliveries are only logged.
+    pub fn deliver(&self, webhook: KeyWebhook, event: &'static str, data: Value) {
+        let body = json!({
+            "event": event,
+            "data": data,
+            "timestamp": now()
+        });
+        let request = self.signed_post(&webhook.url, &webhook.secret, event, body);
+        tokio::spawn(async move {
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    info!("Delivered {} webhook to {}", event, webhook.username)
+                }
+                Ok(response) => warn!(
+                    "Webhook for {} answered {} to {}",
+                    webhook.username,
+                    response.status(),
+                    event
+                ),
+                Err(e) => warn!(
+                    "Failed to deliver {} webhook to {}: {}",
+                    event, webhook.username, e
+                ),
+            }
+        });
+    }
+
+    fn signed_post(
+        &self,
+        url: &str,
+        secret: &str,
+        event: &str,
+        body: Value,
+    ) -> reqwest::RequestBuilder {
+        let body = body.to_string();
+        let signature = to_hex(&hmac_sha256(secret.as_bytes(), body.as_bytes()));
+        self.client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, format!("sha256={}", signature))
+            .header(EVENT_HEADER, event)
+            .body(body)
+    }
+}
+
+fn now() -> String {
+    OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use tokio::sync::mpsc;
+    use warp::Filter;
+
+    /// A webhook receiver on localhost that sends every request's
+    /// signature and body to the returned channel and answers with
+    /// `reply(body)`.
+    fn receiver(reply: fn(&Value) -> String) -> (Url, mpsc::UnboundedReceiver<(String, Bytes)>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let route = warp::post()
+            .and(warp::header::<String>(SIGNATURE_HEADER))
+            .and(warp::body::bytes())
+            .map(move |signature: String, body: Bytes| {
+                let json: Value = serde_json::from_slice(&body).unwrap();
+                tx.send((signature, body)).unwrap();
+                reply(&json)
+            });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let url = Url::parse(&format!("http://{}/hook", addr)).unwrap();
+        (url, rx)
+    }
+
+    fn echo(body: &Value) -> String {
+        body["challenge"].as_str().unwrap_or_default().to_string()
+    }
+
+    #[tokio::test]
+    async fn challenge_must_be_echoed() {
+        let webhooks = KeyWebhooks::new().unwrap();
+        let (url, mut requests) = receiver(echo);
+        webhooks.verify(&url, "secret").await.unwrap();
+
+        let (signature, body) = requests.recv().await.unwrap();
+        let expected = to_hex(&hmac_sha256(b"secret", &body));
+        assert_eq!(signature, format!("sha256={}", expected));
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["event"], "webhook.challenge");
+
+        let (url, _requests) = receiver(|_| "something else".to_string());
+        let err = webhooks.verify(&url, "secret").await.unwrap_err();
+        assert!(err.to_string().contains("echo"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_signed_with_the_webhook_secret() {
+        let webhooks = KeyWebhooks::new().unwrap();
+        let (url, mut requests) = receiver(|_| String::new());
+        let webhook = KeyWebhook {
+            username: "alice".to_string(),
+            url: url.to_string(),
+            secret: "alice-secret".to_string(),
+            created_at: now(),
+        };
+        webhooks.deliver(webhook, "image.deleted", json!({ "hash": "abc" }));
+
+        let (signature, body) = requests.recv().await.unwrap();
+        let expected = to_hex(&hmac_sha256(b"alice-secret", &body));
+        assert_eq!(signature, format!("sha256={}", expected));
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["event"], "image.deleted");
+        assert_eq!(body["data"]["hash"], "abc");
+    }
+}
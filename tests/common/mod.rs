@@ -0,0 +1,53 @@
+//! Starts the server binary for the integration tests.
+
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::net::TcpStream;
+
+/// The admin key the test server is started with.
+pub const ADMIN_KEY: &str = "test-admin";
+
+/// The server running in its own working directory, killed on drop.
+pub struct Server {
+    child: Child,
+    pub port: u16,
+    _dir: TempDir,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub async fn start_server() -> Server {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("free port")
+        .port();
+    let dir = tempfile::tempdir().expect("temp dir");
+    let child = Command::new(env!("CARGO_BIN_EXE_waifu"))
+        .args(["--admin-key", ADMIN_KEY, "--port", &port.to_string()])
+        .current_dir(dir.path())
+        .env_remove("ADMIN_KEY")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("start server");
+    let server = Server {
+        child,
+        port,
+        _dir: dir,
+    };
+
+    for _ in 0..100 {
+        if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+            return server;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("server didn't start listening on port {}", port);
+}
@@ -0,0 +1,56 @@
+//! Drives the server binary over cleartext HTTP/2 and HTTP/1.1 on the same
+//! listener.
+
+mod common;
+
+use common::start_server;
+use futures_util::future::{join_all, poll_fn};
+use hyper::{Body, Request, StatusCode, Version};
+use tokio::net::TcpStream;
+
+#[tokio::test]
+async fn h2c_multiplexes_requests_next_to_http1() {
+    let server = start_server().await;
+
+    // one TCP connection, spoken to with HTTP/2 prior knowledge
+    let stream = TcpStream::connect(("127.0.0.1", server.port))
+        .await
+        .unwrap();
+    let (mut sender, connection) = hyper::client::conn::Builder::new()
+        .http2_only(true)
+        .handshake::<_, Body>(stream)
+        .await
+        .expect("h2c handshake");
+    tokio::spawn(connection);
+
+    // every request is sent before any response is awaited, so they share
+    // the connection as concurrent streams
+    let mut responses = Vec::new();
+    for i in 0..32 {
+        let path = if i % 2 == 0 { "/health" } else { "/" };
+        let request = Request::get(format!("http://127.0.0.1:{}{}", server.port, path))
+            .body(Body::empty())
+            .unwrap();
+        poll_fn(|cx| sender.poll_ready(cx))
+            .await
+            .expect("connection open");
+        responses.push(sender.send_request(request));
+    }
+    let responses = join_all(responses).await;
+    for response in responses {
+        let response = response.expect("stream on the shared connection");
+        assert_eq!(response.version(), Version::HTTP_2);
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let response = hyper::Client::new()
+        .get(
+            format!("http://127.0.0.1:{}/health", server.port)
+                .parse()
+                .unwrap(),
+        )
+        .await
+        .expect("HTTP/1.1 request");
+    assert_eq!(response.version(), Version::HTTP_11);
+    assert_eq!(response.status(), StatusCode::OK);
+}
@@ -0,0 +1,93 @@
+//! Presigns an upload and sends it through the unauthenticated `PUT` route.
+
+mod common;
+
+use common::{start_server, ADMIN_KEY};
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Value};
+use std::io::Cursor;
+
+fn png() -> Vec<u8> {
+    let image = image::RgbImage::from_fn(8, 8, |x, y| image::Rgb([x as u8 * 30, y as u8 * 30, 90]));
+    let mut bytes = Vec::new();
+    image::DynamicImage::ImageRgb8(image)
+        .write_to(&mut Cursor::new(&mut bytes), image::ImageOutputFormat::Png)
+        .expect("encode png");
+    bytes
+}
+
+/// Presigns a PNG upload and returns the `PUT` URL on the test server.
+async fn presign(client: &Client, port: u16, max_size_bytes: u64) -> String {
+    let response = client
+        .post(format!("http://127.0.0.1:{}/upload/presign", port))
+        .bearer_auth(ADMIN_KEY)
+        .header("Content-Type", "application/json")
+        .body(
+            json!({
+                "content_type": "image/png",
+                "tags": ["neko"],
+                "max_size_bytes": max_size_bytes
+            })
+            .to_string(),
+        )
+        .send()
+        .await
+        .expect("presign request");
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let body: Value =
+        serde_json::from_slice(&response.bytes().await.expect("presign body")).expect("json");
+    let url = body["url"].as_str().expect("url");
+    let token = url.rsplit('/').next().expect("token");
+    format!("http://127.0.0.1:{}/upload/presigned/{}", port, token)
+}
+
+async fn put(client: &Client, url: &str, content_type: &str, body: Vec<u8>) -> StatusCode {
+    client
+        .put(url)
+        .header("Content-Type", content_type)
+        .body(body)
+        .send()
+        .await
+        .expect("presigned upload")
+        .status()
+}
+
+#[tokio::test]
+async fn presigned_urls_take_one_matching_upload() {
+    let server = start_server().await;
+    let client = Client::new();
+    let png = png();
+
+    let url = presign(&client, server.port, png.len() as u64).await;
+    assert_eq!(
+        put(&client, &url, "image/jpeg", png.clone()).await,
+        StatusCode::FORBIDDEN
+    );
+    // a refused upload doesn't use up the URL
+    assert_eq!(
+        put(&client, &url, "image/png", png.clone()).await,
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        put(&client, &url, "image/png", png.clone()).await,
+        StatusCode::FORBIDDEN
+    );
+
+    let small = presign(&client, server.port, png.len() as u64 - 1).await;
+    assert_eq!(
+        put(&client, &small, "image/png", png.clone()).await,
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+
+    let listing = client
+        .get(format!("http://127.0.0.1:{}/images", server.port))
+        .bearer_auth(ADMIN_KEY)
+        .send()
+        .await
+        .expect("list images")
+        .bytes()
+        .await
+        .expect("listing body");
+    let listing: Value = serde_json::from_slice(&listing).expect("json");
+    assert_eq!(listing["total"], 1, "{}", listing);
+}